@@ -1,4 +1,4 @@
-use clap::{Arg, Command, value_parser};
+use clap::{Arg, ArgAction, Command, value_parser};
 use std::path::PathBuf;
 
 // Way over engineered sure, but very ergonomic for future uses
@@ -7,6 +7,12 @@ struct SubCommand;
 enum EV {
     PATH,
     NAME,
+    // Everything after a literal `--`, handed through untouched (e.g. a command to run)
+    TRAILING,
+    // --key <value>
+    OPTION,
+    // --key on its own, no value
+    FLAG,
 }
 
 impl SubCommand {
@@ -18,17 +24,44 @@ impl SubCommand {
         let mut comm = Command::new(name).about(about);
 
         for (key, req, value) in args {
-            let par = match value {
-                EV::PATH => value_parser!(PathBuf),
-                EV::NAME => value_parser!(String),
+            let arg = match value {
+                EV::PATH => Arg::new(key).value_parser(value_parser!(PathBuf)),
+                EV::NAME => Arg::new(key).value_parser(value_parser!(String)),
+                EV::TRAILING => Arg::new(key)
+                    .value_parser(value_parser!(String))
+                    .num_args(1..)
+                    .last(true),
+                EV::OPTION => Arg::new(key).long(key).value_parser(value_parser!(String)),
+                EV::FLAG => Arg::new(key).long(key).action(ArgAction::SetTrue),
             };
 
-            comm = comm.arg(Arg::new(key).required(req).value_parser(par));
+            comm = comm.arg(arg.required(req));
         }
         comm
     }
 }
 
+// Options that work with every subcommand
+pub fn global_args() -> Vec<Arg> {
+    vec![
+        Arg::new("output")
+            .long("output")
+            .global(true)
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .help(
+                "text for people, json for scripts. Errors are printed as JSON to stderr as well.",
+            ),
+        Arg::new("allow-unsigned")
+            .long("allow-unsigned")
+            .global(true)
+            .action(ArgAction::SetTrue)
+            .help(
+                "reads unsigned entries even though every entry of the database is signed. Only use it for entries you checked.",
+            ),
+    ]
+}
+
 pub fn add_all_comm() -> Vec<Command> {
     vec![
         SubCommand::new(
@@ -46,6 +79,46 @@ pub fn add_all_comm() -> Vec<Command> {
             "dumps all blocks into individual env files from the namespace to current working directory",
             vec![("name", true, EV::NAME)],
         ),
+        SubCommand::new(
+            "run",
+            "runs a command with the variables of a stored .env entry set, without writing the entry to disk. Usage: clenv run <entry> -- <cmd>",
+            vec![("entry", true, EV::NAME), ("cmd", true, EV::TRAILING)],
+        ),
+        SubCommand::new(
+            "get",
+            "prints a single variable from a stored .env entry.",
+            vec![("entry", true, EV::NAME), ("var", true, EV::NAME)],
+        ),
+        SubCommand::new(
+            "set",
+            "sets a single variable in a stored .env entry, adding it if it doesn't exist yet.",
+            vec![
+                ("entry", true, EV::NAME),
+                ("var", true, EV::NAME),
+                ("value", true, EV::NAME),
+            ],
+        ),
+        SubCommand::new(
+            "history",
+            "lists every stored revision of an entry with when and by whom it was stored.",
+            vec![("entry", true, EV::NAME)],
+        ),
+        SubCommand::new(
+            "rollback",
+            "restores an older revision of an entry. The revision number can be found with 'clenv history <entry>'.",
+            vec![("entry", true, EV::NAME), ("rev", true, EV::NAME)],
+        ),
+        SubCommand::new(
+            "diff",
+            "compares two entries, or an entry with a local file. .env files are compared variable by variable. Use --ns and --other-ns to compare across namespaces and --mask to hide values.",
+            vec![
+                ("entry", true, EV::NAME),
+                ("other", true, EV::NAME),
+                ("ns", false, EV::OPTION),
+                ("other-ns", false, EV::OPTION),
+                ("mask", false, EV::FLAG),
+            ],
+        ),
         SubCommand::new(
             "show",
             "shows the currently selected database, users who have access, and available namespaces. Put the name of the namespace to instead list the namespace from a different namespace.",
@@ -58,13 +131,121 @@ pub fn add_all_comm() -> Vec<Command> {
         ),
         SubCommand::new(
             "add",
-            "adds a user to the keyring. Provide the name of the user their public and private key will be added.",
-            vec![("name", true, EV::NAME)],
+            "adds a user to the keyring. Pass the public key they made with 'clenv keygen' using --pubkey, otherwise a new key pair is generated and their private key is written to the current directory. Works on the current namespace unless --all-namespaces or --ns a,b is given.",
+            vec![
+                ("name", true, EV::NAME),
+                ("pubkey", false, EV::OPTION),
+                ("all-namespaces", false, EV::FLAG),
+                ("ns", false, EV::OPTION),
+            ],
+        ),
+        SubCommand::new(
+            "keygen",
+            "generates your own key pair locally (at the configured private_key path unless a file is given) and writes the public key next to it to share with the database owner.",
+            vec![("file", false, EV::NAME)],
+        ),
+        SubCommand::new(
+            "key",
+            "manages your private key. 'clenv key passwd' changes the passphrase of the configured private key (or the file you give it). An empty passphrase removes it. 'clenv key id' prints the id of your key, or of the public key file you give it, for others to check the keyring against.",
+            vec![("action", true, EV::NAME), ("file", false, EV::NAME)],
+        ),
+        SubCommand::new(
+            "agent",
+            "keeps your unlocked private key in memory so the passphrase is typed once. 'clenv agent' starts it (--timeout in seconds, default 600), 'clenv agent status|lock|unlock|stop' controls a running one.",
+            vec![("action", false, EV::NAME), ("timeout", false, EV::OPTION)],
         ),
         SubCommand::new(
             "remove",
-            "removes a user to the keyring. Just include the name.",
-            vec![("name", true, EV::NAME)],
+            "removes a user to the keyring. Just include the name. Add --rekey to also move every entry they could read to a new encryption key. Works on the current namespace unless --all-namespaces or --ns a,b is given.",
+            vec![
+                ("name", true, EV::NAME),
+                ("rekey", false, EV::FLAG),
+                ("all-namespaces", false, EV::FLAG),
+                ("ns", false, EV::OPTION),
+            ],
+        ),
+        SubCommand::new(
+            "dictionary",
+            "'clenv dictionary train' trains a zstd dictionary on the entries of the current namespace that everyone in the keyring can read. Entries stored in the namespace afterwards are compressed with it, which makes small .env files a lot smaller.",
+            vec![("action", true, EV::NAME)],
+        ),
+        SubCommand::new(
+            "obfuscate",
+            "encrypts the names of namespaces and entries of a database made before names were hidden, so only users in the keyring can list them.",
+            vec![],
+        ),
+        SubCommand::new(
+            "reseal",
+            "binds entries stored by older versions of clenv to their namespace, name and extension and signs the unsigned ones, so changing, moving or renaming them inside the database is detected. Works on the current namespace unless --all-namespaces or --ns a,b is given.",
+            vec![
+                ("all-namespaces", false, EV::FLAG),
+                ("ns", false, EV::OPTION),
+            ],
+        ),
+        SubCommand::new(
+            "verify",
+            "checks that entries were signed by a user in the keyring and weren't changed since. Checks a single entry when given, otherwise the current namespace unless --all-namespaces or --ns a,b is given. --strict also fails on unsigned entries.",
+            vec![
+                ("entry", false, EV::NAME),
+                ("all-namespaces", false, EV::FLAG),
+                ("ns", false, EV::OPTION),
+                ("strict", false, EV::FLAG),
+            ],
+        ),
+        SubCommand::new(
+            "fsck",
+            "checks that every entry, old revision and dictionary of the database can be read, that their keys match the keyring and that you can decrypt the ones you have access to, and looks for leftover chunks. --repair drops keys of users who left the keyring, gives keyring members a key for every entry you can decrypt and deletes leftover chunks.",
+            vec![("repair", false, EV::FLAG)],
+        ),
+        SubCommand::new(
+            "merge",
+            "merges the database at <path> into yours: users of its keyring you don't have are added and get access to every entry you can read, users with another key are reported and keep yours, and its entries are copied over like 'clenv import' would import them. --on-conflict skip|overwrite|keep-newest decides about entries both databases have, skip is the default. The other database isn't changed.",
+            vec![("path", true, EV::NAME), ("on-conflict", false, EV::OPTION)],
+        ),
+        SubCommand::new(
+            "push",
+            "copies what changed in your database since the last push or pull to the bucket set with 'clenv cfg remote https://host/bucket/prefix'. Everything stays encrypted. Entries somebody else changed on the remote in the meantime are reported and left alone, pull first. --force overwrites them.",
+            vec![("force", false, EV::FLAG)],
+        ),
+        SubCommand::new(
+            "pull",
+            "brings in what changed on the remote since the last push or pull. Entries you changed as well are reported and kept as they are, --force takes the remote's version. Keyring changes are listed with their key ids and only applied with --accept-keyring. Without a database, lists the remote's keyring, and copies the database with --accept-keyring.",
+            vec![
+                ("force", false, EV::FLAG),
+                ("accept-keyring", false, EV::FLAG),
+            ],
+        ),
+        SubCommand::new(
+            "migrate",
+            "upgrades a database written by an older clenv to the current format. Entries are rewritten without being decrypted, so everyone's entries are upgraded. The database is copied to <db>.backup-<time> first unless --backup <path> or --no-backup is given. --dry-run only tells what would change.",
+            vec![
+                ("dry-run", false, EV::FLAG),
+                ("backup", false, EV::OPTION),
+                ("no-backup", false, EV::FLAG),
+            ],
+        ),
+        SubCommand::new(
+            "export",
+            "writes the entries of the current namespace, or of --all-namespaces or --ns a,b, to a bundle file that 'clenv import' reads on another machine. --entries a,b picks entries. Entries stay encrypted and the keyring is included. With --age, writes the entry <target> of the current namespace as an age file that the users with access to it can open with the age CLI instead. Only X25519 and age keys can be encrypted to, RSA users are left out. Written to <entry>.age unless --out is given.",
+            vec![
+                ("target", true, EV::NAME),
+                ("all-namespaces", false, EV::FLAG),
+                ("ns", false, EV::OPTION),
+                ("entries", false, EV::OPTION),
+                ("age", false, EV::FLAG),
+                ("out", false, EV::OPTION),
+            ],
+        ),
+        SubCommand::new(
+            "import",
+            "stores the entries of a bundle written by 'clenv export' in their namespaces and gives everyone in the keyring access to them. --on-conflict skip|overwrite|keep-newest decides about entries that already exist, skip is the default. Entries must be signed by someone in your keyring with the key you have for them, --trust-bundle-keys checks the others with the bundle's keyring and accepts unsigned ones. With --age, decrypts an age file encrypted to your X25519 or age key and stores it in the current namespace, named after the file without .age unless a name is given.",
+            vec![
+                ("file", true, EV::NAME),
+                ("name", false, EV::NAME),
+                ("age", false, EV::FLAG),
+                ("on-conflict", false, EV::OPTION),
+                ("trust-bundle-keys", false, EV::FLAG),
+            ],
         ),
     ]
 }
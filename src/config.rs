@@ -1,4 +1,5 @@
 pub mod config;
+pub use config::Config;
 pub use config::Config as conf;
 mod path_utils;
 pub use path_utils::resolve_path;
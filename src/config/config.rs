@@ -1,8 +1,6 @@
-use super::path_utils::resolve_path;
+use crate::error::ClenvError;
 use configparser::ini::Ini;
-use std::error::Error;
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 const CONFIG_DIR: &str = "clenv";
 const CONFIG_FILE: &str = "config.ini";
@@ -16,56 +14,28 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn init() -> Result<Self, Box<dyn Error>> {
-        fn prompt(label: &str) -> Result<String, io::Error> {
-            print!("{}: ", label);
-            io::stdout().flush()?;
-            let mut buf = String::new();
-            io::stdin().read_line(&mut buf)?;
-            Ok(buf.trim().to_string())
-        }
-
-        fn prompt_path(label: &str, file_ext: &str) -> Result<String, io::Error> {
-            print!("{}: ", label);
-            io::stdout().flush()?;
-
-            let mut buf = String::new();
-            io::stdin().read_line(&mut buf)?;
-            let input = buf.trim();
-            Ok(resolve_path(input, file_ext)
-                .into_os_string()
-                .into_string()
-                .unwrap())
-        }
-
-        let name = prompt("Enter your name")?;
-        let db = prompt_path("Enter database name", "")?;
-        let private_key = prompt_path(
-            "Enter the location of your private key file (or just file name in the current directory)",
-            "pem",
-        )?;
-        let ns = prompt("Enter the namespace")?;
-
+    pub fn new(name: &str, db: &str, private_key: &str, ns: &str) -> Self {
         let mut ini = Ini::new();
-        ini.set(SECTION, "name", Some(name));
-        ini.set(SECTION, "db", Some(db));
-        ini.set(SECTION, "private_key", Some(private_key));
-        ini.set(SECTION, "ns", Some(ns));
-
-        let config = Config { ini };
-        config.save()?;
+        ini.set(SECTION, "name", Some(name.to_string()));
+        ini.set(SECTION, "db", Some(db.to_string()));
+        ini.set(SECTION, "private_key", Some(private_key.to_string()));
+        ini.set(SECTION, "ns", Some(ns.to_string()));
 
-        Ok(config)
+        Config { ini }
     }
 
-    pub fn load() -> Result<Config, Box<dyn Error>> {
+    pub fn load() -> Result<Config, ClenvError> {
         let path = config_file_path()?;
         if !path.exists() {
-            return Err(format!("Config file not found at {}", path.display()).into());
+            return Err(ClenvError::Config(format!(
+                "Config file not found at {}",
+                path.display()
+            )));
         }
 
         let mut ini = Ini::new();
-        ini.load(&path)?;
+        ini.load(&path)
+            .map_err(|e| ClenvError::Config(format!("Could not read {}: {}", path.display(), e)))?;
         Ok(Config { ini })
     }
 
@@ -73,43 +43,58 @@ impl Config {
         self.ini.get("default", key)
     }
 
-    pub fn list_all(&self) {
+    // Same as get, for keys clenv can't work without
+    pub fn require(&self, key: &str) -> Result<String, ClenvError> {
+        self.get(key).ok_or_else(|| {
+            ClenvError::Config(format!(
+                "Missing configuration '{}'. Try running 'clenv cfg init' to reset your settings.",
+                key
+            ))
+        })
+    }
+
+    // Every key in the config with its value
+    pub fn entries(&self) -> Vec<(String, String)> {
         let map = self.ini.get_map_ref();
-        let mut found_entries = false;
-        if let Some(section) = map.get("default") {
-            if !section.is_empty() {
-                found_entries = true;
-                for (key, value_opt) in section {
-                    if let Some(value) = value_opt {
-                        println!("{} => {}", key, value);
-                    }
-                }
-            }
-        }
+        let Some(section) = map.get("default") else {
+            return Vec::new();
+        };
+
+        section
+            .iter()
+            .filter_map(|(key, value)| value.as_ref().map(|value| (key.clone(), value.clone())))
+            .collect()
+    }
 
-        if !found_entries {
-            println!("No entries found in config!");
-        }
+    pub fn set(&mut self, key: &str, value: &str) -> Result<PathBuf, ClenvError> {
+        self.insert(key, value);
+        self.save()
     }
 
-    pub fn set(&mut self, key: &str, value: &str) {
+    // Same as set, without writing the config file
+    pub fn insert(&mut self, key: &str, value: &str) {
         self.ini.set(SECTION, key, Some(value.to_string()));
-        let _ = self.save();
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+    // Writes the config file and returns where it was written to
+    pub fn save(&self) -> Result<PathBuf, ClenvError> {
         let config_path = config_file_path()?;
-        let config_dir = config_path.parent().ok_or("Invalid config path")?;
+        let config_dir = config_path
+            .parent()
+            .ok_or_else(|| ClenvError::Config("Invalid config path".to_string()))?;
 
-        std::fs::create_dir_all(config_dir)?;
-        self.ini.write(&config_path)?;
+        std::fs::create_dir_all(config_dir)
+            .map_err(|e| ClenvError::io(format!("Could not create {}", config_dir.display()), e))?;
+        self.ini
+            .write(&config_path)
+            .map_err(|e| ClenvError::io(format!("Could not write {}", config_path.display()), e))?;
 
-        println!("Config written to: {}", config_path.display());
-        Ok(())
+        Ok(config_path)
     }
 }
 
-fn config_file_path() -> Result<PathBuf, Box<dyn Error>> {
-    let dir = dirs::config_dir().ok_or("Could not find config directory")?;
+fn config_file_path() -> Result<PathBuf, ClenvError> {
+    let dir = dirs::config_dir()
+        .ok_or_else(|| ClenvError::Config("Could not find config directory".to_string()))?;
     Ok(dir.join(CONFIG_DIR).join(CONFIG_FILE))
 }
@@ -9,18 +9,18 @@ pub fn resolve_path(pathin: &str, file_ext: &str) -> PathBuf {
         path.to_path_buf()
     } else if path.components().count() > 0 {
         env::current_dir()
-            .unwrap()
+            .unwrap_or_default()
             .join(path)
             .canonicalize()
             .unwrap_or_else(|_| {
-                let mut joined = env::current_dir().unwrap().join(path);
+                let mut joined = env::current_dir().unwrap_or_default().join(path);
                 if !joined.extension().is_some() && !file_ext.is_empty() {
                     joined.set_extension(file_ext);
                 }
                 joined
             })
     } else {
-        let mut new_path = env::current_dir().unwrap();
+        let mut new_path = env::current_dir().unwrap_or_default();
         new_path.push(pathin);
         if !file_ext.is_empty() {
             new_path.set_extension(file_ext);
@@ -0,0 +1,158 @@
+use crate::sec_db::i_keys::CryptoError;
+use thiserror::Error;
+
+// Exit codes so scripts can branch on what went wrong. 2 is left to clap for usage errors.
+pub const EXIT_GENERAL: u8 = 1;
+pub const EXIT_NOT_FOUND: u8 = 3;
+pub const EXIT_ACCESS_DENIED: u8 = 4;
+pub const EXIT_CONFIG: u8 = 5;
+pub const EXIT_STORAGE: u8 = 6;
+pub const EXIT_INTEGRITY: u8 = 7;
+pub const EXIT_CONFLICT: u8 = 8;
+
+// Everything that can go wrong in clenv. The messages are shown to the user as is, so keep them actionable.
+#[derive(Debug, Error)]
+pub enum ClenvError {
+    // Missing entries, namespaces, revisions, variables or users
+    #[error("{0}")]
+    NotFound(String),
+
+    // The caller has no wrapped key for an entry or their private key can't unwrap it
+    #[error("{0}")]
+    AccessDenied(String),
+
+    #[error("{0}")]
+    Config(String),
+
+    // Database or remote failures and entries that can't be decoded
+    #[error("{0}")]
+    Storage(String),
+
+    // An entry whose ciphertext doesn't match the namespace, name or extension it was read under
+    #[error("{0}")]
+    Integrity(String),
+
+    // Records changed both here and on the remote since they were last synced
+    #[error("{0}")]
+    Conflict(String),
+
+    // Input that clenv can't work with, e.g. `get` on an entry that isn't a dotenv file
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+}
+
+impl ClenvError {
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        ClenvError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ClenvError::NotFound(_) => EXIT_NOT_FOUND,
+            ClenvError::AccessDenied(_) | ClenvError::Crypto(_) => EXIT_ACCESS_DENIED,
+            ClenvError::Config(_) => EXIT_CONFIG,
+            ClenvError::Storage(_) => EXIT_STORAGE,
+            ClenvError::Integrity(_) => EXIT_INTEGRITY,
+            ClenvError::Conflict(_) => EXIT_CONFLICT,
+            ClenvError::Io { source, .. } => match source.kind() {
+                std::io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+                std::io::ErrorKind::PermissionDenied => EXIT_ACCESS_DENIED,
+                _ => EXIT_GENERAL,
+            },
+            ClenvError::Invalid(_) => EXIT_GENERAL,
+        }
+    }
+}
+
+impl From<rocksdb::Error> for ClenvError {
+    fn from(err: rocksdb::Error) -> Self {
+        ClenvError::Storage(format!("Database error: {}", err))
+    }
+}
+
+impl From<bincode::error::DecodeError> for ClenvError {
+    fn from(err: bincode::error::DecodeError) -> Self {
+        ClenvError::Storage(format!(
+            "Could not decode entry, the database may be corrupted: {}. 'clenv fsck' lists what is affected",
+            err
+        ))
+    }
+}
+
+impl From<bincode::error::EncodeError> for ClenvError {
+    fn from(err: bincode::error::EncodeError) -> Self {
+        ClenvError::Storage(format!("Could not encode entry: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error as IoError, ErrorKind};
+
+    #[test]
+    fn exit_codes() {
+        let cases = [
+            (ClenvError::NotFound("a".into()), EXIT_NOT_FOUND),
+            (ClenvError::AccessDenied("a".into()), EXIT_ACCESS_DENIED),
+            (
+                ClenvError::Crypto(CryptoError::Agent("a".into())),
+                EXIT_ACCESS_DENIED,
+            ),
+            (ClenvError::Config("a".into()), EXIT_CONFIG),
+            (ClenvError::Storage("a".into()), EXIT_STORAGE),
+            (ClenvError::Integrity("a".into()), EXIT_INTEGRITY),
+            (ClenvError::Conflict("a".into()), EXIT_CONFLICT),
+            (ClenvError::Invalid("a".into()), EXIT_GENERAL),
+            (
+                ClenvError::io("a", IoError::from(ErrorKind::NotFound)),
+                EXIT_NOT_FOUND,
+            ),
+            (
+                ClenvError::io("a", IoError::from(ErrorKind::PermissionDenied)),
+                EXIT_ACCESS_DENIED,
+            ),
+            (
+                ClenvError::io("a", IoError::from(ErrorKind::UnexpectedEof)),
+                EXIT_GENERAL,
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+
+        // 0 is success and 2 is what clap exits with on bad arguments
+        let codes = [
+            EXIT_GENERAL,
+            EXIT_NOT_FOUND,
+            EXIT_ACCESS_DENIED,
+            EXIT_CONFIG,
+            EXIT_STORAGE,
+            EXIT_INTEGRITY,
+            EXIT_CONFLICT,
+        ];
+        assert!(!codes.contains(&0) && !codes.contains(&2));
+    }
+
+    #[test]
+    fn undecodable_entries_are_storage_errors() {
+        let error: ClenvError =
+            bincode::serde::decode_from_slice::<String, _>(&[0xff], bincode::config::standard())
+                .unwrap_err()
+                .into();
+        assert_eq!(error.exit_code(), EXIT_STORAGE);
+    }
+}
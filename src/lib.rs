@@ -0,0 +1,45 @@
+//! clenv - simple cmd tool for not so simple configs
+//!
+//! The library behind the `clenv` binary. Everything returns data and `Result`s, nothing is printed,
+//! so other tools can read and write a clenv database without scraping the CLI output.
+//!
+//! ```no_run
+//! use clenv::{Config, SecDb};
+//!
+//! let conf = Config::load()?;
+//! let mut db = SecDb::new(conf)?;
+//!
+//! db.store("settings.json", b"{}", "json", false)?;
+//! for name in db.list_entries("dev")? {
+//!     println!("{}", name);
+//! }
+//! let plain = db.read_entry("settings.json")?;
+//! # Ok::<(), clenv::ClenvError>(())
+//! ```
+
+pub mod config;
+pub mod error;
+pub mod sec_db;
+
+pub use config::Config;
+pub use error::ClenvError;
+pub use sec_db::SecDb;
+pub use sec_db::age_file::AgeExport;
+pub use sec_db::bundle::{BundleExport, BundleImport, ConflictPolicy};
+pub use sec_db::chunked::{CHUNKED_FROM, ChunkStore, ChunkedBlob};
+pub use sec_db::compression::{Codec, Compression, CompressionConfig, DictionaryInfo};
+pub use sec_db::diff::{Changes, Diff, LineChange, LineTag, VarChange};
+pub use sec_db::fsck::{Finding, FsckReport, Problem};
+pub use sec_db::handle_db::{
+    AccessReport, ENTRY_FORMAT, EncryptedEntry, EncryptedLine, EntryInfo, EntryKind,
+    NamespaceReport,
+};
+pub use sec_db::history::Revision;
+pub use sec_db::i_keys::{CryptoError, PASSPHRASE_ENV, Passphrase, PassphraseRequest, i_keys};
+pub use sec_db::merge::MergeReport;
+pub use sec_db::migrate::{MigrationReport, SCHEMA_VERSION};
+pub use sec_db::s3::{Bucket, ObjectStore, RemoteObject};
+pub use sec_db::signature::{EntrySignature, SignatureStatus, Verification};
+pub use sec_db::store::{DirStore, RocksStore, Store};
+pub use sec_db::suite::{PrivateKey, PublicKey, Suite};
+pub use sec_db::sync::{SyncConflict, SyncReport};
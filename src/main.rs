@@ -1,13 +1,17 @@
-use clap::{Command, Parser, command};
-
-mod config;
-use config::conf;
-use config::resolve_path;
-
-mod sec_db;
-use sec_db::SecDb;
+use clap::{ArgMatches, Command, Parser, command};
+use clenv::config::{conf, resolve_path};
+use clenv::{
+    ClenvError, ConflictPolicy, PASSPHRASE_ENV, PassphraseRequest, SCHEMA_VERSION, SecDb,
+    SignatureStatus, Suite, SyncReport, Verification, i_keys,
+};
+use colored::Colorize;
+use serde_json::json;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::ExitCode;
 
 mod command_factory;
+mod output;
 
 /// clenv - simple cmd tool for not so simple configs
 #[derive(Parser, Debug)]
@@ -18,22 +22,170 @@ struct Cli {
     name: Option<String>,
 }
 
-fn main() {
+fn main() -> ExitCode {
     let mut matches = Command::new("clenv");
 
+    for arg in command_factory::global_args() {
+        matches = matches.arg(arg);
+    }
     for comms in command_factory::add_all_comm() {
         matches = matches.subcommand(comms);
     }
 
+    let parser = matches.get_matches();
+    let json = parser
+        .get_one::<String>("output")
+        .is_some_and(|format| format == "json");
+
+    match run(&parser, json) {
+        Ok(code) => code,
+        Err(e) => {
+            if json {
+                output::json_error(&e);
+            } else {
+                eprintln!("{} {}", "error:".red().bold(), e);
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+// Asks for every setting clenv needs and writes a fresh config file
+fn init_config() -> Result<conf, ClenvError> {
+    fn prompt(label: &str) -> Result<String, io::Error> {
+        print!("{}: ", label);
+        io::stdout().flush()?;
+        let mut buf = String::new();
+        io::stdin().read_line(&mut buf)?;
+        Ok(buf.trim().to_string())
+    }
+
+    fn prompt_path(label: &str, file_ext: &str) -> Result<String, io::Error> {
+        let input = prompt(label)?;
+        Ok(resolve_path(&input, file_ext)
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    let read_err = |e| ClenvError::io("Could not read from the terminal", e);
+    let name = prompt("Enter your name").map_err(read_err)?;
+    let db = prompt_path("Enter database name", "").map_err(read_err)?;
+    let private_key = prompt_path(
+        "Enter the location of your private key file (or just file name in the current directory)",
+        "pem",
+    )
+    .map_err(read_err)?;
+    let ns = prompt("Enter the namespace").map_err(read_err)?;
+
+    let config = conf::new(&name, &db, &private_key, &ns);
+    let path = config.save()?;
+    println!("Config written to: {}", path.display());
+
+    Ok(config)
+}
+
+// New passphrase for 'clenv key passwd' in scripts, next to the old one in CLENV_PASSPHRASE
+const NEW_PASSPHRASE_ENV: &str = "CLENV_NEW_PASSPHRASE";
+
+fn open_db(config: conf) -> Result<SecDb, ClenvError> {
+    let db = SecDb::with_passphrase(config, Box::new(ask_passphrase))?;
+    if db.schema_version()? < SCHEMA_VERSION {
+        eprintln!(
+            "{} the database is in an older format, 'clenv migrate' upgrades it",
+            "warning:".yellow().bold()
+        );
+    }
+    Ok(db)
+}
+
+fn print_sync(action: &str, report: &SyncReport, json: bool) {
+    if json {
+        output::json(
+            &json!({ "ok": report.conflicts.is_empty(), "action": action, "report": report }),
+        );
+    } else {
+        output::print_sync(action, report);
+    }
+}
+
+// Conflicts fail the command once everything else was synced
+fn sync_conflicts(report: &SyncReport, hint: &str) -> Result<(), ClenvError> {
+    match report.conflicts.len() {
+        0 => Ok(()),
+        n => Err(ClenvError::Conflict(format!(
+            "{} records were changed here and on {} and were left alone, {}",
+            n, report.remote, hint
+        ))),
+    }
+}
+
+// CLENV_PASSPHRASE wins when it's set, so scripts never get stuck on a prompt
+fn ask_passphrase(request: PassphraseRequest) -> Result<String, ClenvError> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(secret) => Ok(secret),
+        Err(_) => prompt_passphrase(request),
+    }
+}
+
+// Prompts go to the terminal rather than stdout, so they don't end up in piped or JSON output
+fn prompt_passphrase(request: PassphraseRequest) -> Result<String, ClenvError> {
+    let read_err = |e| {
+        ClenvError::io(
+            format!(
+                "Could not read a passphrase from the terminal, set {} instead",
+                PASSPHRASE_ENV
+            ),
+            e,
+        )
+    };
+    match request {
+        PassphraseRequest::Unlock(file) => {
+            rpassword::prompt_password(format!("Passphrase for {}: ", file)).map_err(read_err)
+        }
+        PassphraseRequest::New(file) => {
+            let secret = rpassword::prompt_password(format!(
+                "New passphrase for {} (leave empty for none): ",
+                file
+            ))
+            .map_err(read_err)?;
+            if secret.is_empty() {
+                return Ok(secret);
+            }
+            let repeated =
+                rpassword::prompt_password("Repeat the passphrase: ").map_err(read_err)?;
+            if secret != repeated {
+                return Err(ClenvError::Invalid(
+                    "The passphrases don't match".to_string(),
+                ));
+            }
+            Ok(secret)
+        }
+    }
+}
+
+// Entries that can't be trusted are refused when they are read, unsigned ones are read with a warning
+// until every entry of the database is signed.
+// Anyone who can write to the database files can plant one, so every command reading entries warns about them.
+fn warn_unsigned(db: &SecDb, ns: &str, name: &str) -> Result<(), ClenvError> {
+    let status = db.verify_entry(ns, name)?;
+    if matches!(status, SignatureStatus::Unsigned) {
+        output::warn_signature(ns, name, &status);
+    }
+    Ok(())
+}
+
+fn run(parser: &ArgMatches, json: bool) -> Result<ExitCode, ClenvError> {
     let mut confi = match conf::load() {
         Ok(cfg) => cfg,
         Err(_) => {
             eprintln!("Configuration file not found. Creating one...");
-            conf::init().expect("Failed to initialize config")
+            init_config()?
         }
     };
 
-    let parser = matches.get_matches();
+    if parser.get_flag("allow-unsigned") {
+        confi.insert("allow_unsigned", "true");
+    }
 
     match parser.subcommand() {
         Some(("cfg", sub_matches)) => {
@@ -42,27 +194,61 @@ fn main() {
 
             // Quick and dirty way to reset your configuration file
             if key == Some(&String::from("init")) {
-                conf::init().expect("Could not create a configuration");
-                let _db = SecDb::new(confi.clone());
-                return;
+                let confi = init_config()?;
+                let db_path = confi.require("db")?;
+                let existed = Path::new(&db_path).exists();
+                let _db = open_db(confi)?;
+                if json {
+                    output::json(&json!({ "ok": true, "db": db_path, "created": !existed }));
+                } else if !existed {
+                    println!("Created database at {}", db_path);
+                }
+                return Ok(ExitCode::SUCCESS);
             }
             match (key, value) {
                 (Some(k), Some(v)) => {
-                    confi.set(k, v);
-                    println!("Set {} = {}", k, v);
+                    if k == "ns" {
+                        SecDb::check_namespace_name(v)?;
+                    }
+                    confi.set(k, v)?;
+                    if json {
+                        output::json(&json!({ "ok": true, "key": k, "value": v }));
+                    } else {
+                        println!("Set {} = {}", k, v);
+                    }
                 }
                 (Some(k), None) => match confi.get(k) {
+                    Some(v) if json => output::json(&json!({ "key": k, "value": v })),
                     Some(v) => {
                         println!("{} = {}", k, v)
                     }
-                    None => println!("Key '{}' not found", k),
+                    None => {
+                        return Err(ClenvError::NotFound(format!("Key '{}' not found", k)));
+                    }
                 },
+                (None, None) if json => {
+                    let entries: serde_json::Map<_, _> = confi
+                        .entries()
+                        .into_iter()
+                        .map(|(key, value)| (key, value.into()))
+                        .collect();
+                    output::json(&json!({ "config": entries }));
+                }
                 (None, None) => {
                     println!("Listing all config entries:");
-                    confi.list_all();
+                    let entries = confi.entries();
+                    if entries.is_empty() {
+                        println!("No entries found in config!");
+                    }
+                    for (key, value) in entries {
+                        println!("{} => {}", key, value);
+                    }
                 }
                 (None, Some(_)) => {
-                    eprintln!("Error: value provided without key");
+                    return Err(ClenvError::Invalid(
+                        "A value was given without a key. Usage: clenv cfg <key> <value>"
+                            .to_string(),
+                    ));
                 }
             }
         }
@@ -70,87 +256,819 @@ fn main() {
             let file = sub_matches.get_one::<String>("file");
             let name = sub_matches.get_one::<String>("name");
 
-            let mut db = SecDb::new(confi.clone());
+            let mut db = open_db(confi.clone())?;
             match (file, name) {
-                (Some(n), Some(f)) => {
-                    let target_file = resolve_path(f, "").into_os_string().into_string().unwrap();
-                    db.store_file(n, &target_file);
-                }
-                (Some(f), None) => {
-                    let target_file = resolve_path(f, "").into_os_string().into_string().unwrap();
-                    db.store_file(f, &target_file);
-                }
-                (None, Some(_)) => {
-                    eprintln!("File not entered");
+                (Some(f), n) => {
+                    let target_file = resolve_path(f, "").to_string_lossy().into_owned();
+                    let n = n.unwrap_or(f);
+                    let revision = db.store_file(n, &target_file)?;
+                    if json {
+                        output::json(&json!({
+                            "ok": true,
+                            "namespace": db.namespace()?,
+                            "entry": n,
+                            "file": target_file,
+                            "revision": revision,
+                        }));
+                    } else {
+                        println!("Stored encrypted file '{}' successfully.", target_file);
+                    }
                 }
-                (None, None) => {
-                    eprintln!("Filename not entered");
+                (None, _) => {
+                    return Err(ClenvError::Invalid(
+                        "Missing file to store. Usage: clenv store <file> [name]".to_string(),
+                    ));
                 }
             }
         }
         Some(("dump", sub_matches)) => {
             let name = sub_matches.get_one::<String>("name");
-            let db = SecDb::new(confi.clone());
+            let db = open_db(confi.clone())?;
+
+            match name {
+                Some(n) => {
+                    let path = db.dump_file(n)?;
+                    let signature = db.verify_entry(&db.namespace()?, n)?;
+                    if json {
+                        output::json(
+                            &json!({ "ok": true, "entry": n, "file": path, "signature": signature }),
+                        );
+                    } else {
+                        output::warn_signature(&db.namespace()?, n, &signature);
+                        println!("Successfully wrote to {}", n);
+                    }
+                }
+                None => {
+                    return Err(ClenvError::Invalid(
+                        "Missing name of entry. Usage: clenv dump <entry>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("run", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("entry");
+            let cmd: Vec<String> = sub_matches
+                .get_many::<String>("cmd")
+                .map(|vals| vals.cloned().collect())
+                .unwrap_or_default();
+            let db = open_db(confi.clone())?;
+
+            match name {
+                Some(n) if !cmd.is_empty() => {
+                    warn_unsigned(&db, &db.namespace()?, n)?;
+                    let code = db.run_with_entry(n, &cmd)?;
+                    return Ok(ExitCode::from(u8::try_from(code).unwrap_or(1)));
+                }
+                Some(_) => {
+                    return Err(ClenvError::Invalid(
+                        "Missing command to run. Usage: clenv run <entry> -- <cmd>".to_string(),
+                    ));
+                }
+                None => {
+                    return Err(ClenvError::Invalid(
+                        "Missing name of entry. Usage: clenv run <entry> -- <cmd>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("get", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("entry");
+            let var = sub_matches.get_one::<String>("var");
+            let db = open_db(confi.clone())?;
+
+            match (name, var) {
+                (Some(n), Some(v)) => {
+                    warn_unsigned(&db, &db.namespace()?, n)?;
+                    let value = db.get_var(n, v)?;
+                    if json {
+                        output::json(&json!({ "entry": n, "var": v, "value": value }));
+                    } else {
+                        println!("{}", value);
+                    }
+                }
+                _ => {
+                    return Err(ClenvError::Invalid(
+                        "Usage: clenv get <entry> <VAR>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("set", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("entry");
+            let var = sub_matches.get_one::<String>("var");
+            let value = sub_matches.get_one::<String>("value");
+            let mut db = open_db(confi.clone())?;
 
+            match (name, var, value) {
+                (Some(n), Some(k), Some(v)) => {
+                    warn_unsigned(&db, &db.namespace()?, n)?;
+                    let revision = db.set_var(n, k, v)?;
+                    if json {
+                        output::json(&json!({
+                            "ok": true,
+                            "entry": n,
+                            "var": k,
+                            "revision": revision,
+                        }));
+                    } else {
+                        println!("Set {} in {}", k, n);
+                    }
+                }
+                _ => {
+                    return Err(ClenvError::Invalid(
+                        "Usage: clenv set <entry> <VAR> <value>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("history", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("entry");
+            let db = open_db(confi.clone())?;
             match name {
+                Some(n) if json => {
+                    output::json(&json!({ "entry": n, "revisions": db.history(n)? }));
+                }
                 Some(n) => {
-                    db.dump_file(n);
+                    println!("History of {}:", n);
+                    for revision in db.history(n)? {
+                        match revision.current {
+                            true => println!("* {} (current)", revision),
+                            false => println!("  {}", revision),
+                        }
+                    }
                 }
                 None => {
-                    eprintln!("Missing name of file");
+                    return Err(ClenvError::Invalid(
+                        "Missing name of entry. Usage: clenv history <entry>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("rollback", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("entry");
+            let rev = sub_matches
+                .get_one::<String>("rev")
+                .and_then(|r| r.parse::<u64>().ok());
+            let mut db = open_db(confi.clone())?;
+            match (name, rev) {
+                (Some(n), Some(r)) => {
+                    let revision = db.rollback(n, r)?;
+                    if json {
+                        output::json(&json!({
+                            "ok": true,
+                            "entry": n,
+                            "restored": r,
+                            "revision": revision,
+                        }));
+                    } else {
+                        println!("Rolled {} back to revision {}", n, r);
+                    }
+                }
+                (Some(_), None) => {
+                    return Err(ClenvError::Invalid(
+                        "Revision must be a number, see 'clenv history <entry>'".to_string(),
+                    ));
+                }
+                _ => {
+                    return Err(ClenvError::Invalid(
+                        "Usage: clenv rollback <entry> <rev>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("diff", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("entry");
+            let other = sub_matches.get_one::<String>("other");
+            let ns = sub_matches.get_one::<String>("ns");
+            let other_ns = sub_matches.get_one::<String>("other-ns");
+            let mask = sub_matches.get_flag("mask");
+            let db = open_db(confi.clone())?;
+
+            match (name, other) {
+                (Some(a), Some(b)) => {
+                    let left_ns = match ns {
+                        Some(ns) => ns.clone(),
+                        None => db.namespace()?,
+                    };
+                    warn_unsigned(&db, &left_ns, a)?;
+                    if other_ns.is_some() || !resolve_path(b, "").is_file() {
+                        warn_unsigned(&db, other_ns.unwrap_or(&left_ns), b)?;
+                    }
+                    let diff =
+                        db.diff(a, b, ns.map(String::as_str), other_ns.map(String::as_str))?;
+                    if json {
+                        let diff = if mask { diff.masked() } else { diff };
+                        output::json(&diff);
+                    } else {
+                        output::print_diff(&diff, mask);
+                    }
+                }
+                _ => {
+                    return Err(ClenvError::Invalid(
+                        "Usage: clenv diff <entry> <entry or file>".to_string(),
+                    ));
                 }
             }
         }
         Some(("show", sub_matches)) => {
             let namespace = sub_matches.get_one::<String>("namespace");
-            let db = SecDb::new(confi.clone());
+            let db = open_db(confi.clone())?;
             match namespace {
+                // The keyring holds public keys, not entries
+                Some(namespace) if json && namespace == "keyring" => {
+                    output::json(&json!({
+                        "namespace": namespace,
+                        "users": db.list_entries(namespace)?,
+                    }));
+                }
+                Some(namespace) if json => {
+                    output::json(&json!({
+                        "namespace": namespace,
+                        "entries": db.entry_infos(namespace)?,
+                    }));
+                }
+                None if json => {
+                    output::json(&json!({ "namespaces": db.namespaces()? }));
+                }
                 Some(namespace) => {
-                    db.list_cf_formatted(namespace);
+                    for entry in db.list_entries(namespace)? {
+                        println!("{}", entry);
+                    }
                 }
                 None => {
-                    db.list_cfs();
+                    println!("Namespaces:");
+                    for cf in db.namespaces()? {
+                        println!("- {}", cf);
+                    }
                 }
             }
         }
         Some(("rm", sub_matches)) => {
             let name = sub_matches.get_one::<String>("entry");
-            let db = SecDb::new(confi.clone());
+            let db = open_db(confi.clone())?;
             match name {
                 Some(name) => {
-                    db.rm(name);
+                    db.rm(name)?;
+                    if json {
+                        output::json(&json!({ "ok": true, "entry": name }));
+                    } else {
+                        println!("Successfuly removed entry from clenv: {}", name);
+                    }
                 }
                 None => {
-                    eprintln!("Please provide name of the entry you would like to remove")
+                    return Err(ClenvError::Invalid(
+                        "Missing name of entry. Usage: clenv rm <entry>".to_string(),
+                    ));
                 }
             }
         }
         Some(("add", sub_matches)) => {
             let name = sub_matches.get_one::<String>("name");
-            let db = SecDb::new(confi.clone());
+            let pubkey = sub_matches.get_one::<String>("pubkey");
+            let db = open_db(confi.clone())?;
+            let namespaces = db.resolve_namespaces(
+                sub_matches.get_flag("all-namespaces"),
+                sub_matches.get_one::<String>("ns").map(String::as_str),
+            )?;
             match name {
                 Some(name) => {
-                    db.add_user(name);
+                    let report = db.add_user(name, pubkey.map(String::as_str), &namespaces)?;
+                    if json {
+                        output::json(&json!({ "ok": true, "user": name, "report": report }));
+                    } else {
+                        output::print_added(name, &report);
+                    }
                 }
                 None => {
-                    eprintln!("Pleas provide a name for the user you are adding.")
+                    return Err(ClenvError::Invalid(
+                        "Missing name of the user. Usage: clenv add <name>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("keygen", sub_matches)) => {
+            let file = sub_matches.get_one::<String>("file");
+            let name = confi.get("name").unwrap_or_default();
+            let private_key = match file {
+                Some(f) => resolve_path(f, "pem"),
+                None => resolve_path(&confi.get("private_key").unwrap_or_default(), "pem"),
+            };
+
+            let private_path = private_key.to_string_lossy().into_owned();
+            let public_path = private_key
+                .with_extension("pub.pem")
+                .to_string_lossy()
+                .into_owned();
+
+            let existed = Path::new(&private_path).exists();
+            let suite = Suite::configured(&confi)?;
+            if !json {
+                match (existed, suite) {
+                    (true, _) => println!("Loading existing keys for {}...", name),
+                    (false, Suite::Rsa) => println!("Generating RSA key pair for {}...", name),
+                    (false, Suite::X25519) => {
+                        println!("Generating X25519 key pair for {}...", name)
+                    }
+                }
+            }
+            let (_priv_key, pub_key) =
+                i_keys::generate_key_pair(&private_path, &ask_passphrase, suite)?;
+            i_keys::export_public_key(&pub_key, &public_path)?;
+
+            if json {
+                output::json(&json!({
+                    "ok": true,
+                    "private_key": private_path,
+                    "public_key": public_path,
+                    "generated": !existed,
+                }));
+                return Ok(ExitCode::SUCCESS);
+            }
+            println!("Private key: {}", private_path);
+            println!("Public key:  {}", public_path);
+            println!(
+                "Send the public key to someone with access and have them run 'clenv add {} --pubkey <file>'",
+                name
+            );
+        }
+        Some(("key", sub_matches)) => {
+            let action = sub_matches
+                .get_one::<String>("action")
+                .map(String::as_str)
+                .unwrap_or_default();
+            if action == "id" {
+                // What others compare against the key ids push, pull and verify report
+                let public_key = match sub_matches.get_one::<String>("file") {
+                    Some(f) => i_keys::read_public_key(&resolve_path(f, "pem").to_string_lossy())?,
+                    None => {
+                        let private_key = resolve_path(&confi.require("private_key")?, "pem");
+                        i_keys::read_private_key(&private_key.to_string_lossy(), &ask_passphrase)?
+                            .public_key()
+                    }
+                };
+                let key_id = i_keys::key_id(&public_key)?;
+                if json {
+                    output::json(&json!({ "ok": true, "key_id": key_id }));
+                } else {
+                    println!("{}", key_id);
                 }
+                return Ok(ExitCode::SUCCESS);
+            }
+            if action != "passwd" {
+                return Err(ClenvError::Invalid(format!(
+                    "Unknown key action '{}'. Did you mean 'clenv key passwd' or 'clenv key id'?",
+                    action
+                )));
+            }
+
+            let private_key = match sub_matches.get_one::<String>("file") {
+                Some(f) => resolve_path(f, "pem"),
+                None => resolve_path(&confi.require("private_key")?, "pem"),
+            };
+            let private_path = private_key.to_string_lossy().into_owned();
+            let encrypted = i_keys::change_passphrase(&private_path, &|request| match request {
+                PassphraseRequest::New(_) => match std::env::var(NEW_PASSPHRASE_ENV) {
+                    Ok(secret) => Ok(secret),
+                    Err(_) => prompt_passphrase(request),
+                },
+                PassphraseRequest::Unlock(_) => ask_passphrase(request),
+            })?;
+
+            if json {
+                output::json(
+                    &json!({ "ok": true, "private_key": private_path, "encrypted": encrypted }),
+                );
+            } else if encrypted {
+                println!("Changed the passphrase of {}", private_path);
+            } else {
+                println!(
+                    "Removed the passphrase of {}, the key is stored unencrypted",
+                    private_path
+                );
             }
         }
         Some(("remove", sub_matches)) => {
             let name = sub_matches.get_one::<String>("name");
-            let db = SecDb::new(confi.clone());
+            let rekey = sub_matches.get_flag("rekey");
+            let mut db = open_db(confi.clone())?;
+            let namespaces = db.resolve_namespaces(
+                sub_matches.get_flag("all-namespaces"),
+                sub_matches.get_one::<String>("ns").map(String::as_str),
+            )?;
             match name {
                 Some(name) => {
-                    db.remove_user(name);
+                    let report = db.remove_user(name, rekey, &namespaces)?;
+                    if json {
+                        output::json(&json!({ "ok": true, "user": name, "report": report }));
+                    } else {
+                        output::print_removed(name, rekey, &report);
+                    }
+                }
+                None => {
+                    return Err(ClenvError::Invalid(
+                        "Missing name of the user. Usage: clenv remove <name>".to_string(),
+                    ));
+                }
+            }
+        }
+        Some(("dictionary", sub_matches)) => {
+            let action = sub_matches
+                .get_one::<String>("action")
+                .map(String::as_str)
+                .unwrap_or_default();
+            if action != "train" {
+                return Err(ClenvError::Invalid(format!(
+                    "Unknown dictionary action '{}'. Did you mean 'clenv dictionary train'?",
+                    action
+                )));
+            }
+            let mut db = open_db(confi.clone())?;
+            let info = db.train_dictionary()?;
+            if json {
+                output::json(&json!({ "ok": true, "dictionary": info }));
+            } else {
+                output::print_dictionary(&info);
+            }
+        }
+        Some(("obfuscate", _)) => {
+            let mut db = open_db(confi.clone())?;
+            let namespaces = db.obfuscate()?;
+            if json {
+                output::json(&json!({ "ok": true, "namespaces": namespaces }));
+            } else {
+                for ns in &namespaces {
+                    println!("Obfuscated namespace {}", ns);
+                }
+                println!(
+                    "Names of namespaces and entries are now only readable by users in the keyring"
+                );
+            }
+        }
+        Some(("reseal", sub_matches)) => {
+            let db = open_db(confi.clone())?;
+            let namespaces = db.resolve_namespaces(
+                sub_matches.get_flag("all-namespaces"),
+                sub_matches.get_one::<String>("ns").map(String::as_str),
+            )?;
+            let reports = db.reseal(&namespaces)?;
+            if json {
+                output::json(&json!({ "ok": true, "namespaces": reports }));
+            } else {
+                output::print_resealed(&reports);
+            }
+        }
+        Some(("verify", sub_matches)) => {
+            let db = open_db(confi.clone())?;
+            let verifications = match sub_matches.get_one::<String>("entry") {
+                Some(entry) => {
+                    let ns = db.namespace()?;
+                    vec![Verification {
+                        status: db.verify_entry(&ns, entry)?,
+                        namespace: ns,
+                        entry: entry.clone(),
+                    }]
                 }
                 None => {
-                    eprintln!("Pleas provide a name for the user you are adding.")
+                    let namespaces = db.resolve_namespaces(
+                        sub_matches.get_flag("all-namespaces"),
+                        sub_matches.get_one::<String>("ns").map(String::as_str),
+                    )?;
+                    db.verify(&namespaces)?
                 }
+            };
+            let refuses_unsigned = db.refuses_unsigned()?;
+            if json {
+                output::json(&json!({ "ok": true, "entries": verifications }));
+            } else {
+                output::print_verifications(&verifications, refuses_unsigned);
+            }
+
+            let strict = sub_matches.get_flag("strict") || refuses_unsigned;
+            let failed = verifications
+                .iter()
+                .filter(|v| match v.status {
+                    SignatureStatus::Valid { .. } => false,
+                    SignatureStatus::Unsigned => strict,
+                    _ => true,
+                })
+                .count();
+            if failed > 0 {
+                return Err(ClenvError::Integrity(format!(
+                    "{} of {} entries failed verification",
+                    failed,
+                    verifications.len()
+                )));
+            }
+        }
+        Some(("fsck", sub_matches)) => {
+            let db = open_db(confi.clone())?;
+            let repair = sub_matches.get_flag("repair");
+            let report = db.fsck(repair)?;
+            if json {
+                output::json(&json!({ "ok": report.unrepaired() == 0, "report": report }));
+            } else {
+                output::print_fsck(&report);
+            }
+
+            let unrepaired = report.unrepaired();
+            if unrepaired > 0 {
+                return Err(ClenvError::Integrity(match repair {
+                    true => format!("{} problems could not be repaired", unrepaired),
+                    false => format!(
+                        "{} problems found. 'clenv fsck --repair' fixes what it can",
+                        unrepaired
+                    ),
+                }));
+            }
+        }
+        Some(("merge", sub_matches)) => {
+            let other_path = sub_matches.get_one::<String>("path").ok_or_else(|| {
+                ClenvError::Invalid(
+                    "Missing path of the database. Usage: clenv merge <path>".to_string(),
+                )
+            })?;
+            let other_path = resolve_path(other_path, "");
+            let policy = match sub_matches.get_one::<String>("on-conflict") {
+                Some(policy) => policy.parse()?,
+                None => ConflictPolicy::Skip,
+            };
+            // Opening a path without a database would create a new one there
+            if !clenv::sec_db::store::exists(&other_path) {
+                return Err(ClenvError::NotFound(format!(
+                    "No database found at {}",
+                    other_path.display()
+                )));
+            }
+            if other_path.canonicalize().ok()
+                == Path::new(&confi.require("db")?).canonicalize().ok()
+            {
+                return Err(ClenvError::Invalid(
+                    "That is your own database, give the path of another one".to_string(),
+                ));
+            }
+            let other_path = other_path.to_string_lossy().into_owned();
+
+            let mut db = open_db(confi.clone())?;
+            // The other database is opened as us, with our key
+            let other_conf = conf::new(
+                &confi.require("name")?,
+                &other_path,
+                &confi.require("private_key")?,
+                &confi.require("ns")?,
+            );
+            let other = SecDb::with_passphrase(other_conf, Box::new(ask_passphrase))?;
+            let report = db.merge(&other, policy)?;
+            if json {
+                output::json(&json!({ "ok": true, "from": other_path, "report": report }));
+            } else {
+                output::print_merge(&other_path, &report);
+            }
+        }
+        Some(("push", sub_matches)) => {
+            let mut db = open_db(confi.clone())?;
+            let report = db.push(sub_matches.get_flag("force"))?;
+            print_sync("push", &report, json);
+            sync_conflicts(&report, "pull first to bring in their changes")?;
+        }
+        Some(("pull", sub_matches)) => {
+            // Without a database the remote's is copied, which is how new team members get one
+            if !clenv::sec_db::store::exists(Path::new(&confi.require("db")?)) {
+                let (db, report) = SecDb::clone_remote(
+                    confi.clone(),
+                    Box::new(ask_passphrase),
+                    sub_matches.get_flag("accept-keyring"),
+                )?;
+                print_sync("clone", &report, json);
+                let name = confi.require("name")?;
+                if !json && !db.get_recipients()?.iter().any(|(user, _)| *user == name) {
+                    eprintln!(
+                        "{} {} is not in the keyring of this database. Send your public key to someone who is, so they can 'clenv add' you",
+                        "warning:".yellow().bold(),
+                        name
+                    );
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let mut db = open_db(confi.clone())?;
+            let report = db.pull(
+                sub_matches.get_flag("force"),
+                sub_matches.get_flag("accept-keyring"),
+            )?;
+            print_sync("pull", &report, json);
+            sync_conflicts(&report, "'clenv pull --force' takes the remote's version")?;
+        }
+        Some(("migrate", sub_matches)) => {
+            // Opened without open_db, which would tell us to run the command we're running
+            let mut db = SecDb::with_passphrase(confi.clone(), Box::new(ask_passphrase))?;
+            let dry_run = sub_matches.get_flag("dry-run");
+            let backup = match sub_matches.get_one::<String>("backup") {
+                _ if sub_matches.get_flag("no-backup") => None,
+                Some(path) => Some(resolve_path(path, "")),
+                None => {
+                    let stamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default();
+                    Some(format!("{}.backup-{}", confi.require("db")?, stamp).into())
+                }
+            };
+            let report = db.migrate(dry_run, backup.as_deref())?;
+            if json {
+                output::json(&json!({ "ok": true, "report": report }));
+            } else {
+                output::print_migration(&report);
+            }
+        }
+        Some(("agent", sub_matches)) => agent_command(sub_matches, &confi, json)?,
+        Some(("export", sub_matches)) => {
+            let target = sub_matches.get_one::<String>("target").ok_or_else(|| {
+                ClenvError::Invalid(
+                    "Missing bundle or entry to export. Usage: clenv export <target>".to_string(),
+                )
+            })?;
+            let db = open_db(confi.clone())?;
+            if sub_matches.get_flag("age") {
+                let entry = target;
+                let export = db.export_age(
+                    entry,
+                    sub_matches.get_one::<String>("out").map(String::as_str),
+                )?;
+                if json {
+                    output::json(&json!({ "ok": true, "entry": entry, "export": export }));
+                } else {
+                    output::print_age_export(entry, &export);
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+
+            let namespaces = db.resolve_namespaces(
+                sub_matches.get_flag("all-namespaces"),
+                sub_matches.get_one::<String>("ns").map(String::as_str),
+            )?;
+            let entries: Option<Vec<String>> =
+                sub_matches.get_one::<String>("entries").map(|list| {
+                    list.split(',')
+                        .map(|name| name.trim().to_string())
+                        .collect()
+                });
+            let export =
+                db.export_bundle(&resolve_path(target, ""), &namespaces, entries.as_deref())?;
+            if json {
+                output::json(&json!({ "ok": true, "export": export }));
+            } else {
+                println!(
+                    "Exported {} entries to {}",
+                    export.entries.len(),
+                    export.path.display()
+                );
+            }
+        }
+        Some(("import", sub_matches)) => {
+            let file = sub_matches.get_one::<String>("file").ok_or_else(|| {
+                ClenvError::Invalid(
+                    "Missing file to import. Usage: clenv import <file>".to_string(),
+                )
+            })?;
+            if !sub_matches.get_flag("age") {
+                let policy = match sub_matches.get_one::<String>("on-conflict") {
+                    Some(policy) => policy.parse()?,
+                    None => ConflictPolicy::Skip,
+                };
+                let mut db = open_db(confi.clone())?;
+                let import = db.import_bundle(
+                    &resolve_path(file, ""),
+                    policy,
+                    sub_matches.get_flag("trust-bundle-keys"),
+                )?;
+                if json {
+                    output::json(&json!({ "ok": true, "policy": policy, "import": import }));
+                } else {
+                    output::print_bundle_import(&import);
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let mut db = open_db(confi.clone())?;
+            let target_file = resolve_path(file, "").to_string_lossy().into_owned();
+            let (entry, revision) = db.import_age(
+                file,
+                sub_matches.get_one::<String>("name").map(String::as_str),
+            )?;
+            if json {
+                output::json(&json!({
+                    "ok": true,
+                    "namespace": db.namespace()?,
+                    "entry": entry,
+                    "file": target_file,
+                    "revision": revision,
+                }));
+            } else {
+                println!("Imported '{}' as {}.", target_file, entry);
             }
         }
         _ => {
             unreachable!("Exhausted list of subcommands");
         }
     }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(unix)]
+fn agent_command(sub_matches: &ArgMatches, confi: &conf, json: bool) -> Result<(), ClenvError> {
+    use clenv::sec_db::agent::{self, AgentClient};
+    use std::time::Duration;
+
+    let socket = agent::socket_path();
+    let action = sub_matches
+        .get_one::<String>("action")
+        .map(String::as_str)
+        .unwrap_or("start");
+    if !["start", "status", "lock", "unlock", "stop"].contains(&action) {
+        return Err(ClenvError::Invalid(format!(
+            "Unknown agent action '{}'. Use start, status, lock, unlock or stop",
+            action
+        )));
+    }
+    let key_file = resolve_path(&confi.require("private_key")?, "pem")
+        .to_string_lossy()
+        .into_owned();
+
+    if action == "start" {
+        let timeout = match sub_matches
+            .get_one::<String>("timeout")
+            .cloned()
+            .or_else(|| confi.get("agent_timeout"))
+        {
+            Some(t) => t.parse::<u64>().map_err(|_| {
+                ClenvError::Invalid(format!("Invalid timeout '{}', expected seconds", t))
+            })?,
+            None => agent::DEFAULT_TIMEOUT,
+        };
+        // Unlocked right away, so the agent starts out useful
+        let key = i_keys::read_private_key(&key_file, &ask_passphrase)?;
+        if json {
+            output::json(&json!({ "ok": true, "socket": socket, "timeout": timeout }));
+        } else {
+            println!(
+                "Agent listening on {}, the key stays unlocked for {}s. Stop it with 'clenv agent stop'",
+                socket.display(),
+                timeout
+            );
+        }
+        return agent::serve(&key_file, Some(key), Duration::from_secs(timeout), &socket);
+    }
+
+    let client = AgentClient::connect(&socket).ok_or_else(|| {
+        ClenvError::NotFound(format!(
+            "No agent is running at {}. Start one with 'clenv agent'",
+            socket.display()
+        ))
+    })?;
+    match action {
+        "status" => {
+            let status = client.status()?;
+            if json {
+                output::json(&json!({ "socket": socket, "agent": status }));
+            } else {
+                output::print_agent_status(&socket, &status);
+            }
+        }
+        "lock" => {
+            client.lock()?;
+            if json {
+                output::json(&json!({ "ok": true }));
+            } else {
+                println!("Locked the agent, the key is no longer held in memory");
+            }
+        }
+        "unlock" => {
+            let key_file = client.status()?.key_file;
+            let passphrase = match i_keys::is_encrypted(&key_file)? {
+                true => ask_passphrase(PassphraseRequest::Unlock(&key_file))?,
+                false => String::new(),
+            };
+            client.unlock(&passphrase)?;
+            if json {
+                output::json(&json!({ "ok": true }));
+            } else {
+                println!("Unlocked {} in the agent", key_file);
+            }
+        }
+        // "stop", the only one left
+        _ => {
+            client.stop()?;
+            if json {
+                output::json(&json!({ "ok": true }));
+            } else {
+                println!("Stopped the agent");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn agent_command(_sub_matches: &ArgMatches, _confi: &conf, _json: bool) -> Result<(), ClenvError> {
+    Err(ClenvError::Invalid(String::from(
+        "clenv agent talks over a Unix domain socket and isn't available on this platform",
+    )))
 }
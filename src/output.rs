@@ -0,0 +1,589 @@
+#[cfg(unix)]
+use clenv::sec_db::agent::AgentStatus;
+use clenv::{
+    AccessReport, AgeExport, BundleImport, Changes, ClenvError, DictionaryInfo, Diff, FsckReport,
+    LineTag, MergeReport, MigrationReport, NamespaceReport, Problem, SignatureStatus, SyncReport,
+    VarChange, Verification,
+};
+use colored::Colorize;
+use serde::Serialize;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::path::Path;
+
+// Everything the CLI prints that's more than a line lives here, the library only hands back data
+
+pub fn print_diff(diff: &Diff, mask: bool) {
+    println!("{}", format!("--- {}", diff.left).red());
+    println!("{}", format!("+++ {}", diff.right).green());
+
+    match &diff.changes {
+        Changes::Vars(changes) => print_var_changes(changes, mask),
+        Changes::Lines(groups) => {
+            for (idx, group) in groups.iter().enumerate() {
+                if idx > 0 {
+                    println!("{}", "...".dimmed());
+                }
+                for change in group {
+                    let text = if mask {
+                        format!("line {}", change.line)
+                    } else {
+                        change.text.clone()
+                    };
+                    match change.tag {
+                        LineTag::Delete => println!("{}", format!("- {}", text).red()),
+                        LineTag::Insert => println!("{}", format!("+ {}", text).green()),
+                        // Context lines are only shown when values aren't being hidden
+                        LineTag::Equal if !mask => println!("  {}", text),
+                        LineTag::Equal => {}
+                    }
+                }
+            }
+        }
+        Changes::Binary(true) => println!("Binary contents differ"),
+        Changes::Binary(false) => {}
+    }
+
+    if diff.is_empty() {
+        println!("No differences");
+    }
+}
+
+fn print_var_changes(changes: &[VarChange], mask: bool) {
+    let show = |value: &str| -> String {
+        if mask {
+            String::from("****")
+        } else {
+            value.to_string()
+        }
+    };
+
+    for change in changes {
+        match change {
+            VarChange::Removed { key, value } => {
+                println!("{}", format!("- {}={}", key, show(value)).red())
+            }
+            VarChange::Changed { key, old, new } => {
+                let line = if mask {
+                    format!("~ {} (changed)", key)
+                } else {
+                    format!("~ {}: {} -> {}", key, old, new)
+                };
+                println!("{}", line.yellow());
+            }
+            VarChange::Added { key, value } => {
+                println!("{}", format!("+ {}={}", key, show(value)).green())
+            }
+        }
+    }
+}
+
+pub fn print_added(name: &str, report: &AccessReport) {
+    for ns in &report.namespaces {
+        for entry in &ns.updated {
+            println!(
+                "Added access for {} to entry '{}/{}'",
+                name, ns.namespace, entry
+            );
+        }
+    }
+    if let Some(key_file) = &report.key_file {
+        println!("Private key for {} written to {}", name, key_file);
+    }
+    print_summary(&report.namespaces);
+}
+
+pub fn print_removed(name: &str, rekey: bool, report: &AccessReport) {
+    for ns in &report.namespaces {
+        for entry in &ns.updated {
+            println!(
+                "Removed {}'s access from entry '{}/{}'",
+                name, ns.namespace, entry
+            );
+        }
+    }
+
+    if rekey {
+        let (mut rotated, mut skipped) = (0, 0);
+        for ns in &report.namespaces {
+            for entry in &ns.rotated {
+                println!("Rotated data key of '{}/{}'", ns.namespace, entry);
+            }
+            rotated += ns.rotated.len();
+        }
+        for ns in &report.namespaces {
+            for entry in &ns.skipped {
+                eprintln!(
+                    "Could not rotate '{}/{}': you don't have access to it. Ask someone who does to run 'clenv remove {} --rekey'",
+                    ns.namespace, entry, name
+                );
+            }
+            skipped += ns.skipped.len();
+        }
+        for ns in &report.namespaces {
+            for entry in &ns.invalid {
+                eprintln!(
+                    "Did not rotate '{}/{}': it was changed after it was signed. Check it with 'clenv verify'",
+                    ns.namespace, entry
+                );
+            }
+            skipped += ns.invalid.len();
+        }
+        println!(
+            "Rotated {} entries, {} could not be rotated",
+            rotated, skipped
+        );
+    }
+    if report.index_rotated {
+        println!(
+            "Moved every namespace and entry to a new identifier, so old names can't be looked up"
+        );
+    }
+    if !report.resigned.is_empty() {
+        println!(
+            "Signed {} entries {} had written last in your name, so they stay readable",
+            report.resigned.len(),
+            name
+        );
+    }
+    for entry in &report.left_signed {
+        eprintln!(
+            "{} '{}' is still signed by {}, who can't be checked any more, so it is refused. Check it and run 'clenv reseal'",
+            "warning:".yellow().bold(),
+            entry,
+            name
+        );
+    }
+    print_summary(&report.namespaces);
+}
+
+pub fn print_resealed(namespaces: &[NamespaceReport]) {
+    for ns in namespaces {
+        for entry in &ns.updated {
+            println!("Resealed entry '{}/{}'", ns.namespace, entry);
+        }
+        for entry in &ns.skipped {
+            eprintln!(
+                "Could not reseal '{}/{}': you don't have access to it. Ask someone who does to run 'clenv reseal'",
+                ns.namespace, entry
+            );
+        }
+        for entry in &ns.invalid {
+            eprintln!(
+                "Did not reseal '{}/{}': it was changed after it was signed. Check it with 'clenv verify'",
+                ns.namespace, entry
+            );
+        }
+    }
+    print_summary(namespaces);
+}
+
+fn print_summary(namespaces: &[NamespaceReport]) {
+    println!("Summary:");
+    for ns in namespaces {
+        let mut line = match (ns.exists, ns.skipped.len()) {
+            (false, _) => format!("{}: namespace does not exist", ns.namespace),
+            (true, 0) => format!("{}: {} entries updated", ns.namespace, ns.updated.len()),
+            (true, skipped) => format!(
+                "{}: {} entries updated, {} skipped (you don't have access to them)",
+                ns.namespace,
+                ns.updated.len(),
+                skipped
+            ),
+        };
+        if !ns.invalid.is_empty() {
+            line.push_str(&format!(
+                ", {} left alone (their signature can't be trusted)",
+                ns.invalid.len()
+            ));
+        }
+        println!("- {}", line);
+    }
+}
+
+// Reads as "<ns>/<entry> {}"
+fn describe_signature(status: &SignatureStatus) -> String {
+    match status {
+        SignatureStatus::Valid { signer } => format!("is signed by {}", signer),
+        SignatureStatus::Unsigned => String::from(
+            "is not signed. Run 'clenv reseal' to sign entries stored by older versions",
+        ),
+        SignatureStatus::Stripped => String::from("had its signature removed"),
+        SignatureStatus::UnknownSigner { signer } => {
+            format!("was signed by {}, who is no longer in the keyring", signer)
+        }
+        SignatureStatus::KeyChanged { signer } => {
+            format!(
+                "was signed by {} with a key they have replaced since",
+                signer
+            )
+        }
+        SignatureStatus::NoSigningKey { signer } => format!(
+            "was signed by {}, who is in the keyring as an age recipient only. Add their clenv public key to check it",
+            signer
+        ),
+        SignatureStatus::Invalid { signer } => format!("was changed after {} signed it", signer),
+    }
+}
+
+// Unsigned entries only fail in databases that refuse them
+pub fn print_verifications(verifications: &[Verification], refuses_unsigned: bool) {
+    for v in verifications {
+        let line = format!(
+            "{}/{} {}",
+            v.namespace,
+            v.entry,
+            describe_signature(&v.status)
+        );
+        match v.status {
+            SignatureStatus::Valid { .. } => println!("{} {}", "ok".green(), line),
+            SignatureStatus::Unsigned if !refuses_unsigned => {
+                println!("{} {}", "warning".yellow(), line)
+            }
+            _ => println!("{} {}", "FAILED".red().bold(), line),
+        }
+    }
+}
+
+// Entries whose signature can't be trusted are refused before we get here, unsigned ones are only worth a warning
+pub fn warn_signature(ns: &str, entry: &str, status: &SignatureStatus) {
+    if !status.is_valid() {
+        eprintln!(
+            "{} {}/{} {}",
+            "warning:".yellow().bold(),
+            ns,
+            entry,
+            describe_signature(status)
+        );
+    }
+}
+
+fn describe_problem(problem: &Problem) -> String {
+    match problem {
+        Problem::Undecodable { error } => format!("can't be decoded ({})", error),
+        Problem::InvalidPublicKey => "has a public key that can't be read".to_string(),
+        Problem::UnknownRecipient { user } => {
+            format!("has a key for {}, who isn't in the keyring", user)
+        }
+        Problem::MissingRecipient { user } => format!("has no key for {}", user),
+        Problem::Unreadable { error } => format!("can't be decrypted: {}", error),
+        Problem::InvalidSignature { signer } => {
+            format!("was changed after {} signed it", signer)
+        }
+        Problem::UntrustedSignature { reason } => reason.clone(),
+        Problem::OrphanedChunks { chunks } => {
+            format!("{} chunks that no entry points to", chunks)
+        }
+        Problem::StrayColumnFamily => "is not a namespace clenv knows of".to_string(),
+    }
+}
+
+pub fn print_fsck(report: &FsckReport) {
+    for finding in &report.findings {
+        let line = format!(
+            "{} {}",
+            finding.location,
+            describe_problem(&finding.problem)
+        );
+        match finding.repaired {
+            true => println!("{} {}", "repaired".green(), line),
+            false => println!("{} {}", "FAILED".red().bold(), line),
+        }
+    }
+    if !report.skipped.is_empty() {
+        println!(
+            "Only checked the keys of {}, you have no access to them",
+            report.skipped.join(", ")
+        );
+    }
+    println!(
+        "Checked {} entries, found {} problems, {} repaired",
+        report.checked,
+        report.findings.len(),
+        report.findings.len() - report.unrepaired()
+    );
+}
+
+pub fn print_migration(report: &MigrationReport) {
+    if report.steps.is_empty() && report.upgraded == 0 && report.moved.is_empty() {
+        println!(
+            "The database is already in format version {}, nothing to do",
+            report.to
+        );
+        return;
+    }
+    let verb = match report.dry_run {
+        true => "Would migrate",
+        false => "Migrated",
+    };
+    println!(
+        "{} the database from format version {} to {}",
+        verb, report.from, report.to
+    );
+    for step in &report.steps {
+        println!("  {}", step);
+    }
+    println!(
+        "{} of {} entries, revisions and dictionaries {} rewritten",
+        report.upgraded,
+        report.checked,
+        match report.dry_run {
+            true => "would be",
+            false => "were",
+        }
+    );
+    for (from, to) in &report.moved {
+        println!(
+            "Namespace {} {} moved to {}, the name is used by clenv itself",
+            from,
+            match report.dry_run {
+                true => "would be",
+                false => "was",
+            },
+            to
+        );
+    }
+    if let Some(backup) = &report.backup {
+        println!("Backed up the database to {}", backup.display());
+    }
+}
+
+pub fn print_bundle_import(import: &BundleImport) {
+    print_imported(import);
+    if !import.unknown_members.is_empty() {
+        eprintln!(
+            "{} not in your keyring, so they got no access to what was imported: {}. 'clenv add' gives it to them",
+            "warning:".yellow().bold(),
+            import.unknown_members.join(", ")
+        );
+    }
+    if !import.changed_keys.is_empty() {
+        eprintln!(
+            "{} the bundle's keyring has other keys than yours for {}",
+            "warning:".yellow().bold(),
+            import.changed_keys.join(", ")
+        );
+    }
+    if !import.untrusted.is_empty() {
+        eprintln!(
+            "{} {} entries are unsigned or signed by someone your keyring can't vouch for. --trust-bundle-keys checks them with the bundle's keyring",
+            "warning:".yellow().bold(),
+            import.untrusted.len()
+        );
+    }
+    println!("Imported {} entries", import.imported.len());
+}
+
+pub fn print_merge(other: &str, report: &MergeReport) {
+    for user in &report.added {
+        println!("Added {} to the keyring", user);
+    }
+    for user in &report.collisions {
+        eprintln!(
+            "{} {} has another key in {}, kept the one in your keyring",
+            "warning:".yellow().bold(),
+            user,
+            other
+        );
+    }
+    if !report.access.is_empty() {
+        print_summary(&report.access);
+    }
+    print_imported(&report.import);
+    println!(
+        "Merged {} entries from {}",
+        report.import.imported.len(),
+        other
+    );
+}
+
+pub fn print_sync(action: &str, report: &SyncReport) {
+    let (verb, done, direction) = match action {
+        "push" => ("pushed", "Pushed", "to"),
+        "pull" => ("pulled", "Pulled", "from"),
+        _ => ("cloned", "Cloned", "from"),
+    };
+    for entry in &report.entries {
+        println!("{} {}", verb.green(), entry);
+    }
+    for conflict in &report.conflicts {
+        let revision = |r: Option<u64>| match r {
+            Some(r) => format!("revision {}", r),
+            None => "deleted".to_string(),
+        };
+        match (conflict.local_revision, conflict.remote_revision) {
+            (None, None) => println!("{} {}", "conflict".red(), conflict.record),
+            (local, remote) => println!(
+                "{} {}, {} here, {} on the remote",
+                "conflict".red(),
+                conflict.record,
+                revision(local),
+                revision(remote)
+            ),
+        }
+    }
+    for change in &report.keyring {
+        eprintln!(
+            "{} the remote {}, check it's someone you expect",
+            "warning:".yellow().bold(),
+            change
+        );
+    }
+    for change in &report.pending_keyring {
+        eprintln!(
+            "{} the remote {}, not applied",
+            "warning:".yellow().bold(),
+            change
+        );
+    }
+    if !report.pending_keyring.is_empty() {
+        eprintln!(
+            "Check the key ids with their owners ('clenv key id'), then 'clenv pull --accept-keyring' applies them"
+        );
+    }
+    if report.merged > 0 {
+        println!("Merged {} entry lists both sides added to", report.merged);
+    }
+    println!(
+        "{} {} records {} {}, deleted {}, {} were up to date",
+        done, report.written, direction, report.remote, report.deleted, report.unchanged
+    );
+}
+
+fn print_imported(import: &BundleImport) {
+    for entry in &import.imported {
+        println!("{} {}", "imported".green(), entry);
+    }
+    for entry in &import.kept {
+        println!("{} {}, it already exists", "kept".yellow(), entry);
+    }
+    for entry in &import.no_access {
+        println!("{} {}, you have no access to it", "skipped".yellow(), entry);
+    }
+    for entry in &import.invalid {
+        println!(
+            "{} {}, it was changed after it was signed or doesn't open",
+            "FAILED".red().bold(),
+            entry
+        );
+    }
+    for entry in &import.untrusted {
+        println!(
+            "{} {}, its signature can't be trusted",
+            "skipped".yellow(),
+            entry
+        );
+    }
+}
+
+pub fn print_age_export(entry: &str, export: &AgeExport) {
+    println!(
+        "Exported '{}' to {} for {}",
+        entry,
+        export.path.display(),
+        export.recipients.join(", ")
+    );
+    if !export.skipped.is_empty() {
+        eprintln!(
+            "{} left out {}, age can't encrypt to RSA keys",
+            "warning:".yellow().bold(),
+            export.skipped.join(", ")
+        );
+    }
+}
+
+pub fn print_dictionary(info: &DictionaryInfo) {
+    println!(
+        "Trained dictionary {} of {} ({} bytes) on {}",
+        info.id,
+        info.namespace,
+        info.size,
+        info.entries.join(", ")
+    );
+    if !info.skipped.is_empty() {
+        eprintln!(
+            "{} left out {}, not everyone in the keyring can read them",
+            "warning:".yellow().bold(),
+            info.skipped.join(", ")
+        );
+    }
+    if !info.invalid.is_empty() {
+        eprintln!(
+            "{} left out {}, their signature can't be trusted",
+            "warning:".yellow().bold(),
+            info.invalid.join(", ")
+        );
+    }
+    println!(
+        "Entries stored in {} from now on are compressed with it",
+        info.namespace
+    );
+}
+
+#[cfg(unix)]
+pub fn print_agent_status(socket: &Path, status: &AgentStatus) {
+    println!("Agent: {}", socket.display());
+    println!("Key: {}", status.key_file);
+    match status.expires_in {
+        Some(secs) => println!(
+            "State: {} for another {}m {}s (timeout {}s)",
+            "unlocked".green(),
+            secs / 60,
+            secs % 60,
+            status.timeout
+        ),
+        None => println!(
+            "State: {}. Run 'clenv agent unlock' to use it again",
+            "locked".yellow()
+        ),
+    }
+}
+
+// Everything is pretty printed, jq and editors don't mind and it's readable in a CI log.
+// A reader that stops early (`| head`) is not an error worth panicking over.
+pub fn json(value: &impl Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(text) => {
+            let _ = writeln!(io::stdout(), "{}", text);
+        }
+        Err(e) => eprintln!("Could not encode output as JSON: {}", e),
+    }
+}
+
+pub fn json_error(err: &ClenvError) {
+    eprintln!("{}", error_value(err));
+}
+
+fn error_value(err: &ClenvError) -> serde_json::Value {
+    serde_json::json!({
+        "ok": false,
+        "error": err.to_string(),
+        "code": err.exit_code(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_as_json() {
+        let err = ClenvError::NotFound("No entry a.txt found".to_string());
+        assert_eq!(
+            error_value(&err).to_string(),
+            r#"{"code":3,"error":"No entry a.txt found","ok":false}"#
+        );
+        let err = ClenvError::io(
+            "Could not read a.txt",
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        let value = error_value(&err);
+        assert_eq!(value["code"], 4);
+        assert!(
+            value["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Could not read a.txt: ")
+        );
+    }
+}
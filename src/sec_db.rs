@@ -1,3 +1,23 @@
 pub mod handle_db;
 pub use handle_db::SecDb;
+pub mod age_file;
+#[cfg(unix)]
+pub mod agent;
+pub mod bundle;
+pub mod chunked;
+pub mod compression;
+pub mod diff;
+pub mod dotenv;
+pub mod fsck;
+pub mod history;
 pub mod i_keys;
+pub mod index;
+pub mod merge;
+pub mod migrate;
+pub mod s3;
+pub mod signature;
+pub mod store;
+pub mod suite;
+pub mod sync;
+#[cfg(test)]
+mod testing;
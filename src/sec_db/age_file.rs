@@ -0,0 +1,332 @@
+use super::dotenv;
+use super::handle_db::SecDb;
+use super::i_keys::CryptoError;
+use super::suite::{PrivateKey, PublicKey};
+use crate::config::resolve_path;
+use crate::error::ClenvError;
+use age::armor::ArmoredReader;
+use bech32::{FromBase32, ToBase32, Variant};
+use serde::Serialize;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use x25519_dalek::StaticSecret;
+
+// Entries can be handed to people and tools that speak age (https://age-encryption.org/v1) but not clenv.
+// An age X25519 recipient ("age1...") is the same kind of key as an X25519 clenv key, just written in
+// Bech32, so age users can be keyring members and X25519 users can open age files with their key file.
+// age has no recipient type for our RSA keys, RSA members are left out of exports.
+
+const RECIPIENT_HRP: &str = "age";
+const IDENTITY_HRP: &str = "age-secret-key-";
+pub const EXTENSION: &str = "age";
+
+/// The "age1..." recipient of an X25519 key
+pub fn recipient(key: &x25519_dalek::PublicKey) -> String {
+    bech32::encode(RECIPIENT_HRP, key.as_bytes().to_base32(), Variant::Bech32)
+        .expect("the age HRP is valid")
+}
+
+// The 32 bytes of a Bech32 key with the given prefix
+fn decode(text: &str, hrp: &str) -> Option<[u8; 32]> {
+    let (found, data, variant) = bech32::decode(text).ok()?;
+    if found != hrp || variant != Variant::Bech32 {
+        return None;
+    }
+    Vec::<u8>::from_base32(&data).ok()?.try_into().ok()
+}
+
+// Lines of a key file without the comments age-keygen writes
+fn key_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// The key of a text holding a single "age1..." recipient, as "age-keygen -y" prints it
+pub fn parse_recipient(text: &str) -> Option<x25519_dalek::PublicKey> {
+    let mut lines = key_lines(text);
+    let line = lines.next()?;
+    if lines.next().is_some() {
+        return None;
+    }
+    decode(line, RECIPIENT_HRP).map(x25519_dalek::PublicKey::from)
+}
+
+/// The secret of an age identity file ("AGE-SECRET-KEY-1..."), as age-keygen writes it
+pub fn parse_identity(text: &str) -> Option<StaticSecret> {
+    key_lines(text)
+        .find_map(|line| decode(line, IDENTITY_HRP))
+        .map(StaticSecret::from)
+}
+
+fn identity(key: &PrivateKey) -> Result<age::x25519::Identity, ClenvError> {
+    let PrivateKey::X25519(secret) = key else {
+        return Err(ClenvError::Invalid(
+            "age files can only be opened with an X25519 key, yours is an RSA key".to_string(),
+        ));
+    };
+    let encoded = bech32::encode(IDENTITY_HRP, secret.as_bytes().to_base32(), Variant::Bech32)
+        .expect("the age HRP is valid");
+    encoded
+        .to_uppercase()
+        .parse()
+        .map_err(|e: &str| CryptoError::Age(e.to_string()).into())
+}
+
+fn age_error(e: impl std::fmt::Display) -> ClenvError {
+    CryptoError::Age(e.to_string()).into()
+}
+
+/// What `export_age` wrote
+#[derive(Debug, Clone, Serialize)]
+pub struct AgeExport {
+    pub path: PathBuf,
+    // Users who can decrypt the file
+    pub recipients: Vec<String>,
+    // Users with access to the entry that age can't encrypt to (RSA keys)
+    pub skipped: Vec<String>,
+}
+
+impl SecDb {
+    /// Writes an entry of the current namespace as an age file, encrypted to every X25519 or age key
+    /// among the users with access to it. Written next to where `dump_file` would put it, with ".age" added,
+    /// unless `out` is given.
+    pub fn export_age(&self, name: &str, out: Option<&str>) -> Result<AgeExport, ClenvError> {
+        let ns = self.namespace()?;
+        let entry = self.entry(&ns, name)?;
+
+        let (mut recipients, mut names, mut skipped) = (Vec::new(), Vec::new(), Vec::new());
+        for (user, key) in self.get_recipients()? {
+            // Nobody gets more access through the age file than they have in clenv
+            if !entry.encrypted_keys.contains_key(&user) {
+                continue;
+            }
+            match key {
+                PublicKey::X25519 { agreement, .. } => {
+                    let recipient: age::x25519::Recipient =
+                        recipient(&agreement).parse().map_err(age_error)?;
+                    recipients.push(recipient);
+                    names.push(user);
+                }
+                PublicKey::Rsa(_) => skipped.push(user),
+            }
+        }
+        if recipients.is_empty() {
+            return Err(ClenvError::Invalid(format!(
+                "Nobody with access to {} has an X25519 or age key, so nobody could open the age file. Add one with 'clenv add <name> --pubkey age1...'",
+                name
+            )));
+        }
+
+        let path = match out {
+            Some(out) => PathBuf::from(out),
+            None => {
+                let mut path = PathBuf::from(name);
+                path.set_extension(&entry.extension);
+                let mut file_name = path.into_os_string();
+                file_name.push(".");
+                file_name.push(EXTENSION);
+                PathBuf::from(file_name)
+            }
+        };
+        let encryptor =
+            age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                .map_err(age_error)?;
+        let write_err = |e| ClenvError::io(format!("Could not write {}", path.display()), e);
+        // Decrypted straight into the age file, so large entries don't have to fit in memory
+        let written = fs::File::create(&path).map_err(write_err).and_then(|file| {
+            let mut writer = encryptor
+                .wrap_output(BufWriter::new(file))
+                .map_err(write_err)?;
+            self.decrypt_entry_to(&ns, name, &mut writer)?;
+            writer
+                .finish()
+                .and_then(|mut file| file.flush())
+                .map_err(write_err)
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+
+        Ok(AgeExport {
+            path,
+            recipients: names,
+            skipped,
+        })
+    }
+
+    /// Decrypts an age file (binary or armored) with our key and stores it in the current namespace.
+    /// The entry is named after the file without ".age" unless `name` is given.
+    /// Returns the entry name and the revision it was stored as.
+    pub fn import_age(
+        &mut self,
+        filename: &str,
+        name: Option<&str>,
+    ) -> Result<(String, u64), ClenvError> {
+        let file = resolve_path(filename, "");
+        let encrypted = fs::read(&file)
+            .map_err(|e| ClenvError::io(format!("Could not read {}", file.display()), e))?;
+        let identity = identity(&self.private_key()?)?;
+
+        let decryptor =
+            age::Decryptor::new(ArmoredReader::new(&encrypted[..])).map_err(age_error)?;
+        if decryptor.is_scrypt() {
+            return Err(ClenvError::Invalid(format!(
+                "{} is encrypted with a passphrase. Only age files encrypted to a recipient can be imported",
+                filename
+            )));
+        }
+        let reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .map_err(|e| match e {
+                age::DecryptError::NoMatchingKeys => ClenvError::AccessDenied(format!(
+                    "{} was not encrypted to your key {}",
+                    filename,
+                    identity.to_public()
+                )),
+                e => age_error(e),
+            })?;
+
+        // "prod.env.age" is stored like "clenv store prod.env" would store it
+        let inner = filename
+            .strip_suffix(&format!(".{}", EXTENSION))
+            .unwrap_or(filename);
+        let path = Path::new(inner);
+        let name = name.unwrap_or(inner).to_string();
+        let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+
+        let revision = self.store_reader(&name, reader, extension, dotenv::is_dotenv(path))?;
+        Ok((name, revision))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::suite::Suite;
+    use crate::sec_db::testing::{TempDir, add_member, open_db, open_db_with};
+    use age::secrecy::ExposeSecret;
+    use std::io::Read;
+
+    fn decrypt(path: &Path, identity: &age::x25519::Identity) -> Vec<u8> {
+        let encrypted = fs::read(path).unwrap();
+        let decryptor = age::Decryptor::new(ArmoredReader::new(&encrypted[..])).unwrap();
+        let mut plain = Vec::new();
+        decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        plain
+    }
+
+    #[test]
+    fn keys_as_age_keygen_writes_them() {
+        let identity = age::x25519::Identity::generate();
+        let secret = identity.to_string();
+        let public = identity.to_public().to_string();
+        let file = format!(
+            "# created: 2026-10-18T12:00:00Z\n# public key: {}\n{}\n",
+            public,
+            secret.expose_secret()
+        );
+
+        let key = parse_identity(&file).unwrap();
+        assert_eq!(recipient(&x25519_dalek::PublicKey::from(&key)), public);
+        let parsed = parse_recipient(&format!("# bob\n{}\n", public)).unwrap();
+        assert_eq!(recipient(&parsed), public);
+
+        // Two recipients or the wrong kind of key are not a recipient
+        assert!(parse_recipient(&format!("{}\n{}\n", public, public)).is_none());
+        assert!(parse_recipient(secret.expose_secret()).is_none());
+        assert!(parse_identity(&public).is_none());
+    }
+
+    #[test]
+    fn entries_round_trip_through_age() {
+        let tmp = TempDir::new();
+        let mut alice = open_db(&tmp, "db", "alice");
+        add_member(&tmp, &alice, "bob", &["dev"]);
+        // Someone who only uses the age CLI
+        let carol = age::x25519::Identity::generate();
+        alice
+            .add_user("carol", Some(&carol.to_public().to_string()), &[])
+            .unwrap();
+        let env = b"export A=1\nB=\"two words\"\n";
+        alice.store(".env", env, "env", true).unwrap();
+
+        let out = tmp.path().join("prod.env.age");
+        let export = alice.export_age(".env", out.to_str()).unwrap();
+        assert_eq!(export.path, out);
+        let mut recipients = export.recipients.clone();
+        recipients.sort();
+        assert_eq!(recipients, ["alice", "bob", "carol"]);
+        assert!(export.skipped.is_empty());
+        assert_eq!(decrypt(&out, &carol), env);
+
+        // Dotenv files go back in as dotenv entries
+        drop(alice);
+        let mut bob = open_db(&tmp, "db", "bob");
+        let (name, revision) = bob
+            .import_age(out.to_str().unwrap(), Some("prod.env"))
+            .unwrap();
+        assert_eq!((name.as_str(), revision), ("prod.env", 1));
+        assert_eq!(bob.read_entry("prod.env").unwrap(), env);
+        assert_eq!(bob.get_var("prod.env", "B").unwrap(), "two words");
+
+        // Armored files are read as well
+        let armored = tmp.path().join("armored.txt.age");
+        let bob_recipient: age::x25519::Recipient = match bob.private_key().unwrap().public_key() {
+            PublicKey::X25519 { agreement, .. } => recipient(&agreement).parse().unwrap(),
+            PublicKey::Rsa(_) => unreachable!(),
+        };
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&bob_recipient as _)).unwrap();
+        let writer = age::armor::ArmoredWriter::wrap_output(
+            fs::File::create(&armored).unwrap(),
+            age::armor::Format::AsciiArmor,
+        )
+        .unwrap();
+        let mut writer = encryptor.wrap_output(writer).unwrap();
+        writer.write_all(b"armored").unwrap();
+        writer.finish().and_then(|w| w.finish()).unwrap();
+        bob.import_age(armored.to_str().unwrap(), Some("armored.txt"))
+            .unwrap();
+        assert_eq!(bob.read_entry("armored.txt").unwrap(), b"armored");
+    }
+
+    #[test]
+    fn files_only_open_for_their_recipients() {
+        let tmp = TempDir::new();
+        let mut alice = open_db(&tmp, "db", "alice");
+        let carol = age::x25519::Identity::generate();
+        let file = tmp.path().join("a.txt.age");
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&carol.to_public() as _)).unwrap();
+        let mut writer = encryptor
+            .wrap_output(fs::File::create(&file).unwrap())
+            .unwrap();
+        writer.write_all(b"for carol").unwrap();
+        writer.finish().unwrap();
+        assert!(matches!(
+            alice.import_age(file.to_str().unwrap(), Some("a.txt")),
+            Err(ClenvError::AccessDenied(_))
+        ));
+
+        // age can't encrypt to RSA keys
+        let mut rsa = open_db_with(&tmp, "rsa", "dave", Suite::Rsa);
+        rsa.store("a.txt", b"a", "txt", false).unwrap();
+        let out = tmp.path().join("rsa.age");
+        assert!(matches!(
+            rsa.export_age("a.txt", out.to_str()),
+            Err(ClenvError::Invalid(_))
+        ));
+        assert!(!out.exists());
+        assert!(matches!(
+            rsa.import_age(file.to_str().unwrap(), Some("a.txt")),
+            Err(ClenvError::Invalid(_))
+        ));
+    }
+}
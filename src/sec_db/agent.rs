@@ -0,0 +1,592 @@
+use super::i_keys::{CryptoError, i_keys};
+use super::suite::PrivateKey;
+use crate::error::ClenvError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// "clenv agent" keeps the decrypted private key in memory so the passphrase is typed once, not for every command.
+// The key never leaves the agent: clients send it wrapped data keys to unwrap and messages to sign over a
+// Unix socket only the user can open, one JSON request and response per line.
+// The key is dropped again when the timeout runs out or on "clenv agent lock".
+
+// Overrides where the socket is created and looked for
+pub const SOCKET_ENV: &str = "CLENV_AGENT_SOCK";
+
+// How long the key stays unlocked when no timeout is given, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 600;
+
+// How often an idle agent checks whether the key's time ran out
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// A client that connects and then says nothing can't keep the agent from dropping the key
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// An agent that doesn't answer within this is treated as absent, so a wedged agent can't hang every command
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+// Unlocking derives the key from the passphrase, which is slow on purpose
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    // In a folder of its own that only the user can open, created before the socket is
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("clenv").join("agent.sock"),
+        None => dirs::config_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("clenv")
+            .join("agent")
+            .join("agent.sock"),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Lock,
+    Unlock { passphrase: String },
+    Unwrap { wrapped: String },
+    Sign { message: String },
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(AgentStatus),
+    Key { key: String },
+    Signature { signature: String },
+    // The key isn't unlocked, the client falls back to reading the key file itself
+    Locked,
+    Error { kind: Failure, message: String },
+}
+
+/// What kind of error the agent ran into, so clients fail the same way they would without an agent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    // The key couldn't unwrap or sign what it was given
+    Crypto,
+    // Wrong passphrase
+    AccessDenied,
+    // The request itself made no sense
+    Invalid,
+    // Anything else, like the key file going missing
+    Other,
+}
+
+impl Response {
+    fn error(e: ClenvError) -> Self {
+        let kind = match e {
+            ClenvError::Crypto(_) => Failure::Crypto,
+            ClenvError::AccessDenied(_) => Failure::AccessDenied,
+            ClenvError::Invalid(_) => Failure::Invalid,
+            _ => Failure::Other,
+        };
+        Response::Error {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// What "clenv agent status" shows
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentStatus {
+    pub key_file: String,
+    pub unlocked: bool,
+    // Seconds until the key is dropped, None while locked
+    pub expires_in: Option<u64>,
+    pub timeout: u64,
+    // `i_keys::key_id` of the unlocked key, so clients can sign without ever seeing it
+    pub key_id: Option<String>,
+}
+
+struct Agent {
+    key_file: String,
+    timeout: Duration,
+    key: Option<(PrivateKey, Instant)>,
+}
+
+impl Agent {
+    // Drops the key once its time ran out
+    fn expire(&mut self) {
+        if self
+            .key
+            .as_ref()
+            .is_some_and(|(_, since)| since.elapsed() >= self.timeout)
+        {
+            self.key = None;
+        }
+    }
+
+    // The key, unless it was never unlocked or its time ran out
+    fn key(&mut self) -> Option<&PrivateKey> {
+        self.expire();
+        self.key.as_ref().map(|(key, _)| key)
+    }
+
+    fn status(&mut self) -> AgentStatus {
+        let timeout = self.timeout;
+        let key_id = self
+            .key()
+            .and_then(|key| i_keys::key_id(&key.public_key()).ok());
+        AgentStatus {
+            key_file: self.key_file.clone(),
+            unlocked: self.key.is_some(),
+            expires_in: self
+                .key
+                .as_ref()
+                .map(|(_, since)| timeout.saturating_sub(since.elapsed()).as_secs()),
+            timeout: timeout.as_secs(),
+            key_id,
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        match request {
+            Request::Status => Response::Status(self.status()),
+            Request::Lock => {
+                self.key = None;
+                Response::Ok
+            }
+            Request::Unlock { passphrase } => {
+                match i_keys::read_private_key(&self.key_file, &move |_| Ok(passphrase.clone())) {
+                    Ok(key) => {
+                        self.key = Some((key, Instant::now()));
+                        Response::Ok
+                    }
+                    Err(e) => Response::error(e),
+                }
+            }
+            Request::Unwrap { wrapped } => {
+                let Some(key) = self.key() else {
+                    return Response::Locked;
+                };
+                match decode(&wrapped).and_then(|wrapped| Ok(i_keys::unwrap_key(&wrapped, key)?)) {
+                    Ok(data_key) => Response::Key {
+                        key: BASE64.encode(data_key),
+                    },
+                    Err(e) => Response::error(e),
+                }
+            }
+            Request::Sign { message } => {
+                let Some(key) = self.key() else {
+                    return Response::Locked;
+                };
+                match decode(&message).and_then(|message| Ok(key.sign(&message)?)) {
+                    Ok(signature) => Response::Signature {
+                        signature: BASE64.encode(signature),
+                    },
+                    Err(e) => Response::error(e),
+                }
+            }
+            // Answered and acted on by `serve`
+            Request::Stop => Response::Ok,
+        }
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, ClenvError> {
+    BASE64
+        .decode(value)
+        .map_err(|e| ClenvError::Invalid(format!("Invalid agent message: {}", e)))
+}
+
+/// Runs the agent for `key_file` until it is told to stop. `key` is the already unlocked key, if any.
+/// Requests are answered one at a time, which is plenty for a single user's terminals.
+pub fn serve(
+    key_file: &str,
+    key: Option<PrivateKey>,
+    timeout: Duration,
+    socket: &Path,
+) -> Result<(), ClenvError> {
+    // Folders we create are private, so nobody else can reach the socket even before it's chmod'ed below
+    if let Some(dir) = socket.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|e| ClenvError::io(format!("Could not create {}", dir.display()), e))?;
+        check_socket_dir(dir)?;
+    }
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(ClenvError::Invalid(format!(
+                "An agent is already running at {}. Stop it with 'clenv agent stop'",
+                socket.display()
+            )));
+        }
+        // Left behind by an agent that didn't shut down cleanly
+        fs::remove_file(socket)
+            .map_err(|e| ClenvError::io(format!("Could not remove {}", socket.display()), e))?;
+    }
+
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| ClenvError::io(format!("Could not listen on {}", socket.display()), e))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))
+        .map_err(|e| ClenvError::io(format!("Could not protect {}", socket.display()), e))?;
+    // Polled, so the key is dropped on time even when nobody asks for it
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| ClenvError::io(format!("Could not listen on {}", socket.display()), e))?;
+
+    let mut agent = Agent {
+        key_file: key_file.to_string(),
+        timeout,
+        key: key.map(|key| (key, Instant::now())),
+    };
+
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                agent.expire();
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        // A client that goes away or stalls mid request only ends its own connection
+        let configured = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(CLIENT_TIMEOUT)));
+        if configured.is_err() {
+            continue;
+        }
+        if let Ok(true) = answer(&mut agent, stream) {
+            break;
+        }
+    }
+    let _ = fs::remove_file(socket);
+    Ok(())
+}
+
+// Whoever can write to the folder of the socket could put their own in its place and be sent passphrases
+// and data keys, so an existing folder has to be ours and closed to everyone else
+fn check_socket_dir(dir: &Path) -> Result<(), ClenvError> {
+    let metadata = fs::symlink_metadata(dir)
+        .map_err(|e| ClenvError::io(format!("Could not read {}", dir.display()), e))?;
+    // SAFETY: geteuid has no preconditions and can't fail
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(ClenvError::AccessDenied(format!(
+            "{} has to be a folder of yours that only you can open, or others could get at the agent's socket. Run 'chmod 700 {}' or set {} to a socket in another folder",
+            dir.display(),
+            dir.display(),
+            SOCKET_ENV
+        )));
+    }
+    Ok(())
+}
+
+// Answers every request on one connection. Returns true when the agent was asked to stop
+fn answer(agent: &mut Agent, stream: UnixStream) -> std::io::Result<bool> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let response = match serde_json::from_str::<Request>(&line?) {
+            Ok(Request::Stop) => {
+                write_line(&mut writer, &Response::Ok)?;
+                return Ok(true);
+            }
+            Ok(request) => agent.handle(request),
+            Err(e) => Response::error(ClenvError::Invalid(format!("Invalid request: {}", e))),
+        };
+        write_line(&mut writer, &response)?;
+    }
+    Ok(false)
+}
+
+fn write_line(writer: &mut impl Write, value: &impl Serialize) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+/// Talks to a running agent
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    socket: PathBuf,
+}
+
+impl AgentClient {
+    /// Client for the agent at `socket`, None when nothing is listening there
+    pub fn connect(socket: &Path) -> Option<AgentClient> {
+        UnixStream::connect(socket).ok()?;
+        Some(AgentClient {
+            socket: socket.to_path_buf(),
+        })
+    }
+
+    /// The agent at the default socket if it serves `key_file`
+    pub fn for_key(key_file: &str) -> Option<AgentClient> {
+        let client = Self::connect(&socket_path())?;
+        let status = client.status().ok()?;
+        same_file(&status.key_file, key_file).then_some(client)
+    }
+
+    pub fn request(&self, request: &Request) -> Result<Response, ClenvError> {
+        self.answer(request)?.ok_or_else(|| {
+            ClenvError::Storage(format!(
+                "The agent at {} didn't answer in time",
+                self.socket.display()
+            ))
+        })
+    }
+
+    // None when the agent doesn't answer in time
+    fn answer(&self, request: &Request) -> Result<Option<Response>, ClenvError> {
+        let io_err = |e| {
+            ClenvError::io(
+                format!("Could not talk to the agent at {}", self.socket.display()),
+                e,
+            )
+        };
+        let timeout = match request {
+            Request::Unlock { .. } => UNLOCK_TIMEOUT,
+            _ => ANSWER_TIMEOUT,
+        };
+        let mut stream = UnixStream::connect(&self.socket).map_err(io_err)?;
+        stream.set_read_timeout(Some(timeout)).map_err(io_err)?;
+        stream.set_write_timeout(Some(timeout)).map_err(io_err)?;
+
+        let mut line = String::new();
+        let exchanged = write_line(&mut stream, request)
+            .and_then(|_| BufReader::new(stream).read_line(&mut line));
+        match exchanged {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None);
+            }
+            Err(e) => return Err(io_err(e)),
+            Ok(_) => {}
+        }
+        let response = serde_json::from_str(&line)
+            .map_err(|e| ClenvError::Storage(format!("Unexpected answer from the agent: {}", e)))?;
+        match response {
+            Response::Error { kind, message } => Err(match kind {
+                Failure::Crypto => CryptoError::Agent(message).into(),
+                Failure::AccessDenied => ClenvError::AccessDenied(message),
+                Failure::Invalid => ClenvError::Invalid(message),
+                Failure::Other => ClenvError::Storage(message),
+            }),
+            response => Ok(Some(response)),
+        }
+    }
+
+    pub fn status(&self) -> Result<AgentStatus, ClenvError> {
+        match self.request(&Request::Status)? {
+            Response::Status(status) => Ok(status),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Id of the key the agent signs with, None while it is locked or doesn't answer in time
+    pub fn key_id(&self) -> Result<Option<String>, ClenvError> {
+        match self.answer(&Request::Status)? {
+            Some(Response::Status(status)) => Ok(status.key_id),
+            None => Ok(None),
+            Some(other) => Err(unexpected(other)),
+        }
+    }
+
+    /// Unwraps a data key with the agent's key. None while the agent is locked or doesn't answer in time
+    pub fn unwrap_key(&self, wrapped: &[u8]) -> Result<Option<Vec<u8>>, ClenvError> {
+        let request = Request::Unwrap {
+            wrapped: BASE64.encode(wrapped),
+        };
+        match self.answer(&request)? {
+            Some(Response::Key { key }) => Ok(Some(decode(&key)?)),
+            None | Some(Response::Locked) => Ok(None),
+            Some(other) => Err(unexpected(other)),
+        }
+    }
+
+    /// Signature over `message` with the agent's key, see `PrivateKey::sign`.
+    /// None while the agent is locked or doesn't answer in time
+    pub fn sign(&self, message: &[u8]) -> Result<Option<Vec<u8>>, ClenvError> {
+        let request = Request::Sign {
+            message: BASE64.encode(message),
+        };
+        match self.answer(&request)? {
+            Some(Response::Signature { signature }) => Ok(Some(decode(&signature)?)),
+            None | Some(Response::Locked) => Ok(None),
+            Some(other) => Err(unexpected(other)),
+        }
+    }
+
+    pub fn lock(&self) -> Result<(), ClenvError> {
+        self.request(&Request::Lock).map(|_| ())
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<(), ClenvError> {
+        let request = Request::Unlock {
+            passphrase: passphrase.to_string(),
+        };
+        self.request(&request).map(|_| ())
+    }
+
+    pub fn stop(&self) -> Result<(), ClenvError> {
+        self.request(&Request::Stop).map(|_| ())
+    }
+}
+
+fn unexpected(response: Response) -> ClenvError {
+    ClenvError::Storage(format!("Unexpected answer from the agent: {:?}", response))
+}
+
+// Config paths and the agent's path may be written differently for the same file
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::suite::Suite;
+    use crate::sec_db::testing::TempDir;
+    use std::thread;
+
+    // Serves `key_file` from a socket in `dir`, unlocked with `key` when given
+    fn start(
+        dir: &TempDir,
+        key_file: &str,
+        key: Option<PrivateKey>,
+    ) -> (AgentClient, thread::JoinHandle<()>) {
+        let socket = dir.path().join("agent").join("agent.sock");
+        let (file, path) = (key_file.to_string(), socket.clone());
+        let agent = thread::spawn(move || {
+            serve(&file, key, Duration::from_secs(60), &path).unwrap();
+        });
+        for _ in 0..200 {
+            if let Some(client) = AgentClient::connect(&socket) {
+                return (client, agent);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the agent didn't start");
+    }
+
+    #[test]
+    fn unwraps_and_signs_with_the_unlocked_key() {
+        let dir = TempDir::new();
+        let key = PrivateKey::generate(Suite::X25519).unwrap();
+        let key_file = dir.path().join("me.pem");
+        let key_file = key_file.to_str().unwrap();
+        i_keys::write_private_key(&key, key_file, "pw").unwrap();
+        let (client, agent) = start(&dir, key_file, Some(key.clone()));
+
+        let data_key = i_keys::generate_data_key();
+        let wrapped = key.public_key().wrap_key(&data_key).unwrap();
+        assert_eq!(client.unwrap_key(&wrapped).unwrap(), Some(data_key.clone()));
+        let signature = client.sign(b"message").unwrap().unwrap();
+        assert!(key.public_key().verify(b"message", &signature));
+        assert!(client.status().unwrap().unlocked);
+
+        client.lock().unwrap();
+        assert_eq!(client.unwrap_key(&wrapped).unwrap(), None);
+        assert_eq!(client.sign(b"message").unwrap(), None);
+
+        assert!(matches!(
+            client.unlock("wrong"),
+            Err(ClenvError::AccessDenied(_))
+        ));
+        client.unlock("pw").unwrap();
+        assert_eq!(client.unwrap_key(&wrapped).unwrap(), Some(data_key));
+
+        client.stop().unwrap();
+        agent.join().unwrap();
+        assert!(AgentClient::connect(&dir.path().join("agent").join("agent.sock")).is_none());
+    }
+
+    #[test]
+    fn errors_are_the_same_as_without_the_agent() {
+        let dir = TempDir::new();
+        let key = PrivateKey::generate(Suite::X25519).unwrap();
+        let (client, agent) = start(&dir, "me.pem", Some(key.clone()));
+
+        // A key wrapped for someone else fails as a crypto error either way, which callers skip over
+        let theirs = PrivateKey::generate(Suite::X25519).unwrap();
+        let wrapped = theirs
+            .public_key()
+            .wrap_key(&i_keys::generate_data_key())
+            .unwrap();
+        assert!(i_keys::unwrap_key(&wrapped, &key).is_err());
+        assert!(matches!(
+            client.unwrap_key(&wrapped),
+            Err(ClenvError::Crypto(_))
+        ));
+
+        // Unlocking reads the key file, which isn't there
+        assert!(matches!(client.unlock("pw"), Err(ClenvError::Storage(_))));
+
+        let garbage = client.request(&Request::Unwrap {
+            wrapped: "not base64!".to_string(),
+        });
+        assert!(matches!(garbage, Err(ClenvError::Invalid(_))));
+
+        client.stop().unwrap();
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn agents_that_dont_answer_are_gone_without() {
+        let dir = TempDir::new();
+        let socket = dir.path().join("agent.sock");
+        // Connections wait in the backlog and are never answered
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let client = AgentClient::connect(&socket).unwrap();
+
+        let started = Instant::now();
+        assert_eq!(client.unwrap_key(b"wrapped").unwrap(), None);
+        assert_eq!(client.key_id().unwrap(), None);
+        assert!(matches!(client.status(), Err(ClenvError::Storage(_))));
+        assert!(started.elapsed() < ANSWER_TIMEOUT * 4);
+    }
+
+    #[test]
+    fn sockets_are_only_served_from_private_folders() {
+        let dir = TempDir::new();
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        let socket = shared.join("agent.sock");
+        for mode in [0o755, 0o770, 0o701] {
+            fs::set_permissions(&shared, fs::Permissions::from_mode(mode)).unwrap();
+            assert!(matches!(
+                serve("me.pem", None, Duration::from_secs(1), &socket),
+                Err(ClenvError::AccessDenied(_))
+            ));
+            assert!(!socket.exists());
+        }
+
+        // Not even a stale socket is removed from there
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(&socket, "").unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o750)).unwrap();
+        assert!(serve("me.pem", None, Duration::from_secs(1), &socket).is_err());
+        assert!(socket.exists());
+
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&shared, &link).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(matches!(
+            serve(
+                "me.pem",
+                None,
+                Duration::from_secs(1),
+                &link.join("agent.sock")
+            ),
+            Err(ClenvError::AccessDenied(_))
+        ));
+    }
+}
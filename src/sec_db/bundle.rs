@@ -0,0 +1,627 @@
+use super::chunked::{CHUNKED_FROM, CHUNKS_CF};
+use super::compression::{Compression, dictionary_name};
+use super::handle_db::{EncryptedEntry, EntryKind, SecDb};
+use super::i_keys::i_keys;
+use super::signature::SignatureStatus;
+use super::suite::PublicKey;
+use crate::error::ClenvError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// A bundle carries entries from one database to another as a single file. Entries stay encrypted as
+// they are stored, with the dictionaries they were compressed with, the chunks of large files and the
+// keyring of the exporting database, so signatures can be checked on import.
+// Entry and namespace names are readable in a bundle, like in a database that isn't obfuscated.
+// The file is a header followed by one record after the other, so large entries never have to fit in memory.
+const BUNDLE_HEADER: &[u8] = b"clenv bundle\n";
+const BUNDLE_VERSION: u32 = 1;
+// The largest record is an entry just under CHUNKED_FROM that didn't compress, with its keys and signature
+const RECORD_LIMIT: usize = CHUNKED_FROM as usize + (1 << 20);
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Member {
+        user: String,
+        public_key: String,
+    },
+    // A dictionary of a namespace as stored, written before the entries of its namespace
+    Dictionary {
+        namespace: String,
+        id: u32,
+        value: Vec<u8>,
+    },
+    // A current entry with its metadata opened. Chunked entries are followed by one `Chunk` per chunk
+    Entry {
+        namespace: String,
+        name: String,
+        value: Vec<u8>,
+    },
+    Chunk(Vec<u8>),
+}
+
+/// What `import_bundle` does with an entry that exists in both the bundle and the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // Keep the entry of the database
+    Skip,
+    // Store the bundled entry as a new revision
+    Overwrite,
+    // Store the bundled entry when it was written after the one in the database
+    KeepNewest,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = ClenvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep-newest" => Ok(ConflictPolicy::KeepNewest),
+            other => Err(ClenvError::Invalid(format!(
+                "Unknown conflict policy '{}', use skip, overwrite or keep-newest",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::KeepNewest => "keep-newest",
+        })
+    }
+}
+
+/// What `export_bundle` wrote
+#[derive(Debug, Serialize)]
+pub struct BundleExport {
+    pub path: PathBuf,
+    // Entries as "namespace/name"
+    pub entries: Vec<String>,
+    pub members: Vec<String>,
+    pub dictionaries: usize,
+}
+
+/// What `import_bundle` did with every entry of the bundle, as "namespace/name"
+#[derive(Debug, Default, Serialize)]
+pub struct BundleImport {
+    pub imported: Vec<String>,
+    // Left as they are in the database because of the conflict policy
+    pub kept: Vec<String>,
+    // Entries or their dictionaries we hold no key for
+    pub no_access: Vec<String>,
+    // Changed after they were signed, or they don't open
+    pub invalid: Vec<String>,
+    // Unsigned, or signed by someone our keyring can't vouch for
+    pub untrusted: Vec<String>,
+    // Members of the bundle's keyring that aren't in ours and don't get access to the imported entries
+    pub unknown_members: Vec<String>,
+    // Members whose key in the bundle differs from the one in our keyring
+    pub changed_keys: Vec<String>,
+}
+
+fn write_record(writer: &mut impl Write, record: &Record) -> Result<(), ClenvError> {
+    bincode::serde::encode_into_std_write(record, writer, bincode::config::standard())?;
+    Ok(())
+}
+
+// The next record, None at the end of the bundle
+fn read_record(reader: &mut impl BufRead) -> Result<Option<Record>, ClenvError> {
+    let at_end = reader
+        .fill_buf()
+        .map_err(|e| ClenvError::io("Could not read the bundle", e))?
+        .is_empty();
+    if at_end {
+        return Ok(None);
+    }
+    // Length prefixes come from the file, so they are capped before anything is allocated for them
+    let config = bincode::config::standard().with_limit::<RECORD_LIMIT>();
+    bincode::serde::decode_from_std_read(reader, config)
+        .map(Some)
+        .map_err(|e| ClenvError::Storage(format!("The bundle is damaged: {}", e)))
+}
+
+fn read_chunk(reader: &mut impl BufRead) -> Result<Vec<u8>, ClenvError> {
+    match read_record(reader)? {
+        Some(Record::Chunk(value)) => Ok(value),
+        _ => Err(ClenvError::Storage(
+            "The bundle is missing chunks of a large entry".to_string(),
+        )),
+    }
+}
+
+fn skip_chunks(reader: &mut impl BufRead, entry: &EncryptedEntry) -> Result<(), ClenvError> {
+    for _ in entry.chunked().map_or(&[][..], |blob| &blob.digests) {
+        read_chunk(reader)?;
+    }
+    Ok(())
+}
+
+fn open_bundle(path: &Path) -> Result<BufReader<fs::File>, ClenvError> {
+    let file = fs::File::open(path)
+        .map_err(|e| ClenvError::io(format!("Could not read {}", path.display()), e))?;
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8; BUNDLE_HEADER.len() + 4];
+    if reader.read_exact(&mut header).is_err() || !header.starts_with(BUNDLE_HEADER) {
+        return Err(ClenvError::Invalid(format!(
+            "{} is not a clenv bundle. Pass --age for age files",
+            path.display()
+        )));
+    }
+    let version = u32::from_be_bytes(header[BUNDLE_HEADER.len()..].try_into().unwrap_or_default());
+    if version > BUNDLE_VERSION {
+        return Err(ClenvError::Invalid(format!(
+            "{} was written by a newer version of clenv (bundle version {}). Update clenv to import it",
+            path.display(),
+            version
+        )));
+    }
+    Ok(reader)
+}
+
+// Checks a bundled entry with the signer's key in our keyring. With `trust_bundle_keys`, signers that
+// aren't in it or have another key in it are checked with the key the bundle carries for them
+fn bundled_status(
+    entry: &EncryptedEntry,
+    ns: &str,
+    name: &str,
+    local: &HashMap<&String, &PublicKey>,
+    members: &HashMap<String, Option<PublicKey>>,
+    trust_bundle_keys: bool,
+) -> Result<SignatureStatus, ClenvError> {
+    let Some(signature) = &entry.signature else {
+        return Ok(entry.signature_status(ns, name, None)?);
+    };
+    let ours = local.get(&signature.signer).copied();
+    let status = entry.signature_status(ns, name, ours)?;
+    match status {
+        SignatureStatus::UnknownSigner { .. } | SignatureStatus::KeyChanged { .. }
+            if trust_bundle_keys =>
+        {
+            let theirs = members.get(&signature.signer).and_then(Option::as_ref);
+            Ok(entry.signature_status(ns, name, theirs)?)
+        }
+        status => Ok(status),
+    }
+}
+
+// Whether a bundled entry with this status may be imported. Unsigned entries of older formats have
+// nobody to vouch for them, so they take `trust_bundle_keys` as well
+fn accepted(status: &SignatureStatus, trust_bundle_keys: bool) -> bool {
+    match status {
+        SignatureStatus::Valid { .. } => true,
+        SignatureStatus::Unsigned => trust_bundle_keys,
+        _ => false,
+    }
+}
+
+impl SecDb {
+    /// Writes the current entries of `namespaces` to a bundle at `path`, together with the keyring and the
+    /// dictionaries the entries were compressed with. Only the named entries are written when `entries`
+    /// is given, every one of them has to be in one of the namespaces. Old revisions stay behind.
+    pub fn export_bundle(
+        &self,
+        path: &Path,
+        namespaces: &[String],
+        entries: Option<&[String]>,
+    ) -> Result<BundleExport, ClenvError> {
+        let file = fs::File::create(path)
+            .map_err(|e| ClenvError::io(format!("Could not write {}", path.display()), e))?;
+        self.write_bundle(file, path, namespaces, entries)
+    }
+
+    // `export_bundle` into a file that is already open. The file is removed again when writing fails
+    pub(super) fn write_bundle(
+        &self,
+        file: fs::File,
+        path: &Path,
+        namespaces: &[String],
+        entries: Option<&[String]>,
+    ) -> Result<BundleExport, ClenvError> {
+        let mut selected = Vec::new();
+        for ns in namespaces {
+            let mut chosen = Vec::new();
+            for name in self.list_entries(ns)? {
+                if entries.is_none_or(|entries| entries.contains(&name)) {
+                    chosen.push(name);
+                }
+            }
+            selected.push((ns, chosen));
+        }
+        for name in entries.unwrap_or_default() {
+            if !selected.iter().any(|(_, names)| names.contains(name)) {
+                return Err(ClenvError::NotFound(format!(
+                    "No entry found for {} in {}",
+                    name,
+                    namespaces.join(", ")
+                )));
+            }
+        }
+
+        let mut export = BundleExport {
+            path: path.to_path_buf(),
+            entries: Vec::new(),
+            members: Vec::new(),
+            dictionaries: 0,
+        };
+        let write_err = |e| ClenvError::io(format!("Could not write {}", path.display()), e);
+        let mut write_all = |writer: &mut BufWriter<fs::File>| -> Result<(), ClenvError> {
+            writer.write_all(BUNDLE_HEADER).map_err(write_err)?;
+            writer
+                .write_all(&BUNDLE_VERSION.to_be_bytes())
+                .map_err(write_err)?;
+
+            for item in self.db.iter(self.cf("keyring")?)? {
+                let (user, public_key) = item?;
+                let user = String::from_utf8_lossy(&user).to_string();
+                let public_key = String::from_utf8_lossy(&public_key).to_string();
+                export.members.push(user.clone());
+                write_record(writer, &Record::Member { user, public_key })?;
+            }
+
+            for (ns, names) in &selected {
+                let mut ns_entries = Vec::new();
+                for name in names {
+                    let mut entry = self.entry(ns, name)?;
+                    entry.meta = None;
+                    ns_entries.push((name, entry));
+                }
+
+                // Only the dictionaries the entries need
+                let used: BTreeSet<u32> = ns_entries
+                    .iter()
+                    .filter_map(|(_, entry)| match entry.compression {
+                        Compression::ZstdDictionary(id) => Some(id),
+                        _ => None,
+                    })
+                    .collect();
+                for (_key, id, entry) in self.dictionaries(ns)? {
+                    if used.contains(&id) {
+                        let record = Record::Dictionary {
+                            namespace: ns.to_string(),
+                            id,
+                            value: entry.to_bytes()?,
+                        };
+                        write_record(writer, &record)?;
+                        export.dictionaries += 1;
+                    }
+                }
+
+                for (name, entry) in ns_entries {
+                    let record = Record::Entry {
+                        namespace: ns.to_string(),
+                        name: name.to_string(),
+                        value: entry.to_bytes()?,
+                    };
+                    write_record(writer, &record)?;
+                    if let Some(blob) = entry.chunked() {
+                        for index in 0..blob.digests.len() {
+                            let chunk = self.chunk_store().sealed(blob, index)?;
+                            write_record(writer, &Record::Chunk(chunk))?;
+                        }
+                    }
+                    export.entries.push(format!("{}/{}", ns, name));
+                }
+            }
+            writer.flush().map_err(write_err)
+        };
+
+        if let Err(e) = write_all(&mut BufWriter::new(file)) {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+        Ok(export)
+    }
+
+    /// Stores the entries of a bundle in the namespaces they were exported from, as new revisions signed by us.
+    /// Every entry gets its key wrapped for everyone in our keyring, so only entries we hold a key for can be
+    /// imported. Signatures are checked with our keyring: entries changed after they were signed are left out, and
+    /// so are unsigned ones and those signed by someone who isn't in our keyring or has another key in it, unless
+    /// `trust_bundle_keys` lets the bundle's keyring vouch for them. `policy` decides about entries that are
+    /// already in the database.
+    pub fn import_bundle(
+        &mut self,
+        path: &Path,
+        policy: ConflictPolicy,
+        trust_bundle_keys: bool,
+    ) -> Result<BundleImport, ClenvError> {
+        let mut reader = open_bundle(path)?;
+        let mut report = BundleImport::default();
+        let recipients = self.get_recipients()?;
+        let local: HashMap<&String, &PublicKey> =
+            recipients.iter().map(|(user, key)| (user, key)).collect();
+
+        let mut members: HashMap<String, Option<PublicKey>> = HashMap::new();
+        // Dictionaries we could open, by namespace and number in the bundle
+        let mut dictionaries: HashMap<(String, u32), Vec<u8>> = HashMap::new();
+        // Dictionaries left out because their signature can't be trusted
+        let mut untrusted: BTreeSet<(String, u32)> = BTreeSet::new();
+        // Numbers the dictionaries got in our database, they are stored along with the first entry needing them
+        let mut stored: HashMap<(String, u32), u32> = HashMap::new();
+
+        while let Some(record) = read_record(&mut reader)? {
+            match record {
+                Record::Member { user, public_key } => {
+                    let public_key = PublicKey::from_pem(&public_key);
+                    match (local.get(&user), &public_key) {
+                        (None, _) => report.unknown_members.push(user.clone()),
+                        (Some(ours), Some(theirs))
+                            if i_keys::key_id(ours)? == i_keys::key_id(theirs)? => {}
+                        (Some(_), _) => report.changed_keys.push(user.clone()),
+                    }
+                    members.insert(user, public_key);
+                }
+                Record::Dictionary {
+                    namespace,
+                    id,
+                    value,
+                } => {
+                    Self::check_namespace_name(&namespace)?;
+                    let entry = EncryptedEntry::from_bytes(&value)?;
+                    let name = dictionary_name(id);
+                    let status = bundled_status(
+                        &entry,
+                        &namespace,
+                        &name,
+                        &local,
+                        &members,
+                        trust_bundle_keys,
+                    )?;
+                    if !accepted(&status, trust_bundle_keys) {
+                        untrusted.insert((namespace, id));
+                        continue;
+                    }
+                    if let Ok(dictionary) = self.open_dictionary(&namespace, id, &entry) {
+                        dictionaries.insert((namespace, id), dictionary);
+                    }
+                }
+                Record::Entry {
+                    namespace,
+                    name,
+                    value,
+                } => {
+                    Self::check_namespace_name(&namespace)?;
+                    let mut entry = EncryptedEntry::from_bytes(&value)?;
+                    let location = format!("{}/{}", namespace, name);
+
+                    let existing = match self.namespaces()?.contains(&namespace) {
+                        true => match self.entry(&namespace, &name) {
+                            Ok(existing) => Some(existing),
+                            Err(ClenvError::NotFound(_)) => None,
+                            Err(e) => return Err(e),
+                        },
+                        false => None,
+                    };
+                    let replace = match (&existing, policy) {
+                        (None, _) | (Some(_), ConflictPolicy::Overwrite) => true,
+                        (Some(_), ConflictPolicy::Skip) => false,
+                        (Some(existing), ConflictPolicy::KeepNewest) => {
+                            entry.stored_at > existing.stored_at
+                        }
+                    };
+                    if !replace {
+                        skip_chunks(&mut reader, &entry)?;
+                        report.kept.push(location);
+                        continue;
+                    }
+
+                    let status = bundled_status(
+                        &entry,
+                        &namespace,
+                        &name,
+                        &local,
+                        &members,
+                        trust_bundle_keys,
+                    )?;
+                    if !accepted(&status, trust_bundle_keys) {
+                        skip_chunks(&mut reader, &entry)?;
+                        match status {
+                            SignatureStatus::Invalid { .. } => report.invalid.push(location),
+                            _ => report.untrusted.push(location),
+                        }
+                        continue;
+                    }
+                    let data_key = match self.own_data_key(&location, &entry) {
+                        Ok(key) => key,
+                        Err(ClenvError::AccessDenied(_) | ClenvError::Crypto(_)) => {
+                            skip_chunks(&mut reader, &entry)?;
+                            report.no_access.push(location);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+
+                    self.ensure_namespace(&namespace)?;
+                    if let Compression::ZstdDictionary(id) = entry.compression {
+                        let key = (namespace.clone(), id);
+                        let local_id = match (stored.get(&key), dictionaries.get(&key)) {
+                            (Some(local_id), _) => *local_id,
+                            (None, Some(dictionary)) => {
+                                let local_id = self.local_dictionary(&namespace, dictionary)?;
+                                stored.insert(key, local_id);
+                                local_id
+                            }
+                            (None, None) => {
+                                skip_chunks(&mut reader, &entry)?;
+                                match untrusted.contains(&key) {
+                                    true => report.untrusted.push(location),
+                                    false => report.no_access.push(location),
+                                }
+                                continue;
+                            }
+                        };
+                        entry.compression = Compression::ZstdDictionary(local_id);
+                    }
+                    if let Some(blob) = entry.chunked() {
+                        self.ensure_cf(CHUNKS_CF)?;
+                        let inserted = self
+                            .chunk_store()
+                            .insert(blob, || read_chunk(&mut reader))?;
+                        entry.kind = EntryKind::Chunked(inserted);
+                    }
+                    entry.encrypted_keys = i_keys::wrap_key(&data_key, &recipients)?;
+
+                    // An entry that doesn't open where it is stored would only fail later
+                    let stored_entry = self
+                        .open_entry_to(&namespace, &name, &entry, &data_key, &mut io::sink())
+                        .and_then(|_| self.put_copy(&namespace, &name, entry.clone()));
+                    match stored_entry {
+                        Ok(_) => report.imported.push(location),
+                        Err(e) => {
+                            if let Some(blob) = entry.chunked() {
+                                let _ = self.chunk_store().delete(blob);
+                            }
+                            match e {
+                                ClenvError::Crypto(_) | ClenvError::Integrity(_) => {
+                                    report.invalid.push(location)
+                                }
+                                e => return Err(e),
+                            }
+                        }
+                    }
+                }
+                Record::Chunk(_) => {
+                    return Err(ClenvError::Storage(
+                        "The bundle holds chunks that belong to no entry".to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // The number of a dictionary of `ns` holding `dictionary`, which is stored when there is none yet
+    fn local_dictionary(&mut self, ns: &str, dictionary: &[u8]) -> Result<u32, ClenvError> {
+        for (_key, id, entry) in self.dictionaries(ns)? {
+            if self
+                .open_dictionary(ns, id, &entry)
+                .is_ok_and(|ours| ours == dictionary)
+            {
+                return Ok(id);
+            }
+        }
+        self.store_dictionary(ns, dictionary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::suite::{PrivateKey, Suite};
+    use crate::sec_db::testing::{TempDir, open_db, put_raw};
+
+    fn import(db: &mut SecDb, bundle: &Path, policy: ConflictPolicy) -> BundleImport {
+        db.import_bundle(bundle, policy, false).unwrap()
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let tmp = TempDir::new();
+        let bundle = tmp.path().join("a.bundle");
+        // The same user with the same key in both databases
+        let mut ours = open_db(&tmp, "ours", "alice");
+        let mut theirs = open_db(&tmp, "theirs", "alice");
+        ours.store("a.txt", b"ours", "txt", false).unwrap();
+        theirs.store("a.txt", b"theirs", "txt", false).unwrap();
+        theirs.store("b.txt", b"new", "txt", false).unwrap();
+        let dev = ["dev".to_string()];
+        theirs.export_bundle(&bundle, &dev, None).unwrap();
+
+        let report = import(&mut ours, &bundle, ConflictPolicy::Skip);
+        assert_eq!(report.imported, ["dev/b.txt"]);
+        assert_eq!(report.kept, ["dev/a.txt"]);
+        assert_eq!(ours.read_entry("a.txt").unwrap(), b"ours");
+        assert_eq!(ours.read_entry("b.txt").unwrap(), b"new");
+
+        // stored_at counts seconds
+        std::thread::sleep(std::time::Duration::from_millis(1_100));
+        ours.store("a.txt", b"newer", "txt", false).unwrap();
+        theirs.store("a.txt", b"newest", "txt", false).unwrap();
+        let newest = tmp.path().join("newest.bundle");
+        theirs.export_bundle(&newest, &dev, None).unwrap();
+
+        // Ours was written after the bundled one
+        let report = import(&mut ours, &bundle, ConflictPolicy::KeepNewest);
+        assert!(report.kept.contains(&"dev/a.txt".to_string()));
+        assert_eq!(ours.read_entry("a.txt").unwrap(), b"newer");
+
+        // Imported entries keep the time they were written, not the time they were imported
+        let report = import(&mut ours, &bundle, ConflictPolicy::Overwrite);
+        assert_eq!(report.imported, ["dev/a.txt", "dev/b.txt"]);
+        assert_eq!(ours.read_entry("a.txt").unwrap(), b"theirs");
+        let written = theirs.history("a.txt").unwrap()[0].stored_at;
+        assert_eq!(ours.entry("dev", "a.txt").unwrap().stored_at, written);
+
+        let report = import(&mut ours, &newest, ConflictPolicy::KeepNewest);
+        assert!(report.imported.contains(&"dev/a.txt".to_string()));
+        assert_eq!(ours.read_entry("a.txt").unwrap(), b"newest");
+        let report = import(&mut ours, &bundle, ConflictPolicy::KeepNewest);
+        assert!(report.kept.contains(&"dev/a.txt".to_string()));
+    }
+
+    #[test]
+    fn untrusted_signers_need_bundle_keys() {
+        let tmp = TempDir::new();
+        let bundle = tmp.path().join("a.bundle");
+        let mut ours = open_db(&tmp, "ours", "alice");
+        let mut theirs = open_db(&tmp, "theirs", "alice");
+
+        // Mallory is only in the keyring the bundle carries
+        let mallory = PrivateKey::generate(Suite::X25519).unwrap();
+        let mallory_pem = tmp.path().join("mallory.pub.pem");
+        fs::write(&mallory_pem, mallory.public_key().to_pem().unwrap()).unwrap();
+        let dev = ["dev".to_string()];
+        theirs
+            .add_user("mallory", mallory_pem.to_str(), &dev)
+            .unwrap();
+        theirs.store("a.txt", b"secret", "txt", false).unwrap();
+        let mut entry = theirs.entry("dev", "a.txt").unwrap();
+        entry.sign("dev", "a.txt", "mallory", &mallory).unwrap();
+        put_raw(&theirs, "dev", "a.txt", entry);
+        theirs.export_bundle(&bundle, &dev, None).unwrap();
+
+        let report = import(&mut ours, &bundle, ConflictPolicy::Skip);
+        assert!(report.imported.is_empty());
+        assert_eq!(report.untrusted, ["dev/a.txt"]);
+        assert_eq!(report.unknown_members, ["mallory"]);
+
+        let report = ours
+            .import_bundle(&bundle, ConflictPolicy::Skip, true)
+            .unwrap();
+        assert_eq!(report.imported, ["dev/a.txt"]);
+        assert_eq!(ours.read_entry("a.txt").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn changed_entries_are_refused() {
+        let tmp = TempDir::new();
+        let bundle = tmp.path().join("a.bundle");
+        let mut ours = open_db(&tmp, "ours", "alice");
+        let mut theirs = open_db(&tmp, "theirs", "alice");
+        theirs.store("a.txt", b"secret", "txt", false).unwrap();
+        let mut entry = theirs.entry("dev", "a.txt").unwrap();
+        entry.stored_at += 1;
+        put_raw(&theirs, "dev", "a.txt", entry);
+        theirs
+            .export_bundle(&bundle, &["dev".to_string()], None)
+            .unwrap();
+
+        for trust_bundle_keys in [false, true] {
+            let report = ours
+                .import_bundle(&bundle, ConflictPolicy::Skip, trust_bundle_keys)
+                .unwrap();
+            assert!(report.imported.is_empty());
+            assert_eq!(report.invalid, ["dev/a.txt"]);
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use super::compression::Codec;
+use super::handle_db::{ENTRY_FORMAT, EncryptedEntry, EntryKind, SecDb, entry_aad};
+use super::i_keys::{CryptoError, i_keys};
+use super::store::Store;
+use super::suite::Suite;
+use crate::error::ClenvError;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+// Files of CHUNKED_FROM bytes or more aren't kept in the entry itself. They are cut into chunks of
+// CHUNK_SIZE bytes that are compressed, sealed and stored one by one in their own column family,
+// so storing and dumping them only ever holds a couple of chunks in memory.
+// Like the STREAM construction, every chunk is sealed with its position and whether it is the last one
+// as associated data, so chunks can't be reordered and a truncated entry doesn't open.
+// The entry lists a SHA-256 digest of every sealed chunk and the signature covers those,
+// so chunks can't be swapped with the ones of another revision either.
+pub const CHUNKS_CF: &str = "chunks";
+pub const CHUNK_SIZE: usize = 1 << 20;
+pub const CHUNKED_FROM: u64 = 8 << 20;
+
+/// Where the chunks of a large entry are and what they have to hash to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkedBlob {
+    // Random, so every entry and archived revision owns its chunks
+    pub id: [u8; 16],
+    // Size of the plaintext
+    pub size: u64,
+    pub digests: Vec<[u8; 32]>,
+}
+
+fn chunk_key(id: &[u8; 16], index: usize) -> Vec<u8> {
+    [&id[..], &(index as u64).to_be_bytes()].concat()
+}
+
+fn chunk_aad(entry_aad: &[u8], index: usize, last: bool) -> Vec<u8> {
+    let mut aad = entry_aad.to_vec();
+    aad.extend_from_slice(&(index as u64).to_be_bytes());
+    aad.push(u8::from(last));
+    aad
+}
+
+// A chunk that is missing or doesn't hash to what the entry lists fails like a ciphertext that
+// doesn't open, callers report it as the entry having been changed
+fn tampered() -> ClenvError {
+    CryptoError::Aes(aes_gcm::Error).into()
+}
+
+// Plaintext chunks of `input`, None once it is used up
+fn chunks_of(mut input: impl Read) -> impl FnMut() -> Result<Option<Vec<u8>>, ClenvError> {
+    move || {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        (&mut input)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)
+            .map_err(|e| ClenvError::io("Could not read the file to store", e))?;
+        Ok((!chunk.is_empty()).then_some(chunk))
+    }
+}
+
+/// The chunks of large entries, see `ChunkedBlob`
+pub struct ChunkStore<'a> {
+    db: &'a dyn Store,
+}
+
+impl ChunkStore<'_> {
+    fn cf(&self) -> Result<&'static str, ClenvError> {
+        match self.db.has_namespace(CHUNKS_CF) {
+            true => Ok(CHUNKS_CF),
+            false => Err(ClenvError::Storage(format!(
+                "Missing '{}' namespace",
+                CHUNKS_CF
+            ))),
+        }
+    }
+
+    // Compresses, seals and writes every chunk `next` hands out under a new id. Nothing is left behind on errors
+    fn write(
+        &self,
+        key: &[u8],
+        suite: Suite,
+        aad: &[u8],
+        codec: &Codec,
+        mut next: impl FnMut() -> Result<Option<Vec<u8>>, ClenvError>,
+    ) -> Result<ChunkedBlob, ClenvError> {
+        let mut blob = ChunkedBlob {
+            id: [0u8; 16],
+            size: 0,
+            digests: Vec::new(),
+        };
+        OsRng.fill_bytes(&mut blob.id);
+
+        let mut write_all = || {
+            let cf = self.cf()?;
+            // One chunk ahead, the last one is only known once there is nothing after it
+            let mut current = next()?.unwrap_or_default();
+            loop {
+                let following = next()?;
+                let index = blob.digests.len();
+                let compressed = codec.compress(&current)?;
+                let (ciphertext, nonce) = suite.seal(
+                    key,
+                    &compressed,
+                    &chunk_aad(aad, index, following.is_none()),
+                )?;
+                let value = [&nonce[..], &ciphertext].concat();
+
+                blob.digests.push(Sha256::digest(&value).into());
+                blob.size += current.len() as u64;
+                self.db.put(cf, &chunk_key(&blob.id, index), &value)?;
+                match following {
+                    Some(chunk) => current = chunk,
+                    None => return Ok(()),
+                }
+            }
+        };
+        if let Err(e) = write_all() {
+            let _ = self.delete(&blob);
+            return Err(e);
+        }
+        Ok(blob)
+    }
+
+    /// The stored value of a chunk, checked against its digest
+    pub(super) fn sealed(&self, blob: &ChunkedBlob, index: usize) -> Result<Vec<u8>, ClenvError> {
+        let value = self
+            .db
+            .get(self.cf()?, &chunk_key(&blob.id, index))?
+            .ok_or_else(tampered)?;
+        if Sha256::digest(&value)[..] != blob.digests[index] {
+            return Err(tampered());
+        }
+        Ok(value)
+    }
+
+    // The sealed value of a chunk, still compressed
+    fn open(
+        &self,
+        blob: &ChunkedBlob,
+        index: usize,
+        key: &[u8],
+        suite: Suite,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, ClenvError> {
+        let value = self.sealed(blob, index)?;
+        let (nonce, ciphertext) = value.split_at_checked(12).ok_or_else(tampered)?;
+        let last = index + 1 == blob.digests.len();
+        Ok(suite.open(key, ciphertext, nonce, &chunk_aad(aad, index, last))?)
+    }
+
+    // Opens the chunks in order and hands every plaintext to `output`
+    pub(super) fn read(
+        &self,
+        blob: &ChunkedBlob,
+        key: &[u8],
+        suite: Suite,
+        aad: &[u8],
+        codec: &Codec,
+        output: &mut dyn Write,
+    ) -> Result<(), ClenvError> {
+        for index in 0..blob.digests.len() {
+            let chunk = codec.decompress(&self.open(blob, index, key, suite, aad)?)?;
+            output
+                .write_all(&chunk)
+                .map_err(|e| ClenvError::io("Could not write the entry", e))?;
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts the chunks under a new data key, suite or associated data. The new chunks get a new id,
+    /// the old ones are left for the caller to delete once the entry pointing to the new ones is written.
+    pub(super) fn reseal(
+        &self,
+        blob: &ChunkedBlob,
+        old: (&[u8], Suite, &[u8]),
+        new: (&[u8], Suite, &[u8]),
+    ) -> Result<ChunkedBlob, ClenvError> {
+        let (old_key, old_suite, old_aad) = old;
+        let (new_key, new_suite, new_aad) = new;
+        let mut chunks = 0..blob.digests.len();
+        // Chunks move over as they were compressed
+        let mut resealed = self.write(new_key, new_suite, new_aad, &Codec::none(), || {
+            chunks
+                .next()
+                .map(|index| self.open(blob, index, old_key, old_suite, old_aad))
+                .transpose()
+        })?;
+        resealed.size = blob.size;
+        Ok(resealed)
+    }
+
+    /// Copies the chunks as they are to a new id, for a second entry holding the same contents
+    pub(super) fn copy(&self, blob: &ChunkedBlob) -> Result<ChunkedBlob, ClenvError> {
+        let cf = self.cf()?;
+        let mut copy = blob.clone();
+        OsRng.fill_bytes(&mut copy.id);
+        for index in 0..blob.digests.len() {
+            let value = self
+                .db
+                .get(cf, &chunk_key(&blob.id, index))?
+                .ok_or_else(tampered)?;
+            self.db.put(cf, &chunk_key(&copy.id, index), &value)?;
+        }
+        Ok(copy)
+    }
+
+    /// Writes chunks sealed elsewhere under a new id, in order as `next` hands them out. Every chunk has to
+    /// hash to the digest `blob` lists for it. Nothing is left behind on errors
+    pub(super) fn insert(
+        &self,
+        blob: &ChunkedBlob,
+        mut next: impl FnMut() -> Result<Vec<u8>, ClenvError>,
+    ) -> Result<ChunkedBlob, ClenvError> {
+        let mut inserted = blob.clone();
+        OsRng.fill_bytes(&mut inserted.id);
+
+        let mut write_all = || {
+            let cf = self.cf()?;
+            for (index, digest) in blob.digests.iter().enumerate() {
+                let value = next()?;
+                if Sha256::digest(&value)[..] != digest[..] {
+                    return Err(tampered());
+                }
+                self.db.put(cf, &chunk_key(&inserted.id, index), &value)?;
+            }
+            Ok(())
+        };
+        if let Err(e) = write_all() {
+            let _ = self.delete(&inserted);
+            return Err(e);
+        }
+        Ok(inserted)
+    }
+
+    pub(super) fn delete(&self, blob: &ChunkedBlob) -> Result<(), ClenvError> {
+        if !self.db.has_namespace(CHUNKS_CF) {
+            return Ok(());
+        }
+        for index in 0..blob.digests.len() {
+            self.db.delete(CHUNKS_CF, &chunk_key(&blob.id, index))?;
+        }
+        Ok(())
+    }
+}
+
+impl EncryptedEntry {
+    /// The chunks of a large entry, None for entries kept in one piece
+    pub fn chunked(&self) -> Option<&ChunkedBlob> {
+        match &self.kind {
+            EntryKind::Chunked(blob) => Some(blob),
+            _ => None,
+        }
+    }
+}
+
+impl SecDb {
+    /// The chunks of large entries, needed to re-encrypt them with `EncryptedEntry::rekey`
+    pub fn chunk_store(&self) -> ChunkStore<'_> {
+        ChunkStore {
+            db: self.db.as_ref(),
+        }
+    }
+
+    // Stores `input` chunk by chunk as an entry of the current namespace, see CHUNKED_FROM
+    pub(super) fn store_chunked(
+        &mut self,
+        name: &str,
+        input: impl Read,
+        extension: &str,
+    ) -> Result<u64, ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let aad = entry_aad(ENTRY_FORMAT, &cf_name, name, extension);
+        let recipients = self.get_recipients()?;
+        let suite = Suite::configured(&self.conf)?;
+        let data_key = i_keys::generate_data_key();
+        self.ensure_cf(CHUNKS_CF)?;
+
+        // Whether compression helps is decided on the first chunk, the rest isn't read yet
+        let users: Vec<String> = recipients.iter().map(|(user, _)| user.clone()).collect();
+        let mut codec = self.new_codec(&cf_name, &users)?;
+        let mut chunks = chunks_of(input);
+        let mut first = chunks()?;
+        codec.compress_all(&[first.as_deref().unwrap_or_default()])?;
+        let next = move || match first.take() {
+            Some(chunk) => Ok(Some(chunk)),
+            None => chunks(),
+        };
+
+        let blob = self
+            .chunk_store()
+            .write(&data_key, suite, &aad, &codec, next)?;
+        let entry = EncryptedEntry {
+            ciphertext: Vec::new(),
+            nonce: [0u8; 12],
+            encrypted_keys: i_keys::wrap_key(&data_key, &recipients)?,
+            extension: extension.to_string(),
+            kind: EntryKind::Chunked(blob.clone()),
+            revision: 0,
+            author: String::new(),
+            stored_at: 0,
+            meta: None,
+            format: ENTRY_FORMAT,
+            signature: None,
+            suite,
+            compression: codec.compression(),
+        };
+
+        let stored = self
+            .ensure_namespace(&cf_name)
+            .and_then(|_| self.put_entry(&cf_name, name, entry));
+        if stored.is_err() {
+            let _ = self.chunk_store().delete(&blob);
+        }
+        stored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::store::DirStore;
+    use crate::sec_db::testing::TempDir;
+
+    const KEY: [u8; 32] = [9; 32];
+
+    fn contents() -> Vec<u8> {
+        (0..CHUNK_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn write(chunks: &ChunkStore, data: &[u8], aad: &[u8]) -> ChunkedBlob {
+        let mut pieces = data.chunks(CHUNK_SIZE).map(<[u8]>::to_vec);
+        chunks
+            .write(&KEY, Suite::X25519, aad, &Codec::none(), || {
+                Ok(pieces.next())
+            })
+            .unwrap()
+    }
+
+    fn read(chunks: &ChunkStore, blob: &ChunkedBlob, aad: &[u8]) -> Result<Vec<u8>, ClenvError> {
+        let mut out = Vec::new();
+        chunks.read(blob, &KEY, Suite::X25519, aad, &Codec::none(), &mut out)?;
+        Ok(out)
+    }
+
+    fn tampered(result: Result<Vec<u8>, ClenvError>) -> bool {
+        matches!(result, Err(ClenvError::Crypto(CryptoError::Aes(_))))
+    }
+
+    #[test]
+    fn chunks_are_bound_to_their_place() {
+        let tmp = TempDir::new();
+        let mut store = DirStore::open(tmp.path()).unwrap();
+        store.create_namespace(CHUNKS_CF).unwrap();
+        let chunks = ChunkStore { db: &store };
+        let data = contents();
+        let blob = write(&chunks, &data, b"entry");
+        assert_eq!(blob.digests.len(), 4);
+        assert_eq!(read(&chunks, &blob, b"entry").unwrap(), data);
+        assert!(tampered(read(&chunks, &blob, b"other entry")));
+
+        // Truncated: the chunk that is now last wasn't sealed as the last one
+        let mut truncated = blob.clone();
+        truncated.digests.pop();
+        assert!(tampered(read(&chunks, &truncated, b"entry")));
+
+        // Reordered, digests and all: every chunk is sealed with its position
+        let mut reordered = chunks.copy(&blob).unwrap();
+        reordered.digests.swap(0, 1);
+        let first = chunks.sealed(&blob, 0).unwrap();
+        let second = chunks.sealed(&blob, 1).unwrap();
+        store
+            .put(CHUNKS_CF, &chunk_key(&reordered.id, 0), &second)
+            .unwrap();
+        store
+            .put(CHUNKS_CF, &chunk_key(&reordered.id, 1), &first)
+            .unwrap();
+        assert!(tampered(read(&chunks, &reordered, b"entry")));
+
+        // Swapped in from another entry: it doesn't hash to the digest the entry lists,
+        // and with the digest changed as well it doesn't open under this entry
+        let other = write(&chunks, &data, b"other entry");
+        let foreign = chunks.sealed(&other, 2).unwrap();
+        let mut swapped = chunks.copy(&blob).unwrap();
+        store
+            .put(CHUNKS_CF, &chunk_key(&swapped.id, 2), &foreign)
+            .unwrap();
+        assert!(tampered(read(&chunks, &swapped, b"entry")));
+        swapped.digests[2] = other.digests[2];
+        assert!(tampered(read(&chunks, &swapped, b"entry")));
+
+        // A missing chunk
+        let missing = chunks.copy(&blob).unwrap();
+        store.delete(CHUNKS_CF, &chunk_key(&missing.id, 3)).unwrap();
+        assert!(tampered(read(&chunks, &missing, b"entry")));
+    }
+}
@@ -0,0 +1,367 @@
+use super::dotenv;
+use super::handle_db::{EntryKind, SecDb};
+use crate::config::resolve_path;
+use crate::error::ClenvError;
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+use std::collections::HashMap;
+use std::fs;
+
+/// Result of comparing an entry with another entry or a local file
+#[derive(Debug, Clone, Serialize)]
+pub struct Diff {
+    // "namespace/entry" or the path of the file
+    pub left: String,
+    pub right: String,
+    pub changes: Changes,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase", tag = "kind", content = "changes")]
+pub enum Changes {
+    // Dotenv contents, compared variable by variable
+    Vars(Vec<VarChange>),
+    // Other text, as groups of changed lines with up to 3 lines of context around them
+    Lines(Vec<Vec<LineChange>>),
+    // Either side isn't text, only tells whether the bytes differ
+    Binary(bool),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase", tag = "change")]
+pub enum VarChange {
+    Removed {
+        key: String,
+        value: String,
+    },
+    Changed {
+        key: String,
+        old: String,
+        new: String,
+    },
+    Added {
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineTag {
+    Equal,
+    Delete,
+    Insert,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LineChange {
+    pub tag: LineTag,
+    // 1-based. Deleted lines are numbered as in the old content, everything else as in the new one
+    pub line: usize,
+    pub text: String,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        match &self.changes {
+            Changes::Vars(changes) => changes.is_empty(),
+            Changes::Lines(groups) => groups.is_empty(),
+            Changes::Binary(differ) => !differ,
+        }
+    }
+
+    /// Same diff with every value and line of text hidden, only what changed is left
+    pub fn masked(mut self) -> Diff {
+        let hidden = || String::from("****");
+        match &mut self.changes {
+            Changes::Vars(changes) => {
+                for change in changes {
+                    match change {
+                        VarChange::Removed { value, .. } | VarChange::Added { value, .. } => {
+                            *value = hidden()
+                        }
+                        VarChange::Changed { old, new, .. } => {
+                            *old = hidden();
+                            *new = hidden();
+                        }
+                    }
+                }
+            }
+            Changes::Lines(groups) => {
+                for group in groups.iter_mut() {
+                    group.retain(|change| change.tag != LineTag::Equal);
+                    for change in group.iter_mut() {
+                        change.text = hidden();
+                    }
+                }
+            }
+            Changes::Binary(_) => {}
+        }
+        self
+    }
+}
+
+// One side of a comparison, already decrypted
+struct Side {
+    label: String,
+    content: Vec<u8>,
+    dotenv: bool,
+}
+
+impl SecDb {
+    /// Compares an entry with another entry or with a local file.
+    /// A file on disk wins over an entry of the same name unless `other_ns` is given.
+    pub fn diff(
+        &self,
+        name: &str,
+        other: &str,
+        ns: Option<&str>,
+        other_ns: Option<&str>,
+    ) -> Result<Diff, ClenvError> {
+        let current_ns = self.conf.require("ns")?;
+        let ns = ns.unwrap_or(&current_ns);
+        let left = self.entry_side(ns, name)?;
+
+        let other_path = resolve_path(other, "");
+        let right = if other_ns.is_none() && other_path.is_file() {
+            Side {
+                label: other_path.display().to_string(),
+                content: fs::read(&other_path).map_err(|e| {
+                    ClenvError::io(format!("Could not read {}", other_path.display()), e)
+                })?,
+                dotenv: dotenv::is_dotenv(&other_path),
+            }
+        } else {
+            self.entry_side(other_ns.unwrap_or(ns), other)?
+        };
+
+        let changes = match (
+            std::str::from_utf8(&left.content),
+            std::str::from_utf8(&right.content),
+        ) {
+            (Ok(a), Ok(b)) if left.dotenv || right.dotenv => Changes::Vars(var_diff(a, b)),
+            (Ok(a), Ok(b)) => Changes::Lines(line_diff(a, b)),
+            _ => Changes::Binary(left.content != right.content),
+        };
+
+        Ok(Diff {
+            left: left.label,
+            right: right.label,
+            changes,
+        })
+    }
+
+    fn entry_side(&self, ns: &str, name: &str) -> Result<Side, ClenvError> {
+        let (entry, content) = self.decrypt_entry_in(ns, name)?;
+        Ok(Side {
+            label: format!("{}/{}", ns, name),
+            content,
+            dotenv: matches!(entry.kind, EntryKind::Dotenv(_)),
+        })
+    }
+}
+
+// Variables are matched by name, so reordering a file doesn't show up as a change
+fn var_diff(a: &str, b: &str) -> Vec<VarChange> {
+    let left = dotenv::parse(a);
+    let right = dotenv::parse(b);
+
+    // Later definitions win, same as when the file is sourced
+    let left_map: HashMap<&str, &str> =
+        left.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let right_map: HashMap<&str, &str> = right
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+
+    let mut changes = Vec::new();
+    let mut seen = Vec::new();
+    for (key, _) in &left {
+        if seen.contains(&key.as_str()) {
+            continue;
+        }
+        seen.push(key.as_str());
+
+        let old = left_map[key.as_str()];
+        match right_map.get(key.as_str()) {
+            None => changes.push(VarChange::Removed {
+                key: key.clone(),
+                value: old.to_string(),
+            }),
+            Some(new) if *new != old => changes.push(VarChange::Changed {
+                key: key.clone(),
+                old: old.to_string(),
+                new: new.to_string(),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (key, _) in &right {
+        if left_map.contains_key(key.as_str()) || seen.contains(&key.as_str()) {
+            continue;
+        }
+        seen.push(key.as_str());
+        changes.push(VarChange::Added {
+            key: key.clone(),
+            value: right_map[key.as_str()].to_string(),
+        });
+    }
+    changes
+}
+
+fn line_diff(a: &str, b: &str) -> Vec<Vec<LineChange>> {
+    let diff = TextDiff::from_lines(a, b);
+
+    diff.grouped_ops(3)
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .flat_map(|op| diff.iter_changes(op))
+                .map(|change| {
+                    let (tag, index) = match change.tag() {
+                        ChangeTag::Delete => (LineTag::Delete, change.old_index()),
+                        ChangeTag::Insert => (LineTag::Insert, change.new_index()),
+                        ChangeTag::Equal => (LineTag::Equal, change.new_index()),
+                    };
+                    LineChange {
+                        tag,
+                        line: index.unwrap_or(0) + 1,
+                        text: change.value().trim_end_matches(['\r', '\n']).to_string(),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::{TempDir, open_db};
+    use serde_json::{Value, json};
+
+    fn changes(diff: &Diff) -> Value {
+        serde_json::to_value(&diff.changes).unwrap()
+    }
+
+    #[test]
+    fn dotenv_entries_compare_by_variable() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store(".env", b"A=1\nB=2\nC=3\n", "env", true).unwrap();
+        db.store("prod.env", b"C=3\nexport B=two\nD=4\n", "env", true)
+            .unwrap();
+
+        let diff = db.diff(".env", "prod.env", None, None).unwrap();
+        assert_eq!(diff.left, "dev/.env");
+        assert_eq!(diff.right, "dev/prod.env");
+        assert_eq!(
+            changes(&diff),
+            json!({"kind": "vars", "changes": [
+                {"change": "removed", "key": "A", "value": "1"},
+                {"change": "changed", "key": "B", "old": "2", "new": "two"},
+                {"change": "added", "key": "D", "value": "4"},
+            ]})
+        );
+
+        let masked = diff.masked();
+        assert_eq!(
+            changes(&masked),
+            json!({"kind": "vars", "changes": [
+                {"change": "removed", "key": "A", "value": "****"},
+                {"change": "changed", "key": "B", "old": "****", "new": "****"},
+                {"change": "added", "key": "D", "value": "****"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn later_definitions_win() {
+        assert!(var_diff("A=1\nA=2\n", "A=2\n").is_empty());
+        assert!(var_diff("A=2\n", "A='2'\n").is_empty());
+        assert!(var_diff("# A=1\nA=2\n", "A=2 # comment\n").is_empty());
+        let changes = var_diff("A=1\n", "A=1\nA=3\nA=4\n");
+        assert!(matches!(
+            changes.as_slice(),
+            [VarChange::Changed { key, old, new }] if key == "A" && old == "1" && new == "4"
+        ));
+    }
+
+    #[test]
+    fn entry_against_a_file() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        let old: String = (1..=12).map(|n| format!("line {}\n", n)).collect();
+        db.store("a.txt", old.as_bytes(), "txt", false).unwrap();
+        let file = tmp.path().join("a.txt");
+        fs::write(&file, old.replace("line 8\n", "eight\n")).unwrap();
+
+        let diff = db
+            .diff("a.txt", file.to_str().unwrap(), None, None)
+            .unwrap();
+        assert_eq!(diff.right, file.display().to_string());
+        let Changes::Lines(groups) = &diff.changes else {
+            panic!("expected a line diff, got {:?}", diff.changes);
+        };
+        // One group, with three lines of context on either side
+        assert_eq!(groups.len(), 1);
+        let lines: Vec<(LineTag, usize, &str)> = groups[0]
+            .iter()
+            .map(|change| (change.tag, change.line, change.text.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (LineTag::Equal, 5, "line 5"),
+                (LineTag::Equal, 6, "line 6"),
+                (LineTag::Equal, 7, "line 7"),
+                (LineTag::Delete, 8, "line 8"),
+                (LineTag::Insert, 8, "eight"),
+                (LineTag::Equal, 9, "line 9"),
+                (LineTag::Equal, 10, "line 10"),
+                (LineTag::Equal, 11, "line 11"),
+            ]
+        );
+
+        // Only the changed lines are left, without their text
+        let masked = diff.masked();
+        assert_eq!(
+            changes(&masked),
+            json!({"kind": "lines", "changes": [[
+                {"tag": "delete", "line": 8, "text": "****"},
+                {"tag": "insert", "line": 8, "text": "****"},
+            ]]})
+        );
+
+        // A file named like an entry is still compared as a file
+        fs::write(&file, &old).unwrap();
+        assert!(
+            db.diff("a.txt", file.to_str().unwrap(), None, None)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn binary_contents_are_only_told_apart() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store("a.bin", &[0xff, 0xfe, 0x00], "bin", false)
+            .unwrap();
+        db.store("b.bin", &[0xff, 0xfe, 0x01], "bin", false)
+            .unwrap();
+        db.store("c.bin", &[0xff, 0xfe, 0x00], "bin", false)
+            .unwrap();
+
+        let diff = db.diff("a.bin", "b.bin", None, None).unwrap();
+        assert_eq!(changes(&diff), json!({"kind": "binary", "changes": true}));
+        assert!(!diff.is_empty());
+        assert!(db.diff("a.bin", "c.bin", None, None).unwrap().is_empty());
+        assert!(matches!(
+            db.diff("a.bin", "missing.bin", None, None),
+            Err(ClenvError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+
+// Small dotenv reader so entries can be consumed without ever being written back to disk.
+// Supports the usual shapes: KEY=value, export KEY=value, 'single quoted', "double quoted" (with escapes)
+// and trailing comments on unquoted values.
+pub fn parse(content: &str) -> Vec<(String, String)> {
+    content.lines().filter_map(parse_line).collect()
+}
+
+// Parses a single line, comments and blank lines yield nothing
+pub fn parse_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=')?;
+
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    Some((key.to_string(), parse_value(value.trim())))
+}
+
+// Builds the line for a variable being set, keeping the `export` prefix if the old line had one
+pub fn format_line(previous: Option<&str>, key: &str, value: &str) -> String {
+    let export = previous.is_some_and(|line| line.trim_start().starts_with("export "));
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '#' | '"' | '\'' | '\\'));
+
+    let value = if needs_quotes {
+        let escaped = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+            .replace('\t', "\\t");
+        format!("\"{}\"", escaped)
+    } else {
+        value.to_string()
+    };
+
+    if export {
+        format!("export {}={}", key, value)
+    } else {
+        format!("{}={}", key, value)
+    }
+}
+
+// .env, .env.local, production.env and friends are stored variable by variable
+pub fn is_dotenv(path: &Path) -> bool {
+    let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+    file_name == ".env"
+        || file_name.starts_with(".env.")
+        || path.extension().is_some_and(|ext| ext == "env")
+}
+
+fn parse_value(raw: &str) -> String {
+    if raw.len() >= 2 && raw.starts_with('\'') && raw.ends_with('\'') {
+        return raw[1..raw.len() - 1].to_string();
+    }
+
+    if raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"') {
+        let inner = &raw[1..raw.len() - 1];
+        let mut value = String::with_capacity(inner.len());
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(other) => value.push(other),
+                None => value.push('\\'),
+            }
+        }
+        return value;
+    }
+
+    // Unquoted values end at the first " #" so inline comments are not exported
+    match raw.find(" #") {
+        Some(idx) => raw[..idx].trim_end().to_string(),
+        None => raw.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parses_quotes_comments_and_export() {
+        // Lines are trimmed, so the indentation here doesn't count
+        let content = r#"
+            # settings
+            PLAIN=value
+            export EXPORTED=yes
+            SINGLE='a "b" \n # c'
+            DOUBLE="line\none\t\"q\" # kept"
+            INLINE=value # comment
+            HASH=a#b
+            SPACED = padded
+            EMPTY=
+            not a variable
+            =no key
+        "#;
+        assert_eq!(
+            parse(content),
+            [
+                pair("PLAIN", "value"),
+                pair("EXPORTED", "yes"),
+                pair("SINGLE", "a \"b\" \\n # c"),
+                pair("DOUBLE", "line\none\t\"q\" # kept"),
+                pair("INLINE", "value"),
+                pair("HASH", "a#b"),
+                pair("SPACED", "padded"),
+                pair("EMPTY", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn formatted_lines_parse_back() {
+        for value in [
+            "plain",
+            "",
+            "two words",
+            "a#b",
+            "quote\"s",
+            "back\\slash",
+            "multi\nline\ttab",
+        ] {
+            let line = format_line(None, "KEY", value);
+            assert_eq!(parse_line(&line), Some(pair("KEY", value)), "{}", line);
+        }
+        assert_eq!(format_line(None, "KEY", "plain"), "KEY=plain");
+        assert_eq!(format_line(None, "KEY", "a b"), "KEY=\"a b\"");
+        assert_eq!(
+            format_line(Some("  export KEY=old"), "KEY", "new"),
+            "export KEY=new"
+        );
+        assert_eq!(format_line(Some("KEY=old"), "KEY", "new"), "KEY=new");
+    }
+
+    #[test]
+    fn recognizes_dotenv_files() {
+        for name in [".env", ".env.local", "production.env", "dir/.env"] {
+            assert!(is_dotenv(Path::new(name)), "{}", name);
+        }
+        for name in ["env", "notes.txt", ".envrc", "env.json"] {
+            assert!(!is_dotenv(Path::new(name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use super::chunked::CHUNKS_CF;
+use super::compression::{DICTIONARIES_CF, dictionary_id, dictionary_name};
+use super::handle_db::{EncryptedEntry, INTERNAL_NAMESPACES, SecDb};
+use super::history::{HISTORY_CF, history_entry_name, history_revision};
+use super::i_keys::i_keys;
+use super::index::namespace_id;
+use super::signature::SignatureStatus;
+use super::suite::PublicKey;
+use crate::error::ClenvError;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io;
+
+// `clenv fsck` reads every value of the database the way commands would and reports what they would trip
+// over, instead of failing halfway through `add` or `dump`. It works on the raw values, so a value that
+// doesn't decode is reported and the scan goes on.
+
+/// Something `fsck` found wrong with the database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "problem")]
+pub enum Problem {
+    // The stored value isn't an entry any version of clenv wrote
+    Undecodable { error: String },
+    // A keyring member whose public key can't be parsed
+    InvalidPublicKey,
+    // A wrapped key for someone who isn't in the keyring (any more)
+    UnknownRecipient { user: String },
+    // A keyring member without a wrapped key for the entry, who holds keys for other values of its namespace
+    MissingRecipient { user: String },
+    // We hold a key for the entry but it doesn't open
+    Unreadable { error: String },
+    // The entry was changed after it was signed
+    InvalidSignature { signer: String },
+    // The signature was removed, or was made by someone who can't be checked against the keyring
+    UntrustedSignature { reason: String },
+    // Chunks no entry or revision points to, left behind by an interrupted write
+    OrphanedChunks { chunks: usize },
+    // A column family that is neither a namespace nor one of clenv's own
+    StrayColumnFamily,
+}
+
+/// One problem and where it was found: "ns/name", "ns/name (rev 3)", "ns/dictionary 1", "keyring/user",
+/// "chunks/<id>" or a column family
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub location: String,
+    #[serde(flatten)]
+    pub problem: Problem,
+    pub repaired: bool,
+}
+
+/// What `fsck` checked and found
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    // Entries, revisions and dictionaries read
+    pub checked: usize,
+    // Values we hold no key for, so only their wrapped keys were checked
+    pub skipped: Vec<String>,
+    pub findings: Vec<Finding>,
+}
+
+impl FsckReport {
+    fn found(&mut self, location: &str, problem: Problem, repaired: bool) {
+        self.findings.push(Finding {
+            location: location.to_string(),
+            problem,
+            repaired,
+        });
+    }
+
+    /// Problems that are still there
+    pub fn unrepaired(&self) -> usize {
+        self.findings.iter().filter(|f| !f.repaired).count()
+    }
+}
+
+// What every entry is checked against: who is in the keyring, the keys of those whose public key parses,
+// who holds keys in the namespace being checked and whether to repair
+struct Scan {
+    members: BTreeSet<String>,
+    keys: BTreeMap<String, PublicKey>,
+    holders: BTreeSet<String>,
+    repair: bool,
+}
+
+// Where a value being checked lives
+enum Origin {
+    Current(String),
+    Archived(String),
+    Dictionary(u32),
+}
+
+impl SecDb {
+    /// Checks every entry, revision and dictionary of every namespace: that it decodes, that its wrapped keys
+    /// match the keyring and that we can decrypt it when we hold a key for it. Also looks for orphaned chunks
+    /// and column families that belong to nothing.
+    /// Members are only missing from values of namespaces they hold keys in, `add --ns` leaves them out of
+    /// the others on purpose.
+    /// With `repair`, wrapped keys for users who aren't in the keyring are dropped, members get a key for
+    /// every value of those namespaces we can decrypt and orphaned chunks are deleted.
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport, ClenvError> {
+        let mut report = FsckReport::default();
+
+        let mut scan = Scan {
+            members: BTreeSet::new(),
+            keys: BTreeMap::new(),
+            holders: BTreeSet::new(),
+            repair,
+        };
+        for item in self.db.iter(self.cf("keyring")?)? {
+            let (key, value) = item?;
+            let user = String::from_utf8_lossy(&key).to_string();
+            match std::str::from_utf8(&value)
+                .ok()
+                .and_then(PublicKey::from_pem)
+            {
+                Some(public_key) => {
+                    scan.keys.insert(user.clone(), public_key);
+                }
+                None => report.found(
+                    &format!("keyring/{}", user),
+                    Problem::InvalidPublicKey,
+                    false,
+                ),
+            }
+            scan.members.insert(user);
+        }
+
+        let index_key = self.index_key()?;
+        let namespaces = self.namespaces()?;
+        let mut referenced = HashSet::new();
+        let mut undecodable = false;
+        for ns in &namespaces {
+            let cf_name = namespace_id(index_key.as_deref(), ns);
+            let names = self.entry_names(ns)?;
+
+            let mut values = Vec::new();
+            for item in self.db.iter(&self.ns_cf(ns)?)? {
+                let (key, value) = item?;
+                let name = Self::name_of(&names, &String::from_utf8_lossy(&key));
+                values.push((
+                    cf_name.clone(),
+                    key.to_vec(),
+                    Origin::Current(name),
+                    value.to_vec(),
+                ));
+            }
+            for (key, value) in self.archived_values(ns, None)? {
+                let name = Self::name_of(&names, &history_entry_name(&key));
+                values.push((HISTORY_CF.to_string(), key, Origin::Archived(name), value));
+            }
+            for (key, value) in self.dictionary_values(ns)? {
+                let id = dictionary_id(&key);
+                values.push((
+                    DICTIONARIES_CF.to_string(),
+                    key,
+                    Origin::Dictionary(id),
+                    value,
+                ));
+            }
+
+            scan.holders = values
+                .iter()
+                .filter_map(|(_, _, _, value)| EncryptedEntry::from_bytes(value).ok())
+                .flat_map(|entry| entry.encrypted_keys.into_keys())
+                .collect();
+
+            for (cf, key, origin, value) in values {
+                let (name, location) = match &origin {
+                    Origin::Current(name) => (name.clone(), format!("{}/{}", ns, name)),
+                    Origin::Archived(name) => (
+                        name.clone(),
+                        format!("{}/{} (rev {})", ns, name, history_revision(&key)),
+                    ),
+                    Origin::Dictionary(id) => {
+                        (dictionary_name(*id), format!("{}/dictionary {}", ns, id))
+                    }
+                };
+                report.checked += 1;
+
+                let mut entry = match EncryptedEntry::from_bytes(&value) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        undecodable = true;
+                        let error = e.to_string();
+                        report.found(&location, Problem::Undecodable { error }, false);
+                        continue;
+                    }
+                };
+                if let Some(blob) = entry.chunked() {
+                    referenced.insert(blob.id);
+                }
+                let is_dictionary = matches!(origin, Origin::Dictionary(_));
+                if !is_dictionary && let Err(e) = self.open_meta(&mut entry) {
+                    let error = e.to_string();
+                    report.found(&location, Problem::Unreadable { error }, false);
+                    continue;
+                }
+
+                if self.check_entry(ns, &name, &location, &mut entry, &scan, &mut report)? {
+                    if !is_dictionary {
+                        self.seal_meta(&name, &mut entry)?;
+                    }
+                    self.db.put(self.cf(&cf)?, &key, &entry.to_bytes()?)?;
+                }
+            }
+        }
+
+        // An entry that doesn't decode may point to chunks, so nothing is deleted while there is one
+        if self.db.has_namespace(CHUNKS_CF) {
+            let mut orphaned: BTreeMap<[u8; 16], Vec<Vec<u8>>> = BTreeMap::new();
+            for item in self.db.iter(CHUNKS_CF)? {
+                let (key, _value) = item?;
+                let id: [u8; 16] = key
+                    .get(..16)
+                    .and_then(|id| id.try_into().ok())
+                    .unwrap_or_default();
+                if !referenced.contains(&id) {
+                    orphaned.entry(id).or_default().push(key.to_vec());
+                }
+            }
+            for (id, chunk_keys) in orphaned {
+                let repaired = repair && !undecodable;
+                if repaired {
+                    for key in &chunk_keys {
+                        self.db.delete(CHUNKS_CF, key)?;
+                    }
+                }
+                let location = format!(
+                    "{}/{}",
+                    CHUNKS_CF,
+                    id.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+                );
+                let chunks = chunk_keys.len();
+                report.found(&location, Problem::OrphanedChunks { chunks }, repaired);
+            }
+        }
+
+        let known: HashSet<String> = namespaces
+            .iter()
+            .map(|ns| namespace_id(index_key.as_deref(), ns))
+            .chain(INTERNAL_NAMESPACES.iter().map(|cf| cf.to_string()))
+            // RocksDB always has a default column family, a namespace or not
+            .chain(["default".to_string()])
+            .collect();
+        for cf in self.column_families()? {
+            if !known.contains(&cf) {
+                report.found(&cf, Problem::StrayColumnFamily, false);
+            }
+        }
+        Ok(report)
+    }
+
+    // Checks one entry with its metadata opened and fixes its wrapped keys when repairing.
+    // Returns whether the entry was changed.
+    fn check_entry(
+        &self,
+        ns: &str,
+        name: &str,
+        location: &str,
+        entry: &mut EncryptedEntry,
+        scan: &Scan,
+        report: &mut FsckReport,
+    ) -> Result<bool, ClenvError> {
+        let mut changed = false;
+
+        let unknown: Vec<String> = entry
+            .encrypted_keys
+            .keys()
+            .filter(|user| !scan.members.contains(*user))
+            .cloned()
+            .collect();
+        for user in unknown {
+            if scan.repair {
+                entry.encrypted_keys.remove(&user);
+                changed = true;
+            }
+            report.found(location, Problem::UnknownRecipient { user }, scan.repair);
+        }
+
+        // An entry that can't be trusted is reported, never handed out to more people
+        let status = self.signature_status(ns, name, entry)?;
+        let tampered = match (self.distrust(&status)?, status) {
+            (None, _) => false,
+            (_, SignatureStatus::Invalid { signer }) => {
+                report.found(location, Problem::InvalidSignature { signer }, false);
+                true
+            }
+            (Some(reason), _) => {
+                report.found(location, Problem::UntrustedSignature { reason }, false);
+                true
+            }
+        };
+
+        let my_name = self.conf.require("name")?;
+        let data_key = match entry.encrypted_keys.get(&my_name) {
+            None => {
+                report.skipped.push(location.to_string());
+                None
+            }
+            Some(wrapped) => match self.unwrap_own(wrapped) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    let error = e.to_string();
+                    report.found(location, Problem::Unreadable { error }, false);
+                    None
+                }
+            },
+        };
+        // Everything is decrypted, chunks and dictionaries included, but nothing is kept
+        let data_key = match data_key {
+            Some(key) => match self.open_entry_to(ns, name, entry, &key, &mut io::sink()) {
+                Ok(()) => Some(key),
+                Err(e) => {
+                    let error = e.to_string();
+                    report.found(location, Problem::Unreadable { error }, false);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        for (user, public_key) in &scan.keys {
+            if entry.encrypted_keys.contains_key(user) || !scan.holders.contains(user) {
+                continue;
+            }
+            // Only keys of entries that open and weren't changed are handed out
+            let repaired = match (&data_key, scan.repair && !tampered) {
+                (Some(key), true) => {
+                    let wrapped = i_keys::wrap_key(key, &[(user.clone(), public_key.clone())])?;
+                    entry.encrypted_keys.extend(wrapped);
+                    changed = true;
+                    true
+                }
+                _ => false,
+            };
+            let user = user.clone();
+            report.found(location, Problem::MissingRecipient { user }, repaired);
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::{TempDir, add_member, open_db, put_raw};
+
+    fn problems(report: &FsckReport) -> Vec<(&str, &Problem, bool)> {
+        report
+            .findings
+            .iter()
+            .map(|f| (f.location.as_str(), &f.problem, f.repaired))
+            .collect()
+    }
+
+    #[test]
+    fn members_granted_some_namespaces_are_only_missing_there() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store("a.txt", b"a", "txt", false).unwrap();
+        db.store("b.txt", b"b", "txt", false).unwrap();
+        db.conf.insert("ns", "prod");
+        db.store("c.txt", b"c", "txt", false).unwrap();
+        db.conf.insert("ns", "dev");
+        add_member(&tmp, &db, "bob", &["dev"]);
+
+        let report = db.fsck(false).unwrap();
+        assert_eq!(report.checked, 3);
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+
+        let mut entry = db.entry("dev", "a.txt").unwrap();
+        entry.encrypted_keys.remove("bob");
+        put_raw(&db, "dev", "a.txt", entry);
+        let missing = Problem::MissingRecipient {
+            user: "bob".to_string(),
+        };
+        assert_eq!(
+            problems(&db.fsck(false).unwrap()),
+            [("dev/a.txt", &missing, false)]
+        );
+
+        // Repairing gives bob his key back in dev and nothing in prod
+        assert_eq!(
+            problems(&db.fsck(true).unwrap()),
+            [("dev/a.txt", &missing, true)]
+        );
+        assert!(
+            db.entry("dev", "a.txt")
+                .unwrap()
+                .encrypted_keys
+                .contains_key("bob")
+        );
+        assert!(
+            !db.entry("prod", "c.txt")
+                .unwrap()
+                .encrypted_keys
+                .contains_key("bob")
+        );
+        assert!(db.fsck(false).unwrap().findings.is_empty());
+    }
+
+    #[test]
+    fn repair_drops_keys_of_strangers() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store("a.txt", b"a", "txt", false).unwrap();
+        let mut entry = db.entry("dev", "a.txt").unwrap();
+        entry
+            .encrypted_keys
+            .insert("mallory".to_string(), vec![1, 2, 3]);
+        put_raw(&db, "dev", "a.txt", entry);
+
+        let unknown = Problem::UnknownRecipient {
+            user: "mallory".to_string(),
+        };
+        assert_eq!(
+            problems(&db.fsck(true).unwrap()),
+            [("dev/a.txt", &unknown, true)]
+        );
+        let entry = db.entry("dev", "a.txt").unwrap();
+        assert_eq!(entry.encrypted_keys.keys().collect::<Vec<_>>(), ["alice"]);
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"a");
+    }
+
+    #[test]
+    fn changed_entries_are_reported_and_not_handed_out() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store("a.txt", b"a", "txt", false).unwrap();
+        db.store("b.txt", b"b", "txt", false).unwrap();
+        add_member(&tmp, &db, "bob", &["dev"]);
+        let mut entry = db.entry("dev", "a.txt").unwrap();
+        entry.encrypted_keys.remove("bob");
+        entry.stored_at += 1;
+        put_raw(&db, "dev", "a.txt", entry);
+
+        let report = db.fsck(true).unwrap();
+        let signer = "alice".to_string();
+        let missing = Problem::MissingRecipient {
+            user: "bob".to_string(),
+        };
+        assert_eq!(
+            problems(&report),
+            [
+                ("dev/a.txt", &Problem::InvalidSignature { signer }, false),
+                ("dev/a.txt", &missing, false)
+            ]
+        );
+        assert!(
+            !db.entry("dev", "a.txt")
+                .unwrap()
+                .encrypted_keys
+                .contains_key("bob")
+        );
+    }
+
+    #[test]
+    fn orphaned_chunks_are_removed_unless_something_is_undecodable() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store("a.txt", b"a", "txt", false).unwrap();
+        db.ensure_cf(CHUNKS_CF).unwrap();
+        let chunk = [[7u8; 16].as_slice(), &0u64.to_be_bytes()].concat();
+        db.db.put(CHUNKS_CF, &chunk, b"left over").unwrap();
+        let cf = db.ns_cf("dev").unwrap();
+        db.db.put(&cf, b"garbage", b"not an entry").unwrap();
+
+        let report = db.fsck(true).unwrap();
+        let findings = problems(&report);
+        assert_eq!(findings.len(), 2);
+        assert!(matches!(
+            findings[0],
+            (_, Problem::Undecodable { .. }, false)
+        ));
+        assert_eq!(
+            findings[1],
+            (
+                "chunks/07070707070707070707070707070707",
+                &Problem::OrphanedChunks { chunks: 1 },
+                false
+            )
+        );
+        assert!(db.db.get(CHUNKS_CF, &chunk).unwrap().is_some());
+
+        db.db.delete(&cf, b"garbage").unwrap();
+        let report = db.fsck(true).unwrap();
+        assert_eq!(report.unrepaired(), 0);
+        assert_eq!(report.findings.len(), 1);
+        assert!(db.db.get(CHUNKS_CF, &chunk).unwrap().is_none());
+        assert!(db.fsck(false).unwrap().findings.is_empty());
+    }
+}
@@ -1,18 +1,56 @@
-use super::i_keys::i_keys;
+use super::age_file;
+#[cfg(unix)]
+use super::agent::AgentClient;
+use super::chunked::{CHUNKED_FROM, CHUNKS_CF, ChunkStore, ChunkedBlob};
+use super::compression::{Codec, Compression, DICTIONARIES_CF, dictionary_name};
+use super::dotenv;
+use super::history::{HISTORY_CF, history_entry_name};
+use super::i_keys::{CryptoError, Passphrase, i_keys};
+use super::index::{self, INDEX_CF};
+use super::migrate;
+use super::signature::EntrySignature;
+use super::store::{self, Store};
+use super::suite::{PrivateKey, PublicKey, Suite};
+use super::sync::SYNC_CF;
 use crate::config::config::Config as Conf;
 use crate::config::resolve_path;
-use rocksdb::{ColumnFamilyDescriptor, DB, Options};
-use rsa::Oaep;
-use rsa::pkcs1::DecodeRsaPrivateKey;
-use rsa::pkcs8::{DecodePublicKey, EncodePublicKey};
-use rsa::{RsaPrivateKey, RsaPublicKey};
+use crate::error::ClenvError;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
 use std::collections::HashMap;
-use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Version of what an entry's ciphertext is bound to, see `EncryptedEntry::associated_data`.
+// Entries written before it existed are format 0 and were sealed without associated data.
+// Entries of formats from `SIGNED_FORMAT` on are always signed.
+pub const ENTRY_FORMAT: u32 = 3;
+
+// Every stored entry starts with this header and the big endian version of its layout, see
+// `EncryptedEntry::to_bytes`. bincode never starts a value with 0xff, so entries written before the
+// header existed can't be mistaken for one and are decoded as layout 0.
+const ENTRY_HEADER: &[u8] = b"\xffclenv";
+// Version of the fields `EncryptedEntry` is encoded with. Bump it when they change and keep decoding
+// the old layout in `from_bytes`, `clenv migrate` rewrites entries to the newest one.
+pub const ENTRY_LAYOUT: u32 = 1;
+
+// Records about the database as a whole: the format version it is in (see migrate.rs) and whether its
+// names are obfuscated (see index.rs)
+pub const META_CF: &str = "meta";
+
+// Column families clenv keeps its own records in. They share the store with the namespaces of plain databases
+pub(super) const INTERNAL_NAMESPACES: &[&str] = &[
+    "keyring",
+    HISTORY_CF,
+    INDEX_CF,
+    CHUNKS_CF,
+    DICTIONARIES_CF,
+    META_CF,
+    SYNC_CF,
+];
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EncryptedEntry {
@@ -20,296 +58,1614 @@ pub struct EncryptedEntry {
     pub nonce: [u8; 12],
     pub encrypted_keys: HashMap<String, Vec<u8>>,
     pub extension: String,
+    pub kind: EntryKind,
+    // Stamped by every write, see history.rs
+    pub revision: u64,
+    pub author: String,
+    pub stored_at: u64,
+    // Name and extension sealed with the index key when the database is obfuscated, see index.rs.
+    // `extension` is left empty in that case.
+    pub meta: Option<Vec<u8>>,
+    pub format: u32,
+    // Who wrote the entry last, see signature.rs
+    pub signature: Option<EntrySignature>,
+    // Algorithms the data key and contents are encrypted with, see suite.rs
+    pub suite: Suite,
+    // How every sealed value was compressed before it was encrypted, see compression.rs
+    pub compression: Compression,
+}
+
+// Blobs keep the whole file in `ciphertext`, dotenv entries keep every line sealed on its own
+// under the same data key so single variables can be read and changed.
+// Large files are stored in chunks outside of the entry, see chunked.rs. Only ever append variants.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EntryKind {
+    Blob,
+    Dotenv(Vec<EncryptedLine>),
+    Chunked(ChunkedBlob),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedLine {
+    pub ciphertext: Vec<u8>,
+    pub nonce: [u8; 12],
+}
+
+// Layout entries were written in before they started with a header, everything in it is a blob
+#[derive(Deserialize)]
+struct LegacyEntry {
+    ciphertext: Vec<u8>,
+    nonce: [u8; 12],
+    encrypted_keys: HashMap<String, Vec<u8>>,
+    extension: String,
+}
+
+// Key and value of a stored record, not decoded yet
+pub(super) type RawRecord = (Vec<u8>, Vec<u8>);
+
+// Associated data the ciphertext of an entry is sealed with. Every part is length prefixed,
+// so no two combinations of namespace, name and extension give the same bytes.
+pub(super) fn entry_aad(format: u32, ns: &str, name: &str, extension: &str) -> Vec<u8> {
+    if format == 0 {
+        return Vec::new();
+    }
+    let mut aad = format.to_be_bytes().to_vec();
+    for part in [ns, name, extension] {
+        aad.extend_from_slice(&(part.len() as u64).to_be_bytes());
+        aad.extend_from_slice(part.as_bytes());
+    }
+    aad
 }
 
-const CONF_ERROR: &str =
-    "Missing conifguration. Try running 'clenv cfg init' to reset your settings.";
+// Decodes a value that has to take up all of `bytes`, so a value of another layout that happens to
+// start like this one isn't taken for it
+fn decode_whole<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, bincode::error::DecodeError> {
+    let (value, read) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())?;
+    if read != bytes.len() {
+        return Err(bincode::error::DecodeError::OtherString(format!(
+            "{} bytes left over after the entry",
+            bytes.len() - read
+        )));
+    }
+    Ok(value)
+}
+
+impl EncryptedEntry {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+        let Some(body) = bytes.strip_prefix(ENTRY_HEADER) else {
+            return Self::from_headerless(bytes);
+        };
+        let (layout, body) = match body.split_first_chunk::<4>() {
+            Some((layout, body)) => (u32::from_be_bytes(*layout), body),
+            None => return Err(bincode::error::DecodeError::UnexpectedEnd { additional: 4 }),
+        };
+        match layout {
+            ENTRY_LAYOUT => decode_whole(body),
+            _ => Err(bincode::error::DecodeError::OtherString(format!(
+                "entry layout {} is newer than this version of clenv understands",
+                layout
+            ))),
+        }
+    }
+
+    /// Layout version the stored value was written with, 0 for entries without a header
+    pub fn layout_of(bytes: &[u8]) -> u32 {
+        bytes
+            .strip_prefix(ENTRY_HEADER)
+            .and_then(|body| body.first_chunk::<4>())
+            .map_or(0, |layout| u32::from_be_bytes(*layout))
+    }
+
+    // Entries written before the header existed, all in the first layout
+    fn from_headerless(bytes: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+        let legacy: LegacyEntry = decode_whole(bytes)?;
+        Ok(EncryptedEntry {
+            ciphertext: legacy.ciphertext,
+            nonce: legacy.nonce,
+            encrypted_keys: legacy.encrypted_keys,
+            extension: legacy.extension,
+            kind: EntryKind::Blob,
+            revision: 1,
+            author: String::new(),
+            stored_at: 0,
+            meta: None,
+            format: 0,
+            signature: None,
+            suite: Suite::Rsa,
+            compression: Compression::Zstd,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        let mut bytes = ENTRY_HEADER.to_vec();
+        bytes.extend_from_slice(&ENTRY_LAYOUT.to_be_bytes());
+        bincode::serde::encode_into_std_write(self, &mut bytes, bincode::config::standard())?;
+        Ok(bytes)
+    }
+
+    /// What the ciphertext is authenticated with: the format version, namespace, entry name and extension.
+    /// Opening the entry under any other namespace, name or extension fails.
+    /// `extension` has to be the real one, so entries of obfuscated databases need their metadata opened first.
+    pub fn associated_data(&self, ns: &str, name: &str) -> Vec<u8> {
+        entry_aad(self.format, ns, name, &self.extension)
+    }
+
+    // Moves the entry to a fresh data key: every sealed value gets re-encrypted with a new nonce
+    // and the new key is wrapped for `recipients` only
+    pub fn rekey(
+        &mut self,
+        old_key: &[u8],
+        ns: &str,
+        name: &str,
+        recipients: &[(String, PublicKey)],
+        chunks: &ChunkStore,
+    ) -> Result<(), ClenvError> {
+        let new_key = i_keys::generate_data_key();
+        self.reseal(old_key, &new_key, ns, name, self.suite, chunks)?;
+        self.encrypted_keys = i_keys::wrap_key(&new_key, recipients)?;
+        Ok(())
+    }
+
+    // Re-encrypts every sealed value under `new_key` with the cipher of `suite` and binds it to the current format.
+    // The same key can be passed twice to only upgrade the format or change the suite.
+    // Chunks are written anew, the old ones are deleted by `update_entries` once the entry is written.
+    pub(super) fn reseal(
+        &mut self,
+        old_key: &[u8],
+        new_key: &[u8],
+        ns: &str,
+        name: &str,
+        suite: Suite,
+        chunks: &ChunkStore,
+    ) -> Result<(), ClenvError> {
+        let old_aad = self.associated_data(ns, name);
+        let new_aad = entry_aad(ENTRY_FORMAT, ns, name, &self.extension);
+        let old_suite = self.suite;
+
+        match &mut self.kind {
+            EntryKind::Blob => {
+                let plain = old_suite.open(old_key, &self.ciphertext, &self.nonce, &old_aad)?;
+                (self.ciphertext, self.nonce) = suite.seal(new_key, &plain, &new_aad)?;
+            }
+            EntryKind::Dotenv(lines) => {
+                for line in lines.iter_mut() {
+                    let plain = old_suite.open(old_key, &line.ciphertext, &line.nonce, &old_aad)?;
+                    (line.ciphertext, line.nonce) = suite.seal(new_key, &plain, &new_aad)?;
+                }
+            }
+            EntryKind::Chunked(blob) => {
+                *blob = chunks.reseal(
+                    blob,
+                    (old_key, old_suite, &old_aad),
+                    (new_key, suite, &new_aad),
+                )?;
+            }
+        }
+
+        self.format = ENTRY_FORMAT;
+        self.suite = suite;
+        Ok(())
+    }
+}
+
+/// What `add_user` or `remove_user` changed
+#[derive(Debug, Default, Serialize)]
+pub struct AccessReport {
+    // Private key written for the user when no public key was given to add_user
+    pub key_file: Option<String>,
+    // remove_user with rekey on an obfuscated database moves every name to a new identifier
+    pub index_rotated: bool,
+    pub namespaces: Vec<NamespaceReport>,
+    // Entries and revisions the removed user signed last, signed anew in our name, as "namespace/label"
+    pub resigned: Vec<String>,
+    // Those we couldn't vouch for, refused until someone checks them and runs reseal
+    pub left_signed: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct NamespaceReport {
+    pub namespace: String,
+    pub exists: bool,
+    // Current entries that were changed
+    pub updated: Vec<String>,
+    // Entries and revisions left alone because we can't read them ourselves
+    pub skipped: Vec<String>,
+    // Entries and revisions moved to a fresh data key by remove_user with rekey
+    pub rotated: Vec<String>,
+    // Entries and revisions that weren't re-encrypted because their signature can't be trusted
+    pub invalid: Vec<String>,
+}
+
+impl NamespaceReport {
+    fn new(namespace: &str) -> Self {
+        NamespaceReport {
+            namespace: namespace.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// What `show` lists for an entry, without decrypting anything
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryInfo {
+    pub name: String,
+    pub extension: String,
+    // "blob", "dotenv" or "chunked"
+    pub kind: &'static str,
+    pub revision: u64,
+    pub author: String,
+    pub stored_at: u64,
+    pub suite: Suite,
+    pub compression: Compression,
+    // Users holding a wrapped key for the entry, sorted by name
+    pub recipients: Vec<String>,
+}
 
 pub struct SecDb {
-    db: DB,
-    conf: Conf,
+    pub(super) db: Box<dyn Store>,
+    pub(super) conf: Conf,
+    // Whether names are stored under keyed identifiers, see index.rs
+    pub(super) obfuscated: bool,
+    // Only set for obfuscated databases we are a keyring member of
+    pub(super) index_key: Option<Vec<u8>>,
+    passphrase: Box<Passphrase>,
+    // Our private key once it has been read, so the passphrase is asked for at most once
+    private_key: OnceCell<PrivateKey>,
+    // A running "clenv agent" holding our key, used before reading the key file, see agent.rs
+    #[cfg(unix)]
+    pub(super) agent: Option<AgentClient>,
 }
 
 impl SecDb {
-    pub fn new(conf: Conf) -> SecDb {
-        let mut db_opts = Options::default();
-        db_opts.create_if_missing(true);
-        db_opts.create_missing_column_families(true);
+    /// Opens the database, or creates it with a new key pair. An encrypted private key is opened
+    /// with the passphrase in CLENV_PASSPHRASE, use `with_passphrase` to ask for it some other way.
+    pub fn new(conf: Conf) -> Result<SecDb, ClenvError> {
+        Self::with_passphrase(conf, Box::new(i_keys::env_passphrase))
+    }
 
+    /// Same as `new`, with the passphrases of private keys coming from `passphrase`
+    pub fn with_passphrase(conf: Conf, passphrase: Box<Passphrase>) -> Result<SecDb, ClenvError> {
         // Thsese are the required configurations for the db
-        let name = conf.get("name").expect(CONF_ERROR);
-        let path = conf.get("db").expect(CONF_ERROR);
-        let private_key = conf.get("private_key").expect(CONF_ERROR);
+        let name = conf.require("name")?;
+        let path = conf.require("db")?;
+        let private_key = conf.require("private_key")?;
 
-        // All the files exist
-        if Path::new(&path).exists() && Path::new(&private_key).exists() {
-            let cfs = rocksdb::DB::list_cf(&db_opts, &path).unwrap();
-            let cf_descriptors = cfs
-                .iter()
-                .map(|name| ColumnFamilyDescriptor::new(name, Options::default()))
-                .collect::<Vec<_>>();
+        let mut db = store::open(&conf)?;
 
-            let db = DB::open_cf_descriptors(&db_opts, &path, cf_descriptors).unwrap();
-            return SecDb { db, conf };
+        // All the files exist. A new directory store can start out as an empty folder or git repository
+        if db.has_namespace("keyring") {
+            if !Path::new(&private_key).exists() {
+                return Err(ClenvError::NotFound(format!(
+                    "The database at {} exists but the private key {} doesn't. Set the right one with 'clenv cfg private_key <path>'",
+                    path, private_key
+                )));
+            }
+            migrate::check_schema(db.as_ref(), &path)?;
+            let mut sec_db = SecDb {
+                obfuscated: index::marked_obfuscated(db.as_ref())?,
+                db,
+                conf,
+                index_key: None,
+                passphrase,
+                private_key: OnceCell::new(),
+                #[cfg(unix)]
+                agent: AgentClient::for_key(&private_key),
+            };
+            sec_db.index_key = sec_db.load_index_key()?;
+            return Ok(sec_db);
         }
 
-        let mut db = DB::open(&db_opts, &path).unwrap();
-
         // Keyring is where the recipients are kept. run "clenv show keyring" to see who has access to this database at any time
-        db.create_cf("keyring", &Options::default()).unwrap();
-        db.create_cf(conf.get("ns").unwrap(), &Options::default())
-            .unwrap();
-        let cf = db.cf_handle("keyring").unwrap();
+        db.create_namespace("keyring")?;
+        // New databases keep their names obfuscated from the start
+        db.create_namespace(INDEX_CF)?;
+        migrate::record_schema(db.as_mut())?;
+        migrate::record_all_signed(db.as_ref())?;
+        index::mark_obfuscated(db.as_ref())?;
 
-        let key_pair = i_keys::generate_key_pair(&name, &private_key);
+        let suite = Suite::configured(&conf)?;
+        let (priv_key, pub_key) = i_keys::generate_key_pair(&private_key, &passphrase, suite)?;
+        db.put("keyring", name.as_bytes(), pub_key.to_pem()?.as_bytes())?;
 
-        db.put_cf(
-            cf,
-            name,
-            key_pair
-                .unwrap()
-                .1
-                .to_public_key_pem(Default::default())
-                .unwrap(),
-        )
-        .unwrap();
+        let ns = conf.require("ns")?;
+        let mut sec_db = SecDb {
+            db,
+            conf,
+            obfuscated: true,
+            index_key: Some(i_keys::generate_data_key()),
+            passphrase,
+            private_key: OnceCell::from(priv_key),
+            #[cfg(unix)]
+            agent: None,
+        };
+        sec_db.grant_index_key(&name, &pub_key)?;
+        sec_db.ensure_namespace(&ns)?;
+        Ok(sec_db)
+    }
 
-        println!("Created database at {}", &path);
-        SecDb { db, conf }
+    /// Every column family in the database, including the keyring and history
+    pub fn column_families(&self) -> Result<Vec<String>, ClenvError> {
+        self.db.namespaces()
     }
 
-    pub fn list_cfs(&self) {
-        let cf_names = DB::list_cf(&Options::default(), self.conf.get("db").unwrap())
-            .unwrap_or_else(|_| vec!["default".to_string()]);
+    /// Names of the entries in a namespace. For the keyring these are the users with access
+    pub fn list_entries(&self, family: &str) -> Result<Vec<String>, ClenvError> {
+        if family != "keyring" && self.is_obfuscated() {
+            self.ns_cf(family)?;
+            let mut names: Vec<String> = self.entry_names(family)?.into_values().collect();
+            names.sort();
+            return Ok(names);
+        }
+        let ring = match family {
+            "keyring" => self.cf(family)?.to_string(),
+            _ => self.ns_cf(family)?,
+        };
+        let iter = self.db.iter(&ring)?;
 
-        println!("Namespaces:");
-        for cf in cf_names {
-            println!("- {}", cf);
+        let mut entries = Vec::new();
+        for item in iter {
+            let (key, _value) = item?;
+            entries.push(String::from_utf8_lossy(&key).to_string());
         }
+        Ok(entries)
     }
 
-    pub fn list_cf_formatted(&self, family: &str) {
-        let ring = self.db.cf_handle(family).unwrap();
-        let iter = self.db.iterator_cf(ring, rocksdb::IteratorMode::Start);
+    /// Metadata of every entry in a namespace, read without any keys
+    pub fn entry_infos(&self, family: &str) -> Result<Vec<EntryInfo>, ClenvError> {
+        let cf = &self.ns_cf(family)?;
+        let names = self.entry_names(family)?;
+        let iter = self.db.iter(cf)?;
 
+        let mut infos = Vec::new();
         for item in iter {
-            match item {
-                Ok((key, _value)) => {
-                    println!("{}", String::from_utf8_lossy(&key));
-                }
-                Err(e) => {
-                    eprintln!("Iteration error: {}", e);
-                }
-            }
+            let (key, value) = item?;
+            let mut entry = EncryptedEntry::from_bytes(&value)?;
+            self.open_meta(&mut entry)?;
+            let mut recipients: Vec<String> = entry.encrypted_keys.keys().cloned().collect();
+            recipients.sort();
+
+            infos.push(EntryInfo {
+                name: Self::name_of(&names, &String::from_utf8_lossy(&key)),
+                extension: entry.extension,
+                kind: match entry.kind {
+                    EntryKind::Blob => "blob",
+                    EntryKind::Dotenv(_) => "dotenv",
+                    EntryKind::Chunked(_) => "chunked",
+                },
+                revision: entry.revision,
+                author: entry.author,
+                stored_at: entry.stored_at,
+                suite: entry.suite,
+                compression: entry.compression,
+                recipients,
+            });
         }
+        Ok(infos)
+    }
+
+    /// The namespace commands work on, from the `ns` setting
+    pub fn namespace(&self) -> Result<String, ClenvError> {
+        self.conf.require("ns")
     }
 
-    /// The meat and potatoes of the whole thing: This stores the file given the ever important filename as a byte stream
-    pub fn store_file(&mut self, name: &str, filename: &str) {
+    /// The meat and potatoes of the whole thing: This stores the file given the ever important filename as a byte stream.
+    /// Returns the revision the entry was stored as.
+    pub fn store_file(&mut self, name: &str, filename: &str) -> Result<u64, ClenvError> {
         let path = resolve_path(filename, "");
         let extension = path
             .extension()
             .and_then(|s| s.to_str())
             .unwrap_or("")
             .to_string();
-        let mut file = File::open(&path).expect("Could not open file");
-        let mut file_data = Vec::new();
-        file.read_to_end(&mut file_data)
-            .expect("Failed to read file");
+        let file = fs::File::open(&path)
+            .map_err(|e| ClenvError::io(format!("Could not read {}", path.display()), e))?;
 
-        let recipients = self.get_recipients().expect("Failed to fetch recipients");
-        let entry = i_keys::encrypt(&file_data, &recipients, extension).expect("Encryption failed");
-
-        let serialized = bincode::serde::encode_to_vec(&entry, bincode::config::standard())
-            .expect("Serialization failed");
+        self.store_reader(name, file, &extension, dotenv::is_dotenv(&path))
+    }
 
-        let cf_name = self.conf.get("ns").expect("Missing namespace");
-        let cf = match self.db.cf_handle(&cf_name) {
-            Some(cf) => cf,
-            None => {
-                self.db
-                    .create_cf(&cf_name, &Options::default())
-                    .expect("Failed to create column family");
+    /// Like `store`, reading the contents from `input`. Anything of CHUNKED_FROM bytes or more that isn't
+    /// a dotenv file is stored in chunks as it is read, so it never has to fit in memory.
+    pub fn store_reader(
+        &mut self,
+        name: &str,
+        mut input: impl Read,
+        extension: &str,
+        dotenv: bool,
+    ) -> Result<u64, ClenvError> {
+        let read_err = |e| ClenvError::io("Could not read the file to store", e);
+        let mut head = Vec::new();
+        (&mut input)
+            .take(CHUNKED_FROM)
+            .read_to_end(&mut head)
+            .map_err(read_err)?;
+        if dotenv {
+            input.read_to_end(&mut head).map_err(read_err)?;
+        }
+        if dotenv || (head.len() as u64) < CHUNKED_FROM {
+            return self.store(name, &head, extension, dotenv);
+        }
+        self.store_chunked(name, head.chain(input), extension)
+    }
 
-                self.db.cf_handle(&cf_name).expect("CF creation failed")
+    /// Stores raw bytes as an entry of the current namespace. `dotenv` entries are sealed line by line
+    /// so `get_var` and `set_var` work on them.
+    pub fn store(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        extension: &str,
+        dotenv: bool,
+    ) -> Result<u64, ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let aad = entry_aad(ENTRY_FORMAT, &cf_name, name, extension);
+        let extension = extension.to_string();
+        let recipients = self.get_recipients()?;
+        let suite = Suite::configured(&self.conf)?;
+        let users: Vec<String> = recipients.iter().map(|(user, _)| user.clone()).collect();
+        let mut codec = self.new_codec(&cf_name, &users)?;
+        let entry = if dotenv {
+            Self::encrypt_dotenv(data, &recipients, extension, &aad, suite, &mut codec)?
+        } else {
+            let (ciphertext, nonce, encrypted_keys, extension) =
+                i_keys::encrypt(data, &recipients, extension, &aad, suite, &mut codec)?;
+            EncryptedEntry {
+                ciphertext,
+                nonce,
+                encrypted_keys,
+                extension,
+                kind: EntryKind::Blob,
+                revision: 0,
+                author: String::new(),
+                stored_at: 0,
+                meta: None,
+                format: ENTRY_FORMAT,
+                signature: None,
+                suite,
+                compression: codec.compression(),
             }
         };
 
-        self.db
-            .put_cf(&cf, name.as_bytes(), &serialized)
-            .expect("DB write failed");
+        self.ensure_namespace(&cf_name)?;
+        self.put_entry(&cf_name, name, entry)
+    }
 
-        println!("Stored encrypted file '{}' successfully.", filename);
+    /// Decrypts an entry of the current namespace
+    pub fn read_entry(&self, name: &str) -> Result<Vec<u8>, ClenvError> {
+        Ok(self.decrypt_entry(name)?.1)
     }
 
-    pub fn dump_file(&self, name: &str) {
-        // First grab the column family and db value, and the private key
-        let cf_name = self.conf.get("ns").expect("Missing namespace");
-        let cf = self.db.cf_handle(&cf_name).expect("Missing column family");
+    /// Writes an entry to the current working directory and returns the path it was written to
+    pub fn dump_file(&self, name: &str) -> Result<PathBuf, ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let entry = self.entry(&cf_name, name)?;
 
-        let value = self
-            .db
-            .get_cf(&cf, name.as_bytes())
-            .expect("DB read failed")
-            .expect(&format!("No entry found for the {} key", name));
+        let mut output_path = PathBuf::from(name);
+        output_path.set_extension(&entry.extension);
+        // Large entries are written as they are decrypted, a half written file never takes the place of the real one
+        let mut partial = output_path.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
 
-        let pem_data = fs::read_to_string(self.conf.get("private_key").unwrap());
-        let priv_key = RsaPrivateKey::from_pkcs1_pem(&pem_data.unwrap()).unwrap();
+        let write_err = |e| ClenvError::io(format!("Could not write {}", output_path.display()), e);
+        let written = fs::File::create(&partial)
+            .map_err(write_err)
+            .and_then(|file| {
+                let mut output = BufWriter::new(file);
+                self.decrypt_entry_to(&cf_name, name, &mut output)?;
+                output.flush().map_err(write_err)
+            })
+            .and_then(|_| fs::rename(&partial, &output_path).map_err(write_err));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
 
-        // Next, we need to get the individual values
-        let entry: EncryptedEntry =
-            bincode::serde::decode_from_slice(&value, bincode::config::standard())
-                .expect("Deserialization failed")
-                .0;
+        Ok(output_path)
+    }
+
+    /// Runs a command with the variables of a dotenv entry injected into its environment.
+    /// The decrypted entry only ever lives in memory, nothing is written to the working directory.
+    /// The database is closed before the command starts, so the command can use it as well.
+    pub fn run_with_entry(self, name: &str, command: &[String]) -> Result<i32, ClenvError> {
+        let (entry, plaintext) = self.decrypt_entry(name)?;
+        if !matches!(entry.kind, EntryKind::Dotenv(_)) {
+            return Err(ClenvError::Invalid(format!(
+                "{} was not stored as a dotenv entry",
+                name
+            )));
+        }
+        let content = String::from_utf8(plaintext)
+            .map_err(|_| ClenvError::Invalid(format!("{} is not a valid dotenv file", name)))?;
+        let vars = dotenv::parse(&content);
+        drop(self);
+
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| ClenvError::Invalid("No command provided".to_string()))?;
+        let status = Command::new(program)
+            .args(args)
+            .envs(vars)
+            .status()
+            .map_err(|e| ClenvError::io(format!("Failed to run '{}'", program), e))?;
+
+        // Mirror the child's exit code so clenv can be dropped into scripts transparently
+        Ok(status.code().unwrap_or(1))
+    }
+
+    // Decrypts an entry from the current namespace and hands back the raw plaintext with its metadata
+    fn decrypt_entry(&self, name: &str) -> Result<(EncryptedEntry, Vec<u8>), ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        self.decrypt_entry_in(&cf_name, name)
+    }
+
+    /// Decrypts an entry of any namespace, handing back the stored entry with the plaintext
+    pub fn decrypt_entry_in(
+        &self,
+        cf_name: &str,
+        name: &str,
+    ) -> Result<(EncryptedEntry, Vec<u8>), ClenvError> {
+        let mut plaintext = Vec::new();
+        let entry = self.decrypt_entry_to(cf_name, name, &mut plaintext)?;
+        Ok((entry, plaintext))
+    }
+
+    /// Decrypts an entry of any namespace into `output` and hands back the stored entry.
+    /// Chunked entries are written chunk by chunk, see chunked.rs.
+    pub fn decrypt_entry_to(
+        &self,
+        cf_name: &str,
+        name: &str,
+        output: &mut dyn Write,
+    ) -> Result<EncryptedEntry, ClenvError> {
+        // First grab the entry, then unwrap its data key with our private key
+        let entry = self.entry(cf_name, name)?;
+        self.check_signature(cf_name, name, &entry)?;
+        let aes_key = self.own_data_key(name, &entry)?;
+        self.open_entry_to(cf_name, name, &entry, &aes_key, output)?;
+        Ok(entry)
+    }
+
+    // Decrypts an entry with its metadata opened into `output` with its data key, whatever its signature says
+    pub(super) fn open_entry_to(
+        &self,
+        cf_name: &str,
+        name: &str,
+        entry: &EncryptedEntry,
+        aes_key: &[u8],
+        output: &mut dyn Write,
+    ) -> Result<(), ClenvError> {
+        let aad = entry.associated_data(cf_name, name);
+        let codec = self.codec_of(cf_name, entry)?;
 
         // Then just do everything backwards
-        let test = i_keys::decrypt(
-            &entry.encrypted_keys[&self.conf.get("name").unwrap()],
-            &entry.ciphertext,
-            &entry.nonce,
-            &priv_key,
-        );
+        let plaintext = match &entry.kind {
+            EntryKind::Blob => {
+                let compressed = (entry.suite)
+                    .open(aes_key, &entry.ciphertext, &entry.nonce, &aad)
+                    .map_err(|e| mismatch(e.into(), cf_name, name))?;
+                codec.decompress(&compressed)?
+            }
+            EntryKind::Dotenv(lines) => Self::open_lines(entry.suite, aes_key, &aad, &codec, lines)
+                .map_err(|e| mismatch(e, cf_name, name))?
+                .join("\n")
+                .into_bytes(),
+            EntryKind::Chunked(blob) => {
+                return self
+                    .chunk_store()
+                    .read(blob, aes_key, entry.suite, &aad, &codec, output)
+                    .map_err(|e| mismatch(e, cf_name, name));
+            }
+        };
 
-        let mut output_path = PathBuf::from(name);
-        output_path.set_extension(&entry.extension);
-        let mut file = File::create(&output_path).expect(&format!(
-            "Failed to create file. Please check entry with 'clenv show {}",
-            &name
-        ));
+        output
+            .write_all(&plaintext)
+            .map_err(|e| ClenvError::io("Could not write the entry", e))
+    }
+
+    /// Reads a single variable of a dotenv entry
+    pub fn get_var(&self, name: &str, var: &str) -> Result<String, ClenvError> {
+        let (entry, lines, cf_name) = self.read_dotenv(name, false)?;
+        let aes_key = self.own_data_key(name, &entry)?;
+        let aad = entry.associated_data(&cf_name, name);
+        let codec = self.codec_of(&cf_name, &entry)?;
+
+        // Variable names are sealed with the lines, so every line is opened to find it
+        let plain = Self::open_lines(entry.suite, &aes_key, &aad, &codec, &lines)
+            .map_err(|e| mismatch(e, &cf_name, name))?;
+        plain
+            .iter()
+            .rev()
+            .filter_map(|line| dotenv::parse_line(line))
+            .find(|(key, _)| key == var)
+            .map(|(_, value)| value)
+            .ok_or_else(|| ClenvError::NotFound(format!("Variable {} not found in {}", var, name)))
+    }
+
+    /// Sets a single variable of a dotenv entry, appending it if it doesn't exist yet.
+    /// Only the changed line is re-encrypted, everything else is left as it was stored.
+    pub fn set_var(&mut self, name: &str, var: &str, value: &str) -> Result<u64, ClenvError> {
+        let (mut entry, mut lines, cf_name) = self.read_dotenv(name, true)?;
+        let aes_key = self.own_data_key(name, &entry)?;
+        let aad = entry.associated_data(&cf_name, name);
+        let suite = entry.suite;
+        // The new line is compressed like the rest of the entry
+        let codec = self.sealing_codec_of(&cf_name, &entry)?;
+
+        let plain = Self::open_lines(suite, &aes_key, &aad, &codec, &lines)
+            .map_err(|e| mismatch(e, &cf_name, name))?;
+        let existing = plain
+            .iter()
+            .rposition(|line| dotenv::parse_line(line).is_some_and(|(key, _)| key == var));
+        let previous = existing.map(|idx| plain[idx].as_str());
+        let new_line = dotenv::format_line(previous, var, value);
+
+        let (ciphertext, nonce) =
+            suite.seal(&aes_key, &codec.compress(new_line.as_bytes())?, &aad)?;
+        let sealed = EncryptedLine { ciphertext, nonce };
+
+        match existing {
+            Some(idx) => lines[idx] = sealed,
+            None => {
+                // Keep the trailing newline of the file at the end
+                let ends_with_newline = plain.last().is_some_and(|last| last.is_empty());
+                let at = if ends_with_newline {
+                    lines.len() - 1
+                } else {
+                    lines.len()
+                };
+                lines.insert(at, sealed);
+            }
+        }
+
+        entry.kind = EntryKind::Dotenv(lines);
+        self.put_entry(&cf_name, name, entry)
+    }
+
+    // The dotenv entry `name` of the current namespace with its lines taken out, and the namespace.
+    // With `resign` it has to be signed well enough to be stored again in our name
+    fn read_dotenv(
+        &self,
+        name: &str,
+        resign: bool,
+    ) -> Result<(EncryptedEntry, Vec<EncryptedLine>, String), ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let mut entry = self.entry(&cf_name, name)?;
+        if resign {
+            self.check_resign(&cf_name, name, &entry)?;
+        } else {
+            self.check_signature(&cf_name, name, &entry)?;
+        }
+
+        let EntryKind::Dotenv(lines) = &mut entry.kind else {
+            return Err(ClenvError::Invalid(format!(
+                "{} was not stored as a dotenv entry",
+                name
+            )));
+        };
+        let lines = std::mem::take(lines);
+        Ok((entry, lines, cf_name))
+    }
+
+    /// The stored entry as is, still encrypted
+    pub fn entry(&self, cf_name: &str, name: &str) -> Result<EncryptedEntry, ClenvError> {
+        let cf = &self.ns_cf(cf_name)?;
+        let key = self.entry_key(cf_name, name)?;
+        let value = self.db.get(cf, key.as_bytes())?.ok_or_else(|| {
+            ClenvError::NotFound(format!(
+                "No entry found for {} in namespace {}. Check 'clenv show {}'",
+                name, cf_name, cf_name
+            ))
+        })?;
+        let mut entry = EncryptedEntry::from_bytes(&value)?;
+        self.open_meta(&mut entry)?;
+        Ok(entry)
+    }
+
+    // Unwraps the entry's data key with our own private key
+    pub(super) fn own_data_key(
+        &self,
+        name: &str,
+        entry: &EncryptedEntry,
+    ) -> Result<Vec<u8>, ClenvError> {
+        let my_name = self.conf.require("name")?;
+        let encrypted_key = entry.encrypted_keys.get(&my_name).ok_or_else(|| {
+            ClenvError::AccessDenied(format!(
+                "{} has no access to {}. Ask someone who does to run 'clenv add {}'",
+                my_name, name, my_name
+            ))
+        })?;
+        self.unwrap_own(encrypted_key)
+    }
+
+    // Unwraps a data key wrapped for us, by the agent when one holds our key and is unlocked
+    pub(super) fn unwrap_own(&self, wrapped: &[u8]) -> Result<Vec<u8>, ClenvError> {
+        #[cfg(unix)]
+        if let Some(agent) = &self.agent
+            && let Some(key) = agent.unwrap_key(wrapped)?
+        {
+            return Ok(key);
+        }
+        Ok(i_keys::unwrap_key(wrapped, &self.private_key()?)?)
+    }
+
+    pub(super) fn private_key(&self) -> Result<PrivateKey, ClenvError> {
+        if let Some(key) = self.private_key.get() {
+            return Ok(key.clone());
+        }
+        let key = i_keys::read_private_key(&self.conf.require("private_key")?, &self.passphrase)?;
+        Ok(self.private_key.get_or_init(|| key).clone())
+    }
+
+    // `cf_name` back when the store has it
+    pub(super) fn cf<'a>(&self, cf_name: &'a str) -> Result<&'a str, ClenvError> {
+        match self.db.has_namespace(cf_name) {
+            true => Ok(cf_name),
+            false => Err(ClenvError::NotFound(format!(
+                "Namespace {} does not exist",
+                cf_name
+            ))),
+        }
+    }
+
+    // Splits a dotenv file into lines and seals every one of them under a single data key.
+    // The lines are compressed with `codec` when that makes the entry smaller.
+    fn encrypt_dotenv(
+        file_data: &[u8],
+        recipients: &[(String, PublicKey)],
+        extension: String,
+        aad: &[u8],
+        suite: Suite,
+        codec: &mut Codec,
+    ) -> Result<EncryptedEntry, ClenvError> {
+        let content = std::str::from_utf8(file_data)
+            .map_err(|_| ClenvError::Invalid("Dotenv file is not valid UTF-8".to_string()))?;
+        let aes_key = i_keys::generate_data_key();
+
+        // split keeps the trailing empty line, so the file comes back byte for byte
+        let lines: Vec<&str> = content.split('\n').collect();
+        let values: Vec<&[u8]> = lines.iter().map(|line| line.as_bytes()).collect();
+        let values = codec.compress_all(&values)?;
+        // Variable names are only kept in the sealed lines, so the database doesn't give them away
+        let lines = values
+            .into_iter()
+            .map(|value| {
+                let (ciphertext, nonce) = suite.seal(&aes_key, &value, aad)?;
+                Ok(EncryptedLine { ciphertext, nonce })
+            })
+            .collect::<Result<Vec<_>, CryptoError>>()?;
+
+        Ok(EncryptedEntry {
+            ciphertext: Vec::new(),
+            nonce: [0u8; 12],
+            encrypted_keys: i_keys::wrap_key(&aes_key, recipients)?,
+            extension,
+            kind: EntryKind::Dotenv(lines),
+            revision: 0,
+            author: String::new(),
+            stored_at: 0,
+            meta: None,
+            format: ENTRY_FORMAT,
+            signature: None,
+            suite,
+            compression: codec.compression(),
+        })
+    }
 
-        file.write_all(&test.unwrap())
-            .expect("Failed to write output");
-        println!("Successfully wrote to {}", name);
+    fn open_lines(
+        suite: Suite,
+        aes_key: &[u8],
+        aad: &[u8],
+        codec: &Codec,
+        lines: &[EncryptedLine],
+    ) -> Result<Vec<String>, ClenvError> {
+        lines
+            .iter()
+            .map(|line| {
+                let compressed = suite.open(aes_key, &line.ciphertext, &line.nonce, aad)?;
+                String::from_utf8(codec.decompress(&compressed)?)
+                    .map_err(|_| ClenvError::Storage("Stored line is not valid UTF-8".to_string()))
+            })
+            .collect()
     }
 
     // This file retrives all the public keys for each recipient of the database
-    pub fn get_recipients(&self) -> Result<Vec<(String, RsaPublicKey)>, Box<dyn Error>> {
-        let ring = self
-            .db
-            .cf_handle("keyring")
-            .ok_or("Missing 'keyring' namespace")?;
-        let iter = self.db.iterator_cf(ring, rocksdb::IteratorMode::Start);
+    pub fn get_recipients(&self) -> Result<Vec<(String, PublicKey)>, ClenvError> {
+        if !self.db.has_namespace("keyring") {
+            return Err(ClenvError::Storage(
+                "Missing 'keyring' namespace".to_string(),
+            ));
+        }
+        let iter = self.db.iter("keyring")?;
         let mut recipients = Vec::new();
 
         for item in iter {
-            match item {
-                Ok((key, value)) => {
-                    let name = String::from_utf8(key.to_vec())?;
-                    let pem = String::from_utf8(value.to_vec())?;
-                    let pubkey = RsaPublicKey::from_public_key_pem(&pem)?;
-                    recipients.push((name, pubkey));
-                }
-                Err(e) => {
-                    eprintln!("Iteration error in keyring: {}", e);
-                }
-            }
+            let (key, value) = item?;
+            let name = String::from_utf8_lossy(&key).to_string();
+            let pubkey = parse_public_key(&name, &value)?;
+            recipients.push((name, pubkey));
         }
         Ok(recipients)
     }
 
-    pub fn rm(&self, name: &str) {
-        let cf_name = self.conf.get("ns").expect("Missing namespace");
-        let cf = self.db.cf_handle(&cf_name).expect("Missing column family");
-        self.db
-            .delete_cf(&cf, name)
-            .expect("Could not find entry in column family");
-        println!("Successfuly removed entry from clenv: {}", name);
+    // Public key of a single keyring member, None if they aren't in the keyring
+    pub(super) fn public_key_of(&self, user: &str) -> Result<Option<PublicKey>, ClenvError> {
+        match self.db.get(self.cf("keyring")?, user.as_bytes())? {
+            Some(value) => Ok(Some(parse_public_key(user, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn rm(&self, name: &str) -> Result<(), ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let cf = &self.ns_cf(&cf_name)?;
+        let key = self.entry_key(&cf_name, name)?;
+        let Some(value) = self.db.get(cf, key.as_bytes())? else {
+            return Err(ClenvError::NotFound(format!(
+                "No entry found for {} in namespace {}",
+                name, cf_name
+            )));
+        };
+        self.db.delete(cf, key.as_bytes())?;
+        if let Some(blob) = EncryptedEntry::from_bytes(&value)?.chunked() {
+            self.chunk_store().delete(blob)?;
+        }
+        self.drop_history(&cf_name, name)?;
+        self.unregister_entry(&cf_name, name)
     }
 
-    pub fn add_user(&self, name: &str) {
-        let filename = format!("{}.pem", name);
-        let (_priv_key, pub_key) =
-            i_keys::generate_key_pair(name, &filename).expect("Failed to generate keypair");
+    /// Adds a user to the keyring and gives them access to every entry of the given namespaces.
+    /// With a public key file only that key is stored, otherwise a key pair is generated
+    /// and the private key is written to <name>.pem in the current directory.
+    pub fn add_user(
+        &self,
+        name: &str,
+        pubkey_file: Option<&str>,
+        namespaces: &[String],
+    ) -> Result<AccessReport, ClenvError> {
+        let mut report = AccessReport::default();
+        let pub_key = match pubkey_file {
+            // An age user's "age1..." recipient can be given as is
+            Some(file) => match age_file::parse_recipient(file) {
+                Some(agreement) => PublicKey::X25519 {
+                    agreement,
+                    signing: None,
+                },
+                None => {
+                    let path = resolve_path(file, "pem").to_string_lossy().into_owned();
+                    i_keys::read_public_key(&path)?
+                }
+            },
+            None => {
+                let filename = format!("{}.pem", name);
+                let (_priv_key, pub_key) = i_keys::generate_key_pair(
+                    &filename,
+                    &self.passphrase,
+                    Suite::configured(&self.conf)?,
+                )?;
+                report.key_file = Some(filename);
+                pub_key
+            }
+        };
 
-        let cf_keyring = self.db.cf_handle("keyring").expect("Missing keyring");
+        let cf_keyring = self.cf("keyring")?;
         self.db
-            .put_cf(
-                cf_keyring,
-                name,
-                pub_key.to_public_key_pem(Default::default()).unwrap(),
-            )
-            .expect("Failed to store new user's public key");
+            .put(cf_keyring, name.as_bytes(), pub_key.to_pem()?.as_bytes())?;
+        self.grant_index_key(name, &pub_key)?;
+
+        report.namespaces = self.grant_access(&[(name.to_string(), pub_key)], namespaces)?;
+        Ok(report)
+    }
 
-        let my_name = self.conf.get("name").expect(CONF_ERROR);
-        let priv_path = self.conf.get("private_key").expect(CONF_ERROR);
-        let priv_pem = fs::read_to_string(priv_path).expect("Failed to read private key");
-        let my_priv_key = RsaPrivateKey::from_pkcs1_pem(&priv_pem).expect("Invalid private key");
+    // Wraps the data key of every entry we can read in the given namespaces for `users`
+    pub(super) fn grant_access(
+        &self,
+        users: &[(String, PublicKey)],
+        namespaces: &[String],
+    ) -> Result<Vec<NamespaceReport>, ClenvError> {
+        let my_name = self.conf.require("name")?;
 
-        let cf_name = self.conf.get("ns").expect("Missing namespace");
-        let cf = self.db.cf_handle(&cf_name).expect("Missing column family");
+        let mut reports = Vec::new();
+        for cf_name in namespaces {
+            let mut ns_report = NamespaceReport::new(cf_name);
+            if !self.has_namespace(cf_name)? {
+                reports.push(ns_report);
+                continue;
+            }
+            ns_report.exists = true;
 
-        let iter = self.db.iterator_cf(cf, rocksdb::IteratorMode::Start);
+            // Archived revisions get the new key as well, so rollbacks stay readable for everyone
+            ns_report.updated = self.update_entries(cf_name, |_name, label, entry| {
+                let Some(encrypted_key) = entry.encrypted_keys.get(&my_name) else {
+                    ns_report.skipped.push(label.to_string());
+                    return Ok(false);
+                };
+                let aes_key = self.unwrap_own(encrypted_key)?;
+                let wrapped = i_keys::wrap_key(&aes_key, users)?;
 
-        for item in iter {
-            if let Ok((key, value)) = item {
-                let key_str = String::from_utf8_lossy(&key);
-
-                let mut entry: EncryptedEntry =
-                    bincode::serde::decode_from_slice(&value, bincode::config::standard())
-                        .expect("Deserialization failed")
-                        .0;
-
-                let encrypted_key = entry.encrypted_keys.get(&my_name).expect("No key for self");
-                let aes_key = my_priv_key
-                    .decrypt(Oaep::new::<sha2::Sha256>(), encrypted_key)
-                    .expect("Failed to decrypt AES key");
-
-                let encrypted_for_new_user = pub_key
-                    .encrypt(
-                        &mut rand::rngs::OsRng,
-                        Oaep::new::<sha2::Sha256>(),
+                entry.encrypted_keys.extend(wrapped);
+                Ok(true)
+            })?;
+            reports.push(ns_report);
+        }
+        Ok(reports)
+    }
+
+    /// Removes a user from the keyring and drops their wrapped keys in the given namespaces.
+    /// With `rekey` every entry they could read is moved to a fresh data key as well, so a data key
+    /// they copied earlier is useless for anything stored from now on.
+    pub fn remove_user(
+        &mut self,
+        name: &str,
+        rekey: bool,
+        namespaces: &[String],
+    ) -> Result<AccessReport, ClenvError> {
+        let cf_keyring = self.cf("keyring")?;
+        if self.db.get(cf_keyring, name.as_bytes())?.is_none() {
+            return Err(ClenvError::NotFound(format!(
+                "{} is not in the keyring. Check 'clenv show keyring'",
+                name
+            )));
+        }
+        // They stay in the keyring until their access is gone everywhere, so a removal that fails
+        // half way can simply be run again
+        let my_name = self.conf.require("name")?;
+        let recipients: Vec<(String, PublicKey)> = self
+            .get_recipients()?
+            .into_iter()
+            .filter(|(user, _)| user != name)
+            .collect();
+
+        let mut report = AccessReport::default();
+        for cf_name in namespaces {
+            let mut ns_report = NamespaceReport::new(cf_name);
+            if !self.has_namespace(cf_name)? {
+                report.namespaces.push(ns_report);
+                continue;
+            }
+            ns_report.exists = true;
+
+            ns_report.updated = self.update_entries(cf_name, |entry_name, label, entry| {
+                if entry.encrypted_keys.remove(name).is_none() {
+                    return Ok(false);
+                }
+                if !rekey {
+                    return Ok(true);
+                }
+
+                let old_key = match entry.encrypted_keys.get(&my_name) {
+                    Some(key) => match self.unwrap_own(key) {
+                        Ok(key) => Some(key),
+                        Err(ClenvError::Crypto(_)) => None,
+                        Err(e) => return Err(e),
+                    },
+                    None => None,
+                };
+                match old_key {
+                    Some(old_key) => {
+                        let rotated =
+                            self.resign_with(cf_name, entry_name, entry, false, |entry| {
+                                entry.rekey(
+                                    &old_key,
+                                    cf_name,
+                                    entry_name,
+                                    &recipients,
+                                    &self.chunk_store(),
+                                )
+                            })?;
+                        match rotated {
+                            true => ns_report.rotated.push(label.to_string()),
+                            false => ns_report.invalid.push(label.to_string()),
+                        }
+                    }
+                    // Without our own key the entry can't be re-encrypted, only the wrapped key is dropped
+                    None => ns_report.skipped.push(label.to_string()),
+                }
+                Ok(true)
+            })?;
+            report.namespaces.push(ns_report);
+        }
+
+        // Their signatures can't be checked once they are out of the keyring, so what they wrote last
+        // is signed in our name while it still can be, wherever it is. That vouches for it, so only
+        // entries we can open and whose signature checks out are signed
+        if name != my_name {
+            for ns in self.namespaces()? {
+                self.update_entries(&ns, |entry_name, label, entry| {
+                    if entry.signature.as_ref().is_none_or(|s| s.signer != name) {
+                        return Ok(false);
+                    }
+                    let location = format!("{}/{}", ns, label);
+                    let opens = match self.own_data_key(&location, entry) {
+                        Ok(_) => true,
+                        Err(ClenvError::AccessDenied(_) | ClenvError::Crypto(_)) => false,
+                        Err(e) => return Err(e),
+                    };
+                    let resigned =
+                        opens && self.resign_with(&ns, entry_name, entry, false, |_| Ok(()))?;
+                    match resigned {
+                        true => report.resigned.push(location),
+                        false => report.left_signed.push(location),
+                    }
+                    Ok(resigned)
+                })?;
+            }
+        }
+
+        // They still know the index key, so every name moves to a new identifier as well
+        if rekey && self.is_obfuscated() {
+            self.rotate_index_key(&recipients)?;
+            report.index_rotated = true;
+        }
+        self.revoke_index_key(name)?;
+        self.db.delete(self.cf("keyring")?, name.as_bytes())?;
+        Ok(report)
+    }
+
+    /// Every namespace in the database, leaving out the keyring, history and the other records clenv keeps
+    /// for itself, which aren't namespaces of entries
+    pub fn namespaces(&self) -> Result<Vec<String>, ClenvError> {
+        if let Some(key) = self.index_key()? {
+            return self.indexed_namespaces(&key);
+        }
+        Ok(self
+            .db
+            .namespaces()?
+            .into_iter()
+            .filter(|cf| !INTERNAL_NAMESPACES.contains(&cf.as_str()))
+            .collect())
+    }
+
+    /// Refuses the names of the column families clenv keeps its own records in as namespace names
+    pub fn check_namespace_name(ns: &str) -> Result<(), ClenvError> {
+        if INTERNAL_NAMESPACES.contains(&ns) {
+            return Err(ClenvError::Invalid(format!(
+                "'{}' is used by clenv itself and can't be a namespace, pick another name",
+                ns
+            )));
+        }
+        Ok(())
+    }
+
+    /// Picks the namespaces a user command works on: all of them, a comma separated list, or the configured one
+    pub fn resolve_namespaces(
+        &self,
+        all: bool,
+        list: Option<&str>,
+    ) -> Result<Vec<String>, ClenvError> {
+        if all {
+            return self.namespaces();
+        }
+        match list {
+            Some(list) => Ok(list
+                .split(',')
+                .map(str::trim)
+                .filter(|ns| !ns.is_empty())
+                .map(String::from)
+                .collect()),
+            None => Ok(vec![self.conf.require("ns")?]),
+        }
+    }
+
+    /// Brings entries and revisions stored by older versions up to date: binds the ones still using format 0
+    /// to their namespace, name and extension (see `EncryptedEntry::associated_data`), moves them to the
+    /// configured suite and signs the unsigned ones in our name. Entries we can't read are skipped.
+    /// Once no entry of a format from before signing is left, unsigned entries are refused from then on.
+    pub fn reseal(&self, namespaces: &[String]) -> Result<Vec<NamespaceReport>, ClenvError> {
+        let my_name = self.conf.require("name")?;
+        let suite = Suite::configured(&self.conf)?;
+
+        let mut reports = Vec::new();
+        for cf_name in namespaces {
+            let mut ns_report = NamespaceReport::new(cf_name);
+            if !self.has_namespace(cf_name)? {
+                reports.push(ns_report);
+                continue;
+            }
+            ns_report.exists = true;
+
+            ns_report.updated = self.update_entries(cf_name, |name, label, entry| {
+                if entry.format >= ENTRY_FORMAT
+                    && entry.suite == suite
+                    && self.signature_status(cf_name, name, entry)?.is_valid()
+                {
+                    return Ok(false);
+                }
+                let Some(encrypted_key) = entry.encrypted_keys.get(&my_name) else {
+                    ns_report.skipped.push(label.to_string());
+                    return Ok(false);
+                };
+                let aes_key = self.unwrap_own(encrypted_key)?;
+                // Resealing opens every value, so only contents that belong to this entry get signed
+                let resealed = self.resign_with(cf_name, name, entry, true, |entry| {
+                    entry.reseal(
                         &aes_key,
+                        &aes_key,
+                        cf_name,
+                        name,
+                        suite,
+                        &self.chunk_store(),
                     )
-                    .expect("Failed to encrypt AES key for new user");
+                })?;
+                if !resealed {
+                    ns_report.invalid.push(label.to_string());
+                }
+                Ok(resealed)
+            })?;
+            reports.push(ns_report);
+        }
+        self.record_if_all_signed()?;
+        Ok(reports)
+    }
 
-                entry
-                    .encrypted_keys
-                    .insert(name.to_string(), encrypted_for_new_user);
+    // Visits every entry of a namespace along with its archived revisions and dictionaries and writes back
+    // the ones `update` changed. `update` gets the entry's name, a label for it ("name", "name (rev 3)" or
+    // "dictionary 1") and the entry with its metadata opened. Returns the names of the current entries that were touched.
+    // Chunks an update replaced are deleted once the entry no longer points to them.
+    pub(super) fn update_entries(
+        &self,
+        cf_name: &str,
+        mut update: impl FnMut(&str, &str, &mut EncryptedEntry) -> Result<bool, ClenvError>,
+    ) -> Result<Vec<String>, ClenvError> {
+        let cf = &self.ns_cf(cf_name)?;
+        let names = self.entry_names(cf_name)?;
+        let mut updated = Vec::new();
 
-                let serialized = bincode::serde::encode_to_vec(&entry, bincode::config::standard())
-                    .expect("Serialization failed");
+        let iter = self.db.iter(cf)?;
+        for item in iter {
+            let (key, value) = item?;
+            let mut entry = EncryptedEntry::from_bytes(&value)?;
+            self.open_meta(&mut entry)?;
+            let name = Self::name_of(&names, &String::from_utf8_lossy(&key));
+            let chunks = entry.chunked().cloned();
+            if update(&name, &name, &mut entry)? {
+                self.seal_meta(&name, &mut entry)?;
+                self.db.put(cf, &key, &entry.to_bytes()?)?;
+                self.drop_replaced_chunks(chunks, &entry)?;
+                updated.push(name);
+            }
+        }
 
+        for (key, mut entry) in self.archived_revisions(cf_name, None)? {
+            self.open_meta(&mut entry)?;
+            let name = Self::name_of(&names, &history_entry_name(&key));
+            let label = format!("{} (rev {})", name, entry.revision);
+            let chunks = entry.chunked().cloned();
+            if update(&name, &label, &mut entry)? {
+                self.seal_meta(&name, &mut entry)?;
                 self.db
-                    .put_cf(&cf, &key, &serialized)
-                    .expect("DB write failed");
+                    .put(self.cf(HISTORY_CF)?, &key, &entry.to_bytes()?)?;
+                self.drop_replaced_chunks(chunks, &entry)?;
+            }
+        }
 
-                println!("Added access for {} to entry '{}'", name, key_str);
+        // Dictionaries have to stay readable for everyone who can read the entries compressed with them
+        for (key, id, mut entry) in self.dictionaries(cf_name)? {
+            let label = format!("dictionary {}", id);
+            if update(&dictionary_name(id), &label, &mut entry)? {
+                self.db
+                    .put(self.cf(DICTIONARIES_CF)?, &key, &entry.to_bytes()?)?;
             }
         }
+        Ok(updated)
     }
 
-    pub fn remove_user(&self, name: &str) {
-        let cf_keyring = self.db.cf_handle("keyring").expect("Missing keyring CF");
-        self.db
-            .delete_cf(&cf_keyring, name)
-            .expect("Failed to delete from keyring");
+    fn drop_replaced_chunks(
+        &self,
+        before: Option<ChunkedBlob>,
+        entry: &EncryptedEntry,
+    ) -> Result<(), ClenvError> {
+        match before {
+            Some(before) if entry.chunked() != Some(&before) => self.chunk_store().delete(&before),
+            _ => Ok(()),
+        }
+    }
 
-        let cf_name = self.conf.get("ns").expect(CONF_ERROR);
-        let cf = self.db.cf_handle(&cf_name).expect("Missing column family");
+    // Column families are only created on first write to them
+    pub(super) fn ensure_cf(&mut self, cf_name: &str) -> Result<(), ClenvError> {
+        if !self.db.has_namespace(cf_name) {
+            self.db.create_namespace(cf_name)?;
+        }
+        Ok(())
+    }
+}
 
-        let iter = self.db.iterator_cf(cf, rocksdb::IteratorMode::Start);
+// The data key unwrapped fine, so a ciphertext that won't open was changed, or copied over from
+// another entry, namespace or extension
+pub(super) fn mismatch(err: ClenvError, ns: &str, name: &str) -> ClenvError {
+    match err {
+        ClenvError::Crypto(CryptoError::Aes(_)) => ClenvError::Integrity(format!(
+            "{}/{} does not match what was encrypted. It was moved, renamed or changed outside of clenv",
+            ns, name
+        )),
+        err => err,
+    }
+}
 
-        for item in iter {
-            if let Ok((key, value)) = item {
-                let key_str = String::from_utf8_lossy(&key);
+fn parse_public_key(name: &str, value: &[u8]) -> Result<PublicKey, ClenvError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(PublicKey::from_pem)
+        .ok_or_else(|| {
+            ClenvError::Storage(format!("Public key of {} in the keyring is invalid", name))
+        })
+}
 
-                let mut entry: EncryptedEntry =
-                    bincode::serde::decode_from_slice(&value, bincode::config::standard())
-                        .expect("Deserialization failed")
-                        .0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::signature::SignatureStatus;
+    use crate::sec_db::testing::{SUITES, TempDir, add_member, open_db, open_db_with};
 
-                if entry.encrypted_keys.remove(name).is_some() {
-                    let serialized =
-                        bincode::serde::encode_to_vec(&entry, bincode::config::standard())
-                            .expect("Serialization failed");
+    // What the first version of clenv stored, without a header
+    #[derive(Serialize)]
+    struct FirstEntry {
+        ciphertext: Vec<u8>,
+        nonce: [u8; 12],
+        encrypted_keys: HashMap<String, Vec<u8>>,
+        extension: String,
+    }
 
-                    self.db
-                        .put_cf(&cf, &key, &serialized)
-                        .expect("Failed to update DB entry");
+    fn first_entry() -> Vec<u8> {
+        let entry = FirstEntry {
+            ciphertext: b"sealed".to_vec(),
+            nonce: [7; 12],
+            encrypted_keys: HashMap::from([("alice".to_string(), b"wrapped".to_vec())]),
+            extension: "txt".to_string(),
+        };
+        bincode::serde::encode_to_vec(&entry, bincode::config::standard()).unwrap()
+    }
 
-                    println!("Removed {}'s access from entry '{}'", name, key_str);
-                }
+    #[test]
+    fn first_layout_decodes() {
+        let bytes = first_entry();
+        assert_eq!(EncryptedEntry::layout_of(&bytes), 0);
+        let entry = EncryptedEntry::from_bytes(&bytes).unwrap();
+        assert_eq!(entry.ciphertext, b"sealed");
+        assert_eq!(entry.extension, "txt");
+        assert_eq!(entry.encrypted_keys["alice"], b"wrapped");
+        assert!(matches!(entry.kind, EntryKind::Blob));
+        assert_eq!((entry.format, entry.suite), (0, Suite::Rsa));
+        assert_eq!(entry.compression, Compression::Zstd);
+        assert!(entry.signature.is_none() && entry.meta.is_none());
+
+        // Upgraded by migrate, it reads back the same
+        let upgraded = entry.to_bytes().unwrap();
+        assert_eq!(EncryptedEntry::layout_of(&upgraded), ENTRY_LAYOUT);
+        let again = EncryptedEntry::from_bytes(&upgraded).unwrap();
+        assert_eq!(again.ciphertext, entry.ciphertext);
+        assert_eq!(again.encrypted_keys, entry.encrypted_keys);
+    }
+
+    #[test]
+    fn leftover_bytes_are_refused() {
+        let mut bytes = first_entry();
+        bytes.push(0);
+        assert!(EncryptedEntry::from_bytes(&bytes).is_err());
+
+        let entry = EncryptedEntry::from_bytes(&first_entry()).unwrap();
+        let mut current = entry.to_bytes().unwrap();
+        current.extend_from_slice(b"junk");
+        assert!(EncryptedEntry::from_bytes(&current).is_err());
+    }
+
+    #[test]
+    fn unknown_layouts_are_refused() {
+        let entry = EncryptedEntry::from_bytes(&first_entry()).unwrap();
+        let mut bytes = entry.to_bytes().unwrap();
+        bytes[ENTRY_HEADER.len()..ENTRY_HEADER.len() + 4]
+            .copy_from_slice(&(ENTRY_LAYOUT + 1).to_be_bytes());
+        assert!(EncryptedEntry::from_bytes(&bytes).is_err());
+        assert!(EncryptedEntry::from_bytes(ENTRY_HEADER).is_err());
+    }
+
+    // Opening an entry under another namespace, name or extension than it was stored with fails,
+    // whatever its signature says
+    #[test]
+    fn associated_data_mismatch_is_refused() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let mut db = open_db_with(&tmp, "db", "alice", suite);
+            db.store("a.txt", b"secret", "txt", false).unwrap();
+            db.store(".env", b"KEY=value", "env", true).unwrap();
+
+            for name in ["a.txt", ".env"] {
+                let entry = db.entry("dev", name).unwrap();
+                let key = db.own_data_key(name, &entry).unwrap();
+                let mut out = Vec::new();
+                db.open_entry_to("dev", name, &entry, &key, &mut out)
+                    .unwrap();
+
+                let refused = |ns: &str, name: &str, entry: &EncryptedEntry| {
+                    let result = db.open_entry_to(ns, name, entry, &key, &mut std::io::sink());
+                    matches!(result, Err(ClenvError::Integrity(_)))
+                };
+                assert!(refused("prod", name, &entry));
+                assert!(refused("dev", "other", &entry));
+                let mut renamed = entry.clone();
+                renamed.extension = "sh".to_string();
+                assert!(refused("dev", name, &renamed));
             }
         }
+        assert_ne!(
+            entry_aad(ENTRY_FORMAT, "ab", "c", ""),
+            entry_aad(ENTRY_FORMAT, "a", "bc", "")
+        );
+    }
+
+    #[test]
+    fn variable_names_stay_sealed() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        let file = b"# settings\nexport API_TOKEN=abc\nDB_URL=\"x y\"\n";
+        db.store(".env", file, "env", true).unwrap();
+        db.set_var(".env", "NEW_SECRET_NAME", "1").unwrap();
+        db.set_var(".env", "API_TOKEN", "def").unwrap();
+
+        assert_eq!(db.get_var(".env", "API_TOKEN").unwrap(), "def");
+        assert_eq!(db.get_var(".env", "DB_URL").unwrap(), "x y");
+        assert!(matches!(
+            db.get_var(".env", "settings"),
+            Err(ClenvError::NotFound(_))
+        ));
+        assert_eq!(
+            db.read_entry(".env").unwrap(),
+            b"# settings\nexport API_TOKEN=def\nDB_URL=\"x y\"\nNEW_SECRET_NAME=1\n"
+        );
+
+        let entry = db.entry("dev", ".env").unwrap();
+        let stored = entry.to_bytes().unwrap();
+        for name in [&b"API_TOKEN"[..], b"DB_URL", b"NEW_SECRET_NAME"] {
+            assert!(!stored.windows(name.len()).any(|window| window == name));
+        }
+        assert!(db.verify_entry("dev", ".env").unwrap().is_valid());
+    }
+
+    #[test]
+    fn removed_users_entries_are_signed_anew() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let alice = open_db_with(&tmp, "db", "alice", suite);
+            add_member(&tmp, &alice, "bob", &["dev"]);
+            drop(alice);
+            let mut bob = open_db_with(&tmp, "db", "bob", suite);
+            bob.store("a.txt", b"by bob", "txt", false).unwrap();
+            bob.store("a.txt", b"by bob again", "txt", false).unwrap();
+            drop(bob);
+
+            // Without --rekey the entries keep their data key, only the signature changes hands
+            let mut alice = open_db_with(&tmp, "db", "alice", suite);
+            let report = alice.remove_user("bob", false, &[]).unwrap();
+            assert_eq!(report.resigned, ["dev/a.txt", "dev/a.txt (rev 1)"]);
+            assert!(report.left_signed.is_empty());
+            assert_eq!(alice.read_entry("a.txt").unwrap(), b"by bob again");
+            assert_eq!(
+                alice.verify_entry("dev", "a.txt").unwrap(),
+                SignatureStatus::Valid {
+                    signer: "alice".to_string()
+                }
+            );
+            alice.rollback("a.txt", 1).unwrap();
+            assert_eq!(alice.read_entry("a.txt").unwrap(), b"by bob");
+        }
+    }
+
+    #[test]
+    fn members_are_added_from_their_public_key() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let mut alice = open_db_with(&tmp, "db", "alice", suite);
+            alice.store("a.txt", b"shared", "txt", false).unwrap();
+
+            // What "clenv keygen" leaves on the new member's machine
+            let private = tmp.path().join("bob.pem");
+            let (_, public_key) =
+                i_keys::generate_key_pair(private.to_str().unwrap(), &|_| Ok(String::new()), suite)
+                    .unwrap();
+            let public = tmp.path().join("bob.pub.pem");
+            i_keys::export_public_key(&public_key, public.to_str().unwrap()).unwrap();
+            let key_before = fs::read(&private).unwrap();
+
+            let report = alice
+                .add_user("bob", public.to_str(), &["dev".to_string()])
+                .unwrap();
+            assert_eq!(report.key_file, None);
+            assert_eq!(report.namespaces[0].updated, ["a.txt"]);
+            assert_eq!(fs::read(&private).unwrap(), key_before);
+            let stored = alice.db.get(alice.cf("keyring").unwrap(), b"bob").unwrap();
+            assert_eq!(stored.unwrap(), public_key.to_pem().unwrap().as_bytes());
+            drop(alice);
+
+            let bob = open_db_with(&tmp, "db", "bob", suite);
+            assert_eq!(bob.read_entry("a.txt").unwrap(), b"shared");
+        }
+    }
+
+    #[test]
+    fn other_public_key_formats_are_taken() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let alice = open_db_with(&tmp, "db", "alice", suite);
+            let key = PrivateKey::generate(suite).unwrap();
+            let text = match key.public_key() {
+                PublicKey::Rsa(public) => {
+                    use rsa::pkcs1::EncodeRsaPublicKey;
+                    public.to_pkcs1_pem(rsa::pkcs8::LineEnding::LF).unwrap()
+                }
+                // An age recipient is taken as is, without a file
+                PublicKey::X25519 { agreement, .. } => age_file::recipient(&agreement),
+            };
+            let given = match suite {
+                Suite::Rsa => {
+                    let file = tmp.path().join("carol.pub.pem");
+                    fs::write(&file, &text).unwrap();
+                    file.to_string_lossy().into_owned()
+                }
+                Suite::X25519 => text,
+            };
+            alice
+                .add_user("carol", Some(&given), &["dev".to_string()])
+                .unwrap();
+            assert!(
+                alice
+                    .get_recipients()
+                    .unwrap()
+                    .iter()
+                    .any(|(name, _)| name == "carol")
+            );
+
+            let garbage = tmp.path().join("garbage.pem");
+            fs::write(&garbage, "not a key").unwrap();
+            assert!(matches!(
+                alice.add_user("dave", garbage.to_str(), &[]),
+                Err(ClenvError::Crypto(_))
+            ));
+            assert!(matches!(
+                alice.add_user("dave", Some("missing.pem"), &[]),
+                Err(ClenvError::Io { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn users_are_added_and_removed_in_every_namespace() {
+        let tmp = TempDir::new();
+        let mut alice = open_db(&tmp, "db", "alice");
+        alice.store("a.txt", b"dev", "txt", false).unwrap();
+        alice.conf.insert("ns", "prod");
+        alice.store("b.txt", b"prod", "txt", false).unwrap();
+
+        let mut all = alice.resolve_namespaces(true, None).unwrap();
+        all.sort();
+        assert_eq!(all, ["dev", "prod"]);
+        assert_eq!(alice.resolve_namespaces(false, None).unwrap(), ["prod"]);
+        assert_eq!(
+            alice
+                .resolve_namespaces(false, Some("prod, ,staging"))
+                .unwrap(),
+            ["prod", "staging"]
+        );
+
+        let key = PrivateKey::generate(Suite::X25519).unwrap();
+        let bob = age_file::recipient(match &key.public_key() {
+            PublicKey::X25519 { agreement, .. } => agreement,
+            PublicKey::Rsa(_) => unreachable!(),
+        });
+        // Namespaces that don't exist yet are reported as such
+        all.push("staging".to_string());
+        let report = alice.add_user("bob", Some(&bob), &all).unwrap();
+        let updated: Vec<(&str, bool, &[String])> = report
+            .namespaces
+            .iter()
+            .map(|ns| (ns.namespace.as_str(), ns.exists, ns.updated.as_slice()))
+            .collect();
+        assert_eq!(
+            updated,
+            [
+                ("dev", true, &["a.txt".to_string()][..]),
+                ("prod", true, &["b.txt".to_string()][..]),
+                ("staging", false, &[][..]),
+            ]
+        );
+        for (ns, name) in [("dev", "a.txt"), ("prod", "b.txt")] {
+            assert!(
+                alice
+                    .entry(ns, name)
+                    .unwrap()
+                    .encrypted_keys
+                    .contains_key("bob")
+            );
+        }
+
+        // Only the namespaces given lose their key
+        let report = alice
+            .remove_user("bob", false, &["dev".to_string()])
+            .unwrap();
+        assert_eq!(report.namespaces.len(), 1);
+        assert_eq!(report.namespaces[0].updated, ["a.txt"]);
+        assert!(
+            !alice
+                .entry("dev", "a.txt")
+                .unwrap()
+                .encrypted_keys
+                .contains_key("bob")
+        );
+        assert!(
+            alice
+                .entry("prod", "b.txt")
+                .unwrap()
+                .encrypted_keys
+                .contains_key("bob")
+        );
+    }
+
+    #[test]
+    fn entry_listing_as_json() {
+        let tmp = TempDir::new();
+        let mut alice = open_db(&tmp, "db", "alice");
+        add_member(&tmp, &alice, "bob", &["dev"]);
+        alice.store(".env", b"A=1\n", "env", true).unwrap();
+        alice.store("a.txt", b"one", "txt", false).unwrap();
+        alice.store("a.txt", b"two", "txt", false).unwrap();
+
+        let infos = serde_json::to_value(alice.entry_infos("dev").unwrap()).unwrap();
+        let mut infos = infos.as_array().unwrap().clone();
+        infos.sort_by_key(|info| info["name"].as_str().unwrap().to_string());
+        assert_eq!(infos.len(), 2);
+        for (info, name, extension, kind, revision) in [
+            (&infos[0], ".env", "env", "dotenv", 1),
+            (&infos[1], "a.txt", "txt", "blob", 2),
+        ] {
+            assert_eq!(info["name"], name);
+            assert_eq!(info["extension"], extension);
+            assert_eq!(info["kind"], kind);
+            assert_eq!(info["revision"], revision);
+            assert_eq!(info["author"], "alice");
+            assert_eq!(info["suite"], "x25519");
+            assert!(info["compression"].is_string());
+            assert!(info["stored_at"].as_u64().unwrap() > 0);
+            assert_eq!(info["recipients"], serde_json::json!(["alice", "bob"]));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_run_with_the_variables_of_dotenv_entries() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        db.store(".env", b"A=1\n", "env", true).unwrap();
+        db.store("a.txt", b"A=1\n", "txt", false).unwrap();
+        let sh = |script: &str| ["sh", "-c", script].map(String::from);
+
+        assert!(matches!(
+            db.run_with_entry("a.txt", &sh("true")),
+            Err(ClenvError::Invalid(_))
+        ));
+        let db = open_db(&tmp, "db", "alice");
+        assert_eq!(
+            db.run_with_entry(".env", &sh("test \"$A\" = 1")).unwrap(),
+            0
+        );
+        let db = open_db(&tmp, "db", "alice");
+        assert_eq!(db.run_with_entry(".env", &sh("exit 3")).unwrap(), 3);
+    }
+
+    // Plain databases keep namespaces and clenv's own records side by side
+    #[test]
+    fn internal_names_are_no_namespaces() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        for name in INTERNAL_NAMESPACES {
+            assert!(matches!(
+                SecDb::check_namespace_name(name),
+                Err(ClenvError::Invalid(_))
+            ));
+            db.conf.insert("ns", name);
+            assert!(matches!(
+                db.store("a.txt", b"secret", "txt", false),
+                Err(ClenvError::Invalid(_))
+            ));
+        }
+        assert!(SecDb::check_namespace_name("prod").is_ok());
+        assert_eq!(db.namespaces().unwrap(), ["dev"]);
     }
 }
@@ -0,0 +1,392 @@
+use super::handle_db::{EncryptedEntry, EntryKind, RawRecord, SecDb};
+use super::index::{entry_id, namespace_id};
+use crate::error::ClenvError;
+use serde::Serialize;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Every revision an entry is replaced by gets archived here, keyed by namespace, entry and revision number.
+// In obfuscated databases the namespace and entry parts are their identifiers, see index.rs.
+// Run "clenv history <entry>" to see what is kept for an entry.
+pub const HISTORY_CF: &str = "history";
+
+pub(super) fn history_prefix(ns: &str, name: Option<&str>) -> Vec<u8> {
+    match name {
+        Some(name) => format!("{}\0{}\0", ns, name).into_bytes(),
+        None => format!("{}\0", ns).into_bytes(),
+    }
+}
+
+// Zero padded so revisions sort numerically inside the store
+pub(super) fn history_key(ns: &str, name: &str, revision: u64) -> Vec<u8> {
+    let mut key = history_prefix(ns, Some(name));
+    key.extend_from_slice(format!("{:020}", revision).as_bytes());
+    key
+}
+
+/// One stored version of an entry, as listed by `history`
+#[derive(Debug, Clone, Serialize)]
+pub struct Revision {
+    pub revision: u64,
+    pub author: String,
+    // Unix seconds, 0 for entries stored before revisions were tracked
+    pub stored_at: u64,
+    pub current: bool,
+}
+
+impl Revision {
+    fn new(entry: &EncryptedEntry, current: bool) -> Self {
+        Revision {
+            revision: entry.revision,
+            author: entry.author.clone(),
+            stored_at: entry.stored_at,
+            current,
+        }
+    }
+}
+
+// Entry part of a history key
+pub(super) fn history_entry_name(key: &[u8]) -> String {
+    let key = String::from_utf8_lossy(key);
+    key.split('\0').nth(1).unwrap_or_default().to_string()
+}
+
+// Revision part of a history key
+pub(super) fn history_revision(key: &[u8]) -> u64 {
+    let key = String::from_utf8_lossy(key);
+    key.rsplit('\0')
+        .next()
+        .and_then(|revision| revision.parse().ok())
+        .unwrap_or_default()
+}
+
+impl SecDb {
+    /// Writes the entry as the newest revision, archiving the one it replaces. Returns the new revision number
+    pub(super) fn put_entry(
+        &mut self,
+        cf_name: &str,
+        name: &str,
+        entry: EncryptedEntry,
+    ) -> Result<u64, ClenvError> {
+        self.put_revision(cf_name, name, entry, false)
+    }
+
+    /// Like `put_entry`, but keeps who wrote the entry and when, for entries copied over from another database
+    pub(super) fn put_copy(
+        &mut self,
+        cf_name: &str,
+        name: &str,
+        entry: EncryptedEntry,
+    ) -> Result<u64, ClenvError> {
+        self.put_revision(cf_name, name, entry, true)
+    }
+
+    fn put_revision(
+        &mut self,
+        cf_name: &str,
+        name: &str,
+        mut entry: EncryptedEntry,
+        keep_origin: bool,
+    ) -> Result<u64, ClenvError> {
+        self.ensure_cf(HISTORY_CF)?;
+        let cf = &self.ns_cf(cf_name)?;
+        let cf_history = self.cf(HISTORY_CF)?;
+        let key = self.entry_key(cf_name, name)?;
+
+        let previous = match self.db.get(cf, key.as_bytes())? {
+            Some(value) => Some(EncryptedEntry::from_bytes(&value)?),
+            None => None,
+        };
+
+        // Removed entries have their history dropped, so the newest archived revision only matters when there's no current one
+        let last_revision = match &previous {
+            Some(previous) => previous.revision,
+            None => self
+                .archived_revisions(cf_name, Some(name))?
+                .last()
+                .map_or(0, |(_, entry)| entry.revision),
+        };
+
+        if let Some(previous) = previous {
+            self.db.put(
+                cf_history,
+                &self.history_key_for(cf_name, name, previous.revision)?,
+                &previous.to_bytes()?,
+            )?;
+        }
+
+        entry.revision = last_revision + 1;
+        if !keep_origin {
+            entry.author = self.conf.get("name").unwrap_or_default();
+            entry.stored_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+        }
+
+        // The signature covers the real extension, which a restored revision only has in its sealed metadata
+        self.open_meta(&mut entry)?;
+        self.sign_entry(cf_name, name, &mut entry)?;
+        self.seal_meta(name, &mut entry)?;
+        self.db.put(cf, key.as_bytes(), &entry.to_bytes()?)?;
+        self.register_entry(cf_name, name)?;
+        Ok(entry.revision)
+    }
+
+    /// Lists every stored revision of an entry, oldest first
+    pub fn history(&self, name: &str) -> Result<Vec<Revision>, ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let cf = &self.ns_cf(&cf_name)?;
+        let key = self.entry_key(&cf_name, name)?;
+
+        let current = match self.db.get(cf, key.as_bytes())? {
+            Some(value) => Some(EncryptedEntry::from_bytes(&value)?),
+            None => None,
+        };
+        let archived = self.archived_revisions(&cf_name, Some(name))?;
+
+        if current.is_none() && archived.is_empty() {
+            return Err(ClenvError::NotFound(format!(
+                "No history found for {}",
+                name
+            )));
+        }
+
+        let mut revisions: Vec<Revision> = archived
+            .iter()
+            .map(|(_, entry)| Revision::new(entry, false))
+            .collect();
+        if let Some(entry) = current {
+            revisions.push(Revision::new(&entry, true));
+        }
+        Ok(revisions)
+    }
+
+    /// Restores an old revision by storing it again as the newest one, so the rollback itself can be undone.
+    /// Returns the revision number the restored version was stored as.
+    pub fn rollback(&mut self, name: &str, revision: u64) -> Result<u64, ClenvError> {
+        let cf_name = self.conf.require("ns")?;
+        let missing = || {
+            ClenvError::NotFound(format!(
+                "No revision {} found for {}. Check 'clenv history {}'",
+                revision, name, name
+            ))
+        };
+
+        if !self.db.has_namespace(HISTORY_CF) {
+            return Err(missing());
+        }
+        let value = self
+            .db
+            .get(HISTORY_CF, &self.history_key_for(&cf_name, name, revision)?)?
+            .ok_or_else(missing)?;
+        let mut entry = EncryptedEntry::from_bytes(&value)?;
+        // Storing it again signs it in our name, so it has to be what was signed back then
+        self.open_meta(&mut entry)?;
+        self.check_resign(&cf_name, name, &entry)?;
+        // The archived revision keeps its chunks, the restored one gets its own
+        if let EntryKind::Chunked(blob) = &mut entry.kind {
+            *blob = self.chunk_store().copy(blob)?;
+        }
+
+        self.put_entry(&cf_name, name, entry)
+    }
+
+    // Archived revisions of one entry, or of the whole namespace when no name is given
+    pub(super) fn archived_revisions(
+        &self,
+        cf_name: &str,
+        name: Option<&str>,
+    ) -> Result<Vec<(Vec<u8>, EncryptedEntry)>, ClenvError> {
+        self.archived_values(cf_name, name)?
+            .into_iter()
+            .map(|(key, value)| Ok((key, EncryptedEntry::from_bytes(&value)?)))
+            .collect()
+    }
+
+    // Like `archived_revisions`, with the stored values left as they are
+    pub(super) fn archived_values(
+        &self,
+        cf_name: &str,
+        name: Option<&str>,
+    ) -> Result<Vec<RawRecord>, ClenvError> {
+        if !self.db.has_namespace(HISTORY_CF) {
+            return Ok(Vec::new());
+        }
+        let index_key = self.index_key()?;
+        let ns_id = namespace_id(index_key.as_deref(), cf_name);
+        let name_id = name.map(|name| entry_id(index_key.as_deref(), cf_name, name));
+        let prefix = history_prefix(&ns_id, name_id.as_deref());
+
+        let mut revisions = Vec::new();
+        for item in self.db.scan(HISTORY_CF, &prefix)? {
+            revisions.push(item?);
+        }
+        Ok(revisions)
+    }
+
+    fn history_key_for(
+        &self,
+        cf_name: &str,
+        name: &str,
+        revision: u64,
+    ) -> Result<Vec<u8>, ClenvError> {
+        let index_key = self.index_key()?;
+        Ok(history_key(
+            &namespace_id(index_key.as_deref(), cf_name),
+            &entry_id(index_key.as_deref(), cf_name, name),
+            revision,
+        ))
+    }
+
+    pub(super) fn drop_history(&self, cf_name: &str, name: &str) -> Result<(), ClenvError> {
+        if !self.db.has_namespace(HISTORY_CF) {
+            return Ok(());
+        }
+        for (key, entry) in self.archived_revisions(cf_name, Some(name))? {
+            self.db.delete(HISTORY_CF, &key)?;
+            if let Some(blob) = entry.chunked() {
+                self.chunk_store().delete(blob)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let author = match self.author.as_str() {
+            "" => "unknown",
+            author => author,
+        };
+        write!(
+            f,
+            "rev {:<4} {}  by {}",
+            self.revision,
+            format_timestamp(self.stored_at),
+            author
+        )
+    }
+}
+
+// Unix seconds to "YYYY-MM-DD HH:MM:SS UTC" without pulling in a date crate
+fn format_timestamp(secs: u64) -> String {
+    if secs == 0 {
+        return String::from("unknown date");
+    }
+    let (year, month, day, rem) = civil_time(secs);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+// Unix seconds to year, month, day and the seconds into that day, all UTC
+pub(super) fn civil_time(secs: u64) -> (i64, i64, i64, u64) {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, rem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::{TempDir, open_db};
+
+    #[test]
+    fn revisions_are_listed_in_order() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        // Past nine, so "10" would sort before "2" without the padding
+        for revision in 1..=12u64 {
+            let stored = db
+                .store("a.txt", format!("v{}", revision).as_bytes(), "txt", false)
+                .unwrap();
+            assert_eq!(stored, revision);
+        }
+
+        let history = db.history("a.txt").unwrap();
+        let revisions: Vec<u64> = history.iter().map(|rev| rev.revision).collect();
+        assert_eq!(revisions, (1..=12).collect::<Vec<u64>>());
+        let current: Vec<bool> = history.iter().map(|rev| rev.current).collect();
+        assert_eq!(current.iter().filter(|current| **current).count(), 1);
+        assert!(history.last().unwrap().current);
+        assert!(history.iter().all(|rev| rev.author == "alice"));
+
+        let keys: Vec<Vec<u8>> = db
+            .archived_values("dev", Some("a.txt"))
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let archived: Vec<u64> = keys.iter().map(|key| history_revision(key)).collect();
+        assert_eq!(archived, (1..=11).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn rollback_stores_the_old_revision_again() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        for revision in 1..=10 {
+            db.store("a.txt", format!("v{}", revision).as_bytes(), "txt", false)
+                .unwrap();
+        }
+
+        assert_eq!(db.rollback("a.txt", 2).unwrap(), 11);
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"v2");
+        let history = db.history("a.txt").unwrap();
+        assert_eq!(history.len(), 11);
+        assert_eq!(history.last().unwrap().revision, 11);
+
+        // The rollback is a revision of its own
+        assert_eq!(db.rollback("a.txt", 10).unwrap(), 12);
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"v10");
+
+        assert!(matches!(
+            db.rollback("a.txt", 40),
+            Err(ClenvError::NotFound(_))
+        ));
+        assert!(matches!(db.history("b.txt"), Err(ClenvError::NotFound(_))));
+    }
+
+    #[test]
+    fn keys_sort_by_revision() {
+        let nine = history_key("dev", "a.txt", 9);
+        let ten = history_key("dev", "a.txt", 10);
+        assert!(nine < ten);
+        assert!(history_key("dev", "a.txt", 99) < history_key("dev", "a.txt", 100));
+        assert!(ten.starts_with(&history_prefix("dev", Some("a.txt"))));
+        assert!(!ten.starts_with(&history_prefix("dev", Some("a"))));
+        assert_eq!(history_entry_name(&ten), "a.txt");
+        assert_eq!(history_revision(&ten), 10);
+        assert_eq!(
+            history_revision(&history_key("dev", "a.txt", u64::MAX)),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "unknown date");
+        assert_eq!(format_timestamp(1), "1970-01-01 00:00:01 UTC");
+        // Leap day
+        assert_eq!(format_timestamp(951_825_845), "2000-02-29 12:04:05 UTC");
+        assert_eq!(civil_time(1_704_067_199), (2023, 12, 31, 86_399));
+        assert_eq!(civil_time(1_704_067_200), (2024, 1, 1, 0));
+    }
+}
@@ -1,117 +1,399 @@
-use aes_gcm::aead::{Aead, KeyInit};
+use super::age_file;
+use super::compression::Codec;
+use super::suite::{PrivateKey, PublicKey, Suite};
+use crate::error::ClenvError;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
-use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::der::pem::{LineEnding, PemLabel};
+use rsa::pkcs8::{EncryptedPrivateKeyInfo, PrivateKeyInfo, SecretDocument};
+use rsa::pss::{BlindedSigningKey, Signature, VerifyingKey};
 use rsa::rand_core::RngCore;
+use rsa::signature::{RandomizedSigner, SignatureEncoding, Verifier};
 use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 use thiserror::Error;
 use zstd::decode_all;
 use zstd::stream::encode_all;
 
 pub struct i_keys;
 
+// Data keys come out of wrapped keys any keyring member can write, so a key of the wrong size is an error, not a panic
+fn aes_cipher(aes_key: &[u8]) -> Result<Aes256Gcm, CryptoError> {
+    if aes_key.len() != 32 {
+        return Err(CryptoError::Aes(aes_gcm::Error));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(aes_key)))
+}
+
+// Writes `contents` to a new file next to `filename` that only we can read, flushes it to disk and renames it
+// over `filename`, so a crash leaves the old key or the new one but never half of one
+fn write_private(filename: &str, contents: &[u8]) -> Result<(), ClenvError> {
+    let path = Path::new(filename);
+    let mut id = [0u8; 8];
+    OsRng.fill_bytes(&mut id);
+    let suffix: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", suffix));
+    let temp = Path::new(&temp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let written = options.open(temp).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(temp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(temp);
+            Err(ClenvError::io(format!("Could not write {}", filename), e))
+        }
+    }
+}
+
+// Passphrase of an encrypted private key for scripts and CI, where nobody can be asked
+pub const PASSPHRASE_ENV: &str = "CLENV_PASSPHRASE";
+
+/// What a passphrase is needed for, with the path of the key file so a prompt can name it
+#[derive(Debug, Clone, Copy)]
+pub enum PassphraseRequest<'a> {
+    // Decrypting an existing key
+    Unlock(&'a str),
+    // Protecting a newly written key. An empty passphrase writes the key unencrypted
+    New(&'a str),
+}
+
+/// Where passphrases come from. The CLI asks on the terminal, `i_keys::env_passphrase` only reads the environment
+pub type Passphrase = dyn Fn(PassphraseRequest) -> Result<String, ClenvError>;
+
 // Encruption structure in order to handle any errors while encrypting
 #[derive(Debug, Error)]
 pub enum CryptoError {
-    #[error("AES encryption error: {0:?}")]
+    #[error("Could not encrypt or decrypt the entry. It may have been tampered with")]
     Aes(aes_gcm::Error),
 
-    #[error("RSA error")]
+    #[error("RSA error: {0}. Check that your private key matches the one in the keyring")]
     Rsa(#[from] rsa::errors::Error),
+
+    #[error("Compression error: {0}")]
+    Compression(#[from] std::io::Error),
+
+    #[error("Invalid key file {0}: {1}")]
+    Key(String, String),
+
+    #[error("Could not sign the entry: {0}")]
+    Signature(String),
+
+    #[error("age error: {0}")]
+    Age(String),
+
+    // Unwrapping or signing failed in the agent, which holds the key
+    #[error("{0}")]
+    Agent(String),
 }
 
 // interface for key handling and management
 impl i_keys {
-    // Generates the public key pairs
+    // Generates the public key pairs, or loads the existing pair when the private key file is already there.
+    // New keys are of the kind `suite` uses, asked a passphrase for and written as PKCS#8,
+    // encrypted unless the passphrase is empty.
     pub fn generate_key_pair(
-        name: &str,
         filename: &str,
-    ) -> Result<(RsaPrivateKey, RsaPublicKey), Box<dyn std::error::Error>> {
+        passphrase: &Passphrase,
+        suite: Suite,
+    ) -> Result<(PrivateKey, PublicKey), ClenvError> {
         let priv_key_file = filename;
 
         if !std::path::Path::new(&priv_key_file).exists() {
-            println!("Generating RSA key pair for {}...", name);
-
-            let mut rng = OsRng;
-            let bits = 2048;
+            let private_key = PrivateKey::generate(suite)?;
+            let public_key = private_key.public_key();
 
-            // Generate RSA private keys
-            let private_key = RsaPrivateKey::new(&mut rng, bits)?;
-            let public_key = RsaPublicKey::from(&private_key);
-
-            // Save key in PEM format
-            let private_pem = private_key.to_pkcs1_pem(rsa::pkcs1::LineEnding::LF)?;
-            fs::write(&priv_key_file, private_pem.as_bytes())?;
+            let new_passphrase = passphrase(PassphraseRequest::New(filename))?;
+            Self::write_private_key(&private_key, filename, &new_passphrase)?;
 
             Ok((private_key, public_key))
         } else {
-            println!("Loading existing keys for {}...", name);
-            let private_pem = fs::read_to_string(&priv_key_file)?;
-            let private_key = RsaPrivateKey::from_pkcs1_pem(&private_pem)?;
-            let public_key = RsaPublicKey::from(&private_key);
+            let private_key = Self::read_private_key(filename, passphrase)?;
+            let public_key = private_key.public_key();
             Ok((private_key, public_key))
         }
     }
 
+    // Reads encrypted PKCS#8 ("BEGIN ENCRYPTED PRIVATE KEY"), plain PKCS#8 and the PKCS#1 keys older versions wrote.
+    // PKCS#8 files hold either kind of key. The passphrase is only asked for when the key is encrypted.
+    pub fn read_private_key(
+        filename: &str,
+        passphrase: &Passphrase,
+    ) -> Result<PrivateKey, ClenvError> {
+        let private_pem = fs::read_to_string(filename)
+            .map_err(|e| ClenvError::io(format!("Could not read private key {}", filename), e))?;
+        let key_error = |e: String| CryptoError::Key(filename.to_string(), e);
+
+        if private_pem.contains("BEGIN ENCRYPTED PRIVATE KEY") {
+            let secret = passphrase(PassphraseRequest::Unlock(filename))?;
+            let (_, document) =
+                SecretDocument::from_pem(&private_pem).map_err(|e| key_error(e.to_string()))?;
+            let der = EncryptedPrivateKeyInfo::try_from(document.as_bytes())
+                .map_err(|e| key_error(e.to_string()))?
+                .decrypt(secret)
+                .map_err(|_| {
+                    ClenvError::AccessDenied(format!("Wrong passphrase for {}", filename))
+                })?;
+            return Ok(PrivateKey::from_pkcs8(der.as_bytes(), filename)?);
+        }
+        let private_key = if private_pem.contains("BEGIN PRIVATE KEY") {
+            let (_, document) =
+                SecretDocument::from_pem(&private_pem).map_err(|e| key_error(e.to_string()))?;
+            PrivateKey::from_pkcs8(document.as_bytes(), filename)?
+        } else if let Some(secret) = age_file::parse_identity(&private_pem) {
+            PrivateKey::X25519(secret)
+        } else {
+            RsaPrivateKey::from_pkcs1_pem(&private_pem)
+                .map_err(|e| key_error(e.to_string()))?
+                .into()
+        };
+        Ok(private_key)
+    }
+
+    // Whether reading the key file will ask for a passphrase
+    pub fn is_encrypted(filename: &str) -> Result<bool, ClenvError> {
+        let private_pem = fs::read_to_string(filename)
+            .map_err(|e| ClenvError::io(format!("Could not read private key {}", filename), e))?;
+        Ok(private_pem.contains("BEGIN ENCRYPTED PRIVATE KEY"))
+    }
+
+    // Writes a private key as PKCS#8, encrypted with the passphrase unless it is empty
+    pub fn write_private_key(
+        private_key: &PrivateKey,
+        filename: &str,
+        passphrase: &str,
+    ) -> Result<(), ClenvError> {
+        let key_error = |e: String| CryptoError::Key(filename.to_string(), e);
+        let document = private_key.to_pkcs8(filename)?;
+        let pem = match passphrase {
+            "" => document
+                .to_pem(PrivateKeyInfo::PEM_LABEL, LineEnding::LF)
+                .map_err(|e| key_error(e.to_string()))?,
+            secret => PrivateKeyInfo::try_from(document.as_bytes())
+                .map_err(|e| key_error(e.to_string()))?
+                .encrypt(OsRng, secret)
+                .map_err(|e| key_error(e.to_string()))?
+                .to_pem(EncryptedPrivateKeyInfo::PEM_LABEL, LineEnding::LF)
+                .map_err(|e| key_error(e.to_string()))?,
+        };
+        write_private(filename, pem.as_bytes())
+    }
+
+    // Re-encrypts a key file under a new passphrase. Returns whether the key is protected by one now
+    pub fn change_passphrase(filename: &str, passphrase: &Passphrase) -> Result<bool, ClenvError> {
+        let private_key = Self::read_private_key(filename, passphrase)?;
+        let new_passphrase = passphrase(PassphraseRequest::New(filename))?;
+        Self::write_private_key(&private_key, filename, &new_passphrase)?;
+        Ok(!new_passphrase.is_empty())
+    }
+
+    // Passphrase from CLENV_PASSPHRASE. Without it encrypted keys can't be opened and new keys aren't encrypted
+    pub fn env_passphrase(request: PassphraseRequest) -> Result<String, ClenvError> {
+        match (std::env::var(PASSPHRASE_ENV), request) {
+            (Ok(secret), _) => Ok(secret),
+            (Err(_), PassphraseRequest::New(_)) => Ok(String::new()),
+            (Err(_), PassphraseRequest::Unlock(filename)) => {
+                Err(ClenvError::AccessDenied(format!(
+                    "{} is protected by a passphrase. Set {} to use it",
+                    filename, PASSPHRASE_ENV
+                )))
+            }
+        }
+    }
+
+    // Reads a public key handed to us by someone else, SPKI ("BEGIN PUBLIC KEY") or PKCS#1 ("BEGIN RSA PUBLIC KEY").
+    // X25519 keys come as two SPKI blocks, see `PublicKey::to_pem`
+    pub fn read_public_key(filename: &str) -> Result<PublicKey, ClenvError> {
+        let pem = fs::read_to_string(filename)
+            .map_err(|e| ClenvError::io(format!("Could not read public key {}", filename), e))?;
+        PublicKey::from_pem(&pem).ok_or_else(|| {
+            CryptoError::Key(
+                filename.to_string(),
+                "not a public key clenv can use".to_string(),
+            )
+            .into()
+        })
+    }
+
+    // Writes the public half of a key pair so it can be shared with whoever runs "clenv add"
+    pub fn export_public_key(public_key: &PublicKey, filename: &str) -> Result<(), ClenvError> {
+        let pem = public_key.to_pem()?;
+        fs::write(filename, pem.as_bytes())
+            .map_err(|e| ClenvError::io(format!("Could not write {}", filename), e))?;
+        Ok(())
+    }
+
     // Standard encryption implementation
-    // First, compress the binary
-    // Then encrypt the compressed file.
+    // First, compress the binary with `codec`, which falls back to no compression when that doesn't help
+    // Then encrypt the compressed file with the suite's cipher, authenticating `aad` along with it.
     pub fn encrypt(
         message: &[u8],
-        recipients: &[(String, RsaPublicKey)],
+        recipients: &[(String, PublicKey)],
         extension: String,
+        aad: &[u8],
+        suite: Suite,
+        codec: &mut Codec,
     ) -> Result<(Vec<u8>, [u8; 12], HashMap<String, Vec<u8>>, String), CryptoError> {
-        let mut rng = OsRng;
-        let aes_key = Aes256Gcm::generate_key(&mut rng);
-        let cipher = Aes256Gcm::new(&aes_key);
+        let aes_key = Self::generate_data_key();
 
-        let mut nonce = [0u8; 12];
-        rng.fill_bytes(&mut nonce);
+        let comp = codec.compress_all(&[message])?.remove(0);
+        let (ciphertext, nonce) = suite.seal(&aes_key, &comp, aad)?;
 
-        let comp = Self::compress_binary(message).unwrap();
-        let conv: &[u8] = &comp[..];
+        let encrypted_keys = Self::wrap_key(&aes_key, recipients)?;
+        Ok((ciphertext, nonce, encrypted_keys, extension))
+    }
 
-        let ciphertext = cipher
-            .encrypt(Nonce::from_slice(&nonce), conv)
-            .map_err(CryptoError::Aes)?;
+    // Standard decryption implementation
+    pub fn decrypt(
+        encrypted_key: &[u8],
+        ciphertext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        private_key: &PrivateKey,
+        suite: Suite,
+        codec: &Codec,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let aes_key = Self::unwrap_key(encrypted_key, private_key)?;
+        let decrypted = suite.open(&aes_key, ciphertext, nonce, aad)?;
+        codec.decompress(&decrypted)
+    }
 
+    // Fresh 256 bit data key for a single entry, for AES-256-GCM and XChaCha20-Poly1305 alike
+    pub fn generate_data_key() -> Vec<u8> {
+        Aes256Gcm::generate_key(&mut OsRng).to_vec()
+    }
+
+    // Encrypts the data key for every recipient with their public key, whichever kind it is
+    pub fn wrap_key(
+        aes_key: &[u8],
+        recipients: &[(String, PublicKey)],
+    ) -> Result<HashMap<String, Vec<u8>>, CryptoError> {
         let mut encrypted_keys = HashMap::new();
 
         for (name, pubkey) in recipients {
-            let encrypted_key =
-                pubkey.encrypt(&mut rng, Oaep::new::<Sha256>(), aes_key.as_slice())?;
-            encrypted_keys.insert(name.clone(), encrypted_key);
+            encrypted_keys.insert(name.clone(), pubkey.wrap_key(aes_key)?);
         }
-        Ok((ciphertext, nonce, encrypted_keys, extension))
+        Ok(encrypted_keys)
     }
 
-    // Standard decryption implementation
-    pub fn decrypt(
+    // Recovers the data key from our own wrapped copy
+    pub fn unwrap_key(
         encrypted_key: &[u8],
+        private_key: &PrivateKey,
+    ) -> Result<Vec<u8>, CryptoError> {
+        private_key.unwrap_key(encrypted_key)
+    }
+
+    // AES-GCM encrypts a single value under the data key with a fresh nonce.
+    // `aad` isn't stored, `open` has to be given the same bytes or it fails.
+    pub fn seal(
+        aes_key: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<(Vec<u8>, [u8; 12]), CryptoError> {
+        let cipher = aes_cipher(aes_key)?;
+
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(CryptoError::Aes)?;
+        Ok((ciphertext, nonce))
+    }
+
+    pub fn open(
+        aes_key: &[u8],
         ciphertext: &[u8],
         nonce: &[u8],
-        private_key: &RsaPrivateKey,
+        aad: &[u8],
     ) -> Result<Vec<u8>, CryptoError> {
-        let aes_key_bytes = private_key.decrypt(Oaep::new::<Sha256>(), encrypted_key)?;
-        let aes_key = Key::<Aes256Gcm>::from_slice(&aes_key_bytes);
-        let cipher = Aes256Gcm::new(aes_key);
-        let decrypted = cipher
-            .decrypt(Nonce::from_slice(nonce), ciphertext)
+        let cipher = aes_cipher(aes_key)?;
+        if nonce.len() != 12 {
+            return Err(CryptoError::Aes(aes_gcm::Error));
+        }
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
             .map_err(CryptoError::Aes)
-            .unwrap();
-        let decompress = Self::decompress_binary(&decrypted);
-        Ok(decompress.unwrap())
     }
 
-    pub fn compress_binary(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    // RSA-PSS signature over `message` with SHA-256
+    pub fn sign(private_key: &RsaPrivateKey, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let signing_key = BlindedSigningKey::<Sha256>::new(private_key.clone());
+        let signature = signing_key
+            .try_sign_with_rng(&mut OsRng, message)
+            .map_err(|e| CryptoError::Signature(e.to_string()))?;
+        Ok(signature.to_vec())
+    }
+
+    pub fn verify(public_key: &RsaPublicKey, message: &[u8], signature: &[u8]) -> bool {
+        let verifying_key = VerifyingKey::<Sha256>::new(public_key.clone());
+        Signature::try_from(signature)
+            .is_ok_and(|signature| verifying_key.verify(message, &signature).is_ok())
+    }
+
+    // Short fingerprint of a public key, enough to tell whether a signature was made with the key
+    // a user has in the keyring now or with one they had before
+    pub fn key_id(public_key: &PublicKey) -> Result<String, CryptoError> {
+        let der = public_key.spki_der()?;
+        Ok(Sha256::digest(&der)[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+
+    // Stable identifier for a name under a secret key (HMAC-SHA256, hex encoded).
+    // The parts are joined with a separator byte so ("ab", "c") and ("a", "bc") can't collide.
+    pub fn keyed_id(key: &[u8], parts: &[&str]) -> String {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part.as_bytes());
+            mac.update(&[0]);
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // zstd at `level`, see `Codec` for how entries pick theirs
+    pub fn compress_binary(data: &[u8], level: i32) -> Result<Vec<u8>, std::io::Error> {
         let cursor = Cursor::new(data);
-        encode_all(cursor, 3)
+        encode_all(cursor, level)
     }
 
+    // Any zstd frame made without a dictionary, which is everything entries stored before compression was
+    // configurable hold
     pub fn decompress_binary(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
         let cursor = Cursor::new(data);
         decode_all(cursor)
@@ -145,3 +427,92 @@ impl i_keys {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::TempDir;
+    use rsa::pkcs1::EncodeRsaPublicKey;
+
+    #[test]
+    fn public_keys_are_read_in_either_format() {
+        let dir = TempDir::new();
+        let private = dir.path().join("bob.pem");
+        let (_, public_key) = i_keys::generate_key_pair(
+            private.to_str().unwrap(),
+            &|_| Ok(String::new()),
+            Suite::Rsa,
+        )
+        .unwrap();
+        let public = dir.path().join("bob.pub.pem");
+        let public = public.to_str().unwrap();
+        i_keys::export_public_key(&public_key, public).unwrap();
+        assert_eq!(i_keys::read_public_key(public).unwrap(), public_key);
+
+        // As written by "openssl rsa -RSAPublicKey_out"
+        let PublicKey::Rsa(rsa_key) = &public_key else {
+            unreachable!()
+        };
+        let pkcs1 = rsa_key.to_pkcs1_pem(rsa::pkcs8::LineEnding::LF).unwrap();
+        fs::write(public, pkcs1).unwrap();
+        assert_eq!(i_keys::read_public_key(public).unwrap(), public_key);
+
+        fs::write(public, "not a key").unwrap();
+        assert!(i_keys::read_public_key(public).is_err());
+    }
+
+    // Answers every unlock with `unlock` and asks for `new` as the new passphrase
+    fn passphrases(unlock: &'static str, new: &'static str) -> Box<Passphrase> {
+        Box::new(move |request| match request {
+            PassphraseRequest::Unlock(_) => Ok(unlock.to_string()),
+            PassphraseRequest::New(_) => Ok(new.to_string()),
+        })
+    }
+
+    #[test]
+    fn passphrase_can_be_changed() {
+        let dir = TempDir::new();
+        let file = dir.path().join("me.pem");
+        let file = file.to_str().unwrap();
+        let key = PrivateKey::generate(Suite::X25519).unwrap();
+        i_keys::write_private_key(&key, file, "").unwrap();
+
+        assert!(i_keys::change_passphrase(file, &*passphrases("", "new")).unwrap());
+        assert!(i_keys::is_encrypted(file).unwrap());
+        let reread = i_keys::read_private_key(file, &*passphrases("new", "")).unwrap();
+        assert_eq!(
+            i_keys::key_id(&reread.public_key()).unwrap(),
+            i_keys::key_id(&key.public_key()).unwrap()
+        );
+        assert!(matches!(
+            i_keys::read_private_key(file, &*passphrases("old", "")),
+            Err(ClenvError::AccessDenied(_))
+        ));
+
+        // Only the key file is left, readable by us alone
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn wrong_passphrase_leaves_the_key_alone() {
+        let dir = TempDir::new();
+        let file = dir.path().join("me.pem");
+        let file = file.to_str().unwrap();
+        let key = PrivateKey::generate(Suite::X25519).unwrap();
+        i_keys::write_private_key(&key, file, "right").unwrap();
+        let before = fs::read(file).unwrap();
+
+        assert!(matches!(
+            i_keys::change_passphrase(file, &*passphrases("wrong", "")),
+            Err(ClenvError::AccessDenied(_))
+        ));
+        assert_eq!(fs::read(file).unwrap(), before);
+    }
+}
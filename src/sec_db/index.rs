@@ -0,0 +1,586 @@
+use super::compression::{DICTIONARIES_CF, dictionary_prefix};
+use super::handle_db::{EncryptedEntry, META_CF, SecDb};
+use super::history::{HISTORY_CF, history_entry_name, history_key, history_prefix};
+use super::i_keys::i_keys;
+use super::store::Store;
+use super::suite::PublicKey;
+use crate::error::ClenvError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Obfuscated databases store namespaces and entries under keyed identifiers instead of their names.
+// The index key behind those identifiers is shared by everyone in the keyring, wrapped with their
+// public key the same way an entry's data key is. This column family holds the wrapped index keys
+// and the encrypted lists that map identifiers back to names, so only keyring members can list anything.
+pub const INDEX_CF: &str = "index";
+const NAMESPACES: &str = "namespaces";
+// Set in META_CF for obfuscated databases. Plain databases of older versions can have a namespace called
+// "index", so the column family alone doesn't tell
+const OBFUSCATED_KEY: &[u8] = b"obfuscated";
+
+fn key_record(user: &str) -> String {
+    format!("key\0{}", user)
+}
+
+pub(super) fn entries_record(ns_id: &str) -> String {
+    format!("entries\0{}", ns_id)
+}
+
+// What an obfuscated entry keeps about itself, sealed with the index key.
+// Lets an entry be matched to its name even without the namespace's index.
+#[derive(Serialize, Deserialize)]
+pub struct EntryMeta {
+    pub name: String,
+    pub extension: String,
+}
+
+// Column family a namespace is stored in
+pub(super) fn namespace_id(index_key: Option<&[u8]>, ns: &str) -> String {
+    match index_key {
+        Some(key) => i_keys::keyed_id(key, &["ns", ns]),
+        None => ns.to_string(),
+    }
+}
+
+// Key an entry is stored under. The namespace is part of it, so equal names in two namespaces can't be linked
+pub(super) fn entry_id(index_key: Option<&[u8]>, ns: &str, name: &str) -> String {
+    match index_key {
+        Some(key) => i_keys::keyed_id(key, &["entry", ns, name]),
+        None => name.to_string(),
+    }
+}
+
+// Sealed values are bound to where they are stored, the key of their index record or `META_AAD` for the
+// metadata of entries, so a value copied over another one doesn't open
+const META_AAD: &[u8] = b"meta";
+
+fn seal_value<T: Serialize>(key: &[u8], aad: &[u8], value: &T) -> Result<Vec<u8>, ClenvError> {
+    let plain = bincode::serde::encode_to_vec(value, bincode::config::standard())?;
+    let (ciphertext, nonce) = i_keys::seal(key, &plain, aad)?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open_value<T: DeserializeOwned>(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<T, ClenvError> {
+    if sealed.len() < 12 {
+        return Err(ClenvError::Storage("Index record is truncated".to_string()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let plain = i_keys::open(key, ciphertext, nonce, aad)?;
+    let (value, _) = bincode::serde::decode_from_slice(&plain, bincode::config::standard())?;
+    Ok(value)
+}
+
+pub(super) fn marked_obfuscated(db: &dyn Store) -> Result<bool, ClenvError> {
+    Ok(db.has_namespace(META_CF) && db.get(META_CF, OBFUSCATED_KEY)?.is_some())
+}
+
+pub(super) fn mark_obfuscated(db: &dyn Store) -> Result<(), ClenvError> {
+    db.put(META_CF, OBFUSCATED_KEY, &[1])
+}
+
+// Moves name and extension of an entry into its sealed metadata under `new_key`.
+// `old_key` opens metadata sealed earlier, None when the entry comes from a plain database.
+fn stamp_meta(
+    old_key: Option<&[u8]>,
+    new_key: &[u8],
+    name: &str,
+    entry: &mut EncryptedEntry,
+) -> Result<(), ClenvError> {
+    let extension = match (&entry.meta, old_key) {
+        (Some(meta), Some(old_key)) => open_value::<EntryMeta>(old_key, META_AAD, meta)?.extension,
+        _ => std::mem::take(&mut entry.extension),
+    };
+    entry.meta = Some(seal_value(
+        new_key,
+        META_AAD,
+        &EntryMeta {
+            name: name.to_string(),
+            extension,
+        },
+    )?);
+    entry.extension.clear();
+    Ok(())
+}
+
+impl SecDb {
+    pub fn is_obfuscated(&self) -> bool {
+        self.obfuscated
+    }
+
+    // Unwraps our copy of the index key. None if the database is plain or we aren't in the keyring
+    pub(super) fn load_index_key(&self) -> Result<Option<Vec<u8>>, ClenvError> {
+        if !self.obfuscated {
+            return Ok(None);
+        }
+        let my_name = self.conf.require("name")?;
+        match self.db.get(INDEX_CF, key_record(&my_name).as_bytes())? {
+            Some(wrapped) => Ok(Some(self.unwrap_own(&wrapped)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The index key, or None for a database that still stores plain names
+    pub(super) fn index_key(&self) -> Result<Option<Vec<u8>>, ClenvError> {
+        if !self.is_obfuscated() {
+            return Ok(None);
+        }
+        match &self.index_key {
+            Some(key) => Ok(Some(key.clone())),
+            None => {
+                let my_name = self.conf.require("name")?;
+                Err(ClenvError::AccessDenied(format!(
+                    "{} is not in the keyring, so the names in this database can't be read. Ask someone who is to run 'clenv add {}'",
+                    my_name, my_name
+                )))
+            }
+        }
+    }
+
+    pub(super) fn entry_key(&self, ns: &str, name: &str) -> Result<String, ClenvError> {
+        Ok(entry_id(self.index_key()?.as_deref(), ns, name))
+    }
+
+    // Name of the store namespace holding the entries of `ns`
+    pub(super) fn ns_cf(&self, ns: &str) -> Result<String, ClenvError> {
+        let id = namespace_id(self.index_key()?.as_deref(), ns);
+        match self.db.has_namespace(&id) {
+            true => Ok(id),
+            false => Err(ClenvError::NotFound(format!(
+                "Namespace {} does not exist",
+                ns
+            ))),
+        }
+    }
+
+    pub(super) fn has_namespace(&self, ns: &str) -> Result<bool, ClenvError> {
+        let id = namespace_id(self.index_key()?.as_deref(), ns);
+        Ok(self.db.has_namespace(&id))
+    }
+
+    // Creates the namespace on first write to it and lists it in the index
+    pub(super) fn ensure_namespace(&mut self, ns: &str) -> Result<(), ClenvError> {
+        Self::check_namespace_name(ns)?;
+        let key = self.index_key()?;
+        self.ensure_cf(&namespace_id(key.as_deref(), ns))?;
+
+        if let Some(key) = key {
+            let mut namespaces = self.indexed_namespaces(&key)?;
+            if !namespaces.iter().any(|known| known == ns) {
+                namespaces.push(ns.to_string());
+                namespaces.sort();
+                self.write_record(&key, NAMESPACES, &namespaces)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn indexed_namespaces(&self, key: &[u8]) -> Result<Vec<String>, ClenvError> {
+        Ok(self.read_record(key, NAMESPACES)?.unwrap_or_default())
+    }
+
+    // Names of a namespace's entries by identifier. Empty for plain databases, where the two are the same
+    pub(super) fn entry_names(&self, ns: &str) -> Result<BTreeMap<String, String>, ClenvError> {
+        let Some(key) = self.index_key()? else {
+            return Ok(BTreeMap::new());
+        };
+        let record = entries_record(&namespace_id(Some(&key), ns));
+        Ok(self.read_record(&key, &record)?.unwrap_or_default())
+    }
+
+    // Name of the entry stored under `id`, falls back to the id itself
+    pub(super) fn name_of(names: &BTreeMap<String, String>, id: &str) -> String {
+        names.get(id).cloned().unwrap_or_else(|| id.to_string())
+    }
+
+    pub(super) fn register_entry(&self, ns: &str, name: &str) -> Result<(), ClenvError> {
+        let Some(key) = self.index_key()? else {
+            return Ok(());
+        };
+        let mut names = self.entry_names(ns)?;
+        let id = entry_id(Some(&key), ns, name);
+        if names.insert(id, name.to_string()).is_none() {
+            let record = entries_record(&namespace_id(Some(&key), ns));
+            self.write_record(&key, &record, &names)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn unregister_entry(&self, ns: &str, name: &str) -> Result<(), ClenvError> {
+        let Some(key) = self.index_key()? else {
+            return Ok(());
+        };
+        let mut names = self.entry_names(ns)?;
+        if names.remove(&entry_id(Some(&key), ns, name)).is_some() {
+            let record = entries_record(&namespace_id(Some(&key), ns));
+            self.write_record(&key, &record, &names)?;
+        }
+        Ok(())
+    }
+
+    // Seals name and extension into the entry before it's written. Plain databases leave it alone
+    pub(super) fn seal_meta(
+        &self,
+        name: &str,
+        entry: &mut EncryptedEntry,
+    ) -> Result<(), ClenvError> {
+        match self.index_key()? {
+            Some(key) => stamp_meta(Some(&key), &key, name, entry),
+            None => Ok(()),
+        }
+    }
+
+    // Restores the extension of an entry read from an obfuscated database
+    pub(super) fn open_meta(&self, entry: &mut EncryptedEntry) -> Result<(), ClenvError> {
+        if let (Some(meta), Some(key)) = (&entry.meta, self.index_key()?) {
+            entry.extension = open_value::<EntryMeta>(&key, META_AAD, meta)?.extension;
+        }
+        Ok(())
+    }
+
+    // Wraps the index key for a new keyring member
+    pub(super) fn grant_index_key(
+        &self,
+        user: &str,
+        pub_key: &PublicKey,
+    ) -> Result<(), ClenvError> {
+        let Some(key) = self.index_key()? else {
+            return Ok(());
+        };
+        let wrapped = i_keys::wrap_key(&key, &[(user.to_string(), pub_key.clone())])?;
+        let cf = self.cf(INDEX_CF)?;
+        for (user, wrapped_key) in wrapped {
+            self.db
+                .put(cf, key_record(&user).as_bytes(), &wrapped_key)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn revoke_index_key(&self, user: &str) -> Result<(), ClenvError> {
+        if self.obfuscated {
+            self.db.delete(INDEX_CF, key_record(user).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts the names of every namespace and entry of a plain database.
+    /// Returns the namespaces that were converted.
+    pub fn obfuscate(&mut self) -> Result<Vec<String>, ClenvError> {
+        if self.is_obfuscated() {
+            return Err(ClenvError::Invalid(
+                "The database is already obfuscated".to_string(),
+            ));
+        }
+        if self.db.has_namespace(INDEX_CF) {
+            return Err(ClenvError::Invalid(format!(
+                "The database has a namespace called '{}', run 'clenv migrate' to move it out of the way first",
+                INDEX_CF
+            )));
+        }
+        let recipients = self.get_recipients()?;
+        self.reindex(i_keys::generate_data_key(), &recipients)
+    }
+
+    // Moves everything to identifiers under a fresh index key, wrapped for `recipients` only.
+    // Someone removed from the keyring can't compute the new identifiers or read the new index.
+    pub(super) fn rotate_index_key(
+        &mut self,
+        recipients: &[(String, PublicKey)],
+    ) -> Result<Vec<String>, ClenvError> {
+        self.index_key()?;
+        self.reindex(i_keys::generate_data_key(), recipients)
+    }
+
+    // Everything is copied to its new identifier first, while the old records stay readable under the old
+    // key. Nothing refers to the copies until the new key is written, so a failure before that leaves the
+    // database as it was. The originals are removed only once the index points at the copies.
+    fn reindex(
+        &mut self,
+        new_key: Vec<u8>,
+        recipients: &[(String, PublicKey)],
+    ) -> Result<Vec<String>, ClenvError> {
+        let old_key = self.index_key()?;
+        let namespaces = self.namespaces()?;
+        self.ensure_cf(HISTORY_CF)?;
+
+        let mut indexes = Vec::new();
+        for ns in &namespaces {
+            match self.copy_namespace(ns, &new_key) {
+                Ok(index) => indexes.push((namespace_id(Some(&new_key), ns), index)),
+                Err(e) => {
+                    for ns in &namespaces {
+                        let _ = self.drop_records(&namespace_id(Some(&new_key), ns));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if !self.obfuscated {
+            self.db.create_namespace(INDEX_CF)?;
+        }
+        let cf = self.cf(INDEX_CF)?;
+        let mut current = Vec::new();
+        for (user, wrapped_key) in i_keys::wrap_key(&new_key, recipients)? {
+            let record = key_record(&user);
+            self.db.put(cf, record.as_bytes(), &wrapped_key)?;
+            current.push(record);
+        }
+        self.write_record(&new_key, NAMESPACES, &namespaces)?;
+        current.push(NAMESPACES.to_string());
+        for (new_cf, index) in &indexes {
+            let record = entries_record(new_cf);
+            self.write_record(&new_key, &record, index)?;
+            current.push(record);
+        }
+        if !self.obfuscated {
+            self.ensure_cf(META_CF)?;
+            mark_obfuscated(self.db.as_ref())?;
+            self.obfuscated = true;
+        }
+        self.index_key = Some(new_key);
+
+        // Old entry lists, and the keys of users who aren't recipients any more
+        let stale: Vec<_> = self
+            .db
+            .iter(cf)?
+            .map(|item| item.map(|(key, _)| key))
+            .filter(|key| {
+                key.as_ref().map_or(true, |key| {
+                    !current.iter().any(|record| record.as_bytes() == key)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        for key in stale {
+            self.db.delete(cf, &key)?;
+        }
+        for ns in &namespaces {
+            self.drop_records(&namespace_id(old_key.as_deref(), ns))?;
+        }
+        Ok(namespaces)
+    }
+
+    // Copies the entries, revisions and dictionaries of `ns` to the identifiers they get under `new_key`,
+    // with their metadata sealed under it. Returns the names of the copied entries by their new identifier
+    fn copy_namespace(
+        &mut self,
+        ns: &str,
+        new_key: &[u8],
+    ) -> Result<BTreeMap<String, String>, ClenvError> {
+        let old_key = self.index_key()?;
+        let old_cf = namespace_id(old_key.as_deref(), ns);
+        let new_cf = namespace_id(Some(new_key), ns);
+        let names = self.entry_names(ns)?;
+        self.ensure_cf(&new_cf)?;
+
+        let mut index = BTreeMap::new();
+        let current: Vec<_> = self.db.iter(self.cf(&old_cf)?)?.collect::<Result<_, _>>()?;
+        for (id, value) in current {
+            let name = Self::name_of(&names, &String::from_utf8_lossy(&id));
+            let mut entry = EncryptedEntry::from_bytes(&value)?;
+            stamp_meta(old_key.as_deref(), new_key, &name, &mut entry)?;
+
+            let new_id = entry_id(Some(new_key), ns, &name);
+            self.db
+                .put(self.cf(&new_cf)?, new_id.as_bytes(), &entry.to_bytes()?)?;
+            index.insert(new_id, name);
+        }
+
+        for (old_history_key, mut entry) in self.archived_revisions(ns, None)? {
+            let name = Self::name_of(&names, &history_entry_name(&old_history_key));
+            stamp_meta(old_key.as_deref(), new_key, &name, &mut entry)?;
+
+            let new_history_key =
+                history_key(&new_cf, &entry_id(Some(new_key), ns, &name), entry.revision);
+            self.db
+                .put(self.cf(HISTORY_CF)?, &new_history_key, &entry.to_bytes()?)?;
+        }
+
+        self.copy_dictionaries(ns, &new_cf)?;
+        Ok(index)
+    }
+
+    // Removes the column family `ns_id` with the revisions and dictionaries stored for it
+    pub(super) fn drop_records(&mut self, ns_id: &str) -> Result<(), ClenvError> {
+        for (cf, prefix) in [
+            (HISTORY_CF, history_prefix(ns_id, None)),
+            (DICTIONARIES_CF, dictionary_prefix(ns_id)),
+        ] {
+            if !self.db.has_namespace(cf) {
+                continue;
+            }
+            let keys: Vec<_> = self
+                .db
+                .scan(cf, &prefix)?
+                .map(|item| item.map(|(key, _)| key))
+                .collect::<Result<_, _>>()?;
+            for key in keys {
+                self.db.delete(cf, &key)?;
+            }
+        }
+        if !self.db.has_namespace(ns_id) {
+            return Ok(());
+        }
+        // RocksDB's default column family can't be dropped, it's just left empty
+        if ns_id == "default" {
+            let keys: Vec<_> = self
+                .db
+                .iter(ns_id)?
+                .map(|item| item.map(|(key, _)| key))
+                .collect::<Result<_, _>>()?;
+            for key in keys {
+                self.db.delete(ns_id, &key)?;
+            }
+            return Ok(());
+        }
+        self.db.drop_namespace(ns_id)
+    }
+
+    // Merges two versions of an index record that both gained names, for pull. The namespace list keeps every
+    // namespace of either, an entry list every entry of either that is still stored here.
+    // None for records that can't be merged, the wrapped index keys, or when we have no index key
+    pub(super) fn merge_index_record(
+        &self,
+        record: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+    ) -> Result<Option<Vec<u8>>, ClenvError> {
+        let Some(key) = self.index_key()? else {
+            return Ok(None);
+        };
+        let aad = record;
+        let record = String::from_utf8_lossy(record);
+        if record == NAMESPACES {
+            let mut namespaces: Vec<String> = open_value(&key, aad, ours)?;
+            namespaces.extend(open_value::<Vec<String>>(&key, aad, theirs)?);
+            namespaces.sort();
+            namespaces.dedup();
+            return Ok(Some(seal_value(&key, aad, &namespaces)?));
+        }
+        let Some(ns_id) = record.strip_prefix("entries\0") else {
+            return Ok(None);
+        };
+        let mut names: BTreeMap<String, String> = open_value(&key, aad, ours)?;
+        names.extend(open_value::<BTreeMap<String, String>>(&key, aad, theirs)?);
+        let mut stored = BTreeMap::new();
+        for (id, name) in names {
+            if self.db.has_namespace(ns_id) && self.db.get(ns_id, id.as_bytes())?.is_some() {
+                stored.insert(id, name);
+            }
+        }
+        Ok(Some(seal_value(&key, aad, &stored)?))
+    }
+
+    fn read_record<T: DeserializeOwned>(
+        &self,
+        key: &[u8],
+        record: &str,
+    ) -> Result<Option<T>, ClenvError> {
+        let cf = self.cf(INDEX_CF)?;
+        match self.db.get(cf, record.as_bytes())? {
+            Some(sealed) => Ok(Some(open_value(key, record.as_bytes(), &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_record<T: Serialize>(
+        &self,
+        key: &[u8],
+        record: &str,
+        value: &T,
+    ) -> Result<(), ClenvError> {
+        let cf = self.cf(INDEX_CF)?;
+        self.db.put(
+            cf,
+            record.as_bytes(),
+            &seal_value(key, record.as_bytes(), value)?,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::{TempDir, open_db};
+
+    // Two revisions of a.txt in "dev", one of b.txt in "zz"
+    fn fill(db: &mut SecDb) {
+        db.store("a.txt", b"first", "txt", false).unwrap();
+        db.store("a.txt", b"second", "txt", false).unwrap();
+        db.conf.insert("ns", "zz");
+        db.store("b.txt", b"other", "txt", false).unwrap();
+        db.conf.insert("ns", "dev");
+    }
+
+    fn assert_readable(db: &mut SecDb) {
+        assert_eq!(db.namespaces().unwrap(), ["dev", "zz"]);
+        assert_eq!(db.list_entries("dev").unwrap(), ["a.txt"]);
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"second");
+        assert_eq!(db.history("a.txt").unwrap().len(), 2);
+        db.conf.insert("ns", "zz");
+        assert_eq!(db.read_entry("b.txt").unwrap(), b"other");
+        db.conf.insert("ns", "dev");
+    }
+
+    // A record of "zz" that doesn't decode, so reindexing fails after "dev" was copied
+    fn plant_junk(db: &SecDb) {
+        let cf = db.ns_cf("zz").unwrap();
+        db.db.put(&cf, b"junk", b"junk").unwrap();
+    }
+
+    fn remove_junk(db: &SecDb) {
+        let cf = db.ns_cf("zz").unwrap();
+        db.db.delete(&cf, b"junk").unwrap();
+    }
+
+    #[test]
+    fn reindexing_keeps_every_entry() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        fill(&mut db);
+        let families = db.column_families().unwrap().len();
+
+        let old_key = db.index_key().unwrap();
+        let recipients = db.get_recipients().unwrap();
+        db.rotate_index_key(&recipients).unwrap();
+        assert_ne!(db.index_key().unwrap(), old_key);
+        assert_readable(&mut db);
+        // The namespaces moved to new column families, the old ones are gone
+        assert_eq!(db.column_families().unwrap().len(), families);
+    }
+
+    #[test]
+    fn failed_reindexing_changes_nothing() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        fill(&mut db);
+        plant_junk(&db);
+        let families = db.column_families().unwrap();
+        let key = db.index_key().unwrap();
+        let recipients = db.get_recipients().unwrap();
+
+        assert!(db.rotate_index_key(&recipients).is_err());
+        assert_eq!(db.index_key().unwrap(), key);
+        assert_eq!(db.column_families().unwrap(), families);
+        // Reopened, every name still resolves with the key that is stored
+        drop(db);
+        let mut db = open_db(&tmp, "db", "alice");
+        remove_junk(&db);
+        assert_readable(&mut db);
+    }
+
+    // Index records are bound to their key, so one copied over another doesn't open
+    #[test]
+    fn swapped_records_are_refused() {
+        let tmp = TempDir::new();
+        let mut db = open_db(&tmp, "db", "alice");
+        fill(&mut db);
+        let record = |ns: &str| entries_record(&db.ns_cf(ns).unwrap());
+        let (dev, zz) = (record("dev"), record("zz"));
+        let sealed = db.db.get(INDEX_CF, zz.as_bytes()).unwrap().unwrap();
+        db.db.put(INDEX_CF, dev.as_bytes(), &sealed).unwrap();
+        assert!(db.list_entries("dev").is_err());
+        assert_eq!(db.list_entries("zz").unwrap(), ["b.txt"]);
+    }
+}
@@ -0,0 +1,221 @@
+use super::bundle::{BundleImport, ConflictPolicy};
+use super::handle_db::{NamespaceReport, SecDb};
+use super::i_keys::i_keys;
+use super::suite::PublicKey;
+use crate::error::ClenvError;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::Serialize;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+// Merging brings another database into this one: its keyring members join ours, and its entries are
+// copied over the way a bundle of them would be imported, see bundle.rs. Afterwards everyone in the
+// merged keyring can read everything the merging user can decrypt. The other database isn't changed.
+
+/// What `merge` did
+#[derive(Debug, Serialize)]
+pub struct MergeReport {
+    // Members of the other keyring added to ours
+    pub added: Vec<String>,
+    // Users in both keyrings with different keys. Ours are kept and theirs are left out
+    pub collisions: Vec<String>,
+    // Our entries the added members were given access to, by namespace
+    pub access: Vec<NamespaceReport>,
+    // What happened to the entries of the other database
+    pub import: BundleImport,
+}
+
+impl SecDb {
+    /// Merges `other` into this database. Keyring members of `other` that we don't have are added, users
+    /// with a different key in each keyring are reported and keep the key they have here. The added members
+    /// get access to every entry we can read, then the current entries of every namespace of `other` are
+    /// stored here like `import_bundle` does, with `policy` deciding about entries both databases have. Their
+    /// signatures are checked with the merged keyring, so entries signed with a colliding key are left out.
+    /// When the entries can't be brought over, the added members are taken out of the keyring again.
+    pub fn merge(
+        &mut self,
+        other: &SecDb,
+        policy: ConflictPolicy,
+    ) -> Result<MergeReport, ClenvError> {
+        let ours = self.get_recipients()?;
+        let mut added = Vec::new();
+        let mut collisions = Vec::new();
+        for (user, key) in other.get_recipients()? {
+            match ours.iter().find(|(name, _)| *name == user) {
+                None => added.push((user, key)),
+                Some((_, our_key)) if i_keys::key_id(our_key)? == i_keys::key_id(&key)? => {}
+                Some(_) => collisions.push(user),
+            }
+        }
+
+        let merged = self.merge_with(other, &added, policy);
+        let (access, import) = match merged {
+            Ok(merged) => merged,
+            Err(e) => {
+                // Members only join when their entries came along, so a failed merge can simply be run again
+                self.drop_members(&added)?;
+                return Err(e);
+            }
+        };
+
+        Ok(MergeReport {
+            added: added.into_iter().map(|(user, _)| user).collect(),
+            collisions,
+            access,
+            import,
+        })
+    }
+
+    // Adds the members of `other` we don't have and imports its entries
+    fn merge_with(
+        &mut self,
+        other: &SecDb,
+        added: &[(String, PublicKey)],
+        policy: ConflictPolicy,
+    ) -> Result<(Vec<NamespaceReport>, BundleImport), ClenvError> {
+        let cf_keyring = self.cf("keyring")?;
+        for (user, key) in added {
+            self.db
+                .put(cf_keyring, user.as_bytes(), key.to_pem()?.as_bytes())?;
+            self.grant_index_key(user, key)?;
+        }
+        let access = match added.is_empty() {
+            true => Vec::new(),
+            false => self.grant_access(added, &self.namespaces()?)?,
+        };
+
+        // The entries travel through a bundle, so large ones never have to fit in memory
+        let dir = private_temp_dir()?;
+        let bundle = dir.join("merge.bundle");
+        let import = create_private(&bundle)
+            .and_then(|file| other.write_bundle(file, &bundle, &other.namespaces()?, None))
+            .and_then(|_| self.import_bundle(&bundle, policy, false));
+        let _ = fs::remove_dir_all(&dir);
+        Ok((access, import?))
+    }
+
+    // Takes the added members out of the keyring and every entry again
+    fn drop_members(&self, added: &[(String, PublicKey)]) -> Result<(), ClenvError> {
+        let is_added = |user: &String| added.iter().any(|(name, _)| name == user);
+        for ns in self.namespaces()? {
+            self.update_entries(&ns, |_name, _label, entry| {
+                let before = entry.encrypted_keys.len();
+                entry.encrypted_keys.retain(|user, _| !is_added(user));
+                Ok(entry.encrypted_keys.len() != before)
+            })?;
+        }
+        let cf_keyring = self.cf("keyring")?;
+        for (user, _) in added {
+            self.revoke_index_key(user)?;
+            self.db.delete(cf_keyring, user.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+// A new folder under a random name in the temp dir that only we can open, so nobody can plant or read the bundle
+fn private_temp_dir() -> Result<PathBuf, ClenvError> {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let name: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    let dir = std::env::temp_dir().join(format!("clenv-merge-{}", name));
+
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder
+        .create(&dir)
+        .map_err(|e| ClenvError::io(format!("Could not create {}", dir.display()), e))?;
+    Ok(dir)
+}
+
+// Fails rather than following whatever is at `path` already
+fn create_private(path: &Path) -> Result<fs::File, ClenvError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+        .open(path)
+        .map_err(|e| ClenvError::io(format!("Could not create {}", path.display()), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::{TempDir, add_member, open_db};
+
+    #[test]
+    fn failed_merges_leave_the_keyring_alone() {
+        let tmp = TempDir::new();
+        let mut ours = open_db(&tmp, "ours", "alice");
+        ours.store("a.txt", b"ours", "txt", false).unwrap();
+        let mut theirs = open_db(&tmp, "theirs", "alice");
+        add_member(&tmp, &theirs, "bob", &["dev"]);
+        theirs.store("b.txt", b"theirs", "txt", false).unwrap();
+        // An entry that doesn't decode stops the bundle from being written
+        let cf = theirs.ns_cf("dev").unwrap();
+        let key = theirs.entry_key("dev", "b.txt").unwrap();
+        theirs.db.put(&cf, key.as_bytes(), b"garbage").unwrap();
+
+        assert!(matches!(
+            ours.merge(&theirs, ConflictPolicy::Skip),
+            Err(ClenvError::Storage(_))
+        ));
+        let members: Vec<String> = ours
+            .get_recipients()
+            .unwrap()
+            .into_iter()
+            .map(|(user, _)| user)
+            .collect();
+        assert_eq!(members, ["alice"]);
+        let entry = ours.entry("dev", "a.txt").unwrap();
+        assert_eq!(entry.encrypted_keys.keys().collect::<Vec<_>>(), ["alice"]);
+        assert!(ours.verify_entry("dev", "a.txt").unwrap().is_valid());
+    }
+
+    #[test]
+    fn colliding_members_keep_our_key() {
+        let tmp = TempDir::new();
+        let mut theirs = open_db(&tmp, "theirs", "alice");
+        add_member(&tmp, &theirs, "bob", &["dev"]);
+        add_member(&tmp, &theirs, "carol", &["dev"]);
+        theirs.store("b.txt", b"theirs", "txt", false).unwrap();
+        drop(theirs);
+        let mut carol = open_db(&tmp, "theirs", "carol");
+        carol.store("c.txt", b"by carol", "txt", false).unwrap();
+        drop(carol);
+        let theirs = open_db(&tmp, "theirs", "alice");
+
+        // Another carol with another key
+        let mut ours = open_db(&tmp, "ours", "alice");
+        ours.store("a.txt", b"ours", "txt", false).unwrap();
+        let other_carol = age::x25519::Identity::generate().to_public().to_string();
+        ours.add_user("carol", Some(&other_carol), &[]).unwrap();
+        let carol_key = |db: &SecDb| {
+            let recipients = db.get_recipients().unwrap();
+            let (_, key) = recipients.iter().find(|(user, _)| user == "carol").unwrap();
+            i_keys::key_id(key).unwrap()
+        };
+        let before = carol_key(&ours);
+
+        let report = ours.merge(&theirs, ConflictPolicy::Skip).unwrap();
+        assert_eq!(report.added, ["bob"]);
+        assert_eq!(report.collisions, ["carol"]);
+        assert_eq!(report.access[0].updated, ["a.txt"]);
+        assert_eq!(report.import.imported, ["dev/b.txt"]);
+        // What carol signed over there can't be checked with the key we have for carol
+        assert_eq!(report.import.untrusted, ["dev/c.txt"]);
+        assert_eq!(report.import.changed_keys, ["carol"]);
+        assert_eq!(carol_key(&ours), before);
+        assert!(ours.read_entry("c.txt").is_err());
+        drop(ours);
+
+        let bob = open_db(&tmp, "ours", "bob");
+        assert_eq!(bob.read_entry("a.txt").unwrap(), b"ours");
+        assert_eq!(bob.read_entry("b.txt").unwrap(), b"theirs");
+    }
+}
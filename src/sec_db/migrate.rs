@@ -0,0 +1,432 @@
+use super::compression::{DICTIONARIES_CF, dictionary_prefix};
+use super::handle_db::{ENTRY_LAYOUT, EncryptedEntry, META_CF, SecDb};
+use super::history::{HISTORY_CF, history_prefix};
+use super::index::{INDEX_CF, namespace_id};
+use super::signature::SIGNED_FORMAT;
+use super::store::Store;
+use crate::error::ClenvError;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+// Records which version of the on-disk format a database is in. Databases without the record were
+// created before it existed and are version 0. clenv refuses to open databases newer than it knows,
+// older ones keep working and are brought up to date with "clenv migrate".
+const SCHEMA_KEY: &[u8] = b"schema_version";
+// Set once no entry, revision or dictionary of a format from before signing is left. From then on an
+// unsigned entry was planted by someone who can write to the database files (see `SecDb::refuses_unsigned`)
+const ALL_SIGNED_KEY: &[u8] = b"all_signed";
+
+// What every version changed, the newest last. Adding a version means adding its step to `migrate`.
+const MIGRATIONS: [&str; 1] = ["entries start with a header naming the layout they are written in"];
+
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// What `migrate` did, or would do with `dry_run`
+#[derive(Debug, Serialize)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    // What changed between the two versions
+    pub steps: Vec<String>,
+    // Entries, revisions and dictionaries read
+    pub checked: usize,
+    // Values rewritten in the newest layout
+    pub upgraded: usize,
+    // Namespaces that were in the way of clenv's own records, with the name they were moved to
+    pub moved: Vec<(String, String)>,
+    pub backup: Option<PathBuf>,
+    pub dry_run: bool,
+}
+
+// Refuses databases written by a newer clenv, whose entries or records this one would misread
+pub(super) fn check_schema(db: &dyn Store, path: &str) -> Result<(), ClenvError> {
+    let version = stored_schema(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(ClenvError::Storage(format!(
+            "The database at {} is in format version {}, this version of clenv only knows up to {}. Update clenv to use it",
+            path, version, SCHEMA_VERSION
+        )));
+    }
+    Ok(())
+}
+
+// Marks a database as being in the current format
+pub(super) fn record_schema(db: &mut dyn Store) -> Result<(), ClenvError> {
+    db.create_namespace(META_CF)?;
+    db.put(META_CF, SCHEMA_KEY, &SCHEMA_VERSION.to_be_bytes())
+}
+
+// Marks a database as having every value in a format that is always signed
+pub(super) fn record_all_signed(db: &dyn Store) -> Result<(), ClenvError> {
+    db.put(META_CF, ALL_SIGNED_KEY, &[1])
+}
+
+pub(super) fn all_signed(db: &dyn Store) -> Result<bool, ClenvError> {
+    Ok(db.has_namespace(META_CF) && db.get(META_CF, ALL_SIGNED_KEY)?.is_some())
+}
+
+fn stored_schema(db: &dyn Store) -> Result<u32, ClenvError> {
+    if !db.has_namespace(META_CF) {
+        return Ok(0);
+    }
+    match db.get(META_CF, SCHEMA_KEY)? {
+        None => Ok(0),
+        Some(value) => value
+            .as_slice()
+            .try_into()
+            .map(u32::from_be_bytes)
+            .map_err(|_| ClenvError::Storage("The database format version is corrupt".to_string())),
+    }
+}
+
+impl SecDb {
+    /// Format version the database is in
+    pub fn schema_version(&self) -> Result<u32, ClenvError> {
+        stored_schema(self.db.as_ref())
+    }
+
+    /// Brings the database to the current format: every entry, revision and dictionary written in an
+    /// older layout is rewritten in the newest one and the format version is recorded. A namespace called
+    /// like one of clenv's own column families is moved to another name. Nothing is decrypted, so it works
+    /// on entries you have no access to.
+    /// `backup` gets a copy of the database before anything is changed. Nothing is written with `dry_run`.
+    /// Fails without changing anything when a value doesn't decode.
+    pub fn migrate(
+        &mut self,
+        dry_run: bool,
+        backup: Option<&Path>,
+    ) -> Result<MigrationReport, ClenvError> {
+        let from = self.schema_version()?;
+        let mut report = MigrationReport {
+            from,
+            to: SCHEMA_VERSION,
+            steps: MIGRATIONS[from as usize..]
+                .iter()
+                .map(|step| step.to_string())
+                .collect(),
+            checked: 0,
+            upgraded: 0,
+            moved: self.clashing_namespaces()?,
+            backup: None,
+            dry_run,
+        };
+
+        let mut cfs = self.entry_cfs()?;
+        cfs.extend(report.moved.iter().map(|(from, _)| from.clone()));
+        let mut outdated = Vec::new();
+        let mut undecodable = 0;
+        for cf_name in &cfs {
+            for item in self.db.iter(cf_name)? {
+                let (key, value) = item?;
+                report.checked += 1;
+                if EncryptedEntry::layout_of(&value) == ENTRY_LAYOUT {
+                    continue;
+                }
+                match EncryptedEntry::from_bytes(&value) {
+                    Ok(entry) => outdated.push((cf_name.clone(), key.to_vec(), entry)),
+                    Err(_) => undecodable += 1,
+                }
+            }
+        }
+        if undecodable > 0 {
+            return Err(ClenvError::Integrity(format!(
+                "{} values don't decode, nothing was migrated. 'clenv fsck' lists them",
+                undecodable
+            )));
+        }
+        report.upgraded = outdated.len();
+        if dry_run {
+            return Ok(report);
+        }
+        if from == SCHEMA_VERSION && outdated.is_empty() && report.moved.is_empty() {
+            self.record_if_all_signed()?;
+            return Ok(report);
+        }
+
+        if let Some(path) = backup {
+            if path.exists() {
+                return Err(ClenvError::Invalid(format!(
+                    "{} already exists, pick another backup path",
+                    path.display()
+                )));
+            }
+            self.db.checkpoint(path)?;
+            report.backup = Some(path.to_path_buf());
+        }
+
+        for (cf_name, key, entry) in outdated {
+            self.db.put(&cf_name, &key, &entry.to_bytes()?)?;
+        }
+        for (from, to) in &report.moved {
+            self.move_namespace(from, to)?;
+        }
+        record_schema(self.db.as_mut())?;
+        self.record_if_all_signed()?;
+        Ok(report)
+    }
+
+    // Column families holding entries, archived revisions and dictionaries
+    fn entry_cfs(&self) -> Result<Vec<String>, ClenvError> {
+        let index_key = self.index_key()?;
+        let mut cfs: Vec<String> = self
+            .namespaces()?
+            .iter()
+            .map(|ns| namespace_id(index_key.as_deref(), ns))
+            .collect();
+        cfs.extend([HISTORY_CF, DICTIONARIES_CF].map(String::from));
+        Ok(cfs
+            .into_iter()
+            .filter(|cf| self.db.has_namespace(cf))
+            .collect())
+    }
+
+    // Plain databases of the first versions could have namespaces called like the column families clenv
+    // took for its own records later. "index" is the one that can be told apart, as plain databases have
+    // no index. Its entries are from before entries were bound to their namespace, so they are moved as
+    // they are, to the first free name of "index-moved", "index-moved-2" and so on
+    fn clashing_namespaces(&self) -> Result<Vec<(String, String)>, ClenvError> {
+        if self.is_obfuscated() || !self.db.has_namespace(INDEX_CF) {
+            return Ok(Vec::new());
+        }
+        let mut values = Vec::new();
+        for item in self.db.iter(INDEX_CF)? {
+            values.push(item?.1);
+        }
+        for (cf, prefix) in [
+            (HISTORY_CF, history_prefix(INDEX_CF, None)),
+            (DICTIONARIES_CF, dictionary_prefix(INDEX_CF)),
+        ] {
+            if self.db.has_namespace(cf) {
+                for item in self.db.scan(cf, &prefix)? {
+                    values.push(item?.1);
+                }
+            }
+        }
+        // Undecodable values stop the migration later on
+        if values.iter().any(|value| {
+            EncryptedEntry::from_bytes(value).is_ok_and(|entry| entry.format > 0)
+        }) {
+            return Err(ClenvError::Invalid(format!(
+                "The namespace '{}' is in the way of clenv's index, and its entries are bound to its name, so they can't be moved",
+                INDEX_CF
+            )));
+        }
+
+        let mut to = format!("{}-moved", INDEX_CF);
+        for n in 2.. {
+            if !self.db.has_namespace(&to) {
+                break;
+            }
+            to = format!("{}-moved-{}", INDEX_CF, n);
+        }
+        Ok(vec![(INDEX_CF.to_string(), to)])
+    }
+
+    // Moves the entries, revisions and dictionaries of namespace `from` of a plain database to `to` as they are
+    fn move_namespace(&mut self, from: &str, to: &str) -> Result<(), ClenvError> {
+        self.ensure_cf(to)?;
+        let records: Vec<_> = self.db.iter(from)?.collect::<Result<_, _>>()?;
+        for (key, value) in records {
+            self.db.put(to, &key, &value)?;
+        }
+        for (cf, old, new) in [
+            (HISTORY_CF, history_prefix(from, None), history_prefix(to, None)),
+            (DICTIONARIES_CF, dictionary_prefix(from), dictionary_prefix(to)),
+        ] {
+            if !self.db.has_namespace(cf) {
+                continue;
+            }
+            let records: Vec<_> = self.db.scan(cf, &old)?.collect::<Result<_, _>>()?;
+            for (key, value) in records {
+                self.db.put(cf, &[&new, &key[old.len()..]].concat(), &value)?;
+            }
+        }
+        self.drop_records(from)
+    }
+
+    // Records that the database is all signed once the last value of an older format is gone. Values
+    // that don't decode can't be read as entries either, so they don't hold it back. Databases from
+    // before the format version was recorded get it from `migrate`
+    pub(super) fn record_if_all_signed(&self) -> Result<(), ClenvError> {
+        if !self.db.has_namespace(META_CF) || all_signed(self.db.as_ref())? {
+            return Ok(());
+        }
+        for cf_name in self.entry_cfs()? {
+            for item in self.db.iter(&cf_name)? {
+                let (_, value) = item?;
+                if EncryptedEntry::from_bytes(&value)
+                    .is_ok_and(|entry| entry.format < SIGNED_FORMAT)
+                {
+                    return Ok(());
+                }
+            }
+        }
+        record_all_signed(self.db.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::Config;
+    use crate::sec_db::i_keys::i_keys;
+    use crate::sec_db::store;
+    use crate::sec_db::suite::{PrivateKey, Suite};
+    use crate::sec_db::testing::{TempDir, open_db_with};
+    use rsa::pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey};
+    use rsa::pkcs8::LineEnding;
+    use std::collections::HashMap;
+    use std::fs;
+
+    // What the first version of clenv stored, without a header
+    #[derive(Serialize)]
+    struct FirstEntry {
+        ciphertext: Vec<u8>,
+        nonce: [u8; 12],
+        encrypted_keys: HashMap<String, Vec<u8>>,
+        extension: String,
+    }
+
+    // A database as the first version of clenv left it: PKCS#1 keys, plain entry names, no format record,
+    // and entries compressed with zstd and sealed without associated data
+    fn first_database(dir: &TempDir, entries: &[(&str, &[u8])]) {
+        let key = PrivateKey::generate(Suite::Rsa).unwrap();
+        let PrivateKey::Rsa(rsa_key) = &key else {
+            unreachable!()
+        };
+        let private = dir.path().join("alice.pem");
+        fs::write(&private, rsa_key.to_pkcs1_pem(LineEnding::LF).unwrap()).unwrap();
+
+        let conf = Config::new(
+            "alice",
+            dir.path().join("db").to_str().unwrap(),
+            private.to_str().unwrap(),
+            "dev",
+        );
+        let mut db = store::open(&conf).unwrap();
+        db.create_namespace("keyring").unwrap();
+        let public = rsa::RsaPublicKey::from(rsa_key.as_ref());
+        let pem = public.to_pkcs1_pem(LineEnding::LF).unwrap();
+        db.put("keyring", b"alice", pem.as_bytes()).unwrap();
+
+        db.create_namespace("dev").unwrap();
+        let recipients = [("alice".to_string(), key.public_key())];
+        for (name, data) in entries {
+            let data_key = i_keys::generate_data_key();
+            let compressed = i_keys::compress_binary(data, 3).unwrap();
+            let (ciphertext, nonce) = Suite::Rsa.seal(&data_key, &compressed, &[]).unwrap();
+            let entry = FirstEntry {
+                ciphertext,
+                nonce,
+                encrypted_keys: i_keys::wrap_key(&data_key, &recipients).unwrap(),
+                extension: "txt".to_string(),
+            };
+            let value = bincode::serde::encode_to_vec(&entry, bincode::config::standard()).unwrap();
+            db.put("dev", name.as_bytes(), &value).unwrap();
+        }
+    }
+
+    #[test]
+    fn first_databases_are_brought_up_to_date() {
+        let tmp = TempDir::new();
+        first_database(&tmp, &[("a.txt", b"first"), ("b.txt", b"second")]);
+        let mut db = open_db_with(&tmp, "db", "alice", Suite::Rsa);
+        assert_eq!(db.schema_version().unwrap(), 0);
+        // They can be read before they are migrated
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"first");
+
+        let report = db.migrate(true, None).unwrap();
+        assert_eq!((report.from, report.to), (0, SCHEMA_VERSION));
+        assert_eq!(report.steps, MIGRATIONS);
+        assert_eq!((report.checked, report.upgraded), (2, 2));
+        assert_eq!(db.schema_version().unwrap(), 0);
+
+        let backup = tmp.path().join("backup");
+        let report = db.migrate(false, Some(&backup)).unwrap();
+        assert_eq!(report.upgraded, 2);
+        assert_eq!(report.backup.as_deref(), Some(backup.as_path()));
+        assert!(backup.exists());
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        for item in db.db.iter("dev").unwrap() {
+            let (_, value) = item.unwrap();
+            assert_eq!(EncryptedEntry::layout_of(&value), ENTRY_LAYOUT);
+        }
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"first");
+        assert_eq!(db.read_entry("b.txt").unwrap(), b"second");
+
+        // Nothing left to do, and no backup made for it
+        let again = tmp.path().join("again");
+        let report = db.migrate(false, Some(&again)).unwrap();
+        assert_eq!((report.from, report.upgraded), (SCHEMA_VERSION, 0));
+        assert!(report.steps.is_empty() && !again.exists());
+
+        // Entries stored afterwards sit next to the migrated ones
+        db.store("c.txt", b"third", "txt", false).unwrap();
+        assert_eq!(db.read_entry("c.txt").unwrap(), b"third");
+        assert_eq!(db.read_entry("a.txt").unwrap(), b"first");
+
+        // The migrated entries still aren't signed, until they are resealed
+        assert!(!db.refuses_unsigned().unwrap());
+        db.reseal(&["dev".to_string()]).unwrap();
+        assert!(db.refuses_unsigned().unwrap());
+    }
+
+    #[test]
+    fn namespaces_in_the_way_are_moved() {
+        let tmp = TempDir::new();
+        first_database(&tmp, &[("a.txt", b"first")]);
+        let mut db = open_db_with(&tmp, "db", "alice", Suite::Rsa);
+        let value = db.db.get("dev", b"a.txt").unwrap().unwrap();
+        db.db.create_namespace(INDEX_CF).unwrap();
+        db.db.put(INDEX_CF, b"b.txt", &value).unwrap();
+        db.db.create_namespace("index-moved").unwrap();
+        assert!(!db.is_obfuscated());
+        assert!(matches!(db.obfuscate(), Err(ClenvError::Invalid(_))));
+
+        let report = db.migrate(false, None).unwrap();
+        let moved = ("index".to_string(), "index-moved-2".to_string());
+        assert_eq!(report.moved, [moved]);
+        assert_eq!((report.checked, report.upgraded), (2, 2));
+        assert!(!db.db.has_namespace(INDEX_CF));
+        db.conf.insert("ns", "index-moved-2");
+        assert_eq!(db.read_entry("b.txt").unwrap(), b"first");
+
+        // The name is free for the index now
+        db.obfuscate().unwrap();
+        assert!(db.is_obfuscated());
+        assert_eq!(db.read_entry("b.txt").unwrap(), b"first");
+        assert!(db.migrate(false, None).unwrap().moved.is_empty());
+    }
+
+    #[test]
+    fn undecodable_values_stop_the_migration() {
+        let tmp = TempDir::new();
+        first_database(&tmp, &[("a.txt", b"first")]);
+        let mut db = open_db_with(&tmp, "db", "alice", Suite::Rsa);
+        db.db.put("dev", b"b.txt", b"garbage").unwrap();
+
+        assert!(matches!(
+            db.migrate(false, None),
+            Err(ClenvError::Integrity(_))
+        ));
+        assert_eq!(db.schema_version().unwrap(), 0);
+        let value = db.db.get("dev", b"a.txt").unwrap().unwrap();
+        assert_eq!(EncryptedEntry::layout_of(&value), 0);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let tmp = TempDir::new();
+        first_database(&tmp, &[]);
+        let db = open_db_with(&tmp, "db", "alice", Suite::Rsa);
+        let mut store = db.db;
+        store.create_namespace(META_CF).unwrap();
+        store
+            .put(META_CF, SCHEMA_KEY, &(SCHEMA_VERSION + 1).to_be_bytes())
+            .unwrap();
+        assert!(matches!(
+            check_schema(store.as_ref(), "db"),
+            Err(ClenvError::Storage(_))
+        ));
+    }
+}
@@ -0,0 +1,585 @@
+use super::history::civil_time;
+use crate::config::config::Config as Conf;
+use crate::error::ClenvError;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// A minimal client for S3 compatible object stores, enough for push and pull: listing, reading, writing and
+// deleting objects, signed with AWS Signature Version 4. Buckets are addressed by path, which AWS, MinIO and
+// the other stand-ins all understand. Writes are conditional, so nobody overwrites an object they haven't seen.
+// The remote is set with "clenv cfg remote https://host/bucket/prefix", the credentials come from the
+// usual AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN variables so they never end up in the config.
+
+/// An object of the bucket, its key relative to the remote's prefix
+#[derive(Debug, Clone)]
+pub struct RemoteObject {
+    pub key: String,
+    pub etag: String,
+}
+
+/// What push and pull need of a bucket. `Bucket` talks to a real one, the tests of sync.rs keep one in memory
+pub trait ObjectStore {
+    /// Every object under the prefix
+    fn list(&self) -> Result<Vec<RemoteObject>, ClenvError>;
+    /// The object and its ETag, None if there is no such object
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, ClenvError>;
+    /// Writes the object if the remote still has the version with ETag `expected`, or no object at all when
+    /// `expected` is None. Returns the new ETag, None when the remote has something else
+    fn put(
+        &self,
+        key: &str,
+        body: &[u8],
+        expected: Option<&str>,
+    ) -> Result<Option<String>, ClenvError>;
+    /// Overwrites the object whatever the remote has
+    fn force_put(&self, key: &str, body: &[u8]) -> Result<String, ClenvError>;
+    /// Deleting an object that isn't there is not an error
+    fn delete(&self, key: &str) -> Result<(), ClenvError>;
+}
+
+pub struct Bucket {
+    // Scheme and host, e.g. "http://127.0.0.1:9000"
+    endpoint: String,
+    host: String,
+    bucket: String,
+    // Empty or ending in '/'
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    agent: ureq::Agent,
+}
+
+fn remote_err(context: &str, err: impl std::fmt::Display) -> ClenvError {
+    ClenvError::Storage(format!("{}: {}", context, err))
+}
+
+// Percent encodes everything but the unreserved characters, '/' too unless `keep_slash`
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The date (YYYYMMDD) and timestamp (YYYYMMDDTHHMMSSZ) of `secs` since the epoch, both in UTC
+fn amz_date(secs: u64) -> (String, String) {
+    let (year, month, day, rem) = civil_time(secs);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    );
+    (date, timestamp)
+}
+
+// Query parameters encoded and sorted by name, the way they are signed and sent
+fn canonical_query(query: &[(&str, String)]) -> String {
+    let mut query: Vec<(String, String)> = query
+        .iter()
+        .map(|(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+        .collect();
+    query.sort();
+    query
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// The canonical request of Signature Version 4. `path` and `query` are already encoded, `headers` are the
+// signed headers, their names lowercase and sorted
+fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, String)],
+    payload_hash: &str,
+) -> String {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, path, query, canonical_headers, signed_headers, payload_hash
+    )
+}
+
+fn string_to_sign(timestamp: &str, scope: &str, canonical_request: &str) -> String {
+    format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    )
+}
+
+// Hex signature of `string_to_sign` with the key derived for `date` (YYYYMMDD) and `region`
+fn signature(secret_key: &str, date: &str, region: &str, string_to_sign: &str) -> String {
+    let signing_key = ["s3", "aws4_request"].iter().fold(
+        hmac(
+            &hmac(format!("AWS4{}", secret_key).as_bytes(), date),
+            region,
+        ),
+        |key, part| hmac(&key, part),
+    );
+    hex(&hmac(&signing_key, string_to_sign))
+}
+
+// ETags come quoted, and XML escaped in listings
+fn clean_etag(etag: &str) -> String {
+    etag.replace("&quot;", "").replace('"', "")
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Text of every <tag> element in `xml`, in order. Listings are flat enough not to need a real parser
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    xml_values(xml, tag).into_iter().next()
+}
+
+// The objects of one ListObjectsV2 page under `prefix`, and the token of the next page if it was truncated
+fn parse_listing(xml: &str, prefix: &str) -> (Vec<RemoteObject>, Option<String>) {
+    let mut objects = Vec::new();
+    for contents in xml_values(xml, "Contents") {
+        let (Some(key), Some(etag)) = (xml_value(contents, "Key"), xml_value(contents, "ETag"))
+        else {
+            continue;
+        };
+        let key = xml_unescape(key);
+        if let Some(key) = key.strip_prefix(prefix) {
+            objects.push(RemoteObject {
+                key: key.to_string(),
+                etag: clean_etag(etag),
+            });
+        }
+    }
+    let next = match xml_value(xml, "NextContinuationToken") {
+        Some(next) if xml_value(xml, "IsTruncated") == Some("true") => Some(xml_unescape(next)),
+        _ => None,
+    };
+    (objects, next)
+}
+
+impl Bucket {
+    /// The bucket configured as `remote`, with the credentials from the environment
+    pub fn from_conf(conf: &Conf) -> Result<Bucket, ClenvError> {
+        let remote = conf.get("remote").ok_or_else(|| {
+            ClenvError::Config(
+                "No remote set. Point one at a bucket with 'clenv cfg remote https://host/bucket/prefix'"
+                    .to_string(),
+            )
+        })?;
+        let credential = |name: &str| {
+            std::env::var(name).map_err(|_| {
+                ClenvError::Config(format!("Set {} to the credentials of the remote", name))
+            })
+        };
+        let region = conf
+            .get("remote_region")
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .unwrap_or_else(|| "us-east-1".to_string());
+        Self::new(
+            &remote,
+            &region,
+            &credential("AWS_ACCESS_KEY_ID")?,
+            &credential("AWS_SECRET_ACCESS_KEY")?,
+            std::env::var("AWS_SESSION_TOKEN").ok(),
+        )
+    }
+
+    /// `remote` is "http(s)://host[:port]/bucket[/prefix]"
+    pub fn new(
+        remote: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+        session_token: Option<String>,
+    ) -> Result<Bucket, ClenvError> {
+        let invalid = || {
+            ClenvError::Config(format!(
+                "The remote '{}' should look like https://host/bucket/prefix",
+                remote
+            ))
+        };
+        let (scheme, rest) = remote.split_once("://").ok_or_else(invalid)?;
+        let default_port = match scheme {
+            "https" => ":443",
+            "http" => ":80",
+            _ => return Err(invalid()),
+        };
+        let (host, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if host.is_empty() || bucket.is_empty() {
+            return Err(invalid());
+        }
+        // The Host header leaves out default ports and the signature has to match it
+        let host = host.strip_suffix(default_port).unwrap_or(host);
+        let prefix = prefix.trim_matches('/');
+
+        Ok(Bucket {
+            endpoint: format!("{}://{}", scheme, host),
+            host: host.to_string(),
+            bucket: bucket.to_string(),
+            prefix: match prefix.is_empty() {
+                true => String::new(),
+                false => format!("{}/", prefix),
+            },
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            session_token,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(60))
+                .build(),
+        })
+    }
+
+    // Signs and sends a request for `key`, or for the bucket itself when None
+    #[allow(clippy::result_large_err)]
+    fn send(
+        &self,
+        method: &str,
+        key: Option<&str>,
+        query: &[(&str, String)],
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, ureq::Error> {
+        let path = match key {
+            Some(key) => format!("/{}/{}{}", self.bucket, self.prefix, key),
+            None => format!("/{}", self.bucket),
+        };
+        let path = uri_encode(&path, true);
+        let query = canonical_query(query);
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let (date, timestamp) = amz_date(secs);
+        let payload_hash = sha256_hex(body);
+
+        let mut signed = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            signed.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_request = canonical_request(method, &path, &query, &signed, &payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signature = signature(
+            &self.secret_key,
+            &date,
+            &self.region,
+            &string_to_sign(&timestamp, &scope, &canonical_request),
+        );
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query),
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        for (name, value) in signed.iter().skip(1).chain(headers.iter()) {
+            request = request.set(name, value);
+        }
+        match method {
+            "PUT" => request.send_bytes(body),
+            _ => request.call(),
+        }
+    }
+}
+
+impl ObjectStore for Bucket {
+    fn list(&self) -> Result<Vec<RemoteObject>, ClenvError> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_string())];
+            if !self.prefix.is_empty() {
+                query.push(("prefix", self.prefix.clone()));
+            }
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.clone()));
+            }
+            let response = self
+                .send("GET", None, &query, &[], &[])
+                .map_err(|e| remote_err("Could not list the remote", e))?;
+            let xml = response
+                .into_string()
+                .map_err(|e| remote_err("Could not read the remote listing", e))?;
+
+            let (page, next) = parse_listing(&xml, &self.prefix);
+            objects.extend(page);
+            match next {
+                Some(next) => token = Some(next),
+                None => return Ok(objects),
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, ClenvError> {
+        match self.send("GET", Some(key), &[], &[], &[]) {
+            Ok(response) => {
+                let etag = clean_etag(response.header("ETag").unwrap_or_default());
+                let mut body = Vec::new();
+                response
+                    .into_reader()
+                    .read_to_end(&mut body)
+                    .map_err(|e| remote_err(&format!("Could not read {}", key), e))?;
+                Ok(Some((body, etag)))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(remote_err(&format!("Could not read {}", key), e)),
+        }
+    }
+
+    fn put(
+        &self,
+        key: &str,
+        body: &[u8],
+        expected: Option<&str>,
+    ) -> Result<Option<String>, ClenvError> {
+        let condition = match expected {
+            Some(etag) => ("If-Match", format!("\"{}\"", etag)),
+            None => ("If-None-Match", "*".to_string()),
+        };
+        match self.send("PUT", Some(key), &[], &[condition], body) {
+            Ok(response) => Ok(Some(clean_etag(
+                response.header("ETag").unwrap_or_default(),
+            ))),
+            Err(ureq::Error::Status(409 | 412, _)) => Ok(None),
+            Err(e) => Err(remote_err(&format!("Could not write {}", key), e)),
+        }
+    }
+
+    fn force_put(&self, key: &str, body: &[u8]) -> Result<String, ClenvError> {
+        let response = self
+            .send("PUT", Some(key), &[], &[], body)
+            .map_err(|e| remote_err(&format!("Could not write {}", key), e))?;
+        Ok(clean_etag(response.header("ETag").unwrap_or_default()))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ClenvError> {
+        match self.send("DELETE", Some(key), &[], &[], &[]) {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(e) => Err(remote_err(&format!("Could not delete {}", key), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples of the S3 documentation for signing requests with a single chunk payload
+    // (https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html)
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+    const HOST: &str = "examplebucket.s3.amazonaws.com";
+    const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    // 2013-05-24T00:00:00Z
+    const EXAMPLE_TIME: u64 = 1_369_353_600;
+
+    fn example_headers(extra: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        let (_, timestamp) = amz_date(EXAMPLE_TIME);
+        let mut headers = vec![("host", HOST.to_string())];
+        headers.extend(extra.iter().map(|(name, value)| (*name, value.to_string())));
+        headers.push(("x-amz-content-sha256", EMPTY_HASH.to_string()));
+        headers.push(("x-amz-date", timestamp));
+        headers
+    }
+
+    fn sign_example(canonical_request: &str) -> (String, String) {
+        let (date, timestamp) = amz_date(EXAMPLE_TIME);
+        let to_sign = string_to_sign(
+            &timestamp,
+            &format!("{}/us-east-1/s3/aws4_request", date),
+            canonical_request,
+        );
+        let signature = signature(SECRET_KEY, &date, "us-east-1", &to_sign);
+        (to_sign, signature)
+    }
+
+    #[test]
+    fn signs_the_get_object_example() {
+        let headers = example_headers(&[("range", "bytes=0-9")]);
+        let canonical = canonical_request("GET", "/test.txt", "", &headers, EMPTY_HASH);
+        assert_eq!(
+            canonical,
+            "GET\n/test.txt\n\n\
+             host:examplebucket.s3.amazonaws.com\n\
+             range:bytes=0-9\n\
+             x-amz-content-sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\
+             x-amz-date:20130524T000000Z\n\n\
+             host;range;x-amz-content-sha256;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let (to_sign, signature) = sign_example(&canonical);
+        assert_eq!(
+            to_sign,
+            "AWS4-HMAC-SHA256\n20130524T000000Z\n20130524/us-east-1/s3/aws4_request\n\
+             7344ae5b7ee6c3e7e6b0fe0640412a37625d1fbfff95c48bbb2dc43964946972"
+        );
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn signs_the_list_objects_example() {
+        let query = canonical_query(&[("prefix", "J".to_string()), ("max-keys", "2".to_string())]);
+        assert_eq!(query, "max-keys=2&prefix=J");
+        let canonical = canonical_request("GET", "/", &query, &example_headers(&[]), EMPTY_HASH);
+        let (to_sign, signature) = sign_example(&canonical);
+        assert!(
+            to_sign.ends_with("df57d21db20da04d7fa30298dd4488ba3a2b47ca3a489c74750e0f1e7df1b9b7")
+        );
+        assert_eq!(
+            signature,
+            "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7"
+        );
+    }
+
+    #[test]
+    fn encodes_reserved_characters() {
+        assert_eq!(uri_encode("AZaz09-_.~", false), "AZaz09-_.~");
+        assert_eq!(
+            uri_encode("a b+c=d&e/f%g*h", false),
+            "a%20b%2Bc%3Dd%26e%2Ff%25g%2Ah"
+        );
+        assert_eq!(uri_encode("/bucket/a b/c", true), "/bucket/a%20b/c");
+        assert_eq!(uri_encode("é", false), "%C3%A9");
+        assert_eq!(
+            canonical_query(&[("continuation-token", "1/a+b=".to_string())]),
+            "continuation-token=1%2Fa%2Bb%3D"
+        );
+    }
+
+    #[test]
+    fn unescapes_xml() {
+        assert_eq!(
+            xml_unescape("a &lt;b&gt; &quot;c&quot; &apos;d&apos; &amp;"),
+            "a <b> \"c\" 'd' &"
+        );
+        // "&amp;" is replaced last, so escaped entities stay as they were meant
+        assert_eq!(xml_unescape("&amp;lt;"), "&lt;");
+        assert_eq!(clean_etag("&quot;abc&quot;"), "abc");
+        assert_eq!(clean_etag("\"abc\""), "abc");
+    }
+
+    #[test]
+    fn parses_a_truncated_listing() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name>
+  <Prefix>team/</Prefix>
+  <KeyCount>3</KeyCount>
+  <MaxKeys>3</MaxKeys>
+  <IsTruncated>true</IsTruncated>
+  <Contents><Key>team/keyring/alice</Key><ETag>&quot;111&quot;</ETag><Size>10</Size></Contents>
+  <Contents><Key>team/dev/a&amp;b</Key><ETag>"222"</ETag><Size>20</Size></Contents>
+  <Contents><Key>other/x</Key><ETag>"333"</ETag><Size>30</Size></Contents>
+  <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=&amp;</NextContinuationToken>
+</ListBucketResult>"#;
+        let (objects, next) = parse_listing(xml, "team/");
+        let objects: Vec<_> = objects
+            .iter()
+            .map(|object| (object.key.as_str(), object.etag.as_str()))
+            .collect();
+        assert_eq!(objects, [("keyring/alice", "111"), ("dev/a&b", "222")]);
+        assert_eq!(
+            next.as_deref(),
+            Some("1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=&")
+        );
+
+        let last = xml.replace("<IsTruncated>true", "<IsTruncated>false");
+        assert_eq!(parse_listing(&last, "team/").1, None);
+    }
+
+    #[test]
+    fn splits_the_remote() {
+        let bucket = Bucket::new(
+            "https://s3.example.com:443/bucket/a/b/",
+            "eu",
+            "id",
+            "secret",
+            None,
+        )
+        .unwrap();
+        assert_eq!(bucket.endpoint, "https://s3.example.com");
+        assert_eq!(bucket.host, "s3.example.com");
+        assert_eq!(bucket.bucket, "bucket");
+        assert_eq!(bucket.prefix, "a/b/");
+        assert!(Bucket::new("s3.example.com/bucket", "eu", "id", "secret", None).is_err());
+        assert!(Bucket::new("https://s3.example.com", "eu", "id", "secret", None).is_err());
+    }
+}
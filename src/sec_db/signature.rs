@@ -0,0 +1,558 @@
+use super::handle_db::{EncryptedEntry, EntryKind, SecDb};
+use super::i_keys::{CryptoError, i_keys};
+use super::migrate;
+use super::suite::{PrivateKey, PublicKey};
+use crate::error::ClenvError;
+use serde::{Deserialize, Serialize};
+
+// Every write signs the entry with the writer's private key (see `PrivateKey::sign`: RSA-PSS for RSA keys,
+// Ed25519 with the key derived from X25519 keys), so readers can check it against the writer's public key
+// in the keyring. The signature covers the ciphertext and everything shown about
+// the entry, but not the wrapped data keys, which change whenever a user is added or removed, and not the
+// sealed metadata of obfuscated databases, which is re-encrypted when the index key rotates.
+// Name and extension are covered by their plain values instead. Variable names of dotenv entries are
+// only covered as part of their sealed line.
+// Entries are signed on every write from format 2 on. The format is part of what an entry's values are sealed
+// with (see `entry_aad`), so an unsigned entry of a later format had its signature removed, and passing it off
+// as an older format breaks its seal.
+pub const SIGNED_FORMAT: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntrySignature {
+    pub signer: String,
+    // `i_keys::key_id` of the key the signature was made with
+    pub key_id: String,
+    pub signature: Vec<u8>,
+}
+
+/// What checking an entry's signature against the keyring found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum SignatureStatus {
+    Valid { signer: String },
+    // Stored before entries were signed
+    Unsigned,
+    // Signed when it was written, the signature was removed since
+    Stripped,
+    // The signer has been removed from the keyring
+    UnknownSigner { signer: String },
+    // The signer's key in the keyring was replaced after they signed
+    KeyChanged { signer: String },
+    // The signer is in the keyring as an age recipient, which has no key to check signatures with
+    NoSigningKey { signer: String },
+    // The entry was changed after it was signed
+    Invalid { signer: String },
+}
+
+impl SignatureStatus {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, SignatureStatus::Valid { .. })
+    }
+
+    /// Whether the entry can be read: it was signed by a keyring member or stored before entries were signed.
+    /// Databases refuse unsigned entries as well once all of their entries are signed, see `SecDb::refuses_unsigned`
+    pub fn is_trusted(&self) -> bool {
+        self.distrust().is_none()
+    }
+
+    // Why an entry with this status can't be trusted, None when it can
+    pub(super) fn distrust(&self) -> Option<String> {
+        match self {
+            SignatureStatus::Valid { .. } | SignatureStatus::Unsigned => None,
+            SignatureStatus::Stripped => Some("had its signature removed".to_string()),
+            SignatureStatus::UnknownSigner { signer } => Some(format!(
+                "was signed by {}, who isn't in the keyring",
+                signer
+            )),
+            SignatureStatus::KeyChanged { signer } => Some(format!(
+                "was signed by {} with another key than the one they have in the keyring",
+                signer
+            )),
+            SignatureStatus::NoSigningKey { signer } => Some(format!(
+                "was signed by {}, who is in the keyring as an age recipient only",
+                signer
+            )),
+            SignatureStatus::Invalid { signer } => {
+                Some(format!("was changed after {} signed it", signer))
+            }
+        }
+    }
+}
+
+/// Signature status of one entry, as listed by `verify`
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    pub namespace: String,
+    pub entry: String,
+    #[serde(flatten)]
+    pub status: SignatureStatus,
+}
+
+// Length prefixed, so no two entries give the same message
+fn push_part(message: &mut Vec<u8>, part: &[u8]) {
+    message.extend_from_slice(&(part.len() as u64).to_be_bytes());
+    message.extend_from_slice(part);
+}
+
+impl EncryptedEntry {
+    // What the signature is made over. Like `associated_data` this needs the real extension
+    fn signed_message(&self, ns: &str, name: &str, signer: &str, key_id: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        for part in [ns, name, &self.extension, signer, key_id, &self.author] {
+            push_part(&mut message, part.as_bytes());
+        }
+        for number in [u64::from(self.format), self.revision, self.stored_at] {
+            push_part(&mut message, &number.to_be_bytes());
+        }
+        push_part(&mut message, self.suite.name().as_bytes());
+        push_part(&mut message, self.compression.to_string().as_bytes());
+
+        match &self.kind {
+            EntryKind::Blob => {
+                push_part(&mut message, b"blob");
+                push_part(&mut message, &self.nonce);
+                push_part(&mut message, &self.ciphertext);
+            }
+            EntryKind::Dotenv(lines) => {
+                push_part(&mut message, b"dotenv");
+                for line in lines {
+                    push_part(&mut message, &line.nonce);
+                    push_part(&mut message, &line.ciphertext);
+                }
+            }
+            // The chunks themselves are covered by their digests
+            EntryKind::Chunked(blob) => {
+                push_part(&mut message, b"chunked");
+                push_part(&mut message, &blob.size.to_be_bytes());
+                for digest in &blob.digests {
+                    push_part(&mut message, digest);
+                }
+            }
+        }
+        message
+    }
+
+    /// Signs the entry as `signer`, replacing any earlier signature
+    pub fn sign(
+        &mut self,
+        ns: &str,
+        name: &str,
+        signer: &str,
+        private_key: &PrivateKey,
+    ) -> Result<(), CryptoError> {
+        let key_id = i_keys::key_id(&private_key.public_key())?;
+        let message = self.signed_message(ns, name, signer, &key_id);
+        self.signature = Some(EntrySignature {
+            signer: signer.to_string(),
+            key_id,
+            signature: private_key.sign(&message)?,
+        });
+        Ok(())
+    }
+
+    /// Checks the signature of an entry with its metadata opened against `signer_key`, the key the
+    /// signer has in the keyring, None when they aren't in it
+    pub fn signature_status(
+        &self,
+        ns: &str,
+        name: &str,
+        signer_key: Option<&PublicKey>,
+    ) -> Result<SignatureStatus, CryptoError> {
+        let Some(signature) = &self.signature else {
+            return match self.format >= SIGNED_FORMAT {
+                true => Ok(SignatureStatus::Stripped),
+                false => Ok(SignatureStatus::Unsigned),
+            };
+        };
+        let signer = signature.signer.clone();
+        let Some(public_key) = signer_key else {
+            return Ok(SignatureStatus::UnknownSigner { signer });
+        };
+        if i_keys::key_id(public_key)? != signature.key_id {
+            return Ok(SignatureStatus::KeyChanged { signer });
+        }
+
+        if let PublicKey::X25519 { signing: None, .. } = public_key {
+            return Ok(SignatureStatus::NoSigningKey { signer });
+        }
+
+        let message = self.signed_message(ns, name, &signature.signer, &signature.key_id);
+        if public_key.verify(&message, &signature.signature) {
+            Ok(SignatureStatus::Valid { signer })
+        } else {
+            Ok(SignatureStatus::Invalid { signer })
+        }
+    }
+}
+
+impl SecDb {
+    pub(super) fn sign_entry(
+        &self,
+        ns: &str,
+        name: &str,
+        entry: &mut EncryptedEntry,
+    ) -> Result<(), ClenvError> {
+        let my_name = self.conf.require("name")?;
+        // The agent signs without handing out the key, it tells us the key's id for the signed message
+        #[cfg(unix)]
+        if let Some(agent) = &self.agent
+            && let Some(key_id) = agent.key_id()?
+        {
+            let message = entry.signed_message(ns, name, &my_name, &key_id);
+            if let Some(signature) = agent.sign(&message)? {
+                entry.signature = Some(EntrySignature {
+                    signer: my_name,
+                    key_id,
+                    signature,
+                });
+                return Ok(());
+            }
+        }
+        Ok(entry.sign(ns, name, &my_name, &self.private_key()?)?)
+    }
+
+    // Changes the sealed values of an entry through `change` and signs the result in our name.
+    // Signing vouches for the contents, so an entry whose signature can't be trusted, or that doesn't open
+    // under its own namespace and name, is left as it was. With `vouch` entries whose signer can't be
+    // checked are signed anew as well, only the ones that were changed or lost their signature are left.
+    // Returns whether the entry was changed.
+    pub(super) fn resign_with(
+        &self,
+        ns: &str,
+        name: &str,
+        entry: &mut EncryptedEntry,
+        vouch: bool,
+        change: impl FnOnce(&mut EncryptedEntry) -> Result<(), ClenvError>,
+    ) -> Result<bool, ClenvError> {
+        match self.signature_status(ns, name, entry)? {
+            SignatureStatus::Invalid { .. } | SignatureStatus::Stripped => return Ok(false),
+            SignatureStatus::Unsigned if self.refuses_unsigned()? => return Ok(false),
+            status if !vouch && !status.is_trusted() => return Ok(false),
+            _ => {}
+        }
+        let mut changed = entry.clone();
+        match change(&mut changed) {
+            Ok(()) => {}
+            Err(ClenvError::Crypto(CryptoError::Aes(_))) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        if let Err(e) = self.sign_entry(ns, name, &mut changed) {
+            // Chunks written for the changed entry would never be pointed to
+            if let Some(blob) = changed.chunked()
+                && changed.chunked() != entry.chunked()
+            {
+                let _ = self.chunk_store().delete(blob);
+            }
+            return Err(e);
+        }
+        *entry = changed;
+        Ok(true)
+    }
+
+    // Checks the signature of an entry with its metadata opened against the signer's key in the keyring
+    pub(super) fn signature_status(
+        &self,
+        ns: &str,
+        name: &str,
+        entry: &EncryptedEntry,
+    ) -> Result<SignatureStatus, ClenvError> {
+        let signer_key = match &entry.signature {
+            Some(signature) => self.public_key_of(&signature.signer)?,
+            None => None,
+        };
+        Ok(entry.signature_status(ns, name, signer_key.as_ref())?)
+    }
+
+    /// Whether unsigned entries are refused: every entry of the database was brought to a signed format by
+    /// `reseal` or `migrate`, or it was created signed, and unsigned entries weren't allowed in `conf`
+    /// with `allow_unsigned` (the `--allow-unsigned` option)
+    pub fn refuses_unsigned(&self) -> Result<bool, ClenvError> {
+        let allowed = self.conf.get("allow_unsigned").is_some_and(|v| v == "true");
+        Ok(!allowed && migrate::all_signed(self.db.as_ref())?)
+    }
+
+    // Why an entry with this status can't be trusted in this database, None when it can
+    pub(super) fn distrust(&self, status: &SignatureStatus) -> Result<Option<String>, ClenvError> {
+        if *status == SignatureStatus::Unsigned && self.refuses_unsigned()? {
+            return Ok(Some(
+                "isn't signed, though every entry of the database is".to_string(),
+            ));
+        }
+        Ok(status.distrust())
+    }
+
+    // Refuses entries whose signature can't be trusted: changed after they were signed, stripped of their
+    // signature, or signed by someone we can't check. Unsigned entries of older formats are up to the caller
+    // to warn about, as long as the database still has them
+    pub(super) fn check_signature(
+        &self,
+        ns: &str,
+        name: &str,
+        entry: &EncryptedEntry,
+    ) -> Result<SignatureStatus, ClenvError> {
+        let status = self.signature_status(ns, name, entry)?;
+        let Some(reason) = self.distrust(&status)? else {
+            return Ok(status);
+        };
+        let hint = match status {
+            SignatureStatus::Invalid { .. } | SignatureStatus::Stripped => "",
+            SignatureStatus::Unsigned => {
+                ". If you know it is right, 'clenv --allow-unsigned reseal' signs it in your name"
+            }
+            _ => ". If you know it is right, 'clenv reseal' signs it in your name",
+        };
+        Err(ClenvError::Integrity(format!(
+            "{}/{} {}. Don't trust it, 'clenv history {}' lists earlier revisions{}",
+            ns, name, reason, name, hint
+        )))
+    }
+
+    // Storing a changed or restored entry signs it in our name, vouching for everything in it we didn't
+    // write. Unsigned entries are refused, so they can't be passed off as ours without 'clenv reseal'
+    pub(super) fn check_resign(
+        &self,
+        ns: &str,
+        name: &str,
+        entry: &EncryptedEntry,
+    ) -> Result<(), ClenvError> {
+        if self.check_signature(ns, name, entry)?.is_valid() {
+            return Ok(());
+        }
+        Err(ClenvError::Integrity(format!(
+            "{}/{} isn't signed, so it isn't stored again in your name. Check it and sign it with 'clenv reseal' first",
+            ns, name
+        )))
+    }
+
+    /// Checks who signed an entry and that it wasn't changed since
+    pub fn verify_entry(&self, ns: &str, name: &str) -> Result<SignatureStatus, ClenvError> {
+        let entry = self.entry(ns, name)?;
+        self.signature_status(ns, name, &entry)
+    }
+
+    /// Checks the signature of every entry in the given namespaces
+    pub fn verify(&self, namespaces: &[String]) -> Result<Vec<Verification>, ClenvError> {
+        let mut verifications = Vec::new();
+        for ns in namespaces {
+            for name in self.list_entries(ns)? {
+                verifications.push(Verification {
+                    namespace: ns.clone(),
+                    status: self.verify_entry(ns, &name)?,
+                    entry: name,
+                });
+            }
+        }
+        Ok(verifications)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::suite::Suite;
+    use crate::sec_db::testing::{SUITES, TempDir, open_db_with, put_raw};
+
+    fn refused(db: &SecDb, name: &str) -> bool {
+        matches!(db.read_entry(name), Err(ClenvError::Integrity(_)))
+    }
+
+    // A database of `suite` with an entry a.txt alice stored
+    fn stored(tmp: &TempDir, suite: Suite) -> SecDb {
+        let mut db = open_db_with(tmp, "db", "alice", suite);
+        db.store("a.txt", b"secret", "txt", false).unwrap();
+        db
+    }
+
+    #[test]
+    fn signed_entries_read() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let db = stored(&tmp, suite);
+            let entry = db.entry("dev", "a.txt").unwrap();
+            assert!(entry.format >= SIGNED_FORMAT);
+            assert_eq!(entry.suite, suite);
+            let status = db.verify_entry("dev", "a.txt").unwrap();
+            assert_eq!(
+                status,
+                SignatureStatus::Valid {
+                    signer: "alice".to_string()
+                }
+            );
+            assert_eq!(db.read_entry("a.txt").unwrap(), b"secret");
+        }
+    }
+
+    #[test]
+    fn stripped_signature_is_refused() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let db = stored(&tmp, suite);
+            let mut entry = db.entry("dev", "a.txt").unwrap();
+            entry.signature = None;
+            put_raw(&db, "dev", "a.txt", entry.clone());
+            assert_eq!(
+                db.verify_entry("dev", "a.txt").unwrap(),
+                SignatureStatus::Stripped
+            );
+            assert!(refused(&db, "a.txt"));
+            // Resealing doesn't sign it again either
+            assert_eq!(
+                db.reseal(&["dev".to_string()]).unwrap()[0].invalid,
+                ["a.txt"]
+            );
+
+            // Passing it off as an entry from before signing breaks its seal
+            entry.format = 0;
+            put_raw(&db, "dev", "a.txt", entry);
+            assert_eq!(
+                db.verify_entry("dev", "a.txt").unwrap(),
+                SignatureStatus::Unsigned
+            );
+            assert!(refused(&db, "a.txt"));
+        }
+    }
+
+    #[test]
+    fn forged_signatures_are_refused() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let db = stored(&tmp, suite);
+            let entry = db.entry("dev", "a.txt").unwrap();
+            let mallory = PrivateKey::generate(suite).unwrap();
+
+            // In someone else's name, with a key that isn't theirs
+            let mut forged = entry.clone();
+            forged.sign("dev", "a.txt", "alice", &mallory).unwrap();
+            put_raw(&db, "dev", "a.txt", forged);
+            let status = db.verify_entry("dev", "a.txt").unwrap();
+            assert!(matches!(status, SignatureStatus::KeyChanged { .. }));
+            assert!(refused(&db, "a.txt"));
+
+            // In a name that isn't in the keyring
+            let mut forged = entry.clone();
+            forged.sign("dev", "a.txt", "mallory", &mallory).unwrap();
+            put_raw(&db, "dev", "a.txt", forged);
+            let status = db.verify_entry("dev", "a.txt").unwrap();
+            assert!(matches!(status, SignatureStatus::UnknownSigner { .. }));
+            assert!(refused(&db, "a.txt"));
+
+            // Changed after it was signed: the ciphertext, the time it was written or its suite
+            let mut changed = entry.clone();
+            changed.stored_at += 1;
+            let mut flipped = entry.clone();
+            flipped.ciphertext[0] ^= 1;
+            let mut other_suite = entry;
+            other_suite.suite = match suite {
+                Suite::Rsa => Suite::X25519,
+                Suite::X25519 => Suite::Rsa,
+            };
+            for changed in [changed, flipped, other_suite] {
+                put_raw(&db, "dev", "a.txt", changed);
+                let status = db.verify_entry("dev", "a.txt").unwrap();
+                assert!(matches!(status, SignatureStatus::Invalid { .. }));
+                assert!(refused(&db, "a.txt"));
+            }
+        }
+    }
+
+    // A keyring member who checked an entry of someone who left can sign it in their name
+    #[test]
+    fn reseal_vouches_for_unknown_signers() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let db = stored(&tmp, suite);
+            let mut entry = db.entry("dev", "a.txt").unwrap();
+            let bob = PrivateKey::generate(suite).unwrap();
+            entry.sign("dev", "a.txt", "bob", &bob).unwrap();
+            put_raw(&db, "dev", "a.txt", entry);
+            assert!(refused(&db, "a.txt"));
+
+            assert_eq!(
+                db.reseal(&["dev".to_string()]).unwrap()[0].updated,
+                ["a.txt"]
+            );
+            assert_eq!(db.read_entry("a.txt").unwrap(), b"secret");
+        }
+    }
+
+    // Stores the entry again the way a version from before signing would have, as anyone who can write to
+    // the database files could
+    fn unsign(db: &SecDb, name: &str) {
+        let mut entry = db.entry("dev", name).unwrap();
+        let aes_key = db.unwrap_own(&entry.encrypted_keys["alice"]).unwrap();
+        let aad = entry.associated_data("dev", name);
+        let suite = entry.suite;
+        let unbind = |ciphertext: &mut Vec<u8>, nonce: &mut [u8; 12]| {
+            let plain = suite.open(&aes_key, ciphertext, nonce, &aad).unwrap();
+            (*ciphertext, *nonce) = suite.seal(&aes_key, &plain, &[]).unwrap();
+        };
+        match &mut entry.kind {
+            EntryKind::Dotenv(lines) => {
+                for line in lines {
+                    unbind(&mut line.ciphertext, &mut line.nonce);
+                }
+            }
+            _ => unbind(&mut entry.ciphertext, &mut entry.nonce),
+        }
+        entry.format = 0;
+        entry.signature = None;
+        put_raw(db, "dev", name, entry);
+    }
+
+    #[test]
+    fn unsigned_entries_are_refused_once_all_are_signed() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let mut db = stored(&tmp, suite);
+            assert!(db.refuses_unsigned().unwrap());
+            unsign(&db, "a.txt");
+            assert_eq!(
+                db.verify_entry("dev", "a.txt").unwrap(),
+                SignatureStatus::Unsigned
+            );
+            assert!(refused(&db, "a.txt"));
+            assert_eq!(
+                db.reseal(&["dev".to_string()]).unwrap()[0].invalid,
+                ["a.txt"]
+            );
+
+            // Unless they are allowed, to check them and sign them in our name
+            db.conf.insert("allow_unsigned", "true");
+            assert!(!db.refuses_unsigned().unwrap());
+            assert_eq!(db.read_entry("a.txt").unwrap(), b"secret");
+            assert_eq!(
+                db.reseal(&["dev".to_string()]).unwrap()[0].updated,
+                ["a.txt"]
+            );
+            db.conf.insert("allow_unsigned", "false");
+            assert!(db.verify_entry("dev", "a.txt").unwrap().is_valid());
+            assert_eq!(db.read_entry("a.txt").unwrap(), b"secret");
+        }
+    }
+
+    // Changing or restoring an entry signs it in our name, which mustn't make an unsigned one look checked
+    #[test]
+    fn unsigned_entries_arent_signed_on_write() {
+        for suite in SUITES {
+            let tmp = TempDir::new();
+            let mut db = stored(&tmp, suite);
+            db.conf.insert("allow_unsigned", "true");
+            db.store(".env", b"A=1\n", "env", true).unwrap();
+            unsign(&db, ".env");
+            assert_eq!(db.get_var(".env", "A").unwrap(), "1");
+            assert!(matches!(
+                db.set_var(".env", "A", "2"),
+                Err(ClenvError::Integrity(_))
+            ));
+
+            unsign(&db, "a.txt");
+            db.store("a.txt", b"newer", "txt", false).unwrap();
+            assert!(matches!(
+                db.rollback("a.txt", 1),
+                Err(ClenvError::Integrity(_))
+            ));
+            assert_eq!(db.read_entry("a.txt").unwrap(), b"newer");
+        }
+    }
+}
@@ -0,0 +1,524 @@
+use super::handle_db::RawRecord;
+use crate::config::config::Config as Conf;
+use crate::error::ClenvError;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, DB, Direction, IteratorMode, Options};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Where `SecDb` keeps its values. A store holds namespaces of sorted key/value records: the namespaces
+// users see, plus the keyring, history and the other ones clenv uses itself, which RocksDB calls column
+// families. Everything stored is already encrypted, a store only has to keep the bytes it is given.
+// RocksDB is the default. The directory store keeps every record in a file of its own, so a database
+// can live in a git repository and changes to it can be reviewed and merged like any other file.
+// Pick one with "clenv cfg backend rocksdb|directory" before the database is created, existing databases
+// are opened with the backend they were created with.
+
+pub type Records<'a> = Box<dyn Iterator<Item = Result<RawRecord, ClenvError>> + 'a>;
+
+pub trait Store {
+    /// Every namespace of the store, clenv's own included
+    fn namespaces(&self) -> Result<Vec<String>, ClenvError>;
+    fn has_namespace(&self, ns: &str) -> bool;
+    /// Creates the namespace unless it exists
+    fn create_namespace(&mut self, ns: &str) -> Result<(), ClenvError>;
+    fn drop_namespace(&mut self, ns: &str) -> Result<(), ClenvError>;
+
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Option<Vec<u8>>, ClenvError>;
+    fn put(&self, ns: &str, key: &[u8], value: &[u8]) -> Result<(), ClenvError>;
+    /// Deleting a key that isn't there is not an error
+    fn delete(&self, ns: &str, key: &[u8]) -> Result<(), ClenvError>;
+    /// The records of a namespace whose key starts with `prefix`, ordered by key byte by byte
+    fn scan<'a>(&'a self, ns: &str, prefix: &[u8]) -> Result<Records<'a>, ClenvError>;
+
+    /// Every record of a namespace, ordered by key
+    fn iter<'a>(&'a self, ns: &str) -> Result<Records<'a>, ClenvError> {
+        self.scan(ns, b"")
+    }
+
+    /// Copies the whole store to `path`, which must not exist yet
+    fn checkpoint(&self, path: &Path) -> Result<(), ClenvError>;
+}
+
+/// Opens the database at `db`. Existing databases are opened with the backend they were created with,
+/// new ones are created with the configured backend
+pub fn open(conf: &Conf) -> Result<Box<dyn Store>, ClenvError> {
+    let path = conf.require("db")?;
+    let backend = match detect(Path::new(&path)) {
+        Some(backend) => backend.to_string(),
+        None => conf.get("backend").unwrap_or_else(|| "rocksdb".to_string()),
+    };
+    match backend.as_str() {
+        "rocksdb" => Ok(Box::new(RocksStore::open(&path)?)),
+        "directory" => Ok(Box::new(DirStore::open(Path::new(&path))?)),
+        other => Err(ClenvError::Config(format!(
+            "Unknown backend '{}', use rocksdb or directory",
+            other
+        ))),
+    }
+}
+
+/// Whether `path` holds a database of either backend
+pub fn exists(path: &Path) -> bool {
+    detect(path).is_some()
+}
+
+// RocksDB always writes a CURRENT file, a directory store has a folder for the keyring
+fn detect(path: &Path) -> Option<&'static str> {
+    if path.join("CURRENT").is_file() {
+        return Some("rocksdb");
+    }
+    path.join("keyring").is_dir().then_some("directory")
+}
+
+fn missing(ns: &str) -> ClenvError {
+    ClenvError::NotFound(format!("Namespace {} does not exist", ns))
+}
+
+/// A RocksDB database with a column family per namespace
+pub struct RocksStore {
+    db: DB,
+    path: String,
+}
+
+impl RocksStore {
+    pub fn open(path: &str) -> Result<Self, ClenvError> {
+        let mut db_opts = Options::default();
+        db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
+
+        // Every column family has to be opened along with the database
+        let db = match Path::new(path).exists() {
+            true => {
+                let cf_descriptors = DB::list_cf(&db_opts, path)?
+                    .iter()
+                    .map(|name| ColumnFamilyDescriptor::new(name, Options::default()))
+                    .collect::<Vec<_>>();
+                DB::open_cf_descriptors(&db_opts, path, cf_descriptors)?
+            }
+            false => DB::open(&db_opts, path)?,
+        };
+        Ok(RocksStore {
+            db,
+            path: path.to_string(),
+        })
+    }
+
+    fn cf(&self, ns: &str) -> Result<&ColumnFamily, ClenvError> {
+        self.db.cf_handle(ns).ok_or_else(|| missing(ns))
+    }
+}
+
+impl Store for RocksStore {
+    fn namespaces(&self) -> Result<Vec<String>, ClenvError> {
+        Ok(DB::list_cf(&Options::default(), &self.path)?)
+    }
+
+    fn has_namespace(&self, ns: &str) -> bool {
+        self.db.cf_handle(ns).is_some()
+    }
+
+    fn create_namespace(&mut self, ns: &str) -> Result<(), ClenvError> {
+        if !self.has_namespace(ns) {
+            self.db.create_cf(ns, &Options::default())?;
+        }
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self, ns: &str) -> Result<(), ClenvError> {
+        Ok(self.db.drop_cf(ns)?)
+    }
+
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Option<Vec<u8>>, ClenvError> {
+        Ok(self.db.get_cf(self.cf(ns)?, key)?)
+    }
+
+    fn put(&self, ns: &str, key: &[u8], value: &[u8]) -> Result<(), ClenvError> {
+        Ok(self.db.put_cf(self.cf(ns)?, key, value)?)
+    }
+
+    fn delete(&self, ns: &str, key: &[u8]) -> Result<(), ClenvError> {
+        Ok(self.db.delete_cf(self.cf(ns)?, key)?)
+    }
+
+    fn scan<'a>(&'a self, ns: &str, prefix: &[u8]) -> Result<Records<'a>, ClenvError> {
+        let prefix = prefix.to_vec();
+        let records = self
+            .db
+            .iterator_cf(
+                self.cf(ns)?,
+                IteratorMode::From(&prefix, Direction::Forward),
+            )
+            .map(|item| {
+                item.map(|(key, value)| (key.to_vec(), value.to_vec()))
+                    .map_err(ClenvError::from)
+            })
+            .take_while(move |item| {
+                item.as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            });
+        Ok(Box::new(records))
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), ClenvError> {
+        Ok(Checkpoint::new(&self.db)?.create_checkpoint(path)?)
+    }
+}
+
+// Values are written next to their file under this suffix and renamed over it, so a file is never half written.
+// Names never contain it, `encode_name` escapes '~'
+const PARTIAL: &str = "~partial";
+
+// Names of remote objects are the key with every byte other than letters, digits, '.', '_' and '-'
+// written as %XX, so plain entry names stay readable and every name can be turned back into its key.
+// A leading '.' is written as %2E too, which leaves dot files like .git and .gitattributes to other tools
+pub(super) fn encode_name(key: &[u8]) -> String {
+    escape(key, true)
+}
+
+fn escape(key: &[u8], keep_case: bool) -> String {
+    let mut name = String::with_capacity(key.len());
+    for (i, &byte) in key.iter().enumerate() {
+        match byte {
+            b'.' if i == 0 => name.push_str("%2E"),
+            b'A'..=b'Z' if keep_case => name.push(byte as char),
+            b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+// The key a name stands for, None for files that aren't records: dot files, partial writes and hashed
+// names, whose key is in the file. Uppercase letters are taken as they are, remote object names keep them
+pub(super) fn decode_name(name: &str) -> Option<Vec<u8>> {
+    if name.starts_with('.') {
+        return None;
+    }
+    let bytes = name.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                key.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'~' => return None,
+            byte => {
+                key.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(key)
+}
+
+// File names longer than this are hashed, which leaves room for `PARTIAL` under the 255 byte limit
+// most file systems have
+const MAX_NAME: usize = 200;
+// Starts hashed file names. '_' isn't a hex digit, so no escaped key starts with it
+const HASHED: &str = "%_";
+
+// File names of the directory store are `encode_name` with uppercase letters escaped as well. Case
+// insensitive file systems, the default on macOS and Windows, would take API_KEY and api_key for the
+// same file; escaped, only lowercase letters are left and the escapes' uppercase hex digits can be
+// told apart from them, so no two keys share a name even when case is ignored.
+// Keys whose name would be longer than `MAX_NAME`, such as the history of a long entry name, are
+// stored under `HASHED` and the SHA-256 of the key instead, in a file starting with the key (see `frame`)
+fn file_name(key: &[u8]) -> String {
+    let name = escape(key, false);
+    if name.len() <= MAX_NAME {
+        return name;
+    }
+    let hash: String = Sha256::digest(key)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}{}", HASHED, hash)
+}
+
+fn is_hashed(name: &str) -> bool {
+    name.starts_with(HASHED) && !name.contains('~')
+}
+
+// Value of a hashed file: the length of the key as 4 big endian bytes, the key, then the value
+fn frame(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(4 + key.len() + value.len());
+    framed.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    framed.extend_from_slice(key);
+    framed.extend_from_slice(value);
+    framed
+}
+
+fn unframe(framed: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(framed.get(..4)?.try_into().ok()?) as usize;
+    let key = framed.get(4..4 + len)?;
+    Some((key, &framed[4 + len..]))
+}
+
+fn damaged(file: &Path) -> ClenvError {
+    ClenvError::Storage(format!("{} is damaged", file.display()))
+}
+
+// The value of a record read from `file`
+fn unframed(file: &Path, value: Vec<u8>) -> Result<Vec<u8>, ClenvError> {
+    if !file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(is_hashed)
+    {
+        return Ok(value);
+    }
+    let (_, value) = unframe(&value).ok_or_else(|| damaged(file))?;
+    Ok(value.to_vec())
+}
+
+fn io_err(path: &Path) -> impl FnOnce(io::Error) -> ClenvError + '_ {
+    move |e| ClenvError::io(format!("Could not access {}", path.display()), e)
+}
+
+// Names of the entries of a directory that are records, with their keys, sorted by key
+fn decoded_names(dir: &Path, want_dirs: bool) -> Result<Vec<(Vec<u8>, PathBuf)>, ClenvError> {
+    let mut names = Vec::new();
+    for item in fs::read_dir(dir).map_err(io_err(dir))? {
+        let item = item.map_err(io_err(dir))?;
+        let is_dir = item.file_type().map_err(io_err(dir))?.is_dir();
+        if is_dir != want_dirs {
+            continue;
+        }
+        let Some(name) = item.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if !want_dirs && is_hashed(&name) {
+            let file = item.path();
+            let framed = fs::read(&file).map_err(io_err(&file))?;
+            let (key, _) = unframe(&framed).ok_or_else(|| damaged(&file))?;
+            names.push((key.to_vec(), file));
+        } else if let Some(key) = decode_name(&name) {
+            names.push((key, item.path()));
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// A directory with a directory per namespace and a file per record, see `file_name`
+pub struct DirStore {
+    root: PathBuf,
+}
+
+impl DirStore {
+    pub fn open(root: &Path) -> Result<Self, ClenvError> {
+        fs::create_dir_all(root).map_err(io_err(root))?;
+        Ok(DirStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn dir(&self, ns: &str) -> PathBuf {
+        self.root.join(file_name(ns.as_bytes()))
+    }
+
+    fn file(&self, ns: &str, key: &[u8]) -> Result<PathBuf, ClenvError> {
+        if !self.has_namespace(ns) {
+            return Err(missing(ns));
+        }
+        if key.is_empty() {
+            return Err(ClenvError::Invalid(
+                "Records of a directory store need a name".to_string(),
+            ));
+        }
+        Ok(self.dir(ns).join(file_name(key)))
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), ClenvError> {
+    fs::create_dir(to).map_err(io_err(to))?;
+    for item in fs::read_dir(from).map_err(io_err(from))? {
+        let item = item.map_err(io_err(from))?;
+        let name = item.file_name();
+        let is_record = |name: &str| is_hashed(name) || decode_name(name).is_some();
+        if !name.to_str().is_some_and(is_record) {
+            continue;
+        }
+        let target = to.join(&name);
+        match item.file_type().map_err(io_err(from))?.is_dir() {
+            true => copy_dir(&item.path(), &target)?,
+            false => {
+                fs::copy(item.path(), &target).map_err(io_err(&target))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Store for DirStore {
+    fn namespaces(&self) -> Result<Vec<String>, ClenvError> {
+        Ok(decoded_names(&self.root, true)?
+            .into_iter()
+            .map(|(name, _)| String::from_utf8_lossy(&name).to_string())
+            .collect())
+    }
+
+    fn has_namespace(&self, ns: &str) -> bool {
+        self.dir(ns).is_dir()
+    }
+
+    fn create_namespace(&mut self, ns: &str) -> Result<(), ClenvError> {
+        // Only files have room for a key that is too long to be their name
+        if is_hashed(&file_name(ns.as_bytes())) {
+            return Err(ClenvError::Invalid(format!(
+                "The namespace name {} is too long for a directory store",
+                ns
+            )));
+        }
+        let dir = self.dir(ns);
+        fs::create_dir_all(&dir).map_err(io_err(&dir))
+    }
+
+    fn drop_namespace(&mut self, ns: &str) -> Result<(), ClenvError> {
+        let dir = self.dir(ns);
+        fs::remove_dir_all(&dir).map_err(io_err(&dir))
+    }
+
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Option<Vec<u8>>, ClenvError> {
+        let file = self.file(ns, key)?;
+        match fs::read(&file) {
+            Ok(value) => Ok(Some(unframed(&file, value)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_err(&file)(e)),
+        }
+    }
+
+    fn put(&self, ns: &str, key: &[u8], value: &[u8]) -> Result<(), ClenvError> {
+        let file = self.file(ns, key)?;
+        let mut partial = file.clone().into_os_string();
+        partial.push(PARTIAL);
+        let framed;
+        let value = match file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(is_hashed)
+        {
+            true => {
+                framed = frame(key, value);
+                &framed
+            }
+            false => value,
+        };
+        fs::write(&partial, value)
+            .and_then(|_| fs::rename(&partial, &file))
+            .map_err(io_err(&file))
+    }
+
+    fn delete(&self, ns: &str, key: &[u8]) -> Result<(), ClenvError> {
+        let file = self.file(ns, key)?;
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_err(&file)(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn scan<'a>(&'a self, ns: &str, prefix: &[u8]) -> Result<Records<'a>, ClenvError> {
+        if !self.has_namespace(ns) {
+            return Err(missing(ns));
+        }
+        let prefix = prefix.to_vec();
+        let files = decoded_names(&self.dir(ns), false)?
+            .into_iter()
+            .filter(move |(key, _)| key.starts_with(&prefix));
+        // Values are only read as they are needed
+        let records = files.map(|(key, file)| {
+            let value = fs::read(&file).map_err(io_err(&file))?;
+            Ok((key, unframed(&file, value)?))
+        });
+        Ok(Box::new(records))
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), ClenvError> {
+        if path.exists() {
+            return Err(ClenvError::Invalid(format!(
+                "{} already exists",
+                path.display()
+            )));
+        }
+        copy_dir(&self.root, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::testing::TempDir;
+
+    fn records(store: &dyn Store, ns: &str, prefix: &[u8]) -> Vec<RawRecord> {
+        store
+            .scan(ns, prefix)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    // Runs the same writes against both backends and checks they read back the same
+    #[test]
+    fn dir_store_matches_rocks_store() {
+        let tmp = TempDir::new();
+        let rocks_path = tmp.path().join("rocks");
+        let mut rocks = RocksStore::open(rocks_path.to_str().unwrap()).unwrap();
+        let mut dir = DirStore::open(&tmp.path().join("dir")).unwrap();
+        let long = vec![b'K'; 300];
+        let keys: [&[u8]; 8] = [
+            b"api_key",
+            b"API_KEY",
+            b".env",
+            b"a/b~partial",
+            b"a%2Fb",
+            b"\x00\xff",
+            b"api_key\x00history",
+            &long,
+        ];
+
+        for store in [&mut rocks as &mut dyn Store, &mut dir] {
+            store.create_namespace("Dev").unwrap();
+            store.create_namespace("dev").unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                store.put("Dev", key, &[i as u8; 3]).unwrap();
+            }
+            store.put("dev", b"api_key", b"lower").unwrap();
+            store.delete("Dev", b"a%2Fb").unwrap();
+            store.delete("Dev", b"missing").unwrap();
+        }
+
+        let mut namespaces = dir.namespaces().unwrap();
+        namespaces.sort();
+        assert_eq!(namespaces, ["Dev", "dev"]);
+        for ns in ["Dev", "dev"] {
+            for prefix in [&b""[..], b"api", b"API_", b"K"] {
+                assert_eq!(records(&dir, ns, prefix), records(&rocks, ns, prefix));
+            }
+            for key in keys {
+                assert_eq!(dir.get(ns, key).unwrap(), rocks.get(ns, key).unwrap());
+            }
+        }
+        assert_eq!(records(&dir, "Dev", b"").len(), keys.len() - 1);
+        assert!(dir.get("nope", b"api_key").is_err());
+    }
+
+    #[test]
+    fn file_names_fit_and_ignore_case() {
+        let names = [b"API_KEY".as_slice(), b"api_key", b"Api_Key"].map(file_name);
+        let folded: std::collections::HashSet<_> =
+            names.iter().map(|name| name.to_lowercase()).collect();
+        assert_eq!(folded.len(), names.len());
+        assert_eq!(file_name(b"api_key"), "api_key");
+        assert_eq!(decode_name(&file_name(b"API_KEY")).unwrap(), b"API_KEY");
+
+        let long = file_name(&[b'x'; 1000]);
+        assert!(is_hashed(&long) && long.len() + PARTIAL.len() < 255);
+        assert_eq!(decode_name(&long), None);
+    }
+}
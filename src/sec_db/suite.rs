@@ -0,0 +1,479 @@
+use super::age_file;
+use super::i_keys::CryptoError;
+use crate::config::config::Config as Conf;
+use crate::error::ClenvError;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::der::pem::{self, LineEnding};
+use rsa::pkcs8::{
+    AlgorithmIdentifierRef, DecodePublicKey, EncodePrivateKey, EncodePublicKey, ObjectIdentifier,
+    PrivateKeyInfo, SecretDocument,
+};
+use rsa::rand_core::RngCore;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::str::FromStr;
+use x25519_dalek::{EphemeralSecret, StaticSecret};
+
+// Every entry records the suite it was encrypted with, so a database can move from one to the other
+// entry by entry. The suite picks the cipher of the contents and the kind of key pair new users get.
+// Data keys are wrapped for each recipient by the kind of key they have in the keyring, whatever the
+// suite of the entry, so RSA and X25519 users can share a keyring while everyone moves over.
+//
+// The X25519 suite wraps data keys with a fresh ephemeral key per recipient: the X25519 shared secret
+// goes through HKDF-SHA256 into a wrap key, which seals the data key with XChaCha20-Poly1305.
+// Its 24 byte nonces don't fit the 12 bytes `EncryptedEntry.nonce` has room for, so they are stored in
+// front of the ciphertext and `nonce` is left zeroed.
+// X25519 keys can't sign, so X25519 users sign with an Ed25519 key derived from the same secret.
+// age users can be added by their "age1..." recipient alone, see `age_file`. That is the same X25519
+// key, but without the Ed25519 half their signatures can't be checked until their full key is added.
+
+/// Algorithms an entry is encrypted with. The order of the variants is what gets stored, only ever append
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Suite {
+    // RSA-2048 OAEP wrapped data keys, AES-256-GCM contents. Everything written before suites existed
+    #[default]
+    Rsa,
+    // X25519 + HKDF-SHA256 wrapped data keys, XChaCha20-Poly1305 contents
+    X25519,
+}
+
+const X25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.110");
+// DER of an SPKI holding a raw 32 byte key, see RFC 8410. Only the OID differs between the two
+const X25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00,
+];
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const XNONCE_LEN: usize = 24;
+
+// Like `i_keys::seal`, a data key of the wrong size from a wrapped key is an error, not a panic
+fn xchacha_cipher(key: &[u8]) -> Result<XChaCha20Poly1305, CryptoError> {
+    if key.len() != 32 {
+        return Err(CryptoError::Aes(chacha20poly1305::Error));
+    }
+    Ok(XChaCha20Poly1305::new(Key::from_slice(key)))
+}
+
+impl FromStr for Suite {
+    type Err = ClenvError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_lowercase().as_str() {
+            "rsa" => Ok(Suite::Rsa),
+            "x25519" => Ok(Suite::X25519),
+            other => Err(ClenvError::Config(format!(
+                "Unknown suite '{}'. Use 'rsa' or 'x25519'",
+                other
+            ))),
+        }
+    }
+}
+
+impl Suite {
+    /// The suite set with `clenv cfg suite <rsa|x25519>`, RSA when none is set
+    pub fn configured(conf: &Conf) -> Result<Suite, ClenvError> {
+        match conf.get("suite") {
+            Some(name) if !name.trim().is_empty() => name.parse(),
+            _ => Ok(Suite::default()),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Suite::Rsa => "rsa",
+            Suite::X25519 => "x25519",
+        }
+    }
+
+    /// Encrypts one value under a data key with a fresh nonce, like `i_keys::seal` does for AES-GCM
+    pub fn seal(
+        self,
+        key: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<(Vec<u8>, [u8; 12]), CryptoError> {
+        match self {
+            Suite::Rsa => super::i_keys::i_keys::seal(key, plaintext, aad),
+            Suite::X25519 => {
+                let cipher = xchacha_cipher(key)?;
+                let mut nonce = [0u8; XNONCE_LEN];
+                OsRng.fill_bytes(&mut nonce);
+
+                let ciphertext = cipher
+                    .encrypt(
+                        XNonce::from_slice(&nonce),
+                        Payload {
+                            msg: plaintext,
+                            aad,
+                        },
+                    )
+                    .map_err(CryptoError::Aes)?;
+                Ok(([&nonce[..], &ciphertext].concat(), [0u8; 12]))
+            }
+        }
+    }
+
+    pub fn open(
+        self,
+        key: &[u8],
+        ciphertext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            Suite::Rsa => super::i_keys::i_keys::open(key, ciphertext, nonce, aad),
+            Suite::X25519 => {
+                if ciphertext.len() < XNONCE_LEN {
+                    return Err(CryptoError::Aes(chacha20poly1305::Error));
+                }
+                let (nonce, ciphertext) = ciphertext.split_at(XNONCE_LEN);
+                let cipher = xchacha_cipher(key)?;
+                cipher
+                    .decrypt(
+                        XNonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad,
+                        },
+                    )
+                    .map_err(CryptoError::Aes)
+            }
+        }
+    }
+}
+
+/// Our own key, of whichever kind the key file holds
+#[derive(Clone)]
+pub enum PrivateKey {
+    // Boxed, an RSA key is ten times the size of an X25519 secret
+    Rsa(Box<RsaPrivateKey>),
+    X25519(StaticSecret),
+}
+
+/// A keyring member's key
+#[derive(Clone, Debug, PartialEq)]
+pub enum PublicKey {
+    Rsa(RsaPublicKey),
+    X25519 {
+        agreement: x25519_dalek::PublicKey,
+        // Derived from the same secret, see `signing_key`. None for members added as age recipients
+        signing: Option<VerifyingKey>,
+    },
+}
+
+impl From<RsaPrivateKey> for PrivateKey {
+    fn from(key: RsaPrivateKey) -> Self {
+        PrivateKey::Rsa(Box::new(key))
+    }
+}
+
+impl From<RsaPublicKey> for PublicKey {
+    fn from(key: RsaPublicKey) -> Self {
+        PublicKey::Rsa(key)
+    }
+}
+
+impl PrivateKey {
+    /// A new key pair of the kind the suite uses
+    pub fn generate(suite: Suite) -> Result<PrivateKey, CryptoError> {
+        match suite {
+            Suite::Rsa => Ok(RsaPrivateKey::new(&mut OsRng, 2048)?.into()),
+            Suite::X25519 => Ok(PrivateKey::X25519(StaticSecret::random_from_rng(OsRng))),
+        }
+    }
+
+    pub fn suite(&self) -> Suite {
+        match self {
+            PrivateKey::Rsa(_) => Suite::Rsa,
+            PrivateKey::X25519(_) => Suite::X25519,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            PrivateKey::Rsa(key) => PublicKey::Rsa(RsaPublicKey::from(key.as_ref())),
+            PrivateKey::X25519(secret) => PublicKey::X25519 {
+                agreement: x25519_dalek::PublicKey::from(secret),
+                signing: Some(signing_key(secret).verifying_key()),
+            },
+        }
+    }
+
+    // PKCS#8 DER, the same OpenSSL writes for "genpkey -algorithm X25519"
+    pub(super) fn to_pkcs8(&self, filename: &str) -> Result<SecretDocument, CryptoError> {
+        let key_error = |e: String| CryptoError::Key(filename.to_string(), e);
+        match self {
+            PrivateKey::Rsa(key) => key.to_pkcs8_der().map_err(|e| key_error(e.to_string())),
+            PrivateKey::X25519(secret) => {
+                // The key is an OCTET STRING inside the PKCS#8 OCTET STRING
+                let mut curve_key = vec![0x04, 0x20];
+                curve_key.extend_from_slice(secret.as_bytes());
+                let algorithm = AlgorithmIdentifierRef {
+                    oid: X25519_OID,
+                    parameters: None,
+                };
+                SecretDocument::try_from(PrivateKeyInfo::new(algorithm, &curve_key))
+                    .map_err(|e| key_error(e.to_string()))
+            }
+        }
+    }
+
+    pub(super) fn from_pkcs8(der: &[u8], filename: &str) -> Result<PrivateKey, CryptoError> {
+        let key_error = |e: String| CryptoError::Key(filename.to_string(), e);
+        let info = PrivateKeyInfo::try_from(der).map_err(|e| key_error(e.to_string()))?;
+        if info.algorithm.oid != X25519_OID {
+            return Ok(RsaPrivateKey::try_from(info)
+                .map_err(|e| key_error(e.to_string()))?
+                .into());
+        }
+        match info.private_key {
+            [0x04, 0x20, secret @ ..] if secret.len() == 32 => {
+                let mut bytes = [0u8; 32];
+                bytes.copy_from_slice(secret);
+                Ok(PrivateKey::X25519(StaticSecret::from(bytes)))
+            }
+            _ => Err(key_error("malformed X25519 key".to_string())),
+        }
+    }
+
+    /// Recovers a data key wrapped for this key by `wrap_key`
+    pub fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            PrivateKey::Rsa(key) => Ok(key.decrypt(rsa::Oaep::new::<Sha256>(), wrapped)?),
+            PrivateKey::X25519(secret) => {
+                if wrapped.len() < 32 {
+                    return Err(CryptoError::Aes(chacha20poly1305::Error));
+                }
+                let (ephemeral, sealed) = wrapped.split_at(32);
+                let mut ephemeral_bytes = [0u8; 32];
+                ephemeral_bytes.copy_from_slice(ephemeral);
+                let ephemeral = x25519_dalek::PublicKey::from(ephemeral_bytes);
+
+                let shared = secret.diffie_hellman(&ephemeral);
+                let recipient = x25519_dalek::PublicKey::from(secret);
+                let wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral, &recipient);
+                Suite::X25519.open(&wrap_key, sealed, &[], &[])
+            }
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            PrivateKey::Rsa(key) => super::i_keys::i_keys::sign(key, message),
+            PrivateKey::X25519(secret) => Ok(signing_key(secret).sign(message).to_bytes().to_vec()),
+        }
+    }
+}
+
+impl PublicKey {
+    pub fn suite(&self) -> Suite {
+        match self {
+            PublicKey::Rsa(_) => Suite::Rsa,
+            PublicKey::X25519 { .. } => Suite::X25519,
+        }
+    }
+
+    /// Wraps a data key so only the holder of the matching private key can recover it
+    pub fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            PublicKey::Rsa(key) => {
+                Ok(key.encrypt(&mut OsRng, rsa::Oaep::new::<Sha256>(), data_key)?)
+            }
+            PublicKey::X25519 { agreement, .. } => {
+                let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral_public = x25519_dalek::PublicKey::from(&ephemeral);
+                let shared = ephemeral.diffie_hellman(agreement);
+                if !shared.was_contributory() {
+                    return Err(CryptoError::Key(
+                        "keyring".to_string(),
+                        "X25519 public key of low order".to_string(),
+                    ));
+                }
+                let wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral_public, agreement);
+                let (sealed, _) = Suite::X25519.seal(&wrap_key, data_key, &[])?;
+                Ok([ephemeral_public.as_bytes(), &sealed[..]].concat())
+            }
+        }
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Rsa(key) => super::i_keys::i_keys::verify(key, message, signature),
+            PublicKey::X25519 { signing, .. } => signing.as_ref().is_some_and(|signing| {
+                ed25519_dalek::Signature::from_slice(signature)
+                    .is_ok_and(|signature| signing.verify(message, &signature).is_ok())
+            }),
+        }
+    }
+
+    // SPKI DER of the key that identifies the user, the encryption key for X25519 users
+    pub(super) fn spki_der(&self) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            PublicKey::Rsa(key) => Ok(key
+                .to_public_key_der()
+                .map_err(|e| CryptoError::Key("public key".to_string(), e.to_string()))?
+                .into_vec()),
+            PublicKey::X25519 { agreement, .. } => {
+                Ok([&X25519_SPKI_PREFIX[..], agreement.as_bytes()].concat())
+            }
+        }
+    }
+
+    /// PEM as stored in the keyring and shared with `clenv keygen`. X25519 users get two blocks,
+    /// the encryption key and the Ed25519 key their signatures are checked with.
+    /// Members added as age recipients are stored as their "age1..." recipient.
+    pub fn to_pem(&self) -> Result<String, CryptoError> {
+        let key_error = |e: String| CryptoError::Key("public key".to_string(), e);
+        match self {
+            PublicKey::Rsa(key) => key
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|e| key_error(e.to_string())),
+            PublicKey::X25519 {
+                agreement,
+                signing: None,
+            } => Ok(format!("{}\n", age_file::recipient(agreement))),
+            PublicKey::X25519 {
+                signing: Some(signing),
+                ..
+            } => {
+                let signing_der = [&ED25519_SPKI_PREFIX[..], signing.as_bytes()].concat();
+                let mut text = String::new();
+                for der in [self.spki_der()?, signing_der] {
+                    text.push_str(
+                        &pem::encode_string("PUBLIC KEY", LineEnding::LF, &der)
+                            .map_err(|e| key_error(e.to_string()))?,
+                    );
+                }
+                Ok(text)
+            }
+        }
+    }
+
+    /// Reads any public key `to_pem` writes, RSA keys as PKCS#1 ("BEGIN RSA PUBLIC KEY")
+    /// and age recipients as written by "age-keygen -y"
+    pub fn from_pem(text: &str) -> Option<PublicKey> {
+        if let Some(agreement) = age_file::parse_recipient(text) {
+            return Some(PublicKey::X25519 {
+                agreement,
+                signing: None,
+            });
+        }
+        if let Ok(key) = RsaPublicKey::from_public_key_pem(text) {
+            return Some(PublicKey::Rsa(key));
+        }
+        if let Ok(key) = RsaPublicKey::from_pkcs1_pem(text) {
+            return Some(PublicKey::Rsa(key));
+        }
+
+        let (mut agreement, mut signing) = (None, None);
+        for block in text.split_inclusive("-----END PUBLIC KEY-----") {
+            let Ok((_, der)) = pem::decode_vec(block.trim().as_bytes()) else {
+                continue;
+            };
+            let Some((prefix, key)) = der.split_at_checked(12) else {
+                continue;
+            };
+            let Ok(key) = <[u8; 32]>::try_from(key) else {
+                continue;
+            };
+            if prefix == X25519_SPKI_PREFIX {
+                agreement = Some(x25519_dalek::PublicKey::from(key));
+            } else if prefix == ED25519_SPKI_PREFIX {
+                signing = VerifyingKey::from_bytes(&key).ok();
+            }
+        }
+        Some(PublicKey::X25519 {
+            agreement: agreement?,
+            signing: Some(signing?),
+        })
+    }
+}
+
+fn derive_wrap_key(
+    shared: &[u8],
+    ephemeral: &x25519_dalek::PublicKey,
+    recipient: &x25519_dalek::PublicKey,
+) -> [u8; 32] {
+    let salt = [ephemeral.as_bytes(), &recipient.as_bytes()[..]].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"clenv x25519 wrap key", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn signing_key(secret: &StaticSecret) -> SigningKey {
+    let mut seed = [0u8; 32];
+    Hkdf::<Sha256>::new(None, secret.as_bytes())
+        .expand(b"clenv ed25519 signing key", &mut seed)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    SigningKey::from_bytes(&seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::i_keys::i_keys;
+
+    #[test]
+    fn x25519_values_round_trip() {
+        let key = i_keys::generate_data_key();
+        let (sealed, nonce) = Suite::X25519.seal(&key, b"KEY=value", b"aad").unwrap();
+        let opened = Suite::X25519.open(&key, &sealed, &nonce, b"aad").unwrap();
+        assert_eq!(opened, b"KEY=value");
+
+        assert!(Suite::X25519.open(&key, &sealed, &nonce, b"other").is_err());
+        let other_key = i_keys::generate_data_key();
+        assert!(
+            Suite::X25519
+                .open(&other_key, &sealed, &nonce, b"aad")
+                .is_err()
+        );
+        assert!(
+            Suite::X25519
+                .open(&key, &sealed[..10], &nonce, b"aad")
+                .is_err()
+        );
+        // Keys of the wrong size are an error, not a panic
+        assert!(Suite::X25519.seal(&key[..16], b"", b"").is_err());
+        assert!(
+            Suite::X25519
+                .open(&key[..16], &sealed, &nonce, b"aad")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn x25519_keys_round_trip() {
+        let private_key = PrivateKey::generate(Suite::X25519).unwrap();
+        let public_key = private_key.public_key();
+        assert_eq!(public_key.suite(), Suite::X25519);
+
+        let data_key = i_keys::generate_data_key();
+        let wrapped = public_key.wrap_key(&data_key).unwrap();
+        assert_eq!(private_key.unwrap_key(&wrapped).unwrap(), data_key);
+        let someone_else = PrivateKey::generate(Suite::X25519).unwrap();
+        assert!(someone_else.unwrap_key(&wrapped).is_err());
+
+        let signature = private_key.sign(b"message").unwrap();
+        assert!(public_key.verify(b"message", &signature));
+        assert!(!public_key.verify(b"messages", &signature));
+
+        let pem = public_key.to_pem().unwrap();
+        let parsed = PublicKey::from_pem(&pem).unwrap();
+        assert_eq!(parsed.spki_der().unwrap(), public_key.spki_der().unwrap());
+        assert!(parsed.verify(b"message", &signature));
+
+        let der = private_key.to_pkcs8("key.pem").unwrap();
+        let reread = PrivateKey::from_pkcs8(der.as_bytes(), "key.pem").unwrap();
+        assert_eq!(reread.unwrap_key(&wrapped).unwrap(), data_key);
+    }
+}
@@ -0,0 +1,865 @@
+use super::handle_db::{EncryptedEntry, META_CF, SecDb};
+use super::history::HISTORY_CF;
+use super::i_keys::{Passphrase, i_keys};
+use super::index::{INDEX_CF, entries_record, namespace_id};
+use super::migrate::SCHEMA_VERSION;
+use super::s3::{Bucket, ObjectStore};
+use super::store::{self, Store, decode_name, encode_name};
+use super::suite::PublicKey;
+use crate::config::config::Config as Conf;
+use crate::error::ClenvError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+// Push and pull keep a database in sync with a bucket, see s3.rs. Every record of the store becomes an object
+// named "<namespace>/<key>", encoded like the files of a directory store, so the bucket only ever holds what
+// the database holds: encrypted entries and revisions, the keyring, the encrypted index.
+// For every object we remember the ETag it had and what the record looked like when the two were last in sync.
+// A record changed on one side only is copied to the other, one changed on both is a conflict that is reported
+// and left alone. Writes to the bucket are conditional on the ETag we saw, so a push racing another push can't
+// overwrite it either. The index of entry names is the exception, both sides' lists are merged on pull.
+// Keyring records decide whom data keys are wrapped for, so a pull only reports how the remote changed the
+// keyring until it is told to accept them. Anyone who can write to the bucket could otherwise add themselves.
+pub const SYNC_CF: &str = "sync";
+
+// What an object looked like when it was last pushed or pulled
+#[derive(Serialize, Deserialize)]
+struct Synced {
+    etag: String,
+    digest: Vec<u8>,
+}
+
+/// A record both sides changed since they were last in sync, left as it is on both
+#[derive(Debug, Serialize)]
+pub struct SyncConflict {
+    pub record: String,
+    // Revisions of entries on each side, None for other records or when that side deleted it
+    pub local_revision: Option<u64>,
+    pub remote_revision: Option<u64>,
+}
+
+/// What `push` or `pull` did
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub remote: String,
+    // Entries copied, as namespace/name
+    pub entries: Vec<String>,
+    // Records written on the other side, revisions, chunks, dictionaries and the index included
+    pub written: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+    // Lists of entry names both sides added to, merged by a pull
+    pub merged: usize,
+    pub conflicts: Vec<SyncConflict>,
+    // How a pull changed the keyring, e.g. "added bob with key 0123456789abcdef"
+    pub keyring: Vec<String>,
+    // How the remote changed the keyring, left for 'pull --accept-keyring' to apply
+    pub pending_keyring: Vec<String>,
+}
+
+// A record of the store with the object it's synced to
+struct Record {
+    ns: String,
+    key: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+// A record both sides changed, with what each side has
+struct Clash {
+    ns: String,
+    key: Vec<u8>,
+    object: String,
+    local: Option<Vec<u8>>,
+    remote: Option<(Vec<u8>, String)>,
+}
+
+#[derive(Default)]
+struct Outcome {
+    written: Vec<(String, Vec<u8>)>,
+    deleted: usize,
+    unchanged: usize,
+    clashes: Vec<Clash>,
+    // Keyring members the remote added, changed or removed (None), which weren't applied
+    staged: Vec<(String, Option<Vec<u8>>)>,
+}
+
+fn digest(value: &[u8]) -> Vec<u8> {
+    Sha256::digest(value).to_vec()
+}
+
+fn object_name(ns: &str, key: &[u8]) -> String {
+    format!("{}/{}", encode_name(ns.as_bytes()), encode_name(key))
+}
+
+fn parse_object(object: &str) -> Option<(String, Vec<u8>)> {
+    let (ns, key) = object.split_once('/')?;
+    let ns = String::from_utf8(decode_name(ns)?).ok()?;
+    let key = decode_name(key)?;
+    (ns != SYNC_CF && !key.is_empty()).then_some((ns, key))
+}
+
+// The state of an object is kept per remote, so several remotes can be synced with
+fn state_key(remote: &str, object: &str) -> Vec<u8> {
+    format!("{}\0{}", remote, object).into_bytes()
+}
+
+fn remember(
+    store: &dyn Store,
+    remote: &str,
+    object: &str,
+    etag: &str,
+    digest: Vec<u8>,
+) -> Result<(), ClenvError> {
+    let state = Synced {
+        etag: etag.to_string(),
+        digest,
+    };
+    let value = bincode::serde::encode_to_vec(&state, bincode::config::standard())?;
+    store.put(SYNC_CF, &state_key(remote, object), &value)
+}
+
+// One push or pull
+struct Sync<'a> {
+    store: &'a mut dyn Store,
+    bucket: &'a dyn ObjectStore,
+    remote: &'a str,
+    force: bool,
+    accept_keyring: bool,
+}
+
+impl Sync<'_> {
+    fn synced(&self) -> Result<BTreeMap<String, Synced>, ClenvError> {
+        let mut synced = BTreeMap::new();
+        if !self.store.has_namespace(SYNC_CF) {
+            return Ok(synced);
+        }
+        let prefix = state_key(self.remote, "");
+        for item in self.store.scan(SYNC_CF, &prefix)? {
+            let (key, value) = item?;
+            let (state, _): (Synced, _) =
+                bincode::serde::decode_from_slice(&value, bincode::config::standard())?;
+            synced.insert(
+                String::from_utf8_lossy(&key[prefix.len()..]).to_string(),
+                state,
+            );
+        }
+        Ok(synced)
+    }
+
+    fn remember(&self, object: &str, etag: &str, digest: Vec<u8>) -> Result<(), ClenvError> {
+        remember(self.store, self.remote, object, etag, digest)
+    }
+
+    fn forget(&self, object: &str) -> Result<(), ClenvError> {
+        self.store.delete(SYNC_CF, &state_key(self.remote, object))
+    }
+
+    // Every record of the store by the object it's synced to
+    fn local(&self) -> Result<BTreeMap<String, Record>, ClenvError> {
+        let mut records = BTreeMap::new();
+        for ns in self.store.namespaces()? {
+            if ns == SYNC_CF {
+                continue;
+            }
+            for item in self.store.iter(&ns)? {
+                let (key, value) = item?;
+                records.insert(
+                    object_name(&ns, &key),
+                    Record {
+                        ns: ns.clone(),
+                        digest: digest(&value),
+                        key,
+                    },
+                );
+            }
+        }
+        Ok(records)
+    }
+
+    fn remote(&self) -> Result<BTreeMap<String, String>, ClenvError> {
+        Ok(self
+            .bucket
+            .list()?
+            .into_iter()
+            .filter(|object| parse_object(&object.key).is_some())
+            .map(|object| (object.key, object.etag))
+            .collect())
+    }
+
+    fn clash(
+        &self,
+        object: &str,
+        ns: &str,
+        key: &[u8],
+        remote: Option<(Vec<u8>, String)>,
+    ) -> Result<Clash, ClenvError> {
+        let local = match self.store.has_namespace(ns) {
+            true => self.store.get(ns, key)?,
+            false => None,
+        };
+        Ok(Clash {
+            ns: ns.to_string(),
+            key: key.to_vec(),
+            object: object.to_string(),
+            local,
+            remote,
+        })
+    }
+
+    fn push(&mut self) -> Result<Outcome, ClenvError> {
+        self.store.create_namespace(SYNC_CF)?;
+        let local = self.local()?;
+        let remote = self.remote()?;
+        let synced = self.synced()?;
+        let mut outcome = Outcome::default();
+
+        for (object, record) in &local {
+            let last = synced.get(object);
+            // Unchanged here. Whatever the remote did is for pull to bring in
+            if last.is_some_and(|last| last.digest == record.digest) {
+                outcome.unchanged += 1;
+                continue;
+            }
+            let value = self.store.get(&record.ns, &record.key)?.unwrap_or_default();
+            let expected = last.map(|last| last.etag.as_str());
+            let etag = match self.force {
+                true => Some(self.bucket.force_put(object, &value)?),
+                // The remote changed it too, or has one we never saw
+                false if remote.get(object).map(String::as_str) != expected => None,
+                false => self.bucket.put(object, &value, expected)?,
+            };
+            if let Some(etag) = etag {
+                self.remember(object, &etag, record.digest.clone())?;
+                outcome
+                    .written
+                    .push((record.ns.clone(), record.key.clone()));
+                continue;
+            }
+            match self.bucket.get(object)? {
+                // Both sides made the same change
+                Some((theirs, etag)) if digest(&theirs) == record.digest => {
+                    self.remember(object, &etag, record.digest.clone())?;
+                    outcome.unchanged += 1;
+                }
+                theirs => {
+                    let clash = self.clash(object, &record.ns, &record.key, theirs)?;
+                    outcome.clashes.push(clash);
+                }
+            }
+        }
+
+        // Deleted here since the last sync
+        for (object, last) in &synced {
+            if local.contains_key(object) {
+                continue;
+            }
+            match remote.get(object) {
+                Some(etag) if *etag != last.etag && !self.force => {
+                    if let Some((ns, key)) = parse_object(object) {
+                        let theirs = self.bucket.get(object)?;
+                        outcome.clashes.push(self.clash(object, &ns, &key, theirs)?);
+                    }
+                }
+                Some(_) => {
+                    self.bucket.delete(object)?;
+                    self.forget(object)?;
+                    outcome.deleted += 1;
+                }
+                None => self.forget(object)?,
+            }
+        }
+        Ok(outcome)
+    }
+
+    fn pull(&mut self) -> Result<Outcome, ClenvError> {
+        self.store.create_namespace(SYNC_CF)?;
+        let local = self.local()?;
+        let remote = self.remote()?;
+        let synced = self.synced()?;
+        let mut outcome = Outcome::default();
+
+        // Records of a newer format would be misread, so nothing is pulled from a newer database
+        let schema = object_name(META_CF, b"schema_version");
+        if remote.contains_key(&schema)
+            && let Some((value, _)) = self.bucket.get(&schema)?
+            && let Ok(version) = <[u8; 4]>::try_from(value.as_slice()).map(u32::from_be_bytes)
+            && version > SCHEMA_VERSION
+        {
+            return Err(ClenvError::Storage(format!(
+                "The remote database is in format version {}, this version of clenv only knows up to {}. Update clenv to pull from it",
+                version, SCHEMA_VERSION
+            )));
+        }
+
+        for (object, etag) in &remote {
+            let last = synced.get(object);
+            if last.is_some_and(|last| last.etag == *etag) {
+                outcome.unchanged += 1;
+                continue;
+            }
+            let Some((ns, key)) = parse_object(object) else {
+                continue;
+            };
+            let ours = local.get(object).map(|record| &record.digest);
+            let changed_here = match (last, ours) {
+                (Some(last), Some(ours)) => last.digest != *ours,
+                // Deleted here, or here and never synced
+                (Some(_), None) | (None, Some(_)) => true,
+                (None, None) => false,
+            };
+            // Deleted from the remote since it was listed
+            let Some((value, etag)) = self.bucket.get(object)? else {
+                continue;
+            };
+            let theirs = digest(&value);
+            if ours == Some(&theirs) {
+                self.remember(object, &etag, theirs)?;
+                outcome.unchanged += 1;
+            } else if changed_here && !self.force {
+                let clash = self.clash(object, &ns, &key, Some((value, etag)))?;
+                outcome.clashes.push(clash);
+            } else if ns == "keyring" && !self.accept_keyring {
+                // Not remembered either, so the next pull finds it again
+                let user = String::from_utf8_lossy(&key).to_string();
+                outcome.staged.push((user, Some(value)));
+            } else {
+                self.store.create_namespace(&ns)?;
+                self.store.put(&ns, &key, &value)?;
+                self.remember(object, &etag, theirs)?;
+                outcome.written.push((ns, key));
+            }
+        }
+
+        // Deleted from the remote since the last sync
+        for (object, last) in &synced {
+            if remote.contains_key(object) {
+                continue;
+            }
+            match local.get(object) {
+                Some(record) if record.digest != last.digest && !self.force => {
+                    let clash = self.clash(object, &record.ns, &record.key, None)?;
+                    outcome.clashes.push(clash);
+                }
+                Some(record) if record.ns == "keyring" && !self.accept_keyring => {
+                    let user = String::from_utf8_lossy(&record.key).to_string();
+                    outcome.staged.push((user, None));
+                }
+                Some(record) => {
+                    self.store.delete(&record.ns, &record.key)?;
+                    self.forget(object)?;
+                    outcome.deleted += 1;
+                }
+                None => self.forget(object)?,
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+impl SecDb {
+    /// Copies what changed here since the last sync to the configured remote. Records the remote changed as
+    /// well are reported as conflicts and left alone, pull first to bring their changes in. `force` overwrites them.
+    pub fn push(&mut self, force: bool) -> Result<SyncReport, ClenvError> {
+        let bucket = Bucket::from_conf(&self.conf)?;
+        let remote = self.conf.require("remote")?;
+        let outcome = Sync {
+            store: self.db.as_mut(),
+            bucket: &bucket,
+            remote: &remote,
+            force,
+            accept_keyring: false,
+        }
+        .push()?;
+        self.sync_report(remote, outcome, 0)
+    }
+
+    /// Brings in what changed on the configured remote since the last sync. Records changed here as well are
+    /// reported as conflicts and kept as they are, except the lists of entry names, which are merged.
+    /// `force` takes the remote's version of every conflicting record. Changes to the keyring are only
+    /// reported, with the key ids to check them by, unless `accept_keyring` applies them.
+    pub fn pull(&mut self, force: bool, accept_keyring: bool) -> Result<SyncReport, ClenvError> {
+        let bucket = Bucket::from_conf(&self.conf)?;
+        let remote = self.conf.require("remote")?;
+        let keyring = self.keyring_pems()?;
+        let mut outcome = Sync {
+            store: self.db.as_mut(),
+            bucket: &bucket,
+            remote: &remote,
+            force,
+            accept_keyring,
+        }
+        .pull()?;
+        // The index key may have been pulled or replaced by a rekey on the other side
+        self.index_key = self.load_index_key()?;
+        self.restore_namespaces()?;
+
+        let mut merged = 0;
+        let mut clashes = Vec::new();
+        for clash in std::mem::take(&mut outcome.clashes) {
+            let mergeable = clash.ns == INDEX_CF;
+            match (&clash.local, &clash.remote) {
+                (Some(ours), Some((theirs, etag))) if mergeable => {
+                    match self.merge_index_record(&clash.key, ours, theirs)? {
+                        Some(value) => {
+                            // Remembered as the remote's version, so the next push sends the merged one
+                            self.db.put(INDEX_CF, &clash.key, &value)?;
+                            remember(
+                                self.db.as_ref(),
+                                &remote,
+                                &clash.object,
+                                etag,
+                                digest(theirs),
+                            )?;
+                            merged += 1;
+                        }
+                        None => clashes.push(clash),
+                    }
+                }
+                _ => clashes.push(clash),
+            }
+        }
+        outcome.clashes = clashes;
+
+        let staged = std::mem::take(&mut outcome.staged);
+        let mut report = self.sync_report(remote, outcome, merged)?;
+        let pulled = self.keyring_pems()?;
+        let mut pending = pulled.clone();
+        for (user, pem) in staged {
+            match pem {
+                Some(pem) => pending.insert(user, pem),
+                None => pending.remove(&user),
+            };
+        }
+        report.keyring = keyring_changes(&keyring, &pulled);
+        report.pending_keyring = keyring_changes(&pulled, &pending);
+        Ok(report)
+    }
+
+    /// Creates the database at `db` from the configured remote, which is how a new team member gets a copy
+    /// once someone added them to the keyring. Fails when there is a database already, and without
+    /// `accept_keyring` only lists the remote's keyring, so its keys can be checked before anything is written
+    pub fn clone_remote(
+        conf: Conf,
+        passphrase: Box<Passphrase>,
+        accept_keyring: bool,
+    ) -> Result<(SecDb, SyncReport), ClenvError> {
+        let path = conf.require("db")?;
+        if store::exists(std::path::Path::new(&path)) {
+            return Err(ClenvError::Invalid(format!(
+                "There is a database at {} already, 'clenv pull' updates it",
+                path
+            )));
+        }
+        let bucket = Bucket::from_conf(&conf)?;
+        let remote = conf.require("remote")?;
+        if !accept_keyring {
+            let members = remote_keyring(&bucket)?;
+            return Err(match members.is_empty() {
+                true => {
+                    ClenvError::NotFound(format!("There is no database at the remote {}", remote))
+                }
+                false => ClenvError::Invalid(format!(
+                    "The keyring of {} holds {}. Check the key ids with their owners, 'clenv pull --accept-keyring' then copies the database",
+                    remote,
+                    members.join(", ")
+                )),
+            });
+        }
+        let mut db = store::open(&conf)?;
+        let outcome = Sync {
+            store: db.as_mut(),
+            bucket: &bucket,
+            remote: &remote,
+            force: false,
+            accept_keyring,
+        }
+        .pull()?;
+        if !db.has_namespace("keyring") {
+            return Err(ClenvError::NotFound(format!(
+                "There is no database at the remote {}",
+                remote
+            )));
+        }
+        drop(db);
+
+        let mut sec_db = SecDb::with_passphrase(conf, passphrase)?;
+        sec_db.restore_namespaces()?;
+        let report = sec_db.sync_report(remote, outcome, 0)?;
+        Ok((sec_db, report))
+    }
+
+    // Empty namespaces have no records to sync, the index still lists them
+    fn restore_namespaces(&mut self) -> Result<(), ClenvError> {
+        if let Some(key) = self.index_key()? {
+            for ns in self.indexed_namespaces(&key)? {
+                Self::check_namespace_name(&ns)?;
+                self.db.create_namespace(&namespace_id(Some(&key), &ns))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn keyring_pems(&self) -> Result<BTreeMap<String, Vec<u8>>, ClenvError> {
+        let mut pems = BTreeMap::new();
+        if self.db.has_namespace("keyring") {
+            for item in self.db.iter("keyring")? {
+                let (user, pem) = item?;
+                pems.insert(String::from_utf8_lossy(&user).to_string(), pem);
+            }
+        }
+        Ok(pems)
+    }
+
+    fn sync_report(
+        &self,
+        remote: String,
+        outcome: Outcome,
+        merged: usize,
+    ) -> Result<SyncReport, ClenvError> {
+        // Store namespaces of entries, with the names of the entries in them
+        let mut entry_names: HashMap<String, (String, BTreeMap<String, String>)> = HashMap::new();
+        let index_key = self.index_key()?;
+        for ns in self.namespaces()? {
+            let names = self.entry_names(&ns)?;
+            entry_names.insert(namespace_id(index_key.as_deref(), &ns), (ns, names));
+        }
+        let label = |ns: &str, key: &[u8]| -> String {
+            let key_text = String::from_utf8_lossy(key);
+            match entry_names.get(ns) {
+                Some((ns, names)) => format!("{}/{}", ns, Self::name_of(names, &key_text)),
+                None if ns == "keyring" => format!("keyring/{}", key_text),
+                None if ns == HISTORY_CF => {
+                    match key_text.splitn(3, '\0').collect::<Vec<_>>()[..] {
+                        [ns_id, name, revision] if entry_names.contains_key(ns_id) => {
+                            let (ns, names) = &entry_names[ns_id];
+                            let revision = revision.trim_start_matches('0');
+                            format!(
+                                "{}/{} revision {}",
+                                ns,
+                                Self::name_of(names, name),
+                                revision
+                            )
+                        }
+                        _ => object_name(ns, key),
+                    }
+                }
+                None if ns == INDEX_CF => match entry_names
+                    .iter()
+                    .find(|(id, _)| entries_record(id).as_bytes() == key)
+                {
+                    Some((_, (ns, _))) => format!("entry names of {}", ns),
+                    None => object_name(ns, key),
+                },
+                None => object_name(ns, key),
+            }
+        };
+        let revision = |ns: &str, value: &Option<Vec<u8>>| -> Option<u64> {
+            match value {
+                Some(value) if entry_names.contains_key(ns) => {
+                    EncryptedEntry::from_bytes(value).ok().map(|e| e.revision)
+                }
+                _ => None,
+            }
+        };
+
+        Ok(SyncReport {
+            entries: outcome
+                .written
+                .iter()
+                .filter(|(ns, _)| entry_names.contains_key(ns))
+                .map(|(ns, key)| label(ns, key))
+                .collect(),
+            written: outcome.written.len(),
+            deleted: outcome.deleted,
+            unchanged: outcome.unchanged,
+            merged,
+            conflicts: outcome
+                .clashes
+                .iter()
+                .map(|clash| SyncConflict {
+                    record: label(&clash.ns, &clash.key),
+                    local_revision: revision(&clash.ns, &clash.local),
+                    remote_revision: revision(
+                        &clash.ns,
+                        &clash.remote.as_ref().map(|(value, _)| value.clone()),
+                    ),
+                })
+                .collect(),
+            keyring: Vec::new(),
+            pending_keyring: Vec::new(),
+            remote,
+        })
+    }
+}
+
+// The key id of a keyring record, which its owner can read with 'clenv key id'
+fn key_label(pem: &[u8]) -> String {
+    let key = std::str::from_utf8(pem).ok().and_then(PublicKey::from_pem);
+    match key.map(|key| i_keys::key_id(&key)) {
+        Some(Ok(id)) => format!("key {}", id),
+        _ => "a key clenv can't read".to_string(),
+    }
+}
+
+// The members of the remote's keyring, as "alice with key 0123456789abcdef"
+fn remote_keyring(bucket: &dyn ObjectStore) -> Result<Vec<String>, ClenvError> {
+    let mut members = Vec::new();
+    for object in bucket.list()? {
+        match parse_object(&object.key) {
+            Some((ns, user)) if ns == "keyring" => {
+                if let Some((pem, _)) = bucket.get(&object.key)? {
+                    let user = String::from_utf8_lossy(&user);
+                    members.push(format!("{} with {}", user, key_label(&pem)));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(members)
+}
+
+fn keyring_changes(
+    before: &BTreeMap<String, Vec<u8>>,
+    after: &BTreeMap<String, Vec<u8>>,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (user, pem) in after {
+        match before.get(user) {
+            None => changes.push(format!("added {} with {}", user, key_label(pem))),
+            Some(old) if old != pem => {
+                changes.push(format!("changed the key of {} to {}", user, key_label(pem)))
+            }
+            Some(_) => {}
+        }
+    }
+    for user in before.keys().filter(|user| !after.contains_key(*user)) {
+        changes.push(format!("removed {}", user));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_db::s3::RemoteObject;
+    use crate::sec_db::store::DirStore;
+    use crate::sec_db::testing::TempDir;
+    use std::cell::{Cell, RefCell};
+
+    // A bucket kept in memory, its ETags count the writes
+    #[derive(Default)]
+    struct MemoryBucket {
+        objects: RefCell<BTreeMap<String, (Vec<u8>, String)>>,
+        writes: Cell<u64>,
+    }
+
+    impl MemoryBucket {
+        fn value(&self, key: &str) -> Option<Vec<u8>> {
+            self.objects
+                .borrow()
+                .get(key)
+                .map(|(value, _)| value.clone())
+        }
+    }
+
+    impl ObjectStore for MemoryBucket {
+        fn list(&self) -> Result<Vec<RemoteObject>, ClenvError> {
+            Ok(self
+                .objects
+                .borrow()
+                .iter()
+                .map(|(key, (_, etag))| RemoteObject {
+                    key: key.clone(),
+                    etag: etag.clone(),
+                })
+                .collect())
+        }
+
+        fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, ClenvError> {
+            Ok(self.objects.borrow().get(key).cloned())
+        }
+
+        fn put(
+            &self,
+            key: &str,
+            body: &[u8],
+            expected: Option<&str>,
+        ) -> Result<Option<String>, ClenvError> {
+            let current = self.objects.borrow().get(key).map(|(_, etag)| etag.clone());
+            if current.as_deref() != expected {
+                return Ok(None);
+            }
+            self.force_put(key, body).map(Some)
+        }
+
+        fn force_put(&self, key: &str, body: &[u8]) -> Result<String, ClenvError> {
+            self.writes.set(self.writes.get() + 1);
+            let etag = self.writes.get().to_string();
+            self.objects
+                .borrow_mut()
+                .insert(key.to_string(), (body.to_vec(), etag.clone()));
+            Ok(etag)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), ClenvError> {
+            self.objects.borrow_mut().remove(key);
+            Ok(())
+        }
+    }
+
+    fn store(tmp: &TempDir, name: &str) -> DirStore {
+        let mut store = DirStore::open(&tmp.path().join(name)).unwrap();
+        store.create_namespace("dev").unwrap();
+        store
+    }
+
+    fn push(store: &mut DirStore, bucket: &MemoryBucket, force: bool) -> Outcome {
+        Sync {
+            store,
+            bucket,
+            remote: "test",
+            force,
+            accept_keyring: false,
+        }
+        .push()
+        .unwrap()
+    }
+
+    fn pull(store: &mut DirStore, bucket: &MemoryBucket, force: bool, accept: bool) -> Outcome {
+        Sync {
+            store,
+            bucket,
+            remote: "test",
+            force,
+            accept_keyring: accept,
+        }
+        .pull()
+        .unwrap()
+    }
+
+    #[test]
+    fn changes_on_one_side_are_copied() {
+        let tmp = TempDir::new();
+        let bucket = MemoryBucket::default();
+        let mut ours = store(&tmp, "ours");
+        let mut theirs = store(&tmp, "theirs");
+
+        ours.put("dev", b"a", b"1").unwrap();
+        assert_eq!(push(&mut ours, &bucket, false).written.len(), 1);
+        assert_eq!(pull(&mut theirs, &bucket, false, false).written.len(), 1);
+        assert_eq!(theirs.get("dev", b"a").unwrap().unwrap(), b"1");
+
+        theirs.put("dev", b"a", b"2").unwrap();
+        assert_eq!(push(&mut theirs, &bucket, false).written.len(), 1);
+        let outcome = pull(&mut ours, &bucket, false, false);
+        assert_eq!(outcome.written.len(), 1);
+        assert!(outcome.clashes.is_empty());
+        assert_eq!(ours.get("dev", b"a").unwrap().unwrap(), b"2");
+
+        ours.delete("dev", b"a").unwrap();
+        assert_eq!(push(&mut ours, &bucket, false).deleted, 1);
+        assert_eq!(pull(&mut theirs, &bucket, false, false).deleted, 1);
+        assert_eq!(theirs.get("dev", b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn changes_on_both_sides_conflict() {
+        let tmp = TempDir::new();
+        let bucket = MemoryBucket::default();
+        let mut ours = store(&tmp, "ours");
+        let mut theirs = store(&tmp, "theirs");
+        let object = object_name("dev", b"a");
+        ours.put("dev", b"a", b"1").unwrap();
+        push(&mut ours, &bucket, false);
+        pull(&mut theirs, &bucket, false, false);
+
+        theirs.put("dev", b"a", b"theirs").unwrap();
+        push(&mut theirs, &bucket, false);
+        ours.put("dev", b"a", b"ours").unwrap();
+
+        // The push would overwrite what we never saw
+        let outcome = push(&mut ours, &bucket, false);
+        assert!(outcome.written.is_empty());
+        assert_eq!(outcome.clashes.len(), 1);
+        assert_eq!(bucket.value(&object).unwrap(), b"theirs");
+
+        // The pull would overwrite what we didn't push
+        let outcome = pull(&mut ours, &bucket, false, false);
+        assert!(outcome.written.is_empty());
+        assert_eq!(outcome.clashes.len(), 1);
+        assert_eq!(ours.get("dev", b"a").unwrap().unwrap(), b"ours");
+
+        // Both created it without ever syncing
+        theirs.put("dev", b"b", b"theirs").unwrap();
+        push(&mut theirs, &bucket, false);
+        ours.put("dev", b"b", b"ours").unwrap();
+        assert_eq!(push(&mut ours, &bucket, false).clashes.len(), 2);
+
+        // A forced pull takes the remote's version of both
+        let outcome = pull(&mut ours, &bucket, true, false);
+        assert!(outcome.clashes.is_empty());
+        assert_eq!(ours.get("dev", b"a").unwrap().unwrap(), b"theirs");
+        assert_eq!(ours.get("dev", b"b").unwrap().unwrap(), b"theirs");
+
+        // Deleted on the remote, changed here
+        theirs.delete("dev", b"b").unwrap();
+        assert_eq!(push(&mut theirs, &bucket, false).deleted, 1);
+        ours.put("dev", b"b", b"ours").unwrap();
+        let outcome = pull(&mut ours, &bucket, false, false);
+        assert_eq!(outcome.deleted, 0);
+        assert_eq!(outcome.clashes.len(), 1);
+        assert!(outcome.clashes[0].remote.is_none());
+        assert_eq!(ours.get("dev", b"b").unwrap().unwrap(), b"ours");
+    }
+
+    #[test]
+    fn forced_push_overwrites() {
+        let tmp = TempDir::new();
+        let bucket = MemoryBucket::default();
+        let mut ours = store(&tmp, "ours");
+        let mut theirs = store(&tmp, "theirs");
+        let object = object_name("dev", b"a");
+        theirs.put("dev", b"a", b"theirs").unwrap();
+        push(&mut theirs, &bucket, false);
+        ours.put("dev", b"a", b"ours").unwrap();
+
+        assert_eq!(push(&mut ours, &bucket, false).clashes.len(), 1);
+        let outcome = push(&mut ours, &bucket, true);
+        assert_eq!(outcome.written.len(), 1);
+        assert_eq!(bucket.value(&object).unwrap(), b"ours");
+    }
+
+    #[test]
+    fn keyring_records_wait_for_acceptance() {
+        let tmp = TempDir::new();
+        let bucket = MemoryBucket::default();
+        let mut ours = store(&tmp, "ours");
+        let mut theirs = store(&tmp, "theirs");
+        theirs.create_namespace("keyring").unwrap();
+        theirs.put("keyring", b"mallory", b"key").unwrap();
+        theirs.put("dev", b"a", b"1").unwrap();
+        push(&mut theirs, &bucket, false);
+
+        for force in [false, true] {
+            let outcome = pull(&mut ours, &bucket, force, false);
+            assert_eq!(
+                outcome.staged,
+                [("mallory".to_string(), Some(b"key".to_vec()))]
+            );
+            assert!(!ours.has_namespace("keyring"));
+        }
+        assert_eq!(ours.get("dev", b"a").unwrap().unwrap(), b"1");
+
+        let outcome = pull(&mut ours, &bucket, false, true);
+        assert!(outcome.staged.is_empty());
+        assert_eq!(ours.get("keyring", b"mallory").unwrap().unwrap(), b"key");
+
+        // Removals wait as well
+        theirs.delete("keyring", b"mallory").unwrap();
+        push(&mut theirs, &bucket, false);
+        let outcome = pull(&mut ours, &bucket, false, false);
+        assert_eq!(outcome.staged, [("mallory".to_string(), None)]);
+        assert!(ours.get("keyring", b"mallory").unwrap().is_some());
+    }
+}
@@ -0,0 +1,76 @@
+// Helpers for the unit tests of the database modules
+use super::SecDb;
+use super::handle_db::EncryptedEntry;
+use super::i_keys::i_keys;
+use super::suite::{PrivateKey, Suite};
+use crate::config::config::Config;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory under the system's temporary directory, removed again when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let name: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        let dir = std::env::temp_dir().join(format!("clenv-test-{}", name));
+        fs::create_dir(&dir).expect("temporary directory");
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Every suite, for tests that have to hold for both
+pub const SUITES: [Suite; 2] = [Suite::Rsa, Suite::X25519];
+
+/// Opens the database `db` in `dir` as `name` with an unencrypted X25519 key, creating both when needed
+pub fn open_db(dir: &TempDir, db: &str, name: &str) -> SecDb {
+    open_db_with(dir, db, name, Suite::X25519)
+}
+
+/// Like `open_db`, with a key of `suite` and entries encrypted with it
+pub fn open_db_with(dir: &TempDir, db: &str, name: &str, suite: Suite) -> SecDb {
+    let mut conf = Config::new(
+        name,
+        dir.path().join(db).to_str().unwrap(),
+        dir.path().join(format!("{}.pem", name)).to_str().unwrap(),
+        "dev",
+    );
+    conf.insert("suite", suite.name());
+    SecDb::with_passphrase(conf, Box::new(|_| Ok(String::new()))).expect("test database")
+}
+
+/// Writes `entry` over the stored entry `name` of `ns` as is, like anyone with write access to the files could
+pub fn put_raw(db: &SecDb, ns: &str, name: &str, mut entry: EncryptedEntry) {
+    db.seal_meta(name, &mut entry).unwrap();
+    let key = db.entry_key(ns, name).unwrap();
+    let cf = db.ns_cf(ns).unwrap();
+    db.db
+        .put(&cf, key.as_bytes(), &entry.to_bytes().unwrap())
+        .unwrap();
+}
+
+/// Gives `name` a key of the suite of `db` next to the others in `dir` and adds them to the keyring of `db`
+/// with access to `namespaces`, so `open_db` on the same database then opens it as them
+pub fn add_member(dir: &TempDir, db: &SecDb, name: &str, namespaces: &[&str]) {
+    let key = PrivateKey::generate(Suite::configured(&db.conf).unwrap()).unwrap();
+    let private = dir.path().join(format!("{}.pem", name));
+    i_keys::write_private_key(&key, private.to_str().unwrap(), "").unwrap();
+    let public = dir.path().join(format!("{}.pub.pem", name));
+    fs::write(&public, key.public_key().to_pem().unwrap()).unwrap();
+    let namespaces: Vec<String> = namespaces.iter().map(|ns| ns.to_string()).collect();
+    db.add_user(name, public.to_str(), &namespaces).unwrap();
+}